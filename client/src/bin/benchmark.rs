@@ -0,0 +1,171 @@
+//! Throughput benchmark in the spirit of redis-benchmark: runs each test with a growing
+//! number of concurrent clients and reports the requests per second reached.
+//!
+//! benchmark [-h host] [-p port] [-n requests] [-c clients,...] [-P pipeline] [-r keyspace] [-t test,...]
+
+use std::io::prelude::*;
+use std::net::TcpStream;
+use std::thread;
+use std::time::Instant;
+
+use utils::deserializer::deserialize_partial;
+use utils::prelude::*;
+use utils::serializer::serialize;
+use utils::DataType;
+
+const TESTS: &[&str] = &["set", "get", "incr", "exists"];
+
+struct Options {
+    host: String,
+    port: u16,
+    requests: usize,
+    clients: Vec<usize>,
+    pipeline: usize,
+    keyspace: u64,
+    tests: Vec<String>,
+}
+
+impl Options {
+    fn parse(mut args: impl Iterator<Item = String>) -> std::result::Result<Self, String> {
+        let mut options = Options {
+            host: "127.0.0.1".to_owned(),
+            port: 6379,
+            requests: 100_000,
+            clients: vec![1, 2, 4, 8, 16, 32, 64],
+            pipeline: 1,
+            keyspace: 100_000,
+            tests: TESTS.iter().map(|test| (*test).to_owned()).collect(),
+        };
+        while let Some(option) = args.next() {
+            let value = args.next().ok_or_else(|| f!("missing value for {option}"))?;
+            let number = |value: &str| value.parse::<usize>().ok().filter(|number| *number > 0).ok_or_else(|| f!("bad value for {option}: {value}"));
+            match option.as_str() {
+                "-h" => options.host = value,
+                "-p" => options.port = value.parse().map_err(|_| f!("bad port: {value}"))?,
+                "-n" => options.requests = number(&value)?,
+                "-c" => options.clients = value.split(',').map(number).collect::<std::result::Result<_, _>>()?,
+                "-P" => options.pipeline = number(&value)?,
+                "-r" => options.keyspace = number(&value)? as u64,
+                "-t" => options.tests = value.split(',').map(|test| test.to_ascii_lowercase()).collect(),
+                _ => return Err(f!("unknown option {option}")),
+            }
+        }
+        if let Some(test) = options.tests.iter().find(|test| !TESTS.contains(&test.as_str())) {
+            return Err(f!("unknown test {test}, the tests are {}", TESTS.join(", ")));
+        }
+        Ok(options)
+    }
+}
+
+/// The command `test` sends, on keys drawn by `random`.
+fn test_command(test: &str, keyspace: u64, random: &mut u64) -> Vec<u8> {
+    let mut key = || f!("key:{:012}", next_random(random) % keyspace);
+    let args = match test {
+        "set" => vec!["set".to_owned(), key(), "xxx".to_owned()],
+        "get" => vec!["get".to_owned(), key()],
+        // a single key, so every client contends for it
+        "incr" => vec!["incr".to_owned(), "counter".to_owned()],
+        // several keys, most likely in different shards
+        _ => vec!["exists".to_owned(), key(), key(), key()],
+    };
+    let args = args.into_iter().map(|arg| DataType::BulkString(Some(arg.into_bytes()))).collect();
+    serialize(&DataType::Array(Some(args))).unwrap()
+}
+
+/// Send `requests` commands of `test` over one connection, `pipeline` at a time.
+fn run_client(options: &Options, test: &str, requests: usize, seed: u64) -> std::io::Result<()> {
+    let mut stream = TcpStream::connect((options.host.as_str(), options.port))?;
+    stream.set_nodelay(true)?;
+    let mut random = seed | 1;
+    let mut pending: Vec<u8> = Vec::new();
+    let mut buffer = vec![0; 16 * 1024];
+
+    let mut sent = 0;
+    while sent < requests {
+        let batch = options.pipeline.min(requests - sent);
+        let mut commands = Vec::new();
+        for _ in 0..batch {
+            commands.extend_from_slice(&test_command(test, options.keyspace, &mut random));
+        }
+        stream.write_all(&commands)?;
+        sent += batch;
+
+        let mut replies = 0;
+        while replies < batch {
+            match deserialize_partial(&pending) {
+                Ok((_, used)) => {
+                    pending.drain(..used);
+                    replies += 1;
+                },
+                Err(Error::Incomplete) | Err(Error::EmptyInput) => {
+                    let read = stream.read(&mut buffer)?;
+                    if read == 0 {
+                        return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "connection closed"));
+                    }
+                    pending.extend_from_slice(&buffer[..read]);
+                },
+                Err(e) => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string())),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Requests per second for `test` with `clients` connections sharing `options.requests`.
+fn run(options: &Options, test: &str, clients: usize) -> std::io::Result<f64> {
+    let start = Instant::now();
+    let results: Vec<std::io::Result<()>> = thread::scope(|scope| {
+        let handles: Vec<_> = (0..clients)
+            .map(|client| {
+                // the remainder goes to the first clients
+                let requests = options.requests / clients + usize::from(client < options.requests % clients);
+                scope.spawn(move || run_client(options, test, requests, (client as u64 + 1) * 0x9e37_79b9_7f4a_7c15))
+            })
+            .collect();
+        handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+    });
+    let elapsed = start.elapsed().as_secs_f64();
+    results.into_iter().collect::<std::io::Result<Vec<()>>>()?;
+    Ok(options.requests as f64 / elapsed)
+}
+
+/// xorshift64*, plenty for picking keys.
+fn next_random(state: &mut u64) -> u64 {
+    *state ^= *state >> 12;
+    *state ^= *state << 25;
+    *state ^= *state >> 27;
+    state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+}
+
+fn main() {
+    let options = match Options::parse(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            println!("{e}");
+            println!("usage: benchmark [-h host] [-p port] [-n requests] [-c clients,...] [-P pipeline] [-r keyspace] [-t {}]", TESTS.join(","));
+            std::process::exit(1);
+        },
+    };
+
+    println!("{} requests per run, pipeline {}, {} keys", options.requests, options.pipeline, options.keyspace);
+    print!("{:>8}", "clients");
+    for test in &options.tests {
+        print!("{:>14}", test.to_ascii_uppercase());
+    }
+    println!();
+    for &clients in &options.clients {
+        print!("{clients:>8}");
+        for test in &options.tests {
+            match run(&options, test, clients) {
+                Ok(rate) => print!("{:>14}", f!("{rate:.0}/s")),
+                Err(e) => {
+                    println!();
+                    println!("{test} with {clients} clients failed: {e}");
+                    std::process::exit(1);
+                },
+            }
+            std::io::stdout().flush().unwrap();
+        }
+        println!();
+    }
+}
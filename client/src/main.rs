@@ -1,7 +1,8 @@
 use std::io::prelude::*;
 use std::net::TcpStream;
 
-use utils::deserializer::deserialize;
+use utils::deserializer::deserialize_partial;
+use utils::prelude::*;
 use utils::serializer::serialize;
 use utils::DataType;
 
@@ -18,36 +19,44 @@ fn main() -> std::io::Result<()> {
 
         let mut buf = String::new();
         let mut is_quote = false;
-        for each_char in line.chars().into_iter() {
-            if each_char == ' ' && buf.len() > 0 && !is_quote {
-                let serialized = DataType::BulkString(Some(buf.to_owned()));
+        for each_char in line.chars() {
+            if each_char == ' ' && !buf.is_empty() && !is_quote {
+                let serialized = DataType::BulkString(Some(buf.clone().into_bytes()));
                 input_arr.push(serialized);
                 buf.clear();
             } else if each_char == '"' {
                 if is_quote {
-                    let serialized = DataType::BulkString(Some(buf.to_owned()));
+                    let serialized = DataType::BulkString(Some(buf.clone().into_bytes()));
                     input_arr.push(serialized);
                     buf.clear();
                 }
 
                 is_quote = !is_quote;
-            } else if each_char.is_alphanumeric() || (each_char == ' ' && is_quote) {
+            } else if !each_char.is_whitespace() || (each_char == ' ' && is_quote) {
                 buf.push(each_char);
             }
         }
 
-        if buf.len() > 0 && !is_quote {
-            let serialized = DataType::BulkString(Some(buf.to_owned()));
+        if !buf.is_empty() && !is_quote {
+            let serialized = DataType::BulkString(Some(buf.clone().into_bytes()));
             input_arr.push(serialized);
         }
 
-        if input_arr.len() != 0  {
+        if !input_arr.is_empty() {
             let input_serialized = serialize(&DataType::Array(Some(input_arr))).unwrap();
-            stream.write(&input_serialized.as_bytes())?;
+            stream.write_all(&input_serialized)?;
 
-            let mut buf = vec![0; 1024];
-            stream.read(&mut buf).unwrap();
-            let d_buf = deserialize(&String::from_utf8(buf).unwrap()).unwrap();
+            let mut response: Vec<u8> = Vec::new();
+            let d_buf = loop {
+                let mut buf = vec![0; 1024];
+                let n = stream.read(&mut buf)?;
+                response.extend_from_slice(&buf[..n]);
+
+                match deserialize_partial(&response) {
+                    Err(Error::Incomplete) | Err(Error::EmptyInput) if n > 0 => continue,
+                    result => break result.unwrap().0,
+                }
+            };
 
             println!("{}", d_buf);
         }
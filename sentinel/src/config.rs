@@ -0,0 +1,137 @@
+//! Sentinel configuration: a `sentinel.conf` style file, with `--name value` command line
+//! arguments applied after it as extra lines.
+
+use std::time::Duration;
+
+use utils::prelude::*;
+
+pub const DEFAULT_PORT: u16 = 26379;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MasterConfig {
+    pub name: String,
+    pub ip: String,
+    pub port: u16,
+    /// Sentinels that must agree the primary is down before a failover may start.
+    pub quorum: usize,
+    /// How long the primary may go without answering before it is considered down.
+    pub down_after: Duration,
+    /// How long a failover may take, and how long to wait before retrying one.
+    pub failover_timeout: Duration,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Config {
+    pub port: u16,
+    pub masters: Vec<MasterConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self { port: DEFAULT_PORT, masters: Vec::new() }
+    }
+}
+
+/// Read the config file, if the first argument names one, then the `--name value` arguments.
+pub fn load(mut args: impl Iterator<Item = String>) -> std::result::Result<Config, String> {
+    let mut config = Config::default();
+    let mut args = args.by_ref().peekable();
+    if let Some(path) = args.next_if(|arg| !arg.starts_with("--")) {
+        let text = std::fs::read_to_string(&path).map_err(|e| f!("can't read '{path}': {e}"))?;
+        for line in text.lines() {
+            config.apply(line)?;
+        }
+    }
+
+    let mut line = String::new();
+    for arg in args {
+        if let Some(name) = arg.strip_prefix("--") {
+            config.apply(&line)?;
+            line = name.to_owned();
+        } else if line.is_empty() {
+            return Err(f!("unexpected argument '{arg}'"));
+        } else {
+            line.push(' ');
+            line.push_str(&arg);
+        }
+    }
+    config.apply(&line)?;
+    Ok(config)
+}
+
+impl Config {
+    /// Apply one config line; blank lines and `#` comments are ignored.
+    pub fn apply(&mut self, line: &str) -> std::result::Result<(), String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let invalid = || f!("invalid config line '{line}'");
+        match words.as_slice() {
+            [] => Ok(()),
+            [comment, ..] if comment.starts_with('#') => Ok(()),
+            [name, port] if name.eq_ignore_ascii_case("port") => {
+                self.port = port.parse().map_err(|_| invalid())?;
+                Ok(())
+            },
+            [sentinel, option, rest @ ..] if sentinel.eq_ignore_ascii_case("sentinel") => {
+                match (option.to_ascii_lowercase().as_str(), rest) {
+                    ("monitor", [name, ip, port, quorum]) => {
+                        let port = port.parse().map_err(|_| invalid())?;
+                        let quorum = quorum.parse().ok().filter(|quorum| *quorum > 0).ok_or_else(invalid)?;
+                        self.masters.retain(|master| master.name != *name);
+                        self.masters.push(MasterConfig {
+                            name: (*name).to_owned(),
+                            ip: (*ip).to_owned(),
+                            port,
+                            quorum,
+                            down_after: Duration::from_millis(30_000),
+                            failover_timeout: Duration::from_millis(180_000),
+                        });
+                        Ok(())
+                    },
+                    (option @ ("down-after-milliseconds" | "failover-timeout"), [name, ms]) => {
+                        let ms = Duration::from_millis(ms.parse().map_err(|_| invalid())?);
+                        let master = self.masters.iter_mut()
+                            .find(|master| master.name == *name)
+                            .ok_or_else(|| f!("no such master '{name}' in '{line}'"))?;
+                        match option {
+                            "down-after-milliseconds" => master.down_after = ms,
+                            _ => master.failover_timeout = ms,
+                        }
+                        Ok(())
+                    },
+                    _ => Err(invalid()),
+                }
+            },
+            _ => Err(invalid()),
+        }
+    }
+}
+
+
+// region: --- tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> impl Iterator<Item = String> + '_ {
+        line.split(' ').map(str::to_owned)
+    }
+
+    #[test]
+    fn arguments_read_as_config_lines() {
+        let config = load(args(
+            "--port 26380 --sentinel monitor mymaster 127.0.0.1 6379 2 \
+             --sentinel down-after-milliseconds mymaster 500",
+        )).unwrap();
+        assert_eq!(config.port, 26380);
+        assert_eq!(config.masters.len(), 1);
+        let master = &config.masters[0];
+        assert_eq!((master.name.as_str(), master.ip.as_str(), master.port, master.quorum), ("mymaster", "127.0.0.1", 6379, 2));
+        assert_eq!(master.down_after, Duration::from_millis(500));
+        assert_eq!(master.failover_timeout, Duration::from_millis(180_000));
+
+        assert!(load(args("--sentinel down-after-milliseconds other 500")).is_err());
+        assert!(load(args("--sentinel monitor mymaster 127.0.0.1 6379 0")).is_err());
+        assert!(load(args("stray --port 1")).is_err());
+    }
+}
+// endregion: --- tests
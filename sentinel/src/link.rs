@@ -0,0 +1,64 @@
+//! Talking to the monitored instances and to other sentinels.
+
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use utils::deserializer::deserialize_partial;
+use utils::prelude::*;
+use utils::serializer::serialize;
+use utils::DataType;
+
+use crate::state::Addr;
+
+/// How long a request may take, connecting included, before the instance counts as silent.
+const TIMEOUT: Duration = Duration::from_millis(1000);
+
+pub fn command(args: &[&str]) -> DataType {
+    let args = args.iter().map(|arg| DataType::BulkString(Some(arg.as_bytes().to_vec()))).collect();
+    DataType::Array(Some(args))
+}
+
+/// Send one command to `addr` on a fresh connection and read its reply. Error replies are
+/// returned as errors too.
+pub async fn request(addr: &Addr, args: &[&str]) -> std::result::Result<DataType, String> {
+    let exchange = async {
+        let mut socket = TcpStream::connect((addr.0.as_str(), addr.1)).await.map_err(|e| e.to_string())?;
+        socket.write_all(&serialize(&command(args)).unwrap()).await.map_err(|e| e.to_string())?;
+        read_reply(&mut socket, &mut Vec::new()).await
+    };
+    match tokio::time::timeout(TIMEOUT, exchange).await {
+        Ok(Ok(DataType::Error(e))) => Err(e),
+        Ok(reply) => reply,
+        Err(_) => Err("timed out".to_owned()),
+    }
+}
+
+/// Read the next reply from `socket`, keeping whatever follows it in `pending`.
+pub async fn read_reply(socket: &mut TcpStream, pending: &mut Vec<u8>) -> std::result::Result<DataType, String> {
+    loop {
+        match deserialize_partial(pending) {
+            Ok((reply, used)) => {
+                pending.drain(..used);
+                return Ok(reply);
+            },
+            Err(Error::Incomplete) | Err(Error::EmptyInput) => (),
+            Err(e) => return Err(e.to_string()),
+        }
+        let mut buffer = vec![0; 16 * 1024];
+        let read = socket.read(&mut buffer).await.map_err(|e| e.to_string())?;
+        if read == 0 {
+            return Err("connection closed".to_owned());
+        }
+        pending.extend_from_slice(&buffer[..read]);
+    }
+}
+
+/// The text of a bulk string reply, such as INFO's.
+pub fn text(reply: &DataType) -> Option<String> {
+    match reply {
+        DataType::BulkString(Some(bytes)) => Some(String::from_utf8_lossy(bytes).into_owned()),
+        DataType::SimpleString(text) => Some(text.clone()),
+        _ => None,
+    }
+}
@@ -0,0 +1,88 @@
+mod config;
+mod link;
+mod monitor;
+mod state;
+
+use std::sync::{Arc, Mutex};
+
+use state::Sentinel;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use utils::deserializer::deserialize_partial;
+use utils::prelude::*;
+use utils::serializer::serialize;
+use utils::DataType;
+
+
+async fn handle_client(mut socket: TcpStream, state: &Arc<Mutex<Sentinel>>) {
+    let mut pending: Vec<u8> = Vec::new();
+    loop {
+        let mut buffer = vec![0; 1024];
+        match socket.read(&mut buffer).await {
+            Ok(0) => break,
+            Ok(n) => {
+                pending.extend_from_slice(&buffer[..n]);
+
+                loop {
+                    let response = match deserialize_partial(&pending) {
+                        Ok((d_command, used)) => {
+                            pending.drain(..used);
+                            state.lock().unwrap().command(&arguments(d_command))
+                        },
+                        Err(Error::Incomplete) | Err(Error::EmptyInput) => break,
+                        Err(e) => {
+                            println!("Error: {}", e);
+                            pending.clear();
+                            DataType::Error("ERR error parsing data".to_owned())
+                        },
+                    };
+
+                    if let Err(e) = socket.write_all(&serialize(&response).unwrap()).await {
+                        println!("Failed to write to client: {}", e);
+                        return;
+                    }
+                }
+            },
+            Err(e) => {
+                println!("Failed to read from client: {}", e);
+                break;
+            },
+        }
+    }
+}
+
+fn arguments(command: DataType) -> Vec<String> {
+    match command {
+        DataType::Array(Some(args)) => args.iter().filter_map(link::text).collect(),
+        _ => Vec::new(),
+    }
+}
+
+#[tokio::main]
+async fn main() -> std::io::Result<()> {
+    let config = match config::load(std::env::args().skip(1)) {
+        Ok(config) => config,
+        Err(e) => {
+            println!("Bad configuration: {}", e);
+            std::process::exit(1);
+        },
+    };
+    let listener = TcpListener::bind(("127.0.0.1", config.port)).await?;
+    println!("Sentinel listening on 127.0.0.1:{}", config.port);
+
+    let sentinel = Sentinel::new(("127.0.0.1".to_owned(), config.port), &config.masters);
+    println!("Sentinel ID is {}", sentinel.runid);
+    let state = Arc::new(Mutex::new(sentinel));
+    for master in &config.masters {
+        println!("+monitor master {} {} {} quorum {}", master.name, master.ip, master.port, master.quorum);
+        tokio::spawn(monitor::run(Arc::clone(&state), master.name.clone()));
+    }
+
+    loop {
+        let (socket, _) = listener.accept().await?;
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            handle_client(socket, &state).await;
+        });
+    }
+}
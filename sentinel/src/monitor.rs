@@ -0,0 +1,233 @@
+//! The monitoring loop of one primary: check it and its replicas, exchange hellos with the
+//! other sentinels, agree on whether it is down, and fail over to a replica when elected.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::task::JoinSet;
+use utils::serializer::serialize;
+use utils::DataType;
+
+use crate::link;
+use crate::state::{random, Addr, Sentinel, HELLO_CHANNEL, PERIOD};
+
+pub async fn run(state: Arc<Mutex<Sentinel>>, name: String) {
+    let mut interval = tokio::time::interval(PERIOD);
+    loop {
+        interval.tick().await;
+        check_instances(&state, &name).await;
+        send_hellos(&state, &name);
+        if check_down(&state, &name).await {
+            failover(&state, &name).await;
+        }
+    }
+}
+
+/// Ask the primary and every replica for INFO replication, then point replicas that follow
+/// the wrong instance back at the primary.
+async fn check_instances(state: &Arc<Mutex<Sentinel>>, name: &str) {
+    let instances = state.lock().unwrap().masters[name].instances();
+    let mut checks = JoinSet::new();
+    for addr in instances {
+        checks.spawn(async move {
+            let info = link::request(&addr, &["info", "replication"]).await.ok();
+            (addr, info.as_ref().and_then(link::text))
+        });
+    }
+    let mut infos = Vec::new();
+    while let Some(Ok(info)) = checks.join_next().await {
+        infos.push(info);
+    }
+
+    let (primary, misconfigured) = {
+        let mut sentinel = state.lock().unwrap();
+        let master = sentinel.masters.get_mut(name).unwrap();
+        for (addr, info) in infos {
+            match info {
+                Some(info) if addr == master.addr => master.primary_info(&info),
+                info => master.replica_info(&addr, info.as_deref()),
+            }
+        }
+        (master.addr.clone(), master.misconfigured())
+    };
+    for addr in misconfigured {
+        let port = primary.1.to_string();
+        if link::request(&addr, &["replicaof", &primary.0, &port]).await.is_ok() {
+            println!("+fix-slave-config slave {}:{} {} {} @ {name} {} {}", addr.0, addr.1, addr.0, addr.1, primary.0, primary.1);
+        }
+    }
+}
+
+/// Publish this sentinel's hello on every instance, and follow the hello channel on any
+/// instance not followed yet.
+fn send_hellos(state: &Arc<Mutex<Sentinel>>, name: &str) {
+    let mut sentinel = state.lock().unwrap();
+    let Some(hello) = sentinel.hello(name) else {
+        return;
+    };
+    for addr in sentinel.masters[name].instances() {
+        let hello = hello.clone();
+        let publish_to = addr.clone();
+        tokio::spawn(async move {
+            let _ = link::request(&publish_to, &["publish", HELLO_CHANNEL, &hello]).await;
+        });
+        if sentinel.subscribed.insert(addr.clone()) {
+            tokio::spawn(subscribe(Arc::clone(state), addr));
+        }
+    }
+}
+
+/// Follow the hello channel on `addr` while it belongs to a monitored primary, reconnecting
+/// whenever the link drops.
+async fn subscribe(state: Arc<Mutex<Sentinel>>, addr: Addr) {
+    loop {
+        {
+            let mut sentinel = state.lock().unwrap();
+            if !sentinel.masters.values().any(|master| master.instances().contains(&addr)) {
+                sentinel.subscribed.remove(&addr);
+                return;
+            }
+        }
+        let _ = listen(&state, &addr).await;
+        tokio::time::sleep(PERIOD).await;
+    }
+}
+
+async fn listen(state: &Arc<Mutex<Sentinel>>, addr: &Addr) -> std::result::Result<(), String> {
+    let mut socket = TcpStream::connect((addr.0.as_str(), addr.1)).await.map_err(|e| e.to_string())?;
+    let subscribe = serialize(&link::command(&["subscribe", HELLO_CHANNEL])).unwrap();
+    socket.write_all(&subscribe).await.map_err(|e| e.to_string())?;
+    let mut pending = Vec::new();
+    loop {
+        let DataType::Array(Some(message)) = link::read_reply(&mut socket, &mut pending).await? else {
+            continue;
+        };
+        if let [kind, _, hello] = message.as_slice() {
+            if let (Some("message"), Some(hello)) = (link::text(kind).as_deref(), link::text(hello)) {
+                state.lock().unwrap().process_hello(&hello);
+            }
+        }
+    }
+}
+
+/// Update the down states of the primary, asking the other sentinels whether they see it
+/// down too. Returns whether a failover is due.
+async fn check_down(state: &Arc<Mutex<Sentinel>>, name: &str) -> bool {
+    let (addr, epoch, peers) = {
+        let mut sentinel = state.lock().unwrap();
+        let epoch = sentinel.current_epoch;
+        let master = sentinel.masters.get_mut(name).unwrap();
+        if !master.check_sdown() {
+            return false;
+        }
+        (master.addr.clone(), epoch, peer_addrs(&sentinel, name))
+    };
+    let replies = ask_peers(peers, &addr, epoch, "*").await;
+
+    let mut sentinel = state.lock().unwrap();
+    let master = sentinel.masters.get_mut(name).unwrap();
+    for (runid, peer) in master.peers.iter_mut() {
+        peer.says_down = replies.iter().any(|(id, down, _)| id == runid && *down);
+    }
+    master.check_odown();
+    master.should_failover()
+}
+
+/// Stand for leader of a failover in a new epoch and, once voted in by a majority, promote
+/// the best replica and point the other replicas at it.
+async fn failover(state: &Arc<Mutex<Sentinel>>, name: &str) {
+    // sentinels noticing the failure at once would split the vote, so each waits a little
+    tokio::time::sleep(Duration::from_millis(random() % 1000)).await;
+    let (epoch, runid, addr, peers) = {
+        let mut sentinel = state.lock().unwrap();
+        let Some(epoch) = sentinel.start_failover(name) else {
+            return;
+        };
+        let addr = sentinel.masters[name].addr.clone();
+        (epoch, sentinel.runid.clone(), addr, peer_addrs(&sentinel, name))
+    };
+    let replies = ask_peers(peers, &addr, epoch, &runid).await;
+
+    let promoted = {
+        let mut sentinel = state.lock().unwrap();
+        let master = sentinel.masters.get_mut(name).unwrap();
+        if master.failover_epoch != Some(epoch) {
+            return;
+        }
+        for (id, _, leader) in replies {
+            if let Some(peer) = master.peers.get_mut(&id) {
+                peer.leader = leader;
+            }
+        }
+        let (votes, majority) = (master.votes(&runid, epoch), master.majority());
+        if votes < majority {
+            println!("# {votes}/{majority} votes in epoch {epoch}");
+            master.abort_failover("not-elected");
+            return;
+        }
+        println!("+elected-leader master {name} {} {}", addr.0, addr.1);
+        let Some(promoted) = master.select_replica() else {
+            master.abort_failover("no-good-slave");
+            return;
+        };
+        println!("+selected-slave slave {}:{} {} {} @ {name} {} {}", promoted.0, promoted.1, promoted.0, promoted.1, addr.0, addr.1);
+        promoted
+    };
+
+    if let Err(e) = link::request(&promoted, &["replicaof", "no", "one"]).await {
+        println!("# promoting {}:{} failed: {}", promoted.0, promoted.1, e);
+        state.lock().unwrap().masters.get_mut(name).unwrap().abort_failover("slave-promotion-failed");
+        return;
+    }
+    println!("+promoted-slave slave {}:{} {} {} @ {name} {} {}", promoted.0, promoted.1, promoted.0, promoted.1, addr.0, addr.1);
+
+    let others: Vec<Addr> = state.lock().unwrap().masters[name].replicas.keys()
+        .filter(|replica| **replica != promoted)
+        .cloned()
+        .collect();
+    let port = promoted.1.to_string();
+    for replica in others {
+        if link::request(&replica, &["replicaof", &promoted.0, &port]).await.is_ok() {
+            println!("+slave-reconf-sent slave {}:{} {} {} @ {name} {} {}", replica.0, replica.1, replica.0, replica.1, addr.0, addr.1);
+        }
+    }
+
+    let mut sentinel = state.lock().unwrap();
+    let master = sentinel.masters.get_mut(name).unwrap();
+    if master.failover_epoch == Some(epoch) {
+        println!("+failover-end master {name} {} {}", addr.0, addr.1);
+        master.switch_to(promoted, epoch);
+    }
+}
+
+fn peer_addrs(sentinel: &Sentinel, name: &str) -> Vec<(String, Addr)> {
+    sentinel.masters[name].peers.iter().map(|(runid, peer)| (runid.clone(), peer.addr.clone())).collect()
+}
+
+/// Ask each peer SENTINEL IS-MASTER-DOWN-BY-ADDR about the primary at `addr`, with `runid`
+/// asking for their vote or `*` only for their view. Peers that answered are returned with
+/// whether they see it down and the leader they voted for.
+async fn ask_peers(peers: Vec<(String, Addr)>, addr: &Addr, epoch: u64, runid: &str) -> Vec<(String, bool, Option<(String, u64)>)> {
+    let mut requests = JoinSet::new();
+    for (id, peer) in peers {
+        let (ip, port, epoch, runid) = (addr.0.clone(), addr.1.to_string(), epoch.to_string(), runid.to_owned());
+        requests.spawn(async move {
+            let reply = link::request(&peer, &["sentinel", "is-master-down-by-addr", &ip, &port, &epoch, &runid]).await;
+            (id, reply)
+        });
+    }
+
+    let mut replies = Vec::new();
+    while let Some(Ok((id, reply))) = requests.join_next().await {
+        let Ok(DataType::Array(Some(reply))) = reply else {
+            continue;
+        };
+        if let [DataType::Integer(down), leader, DataType::Integer(leader_epoch)] = reply.as_slice() {
+            let leader = link::text(leader).filter(|leader| leader != "*").map(|leader| (leader, *leader_epoch as u64));
+            replies.push((id, *down == 1, leader));
+        }
+    }
+    replies
+}
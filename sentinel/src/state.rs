@@ -0,0 +1,642 @@
+//! What a sentinel knows about the primaries it monitors, their replicas and the other
+//! sentinels watching them, and the decisions taken from it: the subjective and objective
+//! down states, leader votes and which replica to promote.
+
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::hash::{BuildHasher, Hasher};
+use std::mem;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use utils::prelude::*;
+use utils::DataType;
+
+use crate::config::MasterConfig;
+
+pub type Addr = (String, u16);
+
+/// How often instances are checked and hellos published.
+pub const PERIOD: Duration = Duration::from_millis(1000);
+/// The channel sentinels announce themselves on, on every monitored instance.
+pub const HELLO_CHANNEL: &str = "__sentinel__:hello";
+/// Replicas that have not answered for this long are not promoted or reconfigured.
+const REPLICA_VALIDITY: Duration = Duration::from_millis(5000);
+/// How long a replica must follow the wrong instance before it is reconfigured, leaving
+/// time for the hello of a failover run elsewhere to arrive.
+const RECONFIGURE_AFTER: Duration = Duration::from_millis(4000);
+
+pub struct Replica {
+    /// When it last answered INFO.
+    last_ok: Option<Instant>,
+    /// The primary it replicates from, per its INFO; `None` while it reports being a primary.
+    pub master: Option<Addr>,
+    pub link_up: bool,
+    pub offset: u64,
+    /// Since when it has been following some instance other than the primary.
+    misconfigured_since: Option<Instant>,
+}
+
+impl Replica {
+    fn new() -> Self {
+        Self { last_ok: None, master: None, link_up: false, offset: 0, misconfigured_since: None }
+    }
+
+    fn is_valid(&self) -> bool {
+        self.last_ok.is_some_and(|at| at.elapsed() < REPLICA_VALIDITY)
+    }
+}
+
+/// Another sentinel monitoring the same primary, known from its hellos.
+pub struct Peer {
+    pub addr: Addr,
+    last_hello: Instant,
+    /// Whether it answered that it sees the primary down.
+    pub says_down: bool,
+    /// The leader it voted for, with the epoch of the vote.
+    pub leader: Option<(String, u64)>,
+}
+
+pub struct Master {
+    pub name: String,
+    pub addr: Addr,
+    pub quorum: usize,
+    pub down_after: Duration,
+    pub failover_timeout: Duration,
+    /// The epoch of the failover that made `addr` the primary.
+    pub config_epoch: u64,
+    /// When the primary last answered.
+    last_ok: Instant,
+    pub sdown: bool,
+    pub odown: bool,
+    pub replicas: BTreeMap<Addr, Replica>,
+    /// Other sentinels, by run id.
+    pub peers: BTreeMap<String, Peer>,
+    /// The leader this sentinel voted for, with the epoch of the vote.
+    pub leader: Option<(String, u64)>,
+    /// When this sentinel last tried a failover, or voted for another sentinel's.
+    last_failover: Option<Instant>,
+    /// The epoch of the failover this sentinel is running.
+    pub failover_epoch: Option<u64>,
+}
+
+impl Master {
+    fn new(config: &MasterConfig) -> Self {
+        Self {
+            name: config.name.clone(),
+            addr: (config.ip.clone(), config.port),
+            quorum: config.quorum,
+            down_after: config.down_after,
+            failover_timeout: config.failover_timeout,
+            config_epoch: 0,
+            last_ok: Instant::now(),
+            sdown: false,
+            odown: false,
+            replicas: BTreeMap::new(),
+            peers: BTreeMap::new(),
+            leader: None,
+            last_failover: None,
+            failover_epoch: None,
+        }
+    }
+
+    /// The primary, then its replicas.
+    pub fn instances(&self) -> Vec<Addr> {
+        std::iter::once(self.addr.clone()).chain(self.replicas.keys().cloned()).collect()
+    }
+
+    /// Record the primary's INFO replication reply, adding the replicas it lists.
+    pub fn primary_info(&mut self, info: &str) {
+        self.last_ok = Instant::now();
+        for (key, value) in parse_info(info) {
+            let is_replica_line = key.strip_prefix("slave").is_some_and(|index| index.parse::<usize>().is_ok());
+            let Some(addr) = is_replica_line.then(|| replica_addr(&value)).flatten() else {
+                continue;
+            };
+            if !self.replicas.contains_key(&addr) {
+                println!("+slave slave {}:{} {} {} @ {} {} {}", addr.0, addr.1, addr.0, addr.1, self.name, self.addr.0, self.addr.1);
+                self.replicas.insert(addr, Replica::new());
+            }
+        }
+    }
+
+    /// Record a replica's INFO replication reply, if it answered.
+    pub fn replica_info(&mut self, addr: &Addr, info: Option<&str>) {
+        let (Some(replica), Some(info)) = (self.replicas.get_mut(addr), info) else {
+            return;
+        };
+        let fields = parse_info(info);
+        let field = |name: &str| fields.get(name).map(String::as_str).unwrap_or_default();
+        replica.last_ok = Some(Instant::now());
+        if field("role") == "slave" {
+            replica.master = field("master_port").parse().ok().map(|port| (field("master_host").to_owned(), port));
+            replica.link_up = field("master_link_status") == "up";
+            replica.offset = field("slave_repl_offset").parse().unwrap_or(0);
+        } else {
+            replica.master = None;
+            replica.link_up = false;
+            replica.offset = field("master_repl_offset").parse().unwrap_or(0);
+        }
+        if replica.master.as_ref() == Some(&self.addr) {
+            replica.misconfigured_since = None;
+        } else {
+            replica.misconfigured_since.get_or_insert_with(Instant::now);
+        }
+    }
+
+    /// Replicas to point back at the primary: those answering but following another
+    /// instance for a while, or acting as primaries themselves, as a failed primary does once
+    /// it is back. Nothing is touched unless the primary is answering and not being failed over.
+    pub fn misconfigured(&self) -> Vec<Addr> {
+        if self.last_ok.elapsed() > PERIOD * 2 || self.failover_epoch.is_some() {
+            return Vec::new();
+        }
+        self.replicas.iter()
+            .filter(|(_, replica)| {
+                replica.is_valid() && replica.misconfigured_since.is_some_and(|since| since.elapsed() > RECONFIGURE_AFTER)
+            })
+            .map(|(addr, _)| addr.clone())
+            .collect()
+    }
+
+    /// Update the subjective down state from how long the primary has been silent, and
+    /// return it. A primary that is not down here is not objectively down either.
+    pub fn check_sdown(&mut self) -> bool {
+        let down = self.last_ok.elapsed() > self.down_after;
+        if down != self.sdown {
+            self.sdown = down;
+            println!("{}sdown master {} {} {}", if down { '+' } else { '-' }, self.name, self.addr.0, self.addr.1);
+        }
+        if !down {
+            for peer in self.peers.values_mut() {
+                peer.says_down = false;
+            }
+            self.check_odown();
+        }
+        down
+    }
+
+    /// The primary is objectively down once the quorum of sentinels, this one included,
+    /// sees it down.
+    pub fn check_odown(&mut self) {
+        let agreeing = 1 + self.peers.values().filter(|peer| peer.says_down).count();
+        let odown = self.sdown && agreeing >= self.quorum;
+        if odown != self.odown {
+            self.odown = odown;
+            let (sign, name, (ip, port)) = (if odown { '+' } else { '-' }, &self.name, &self.addr);
+            println!("{sign}odown master {name} {ip} {port} #quorum {agreeing}/{}", self.quorum);
+        }
+    }
+
+    /// Whether to try a failover: the primary is objectively down, and no failover by this
+    /// sentinel or one it voted for was tried recently.
+    pub fn should_failover(&self) -> bool {
+        self.odown
+            && self.failover_epoch.is_none()
+            && self.last_failover.is_none_or(|at| at.elapsed() > self.failover_timeout * 2)
+    }
+
+    /// Votes needed to lead a failover: a majority of the sentinels known, and no fewer than
+    /// the quorum.
+    pub fn majority(&self) -> usize {
+        let sentinels = self.peers.len() + 1;
+        (sentinels / 2 + 1).max(self.quorum)
+    }
+
+    /// Votes for `runid` in `epoch`, this sentinel's included.
+    pub fn votes(&self, runid: &str, epoch: u64) -> usize {
+        let for_runid = |leader: &Option<(String, u64)>| leader.as_ref().is_some_and(|(id, at)| id == runid && *at == epoch);
+        for_runid(&self.leader) as usize + self.peers.values().filter(|peer| for_runid(&peer.leader)).count()
+    }
+
+    /// The replica to promote: one still answering, with the most replicated data, ties
+    /// going to the lowest address.
+    pub fn select_replica(&self) -> Option<Addr> {
+        self.replicas.iter()
+            .filter(|(_, replica)| replica.is_valid() && replica.master.is_some())
+            .max_by(|(a, ra), (b, rb)| ra.offset.cmp(&rb.offset).then_with(|| b.cmp(a)))
+            .map(|(addr, _)| addr.clone())
+    }
+
+    pub fn abort_failover(&mut self, reason: &str) {
+        self.failover_epoch = None;
+        println!("-failover-abort-{reason} master {} {} {}", self.name, self.addr.0, self.addr.1);
+    }
+
+    /// Make `addr` the primary as of `epoch`. The old primary stays on as a replica, to be
+    /// reconfigured once it answers again.
+    pub fn switch_to(&mut self, addr: Addr, epoch: u64) {
+        println!("+switch-master {} {} {} {} {}", self.name, self.addr.0, self.addr.1, addr.0, addr.1);
+        let old = mem::replace(&mut self.addr, addr);
+        self.replicas.remove(&self.addr);
+        self.replicas.insert(old, Replica::new());
+        for replica in self.replicas.values_mut() {
+            replica.misconfigured_since = None;
+        }
+        self.config_epoch = epoch;
+        self.last_ok = Instant::now();
+        self.sdown = false;
+        self.odown = false;
+        self.failover_epoch = None;
+        for peer in self.peers.values_mut() {
+            peer.says_down = false;
+        }
+    }
+
+    fn flags(&self) -> String {
+        let mut flags = "master".to_owned();
+        for (set, flag) in [(self.sdown, ",s_down"), (self.odown, ",o_down"), (self.failover_epoch.is_some(), ",failover_in_progress")] {
+            if set {
+                flags += flag;
+            }
+        }
+        flags
+    }
+
+    fn fields(&self) -> DataType {
+        fields(&[
+            ("name", self.name.clone()),
+            ("ip", self.addr.0.clone()),
+            ("port", self.addr.1.to_string()),
+            ("flags", self.flags()),
+            ("last-ok-ping-reply", self.last_ok.elapsed().as_millis().to_string()),
+            ("num-slaves", self.replicas.len().to_string()),
+            ("num-other-sentinels", self.peers.len().to_string()),
+            ("quorum", self.quorum.to_string()),
+            ("config-epoch", self.config_epoch.to_string()),
+            ("down-after-milliseconds", self.down_after.as_millis().to_string()),
+            ("failover-timeout", self.failover_timeout.as_millis().to_string()),
+        ])
+    }
+}
+
+pub struct Sentinel {
+    pub runid: String,
+    pub addr: Addr,
+    pub current_epoch: u64,
+    pub masters: BTreeMap<String, Master>,
+    /// Instances a task follows the hello channel on.
+    pub subscribed: BTreeSet<Addr>,
+}
+
+impl Sentinel {
+    pub fn new(addr: Addr, masters: &[MasterConfig]) -> Self {
+        let runid: String = (0..3).map(|_| f!("{:016x}", random())).collect();
+        Self {
+            runid: runid[..40].to_owned(),
+            addr,
+            current_epoch: 0,
+            masters: masters.iter().map(|config| (config.name.clone(), Master::new(config))).collect(),
+            subscribed: BTreeSet::new(),
+        }
+    }
+
+    /// This sentinel's hello for the primary `name`: its address, run id and epoch, then the
+    /// primary as it knows it and the epoch of that configuration.
+    pub fn hello(&self, name: &str) -> Option<String> {
+        let master = self.masters.get(name)?;
+        Some(f!(
+            "{},{},{},{},{},{},{},{}",
+            self.addr.0, self.addr.1, self.runid, self.current_epoch,
+            name, master.addr.0, master.addr.1, master.config_epoch,
+        ))
+    }
+
+    /// Learn about another sentinel from its hello, and take up the primary it announces
+    /// when that comes from a newer failover than the one known here.
+    pub fn process_hello(&mut self, hello: &str) {
+        let fields: Vec<&str> = hello.split(',').collect();
+        let [ip, port, runid, epoch, name, master_ip, master_port, config_epoch] = fields.as_slice() else {
+            return;
+        };
+        let (Ok(port), Ok(epoch), Ok(master_port), Ok(config_epoch)) =
+            (port.parse::<u16>(), epoch.parse::<u64>(), master_port.parse::<u16>(), config_epoch.parse::<u64>())
+        else {
+            return;
+        };
+        if *runid == self.runid {
+            return;
+        }
+        self.observe_epoch(epoch);
+        let Some(master) = self.masters.get_mut(*name) else {
+            return;
+        };
+
+        let addr = ((*ip).to_owned(), port);
+        // a sentinel restarted with a new run id replaces its old entry
+        master.peers.retain(|id, peer| id == runid || peer.addr != addr);
+        let peer = master.peers.entry((*runid).to_owned()).or_insert_with(|| {
+            println!("+sentinel sentinel {runid} {ip} {port} @ {name} {} {}", master.addr.0, master.addr.1);
+            Peer { addr: addr.clone(), last_hello: Instant::now(), says_down: false, leader: None }
+        });
+        peer.addr = addr;
+        peer.last_hello = Instant::now();
+
+        if config_epoch > master.config_epoch {
+            let announced = ((*master_ip).to_owned(), master_port);
+            if announced == master.addr {
+                master.config_epoch = config_epoch;
+            } else {
+                println!("+config-update-from sentinel {runid} {ip} {port} @ {name} {} {}", master.addr.0, master.addr.1);
+                master.switch_to(announced, config_epoch);
+            }
+        }
+    }
+
+    fn observe_epoch(&mut self, epoch: u64) {
+        if epoch > self.current_epoch {
+            self.current_epoch = epoch;
+            println!("+new-epoch {epoch}");
+        }
+    }
+
+    /// Start a failover of `name` in a new epoch, voting for this sentinel. Returns the
+    /// epoch, or `None` when no failover is due.
+    pub fn start_failover(&mut self, name: &str) -> Option<u64> {
+        let master = self.masters.get_mut(name).filter(|master| master.should_failover())?;
+        self.current_epoch += 1;
+        let epoch = self.current_epoch;
+        println!("+new-epoch {epoch}");
+        println!("+try-failover master {name} {} {}", master.addr.0, master.addr.1);
+        println!("+vote-for-leader {} {epoch}", self.runid);
+        master.leader = Some((self.runid.clone(), epoch));
+        master.last_failover = Some(Instant::now());
+        master.failover_epoch = Some(epoch);
+        for peer in master.peers.values_mut() {
+            peer.leader = None;
+        }
+        Some(epoch)
+    }
+
+    /// SENTINEL IS-MASTER-DOWN-BY-ADDR: whether the primary at `addr` is down here and, when
+    /// `runid` is not `*`, the leader voted for in `epoch`. The vote in an epoch goes to the
+    /// first sentinel asking for it.
+    pub fn is_master_down_by_addr(&mut self, addr: &Addr, epoch: u64, runid: &str) -> DataType {
+        let Some(master) = self.masters.values_mut().find(|master| master.addr == *addr) else {
+            return vote_reply(false, "*", 0);
+        };
+        if runid == "*" {
+            return vote_reply(master.sdown, "*", 0);
+        }
+
+        if epoch > self.current_epoch {
+            self.current_epoch = epoch;
+            println!("+new-epoch {epoch}");
+        }
+        if master.leader.as_ref().is_none_or(|(_, at)| *at < epoch) && self.current_epoch <= epoch {
+            println!("+vote-for-leader {runid} {epoch}");
+            master.leader = Some((runid.to_owned(), epoch));
+            if runid != self.runid {
+                // leave the failover to the sentinel voted for
+                master.last_failover = Some(Instant::now());
+            }
+        }
+        let (leader, leader_epoch) = master.leader.as_ref().map_or(("*", 0), |(id, at)| (id.as_str(), *at));
+        vote_reply(master.sdown, leader, leader_epoch)
+    }
+
+    /// Run a command from a client or another sentinel.
+    pub fn command(&mut self, args: &[String]) -> DataType {
+        let Some(name) = args.first() else {
+            return DataType::Error("ERR empty command".to_owned());
+        };
+        match (name.to_ascii_lowercase().as_str(), &args[1..]) {
+            ("ping", []) => DataType::SimpleString("PONG".to_owned()),
+            ("role", []) => DataType::Array(Some(vec![
+                bulk("sentinel"),
+                DataType::Array(Some(self.masters.keys().map(|name| bulk(name)).collect())),
+            ])),
+            ("info", [] | [_]) => bulk(&self.info()),
+            ("sentinel", [sub, rest @ ..]) => self.sentinel_command(sub, rest),
+            (command, _) => DataType::Error(f!("ERR unknown command '{command}', or wrong number of arguments")),
+        }
+    }
+
+    fn sentinel_command(&mut self, sub: &str, args: &[String]) -> DataType {
+        let sub = sub.to_ascii_lowercase();
+        let no_such_master = || DataType::Error("ERR No such master with that name".to_owned());
+        match (sub.as_str(), args) {
+            ("myid", []) => bulk(&self.runid),
+            ("masters", []) => DataType::Array(Some(self.masters.values().map(Master::fields).collect())),
+            ("master", [name]) => self.masters.get(name).map_or_else(no_such_master, Master::fields),
+            ("get-master-addr-by-name", [name]) => match self.masters.get(name) {
+                Some(master) => DataType::Array(Some(vec![bulk(&master.addr.0), bulk(&master.addr.1.to_string())])),
+                None => DataType::Array(None),
+            },
+            ("replicas" | "slaves", [name]) => {
+                let Some(master) = self.masters.get(name) else {
+                    return no_such_master();
+                };
+                let replicas = master.replicas.iter().map(|((ip, port), replica)| {
+                    let (master_host, master_port) = replica.master.clone().unwrap_or(("?".to_owned(), 0));
+                    fields(&[
+                        ("name", f!("{ip}:{port}")),
+                        ("ip", ip.clone()),
+                        ("port", port.to_string()),
+                        ("flags", if replica.is_valid() { "slave" } else { "s_down,slave" }.to_owned()),
+                        ("master-link-status", if replica.link_up { "ok" } else { "err" }.to_owned()),
+                        ("master-host", master_host),
+                        ("master-port", master_port.to_string()),
+                        ("slave-repl-offset", replica.offset.to_string()),
+                    ])
+                });
+                DataType::Array(Some(replicas.collect()))
+            },
+            ("sentinels", [name]) => {
+                let Some(master) = self.masters.get(name) else {
+                    return no_such_master();
+                };
+                let peers = master.peers.iter().map(|(runid, peer)| fields(&[
+                    ("name", runid.clone()),
+                    ("ip", peer.addr.0.clone()),
+                    ("port", peer.addr.1.to_string()),
+                    ("runid", runid.clone()),
+                    ("flags", "sentinel".to_owned()),
+                    ("last-hello-message", peer.last_hello.elapsed().as_millis().to_string()),
+                ]));
+                DataType::Array(Some(peers.collect()))
+            },
+            ("is-master-down-by-addr", [ip, port, epoch, runid]) => {
+                let (Ok(port), Ok(epoch)) = (port.parse(), epoch.parse()) else {
+                    return DataType::Error("ERR value is not an integer or out of range".to_owned());
+                };
+                self.is_master_down_by_addr(&(ip.clone(), port), epoch, runid)
+            },
+            _ => DataType::Error(f!("ERR Unknown sentinel subcommand or wrong number of arguments for '{sub}'")),
+        }
+    }
+
+    fn info(&self) -> String {
+        let mut info = f!("# Sentinel\r\nsentinel_masters:{}\r\n", self.masters.len());
+        for (index, master) in self.masters.values().enumerate() {
+            let status = if master.odown { "odown" } else if master.sdown { "sdown" } else { "ok" };
+            info += &f!(
+                "master{index}:name={},status={status},address={}:{},slaves={},sentinels={}\r\n",
+                master.name, master.addr.0, master.addr.1, master.replicas.len(), master.peers.len() + 1,
+            );
+        }
+        info
+    }
+}
+
+/// `name:value` lines of an INFO reply.
+fn parse_info(info: &str) -> HashMap<String, String> {
+    info.lines()
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.to_owned(), value.trim().to_owned()))
+        .collect()
+}
+
+/// The address in an INFO `slaveN:ip=...,port=...` value.
+fn replica_addr(value: &str) -> Option<Addr> {
+    let fields: HashMap<&str, &str> = value.split(',').filter_map(|field| field.split_once('=')).collect();
+    Some(((*fields.get("ip")?).to_owned(), fields.get("port")?.parse().ok()?))
+}
+
+fn vote_reply(down: bool, leader: &str, epoch: u64) -> DataType {
+    DataType::Array(Some(vec![DataType::Integer(down as i64), bulk(leader), DataType::Integer(epoch as i64)]))
+}
+
+fn bulk(text: &str) -> DataType {
+    DataType::BulkString(Some(text.as_bytes().to_vec()))
+}
+
+fn fields(pairs: &[(&str, String)]) -> DataType {
+    DataType::Array(Some(pairs.iter().flat_map(|(name, value)| [bulk(name), bulk(value)]).collect()))
+}
+
+/// A random number from the randomly keyed std hasher.
+pub fn random() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos());
+    hasher.finish()
+}
+
+
+// region: --- tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(quorum: usize) -> MasterConfig {
+        MasterConfig {
+            name: "mymaster".to_owned(),
+            ip: "127.0.0.1".to_owned(),
+            port: 6379,
+            quorum,
+            down_after: Duration::from_millis(30_000),
+            failover_timeout: Duration::from_millis(180_000),
+        }
+    }
+
+    fn addr(port: u16) -> Addr {
+        ("127.0.0.1".to_owned(), port)
+    }
+
+    #[test]
+    fn best_replica_is_promoted() {
+        let mut master = Master::new(&config(2));
+        master.primary_info(
+            "# Replication\r\nrole:master\r\nconnected_slaves:3\r\n\
+             slave0:ip=127.0.0.1,port=6380,state=online,offset=10,lag=0\r\n\
+             slave1:ip=127.0.0.1,port=6381,state=online,offset=30,lag=0\r\n\
+             slave2:ip=127.0.0.1,port=6382,state=online,offset=30,lag=0\r\n",
+        );
+        assert_eq!(master.instances(), vec![addr(6379), addr(6380), addr(6381), addr(6382)]);
+        // nothing is known to be reachable yet
+        assert_eq!(master.select_replica(), None);
+
+        let info = |offset: u64| f!("role:slave\r\nmaster_host:127.0.0.1\r\nmaster_port:6379\r\nmaster_link_status:down\r\nslave_repl_offset:{offset}\r\n");
+        master.replica_info(&addr(6380), Some(&info(10)));
+        master.replica_info(&addr(6381), None);
+        master.replica_info(&addr(6382), Some(&info(30)));
+        assert_eq!(master.select_replica(), Some(addr(6382)));
+        master.replica_info(&addr(6381), Some(&info(30)));
+        assert_eq!(master.select_replica(), Some(addr(6381)));
+        assert!(master.misconfigured().is_empty());
+
+        master.switch_to(addr(6381), 1);
+        assert_eq!(master.instances(), vec![addr(6381), addr(6379), addr(6380), addr(6382)]);
+        // the others still follow the old primary, which is back as a primary itself, but they
+        // are left alone for a while in case a failover elsewhere is yet to be announced
+        master.replica_info(&addr(6380), Some(&info(30)));
+        master.replica_info(&addr(6382), Some(&info(30)));
+        master.replica_info(&addr(6379), Some("role:master\r\nmaster_repl_offset:30\r\n"));
+        assert!(master.misconfigured().is_empty());
+        for replica in master.replicas.values_mut() {
+            if let Some(since) = replica.misconfigured_since.as_mut() {
+                *since -= RECONFIGURE_AFTER;
+            }
+        }
+        assert_eq!(master.misconfigured(), vec![addr(6379), addr(6380), addr(6382)]);
+    }
+
+    #[test]
+    fn one_vote_per_epoch() {
+        let mut sentinel = Sentinel::new(addr(26379), &[config(1)]);
+        let primary = addr(6379);
+        assert_eq!(sentinel.is_master_down_by_addr(&primary, 1, "a"), vote_reply(false, "a", 1));
+        assert_eq!(sentinel.is_master_down_by_addr(&primary, 1, "b"), vote_reply(false, "a", 1));
+        assert_eq!(sentinel.is_master_down_by_addr(&primary, 0, "b"), vote_reply(false, "a", 1));
+        assert_eq!(sentinel.is_master_down_by_addr(&primary, 2, "b"), vote_reply(false, "b", 2));
+        assert_eq!(sentinel.current_epoch, 2);
+        assert_eq!(sentinel.is_master_down_by_addr(&addr(1), 3, "a"), vote_reply(false, "*", 0));
+
+        // having voted for another, this sentinel leaves the failover to it
+        let master = sentinel.masters.get_mut("mymaster").unwrap();
+        master.sdown = true;
+        master.check_odown();
+        assert!(master.odown);
+        assert!(!master.should_failover());
+    }
+
+    #[test]
+    fn elected_with_a_majority() {
+        let mut sentinel = Sentinel::new(addr(26379), &[config(2)]);
+        for port in [26380, 26381] {
+            let hello = f!("127.0.0.1,{port},peer{port},0,mymaster,127.0.0.1,6379,0");
+            sentinel.process_hello(&hello);
+        }
+        let master = sentinel.masters.get_mut("mymaster").unwrap();
+        assert_eq!(master.peers.len(), 2);
+        assert_eq!(master.majority(), 2);
+
+        master.sdown = true;
+        master.check_odown();
+        assert!(!master.odown);
+        master.peers.get_mut("peer26380").unwrap().says_down = true;
+        master.check_odown();
+        assert!(master.odown);
+
+        let epoch = sentinel.start_failover("mymaster").unwrap();
+        assert_eq!(epoch, 1);
+        assert_eq!(sentinel.start_failover("mymaster"), None);
+        let runid = sentinel.runid.clone();
+        let master = sentinel.masters.get_mut("mymaster").unwrap();
+        assert_eq!(master.votes(&runid, epoch), 1);
+        master.peers.get_mut("peer26381").unwrap().leader = Some((runid.clone(), epoch));
+        master.peers.get_mut("peer26380").unwrap().leader = Some(("peer26380".to_owned(), epoch));
+        assert_eq!(master.votes(&runid, epoch), 2);
+    }
+
+    #[test]
+    fn hellos_carry_newer_configurations() {
+        let mut sentinel = Sentinel::new(addr(26379), &[config(2)]);
+        let own = sentinel.hello("mymaster").unwrap();
+        sentinel.process_hello(&own);
+        assert!(sentinel.masters["mymaster"].peers.is_empty());
+
+        sentinel.process_hello("127.0.0.1,26380,peer,5,mymaster,127.0.0.1,6380,4");
+        let master = &sentinel.masters["mymaster"];
+        assert_eq!((sentinel.current_epoch, master.config_epoch), (5, 4));
+        assert_eq!(master.addr, addr(6380));
+        assert!(master.replicas.contains_key(&addr(6379)));
+
+        // an older configuration is ignored, and a restarted sentinel replaces its entry
+        sentinel.process_hello("127.0.0.1,26380,restarted,0,mymaster,127.0.0.1,6379,0");
+        let master = &sentinel.masters["mymaster"];
+        assert_eq!(master.addr, addr(6380));
+        assert_eq!(master.peers.keys().collect::<Vec<_>>(), vec!["restarted"]);
+        assert_eq!(sentinel.hello("mymaster").unwrap(), f!("127.0.0.1,26379,{},5,mymaster,127.0.0.1,6380,4", sentinel.runid));
+    }
+}
+// endregion: --- tests
@@ -0,0 +1,391 @@
+//! The append only file: each write command is appended in RESP form once it has run. Files use
+//! the Redis 7 multi part layout, a base file plus incremental files listed by a manifest in
+//! `appenddirname`, so a rewrite only has to start a new incremental file and write a new base.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use utils::deserializer::deserialize_partial;
+use utils::prelude::*;
+use utils::DataType;
+
+use crate::rdb::{self, Snapshot};
+
+/// When appended commands are flushed to disk with fsync.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Fsync {
+    /// After every command, before it is acknowledged.
+    Always,
+    /// About once a second, from the server cron.
+    EverySec,
+    /// Whenever the operating system decides.
+    No,
+}
+
+impl Fsync {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "always" => Some(Self::Always),
+            "everysec" => Some(Self::EverySec),
+            "no" => Some(Self::No),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Always => "always",
+            Self::EverySec => "everysec",
+            Self::No => "no",
+        }
+    }
+}
+
+/// One file listed in the manifest.
+#[derive(Clone)]
+struct Part {
+    name: String,
+    seq: u64,
+    base: bool,
+}
+
+/// What a file of the AOF held, in the order it should be applied.
+pub enum Loaded {
+    /// A base file written as an RDB snapshot.
+    Snapshot(Snapshot),
+    Commands(Vec<DataType>),
+}
+
+pub struct Aof {
+    dir: PathBuf,
+    filename: String,
+    /// Manifest contents: at most one base, then incremental files oldest first.
+    parts: Vec<Part>,
+    /// The newest incremental file, open while commands are being appended.
+    incr: Option<File>,
+    last_fsync: Instant,
+    /// Replication offset reached by the commands written, and by those known to be on disk.
+    offset: u64,
+    fsynced_offset: u64,
+    /// Sequence number and outcome of a rewrite running in the background.
+    rewrite: Option<(u64, Receiver<io::Result<()>>)>,
+}
+
+impl Aof {
+    pub fn new(dir: PathBuf, filename: &str) -> Self {
+        Self {
+            dir,
+            filename: filename.to_owned(),
+            parts: Vec::new(),
+            incr: None,
+            last_fsync: Instant::now(),
+            offset: 0,
+            fsynced_offset: 0,
+            rewrite: None,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.incr.is_some()
+    }
+
+    pub fn rewrite_in_progress(&self) -> bool {
+        self.rewrite.is_some()
+    }
+
+    /// Read the manifest and every file it lists, `None` when there is no manifest yet. An
+    /// incomplete command at the end of the last file, as a crash mid-write leaves, is cut off.
+    pub fn load(&mut self) -> std::result::Result<Option<Vec<Loaded>>, String> {
+        let manifest = match fs::read_to_string(self.manifest_path()) {
+            Ok(manifest) => manifest,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.to_string()),
+        };
+        self.parts = parse_manifest(&manifest)?;
+
+        let mut loaded = Vec::new();
+        let last = self.parts.len().saturating_sub(1);
+        for (index, part) in self.parts.iter().enumerate() {
+            let path = self.dir.join(&part.name);
+            let bytes = fs::read(&path).map_err(|e| f!("{}: {e}", part.name))?;
+            if bytes.starts_with(b"REDIS") {
+                loaded.push(Loaded::Snapshot(rdb::decode(&bytes).map_err(|e| f!("{}: {e}", part.name))?));
+                continue;
+            }
+
+            let (commands, used) = parse_commands(&bytes).map_err(|e| f!("{}: {e}", part.name))?;
+            if used < bytes.len() {
+                if index != last {
+                    return Err(f!("{}: unexpected end of file", part.name));
+                }
+                println!("!!! Warning: short read while loading the AOF file {}, truncating to {} bytes", part.name, used);
+                OpenOptions::new().write(true).open(&path)
+                    .and_then(|file| file.set_len(used as u64))
+                    .map_err(|e| f!("{}: {e}", part.name))?;
+            }
+            loaded.push(Loaded::Commands(commands));
+        }
+        Ok(Some(loaded))
+    }
+
+    /// Start appending to the newest incremental file, creating the AOF from `snapshot` when
+    /// there is none yet. `offset` is the replication offset the dataset is at.
+    pub fn enable(&mut self, snapshot: &Snapshot, offset: u64) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        if !self.parts.iter().any(|part| !part.base) {
+            let base = self.part_name(1, true);
+            write_synced(&self.dir.join(&base), &rdb::encode(snapshot))?;
+            File::create(self.dir.join(self.part_name(1, false)))?;
+            self.parts = vec![
+                Part { name: base, seq: 1, base: true },
+                Part { name: self.part_name(1, false), seq: 1, base: false },
+            ];
+            self.write_manifest()?;
+        }
+
+        let newest = &self.parts[self.parts.len() - 1];
+        self.incr = Some(OpenOptions::new().append(true).open(self.dir.join(&newest.name))?);
+        self.last_fsync = Instant::now();
+        self.offset = offset;
+        self.fsynced_offset = offset;
+        Ok(())
+    }
+
+    /// Stop appending, flushing what was written.
+    pub fn disable(&mut self) {
+        if let Some(file) = self.incr.take() {
+            let _ = file.sync_data();
+        }
+    }
+
+    /// Append one serialized command, which takes the replication stream to `offset`.
+    pub fn feed(&mut self, command: &[u8], fsync: Fsync, offset: u64) {
+        let Some(file) = &mut self.incr else { return };
+        let written = file.write_all(command).and_then(|_| match fsync {
+            Fsync::Always => file.sync_data(),
+            _ => Ok(()),
+        });
+        if let Err(e) = written {
+            println!("Error writing to the AOF file: {}", e);
+            return;
+        }
+        self.offset = offset;
+        // under `no` flushing is up to the OS, so a write counts as done
+        if fsync != Fsync::EverySec {
+            self.fsynced_offset = offset;
+        }
+    }
+
+    /// The replication offset up to which writes are known to be on disk, for WAITAOF. With
+    /// nothing left to fsync that is `current`, as the rest of the stream is no data to keep.
+    pub fn fsynced_offset(&self, current: u64) -> u64 {
+        match self.fsynced_offset == self.offset {
+            true => current,
+            false => self.fsynced_offset,
+        }
+    }
+
+    /// Start a rewrite: new commands go to a fresh incremental file at once, while a new base
+    /// is written from `snapshot` on another thread. Returns false when one is already running.
+    pub fn rewrite(&mut self, snapshot: Snapshot) -> io::Result<bool> {
+        if self.rewrite.is_some() {
+            return Ok(false);
+        }
+        fs::create_dir_all(&self.dir)?;
+
+        let seq = self.parts.iter().map(|part| part.seq).max().unwrap_or(0) + 1;
+        let incr = self.part_name(seq, false);
+        let file = File::create(self.dir.join(&incr))?;
+        // listed right away, so the commands it gets are not lost if the rewrite never finishes
+        self.parts.push(Part { name: incr, seq, base: false });
+        self.write_manifest()?;
+        if self.incr.is_some() {
+            self.disable();
+            self.incr = Some(file);
+        }
+
+        let (done, outcome) = mpsc::channel();
+        let temp = self.dir.join(f!("temp-rewriteaof-bg-{}.aof", std::process::id()));
+        thread::spawn(move || {
+            let _ = done.send(write_synced(&temp, &rdb::encode(&snapshot)));
+        });
+        self.rewrite = Some((seq, outcome));
+        Ok(true)
+    }
+
+    /// Server cron work: fsync once a second under `everysec`, and finish a completed rewrite
+    /// by swapping in the new base and dropping the files it replaces.
+    pub fn tick(&mut self, fsync: Fsync) {
+        if let Some(file) = &self.incr {
+            if fsync == Fsync::EverySec && self.last_fsync.elapsed() >= Duration::from_secs(1) {
+                match file.sync_data() {
+                    Ok(()) => self.fsynced_offset = self.offset,
+                    Err(e) => println!("Error syncing the AOF file: {}", e),
+                }
+                self.last_fsync = Instant::now();
+            }
+        }
+
+        let Some((seq, outcome)) = &self.rewrite else { return };
+        let seq = *seq;
+        let result = match outcome.try_recv() {
+            Ok(result) => result,
+            Err(TryRecvError::Empty) => return,
+            Err(TryRecvError::Disconnected) => Err(io::Error::other("rewrite thread exited")),
+        };
+        self.rewrite = None;
+
+        match result.and_then(|_| self.finish_rewrite(seq)) {
+            Ok(()) => println!("Background AOF rewrite finished successfully"),
+            Err(e) => println!("Background AOF rewrite failed: {}", e),
+        }
+    }
+
+    fn finish_rewrite(&mut self, seq: u64) -> io::Result<()> {
+        let base = self.part_name(seq, true);
+        let temp = self.dir.join(f!("temp-rewriteaof-bg-{}.aof", std::process::id()));
+        fs::rename(temp, self.dir.join(&base))?;
+
+        let (kept, replaced): (Vec<Part>, Vec<Part>) = self.parts.drain(..).partition(|part| part.seq >= seq);
+        self.parts = vec![Part { name: base, seq, base: true }];
+        self.parts.extend(kept);
+        self.write_manifest()?;
+
+        for part in replaced {
+            let _ = fs::remove_file(self.dir.join(part.name));
+        }
+        Ok(())
+    }
+
+    fn part_name(&self, seq: u64, base: bool) -> String {
+        if base {
+            f!("{}.{seq}.base.rdb", self.filename)
+        } else {
+            f!("{}.{seq}.incr.aof", self.filename)
+        }
+    }
+
+    fn manifest_path(&self) -> PathBuf {
+        self.dir.join(f!("{}.manifest", self.filename))
+    }
+
+    fn write_manifest(&self) -> io::Result<()> {
+        let manifest: String = self.parts.iter()
+            .map(|part| f!("file {} seq {} type {}\n", part.name, part.seq, if part.base { "b" } else { "i" }))
+            .collect();
+        let temp = self.dir.join(f!("temp-{}.manifest", self.filename));
+        write_synced(&temp, manifest.as_bytes())?;
+        fs::rename(temp, self.manifest_path())
+    }
+}
+
+/// Parse manifest lines of the form `file <name> seq <n> type <b|h|i>`. History files, left
+/// over from an interrupted cleanup, are not loaded.
+fn parse_manifest(manifest: &str) -> std::result::Result<Vec<Part>, String> {
+    let mut parts = Vec::new();
+    for line in manifest.lines().filter(|line| !line.trim().is_empty() && !line.starts_with('#')) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let mut name = None;
+        let mut seq = None;
+        let mut kind = None;
+        for pair in fields.chunks(2) {
+            match pair {
+                ["file", value] => name = Some(value.to_string()),
+                ["seq", value] => seq = value.parse::<u64>().ok(),
+                ["type", value] => kind = Some(*value),
+                _ => (),
+            }
+        }
+
+        let (Some(name), Some(seq), Some(kind)) = (name, seq, kind) else {
+            return Err(f!("invalid AOF manifest line: {line}"));
+        };
+        match kind {
+            "b" => parts.insert(0, Part { name, seq, base: true }),
+            "i" => parts.push(Part { name, seq, base: false }),
+            "h" => (),
+            _ => return Err(f!("invalid AOF manifest line: {line}")),
+        }
+    }
+    Ok(parts)
+}
+
+/// Commands in `bytes`, with how many bytes the complete ones used.
+fn parse_commands(bytes: &[u8]) -> std::result::Result<(Vec<DataType>, usize), String> {
+    let mut commands = Vec::new();
+    let mut used = 0;
+    loop {
+        match deserialize_partial(&bytes[used..]) {
+            Ok((command, len)) => {
+                commands.push(command);
+                used += len;
+            },
+            Err(Error::Incomplete) | Err(Error::EmptyInput) => return Ok((commands, used)),
+            Err(e) => return Err(f!("bad file format reading the append only file: {e}")),
+        }
+    }
+}
+
+fn write_synced(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let mut file = File::create(path)?;
+    file.write_all(bytes)?;
+    file.sync_all()
+}
+
+
+// region: --- tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manifest_lists_base_first() {
+        let parts = parse_manifest("file a.2.incr.aof seq 2 type i\nfile a.1.base.rdb seq 1 type b\nfile a.0.incr.aof seq 0 type h\n").unwrap();
+        let names: Vec<&str> = parts.iter().map(|part| part.name.as_str()).collect();
+        assert_eq!(names, ["a.1.base.rdb", "a.2.incr.aof"]);
+        assert!(parse_manifest("file a seq x type i").is_err());
+    }
+
+    #[test]
+    fn truncated_tail_is_left_out() {
+        let bytes = b"*2\r\n$3\r\ndel\r\n$1\r\na\r\n*3\r\n$3\r\nset\r\n$1\r\nb";
+        let (commands, used) = parse_commands(bytes).unwrap();
+        assert_eq!(commands.len(), 1);
+        assert_eq!(used, 20);
+        assert!(parse_commands(b"?bad\r\n").is_err());
+    }
+
+    #[test]
+    fn rewrite_replaces_old_files() {
+        let dir = std::env::temp_dir().join(f!("aof-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let mut aof = Aof::new(dir.clone(), "appendonly.aof");
+        assert!(aof.load().unwrap().is_none());
+        aof.enable(&Snapshot::default(), 0).unwrap();
+        aof.feed(b"*2\r\n$3\r\ndel\r\n$1\r\na\r\n", Fsync::Always, 20);
+        assert_eq!(aof.fsynced_offset(30), 30);
+        aof.feed(b"*2\r\n$3\r\ndel\r\n$1\r\nc\r\n", Fsync::EverySec, 50);
+        assert_eq!(aof.fsynced_offset(60), 20);
+
+        assert!(aof.rewrite(Snapshot::default()).unwrap());
+        aof.feed(b"*2\r\n$3\r\ndel\r\n$1\r\nb\r\n", Fsync::Always, 40);
+        while aof.rewrite_in_progress() {
+            aof.tick(Fsync::No);
+        }
+
+        let manifest = fs::read_to_string(dir.join("appendonly.aof.manifest")).unwrap();
+        assert_eq!(manifest, "file appendonly.aof.2.base.rdb seq 2 type b\nfile appendonly.aof.2.incr.aof seq 2 type i\n");
+        assert!(!dir.join("appendonly.aof.1.incr.aof").exists());
+
+        let loaded = Aof::new(dir.clone(), "appendonly.aof").load().unwrap().unwrap();
+        assert!(matches!(&loaded[0], Loaded::Snapshot(_)));
+        assert!(matches!(&loaded[1], Loaded::Commands(commands) if commands.len() == 1));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
+// endregion: --- tests
@@ -0,0 +1,289 @@
+//! Bit level operations on string values, backing the bitmap commands
+
+/// Largest bit offset SETBIT/BITFIELD accept, matching a 512MB string.
+pub const MAX_BIT_OFFSET: u64 = 512 * 1024 * 1024 * 8 - 1;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum RangeUnit {
+    Byte,
+    Bit,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum BitOp {
+    And,
+    Or,
+    Xor,
+    Not,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    Wrap,
+    Sat,
+    Fail,
+}
+
+/// A BITFIELD encoding such as `i5` or `u16`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct FieldType {
+    pub signed: bool,
+    pub bits: u32,
+}
+
+impl FieldType {
+    pub fn parse(input: &str) -> Option<Self> {
+        let signed = match input.chars().next() {
+            Some('i') | Some('I') => true,
+            Some('u') | Some('U') => false,
+            _ => return None,
+        };
+        let bits: u32 = input[1..].parse().ok()?;
+        let max_bits = if signed { 64 } else { 63 };
+        if bits == 0 || bits > max_bits {
+            return None;
+        }
+
+        Some(Self { signed, bits })
+    }
+
+    fn min(&self) -> i128 {
+        if self.signed { -(1i128 << (self.bits - 1)) } else { 0 }
+    }
+
+    fn max(&self) -> i128 {
+        if self.signed { (1i128 << (self.bits - 1)) - 1 } else { (1i128 << self.bits) - 1 }
+    }
+
+    /// Fit `value` into this encoding, or `None` when it overflows under `Overflow::Fail`.
+    pub fn fit(&self, value: i128, overflow: Overflow) -> Option<i64> {
+        let (min, max) = (self.min(), self.max());
+        if value >= min && value <= max {
+            return Some(value as i64);
+        }
+
+        match overflow {
+            Overflow::Wrap => {
+                let span = 1i128 << self.bits;
+                Some(((value - min).rem_euclid(span) + min) as i64)
+            },
+            Overflow::Sat => Some(if value < min { min as i64 } else { max as i64 }),
+            Overflow::Fail => None,
+        }
+    }
+}
+
+pub fn get_bit(bytes: &[u8], offset: u64) -> u8 {
+    let byte = (offset / 8) as usize;
+    if byte >= bytes.len() {
+        return 0;
+    }
+
+    (bytes[byte] >> (7 - (offset % 8))) & 1
+}
+
+/// Set the bit at `offset`, growing `bytes` with zeros as needed, returning the previous bit.
+pub fn set_bit(bytes: &mut Vec<u8>, offset: u64, on: bool) -> u8 {
+    let byte = (offset / 8) as usize;
+    if byte >= bytes.len() {
+        bytes.resize(byte + 1, 0);
+    }
+
+    let mask = 1u8 << (7 - (offset % 8));
+    let previous = if bytes[byte] & mask != 0 { 1 } else { 0 };
+    if on {
+        bytes[byte] |= mask;
+    } else {
+        bytes[byte] &= !mask;
+    }
+
+    previous
+}
+
+/// Resolve a possibly negative inclusive range against `len`, `None` when it is empty.
+fn normalize_range(start: i64, end: i64, len: i64) -> Option<(i64, i64)> {
+    let mut start = if start < 0 { len + start } else { start };
+    let mut end = if end < 0 { len + end } else { end };
+    if start < 0 { start = 0; }
+    if end < 0 { end = 0; }
+    if end >= len { end = len - 1; }
+
+    if start > end || len == 0 {
+        return None;
+    }
+
+    Some((start, end))
+}
+
+fn unit_len(bytes: &[u8], unit: RangeUnit) -> i64 {
+    match unit {
+        RangeUnit::Byte => bytes.len() as i64,
+        RangeUnit::Bit => bytes.len() as i64 * 8,
+    }
+}
+
+/// Convert a normalized range in `unit` to an inclusive range of bit offsets.
+fn bit_range(start: i64, end: i64, unit: RangeUnit) -> (u64, u64) {
+    match unit {
+        RangeUnit::Byte => (start as u64 * 8, end as u64 * 8 + 7),
+        RangeUnit::Bit => (start as u64, end as u64),
+    }
+}
+
+pub fn count(bytes: &[u8], start: i64, end: i64, unit: RangeUnit) -> i64 {
+    let Some((start, end)) = normalize_range(start, end, unit_len(bytes, unit)) else {
+        return 0;
+    };
+    let (first, last) = bit_range(start, end, unit);
+
+    let mut total = 0;
+    let mut offset = first;
+    while offset <= last {
+        if offset % 8 == 0 && offset + 7 <= last {
+            total += bytes[(offset / 8) as usize].count_ones() as i64;
+            offset += 8;
+        } else {
+            total += get_bit(bytes, offset) as i64;
+            offset += 1;
+        }
+    }
+
+    total
+}
+
+/// Find the first bit set to `bit` in the range. When looking for a clear bit without an
+/// explicit end, the string is treated as padded with zeros on the right.
+pub fn position(bytes: &[u8], bit: u8, start: i64, end: Option<i64>, unit: RangeUnit) -> i64 {
+    let Some((start, norm_end)) = normalize_range(start, end.unwrap_or(-1), unit_len(bytes, unit)) else {
+        return -1;
+    };
+    let (first, last) = bit_range(start, norm_end, unit);
+
+    let skip = if bit == 1 { 0u8 } else { 0xff };
+    let mut offset = first;
+    while offset <= last {
+        if offset % 8 == 0 && offset + 7 <= last && bytes[(offset / 8) as usize] == skip {
+            offset += 8;
+            continue;
+        }
+        if get_bit(bytes, offset) == bit {
+            return offset as i64;
+        }
+        offset += 1;
+    }
+
+    if bit == 0 && end.is_none() {
+        return last as i64 + 1;
+    }
+
+    -1
+}
+
+pub fn bit_op(op: BitOp, sources: &[Vec<u8>]) -> Vec<u8> {
+    let len = sources.iter().map(|src| src.len()).max().unwrap_or(0);
+    let mut result = vec![0u8; len];
+
+    for (i, byte) in result.iter_mut().enumerate() {
+        let mut values = sources.iter().map(|src| src.get(i).copied().unwrap_or(0));
+        let first = values.next().unwrap_or(0);
+        *byte = match op {
+            BitOp::And => values.fold(first, |acc, val| acc & val),
+            BitOp::Or => values.fold(first, |acc, val| acc | val),
+            BitOp::Xor => values.fold(first, |acc, val| acc ^ val),
+            BitOp::Not => !first,
+        };
+    }
+
+    result
+}
+
+pub fn get_field(bytes: &[u8], offset: u64, field: FieldType) -> i64 {
+    let mut value: u64 = 0;
+    for i in 0..field.bits as u64 {
+        value = (value << 1) | get_bit(bytes, offset + i) as u64;
+    }
+
+    if field.signed && field.bits < 64 && value & (1 << (field.bits - 1)) != 0 {
+        value |= !((1u64 << field.bits) - 1);
+    }
+
+    value as i64
+}
+
+pub fn set_field(bytes: &mut Vec<u8>, offset: u64, field: FieldType, value: i64) {
+    let value = value as u64;
+    for i in 0..field.bits as u64 {
+        let on = (value >> (field.bits as u64 - 1 - i)) & 1 == 1;
+        set_bit(bytes, offset + i, on);
+    }
+}
+
+
+// region: --- tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_bit_grows_with_zero_padding() {
+        let mut bytes = Vec::new();
+        assert_eq!(set_bit(&mut bytes, 7, true), 0);
+        assert_eq!(set_bit(&mut bytes, 17, true), 0);
+        assert_eq!(bytes, vec![0x01, 0x00, 0x40]);
+        assert_eq!(set_bit(&mut bytes, 7, false), 1);
+        assert_eq!(get_bit(&bytes, 17), 1);
+        assert_eq!(get_bit(&bytes, 1000), 0);
+    }
+
+    #[test]
+    fn count_byte_and_bit_ranges() {
+        let bytes = b"foobar";
+        assert_eq!(count(bytes, 0, -1, RangeUnit::Byte), 26);
+        assert_eq!(count(bytes, 0, 0, RangeUnit::Byte), 4);
+        assert_eq!(count(bytes, 1, 1, RangeUnit::Byte), 6);
+        assert_eq!(count(bytes, 5, 30, RangeUnit::Bit), 17);
+        assert_eq!(count(bytes, 3, 1, RangeUnit::Byte), 0);
+    }
+
+    #[test]
+    fn position_matches_redis() {
+        assert_eq!(position(&[0xff, 0xf0, 0x00], 0, 0, None, RangeUnit::Byte), 12);
+        assert_eq!(position(&[0x00, 0xff, 0xf0], 1, 2, None, RangeUnit::Byte), 16);
+        assert_eq!(position(&[0x00, 0xff, 0xf0], 1, 7, Some(15), RangeUnit::Bit), 8);
+        assert_eq!(position(&[0xff, 0xff], 0, 0, None, RangeUnit::Byte), 16);
+        assert_eq!(position(&[0xff, 0xff], 0, 0, Some(-1), RangeUnit::Byte), -1);
+        assert_eq!(position(&[0x00], 1, 0, None, RangeUnit::Byte), -1);
+    }
+
+    #[test]
+    fn bit_op_pads_shorter_sources() {
+        let sources = vec![b"abc".to_vec(), b"ab".to_vec()];
+        assert_eq!(bit_op(BitOp::And, &sources), vec![b'a', b'b', 0]);
+        assert_eq!(bit_op(BitOp::Or, &sources), b"abc".to_vec());
+        assert_eq!(bit_op(BitOp::Xor, &sources), vec![0, 0, b'c']);
+        assert_eq!(bit_op(BitOp::Not, &[vec![0x0f]]), vec![0xf0]);
+    }
+
+    #[test]
+    fn fields_round_trip_and_overflow() {
+        let mut bytes = Vec::new();
+        let i5 = FieldType::parse("i5").unwrap();
+        let u8 = FieldType::parse("u8").unwrap();
+        set_field(&mut bytes, 0, i5, -3);
+        assert_eq!(get_field(&bytes, 0, i5), -3);
+        set_field(&mut bytes, 100, u8, 255);
+        assert_eq!(get_field(&bytes, 100, u8), 255);
+
+        assert_eq!(u8.fit(256, Overflow::Wrap), Some(0));
+        assert_eq!(u8.fit(300, Overflow::Sat), Some(255));
+        assert_eq!(u8.fit(-5, Overflow::Sat), Some(0));
+        assert_eq!(u8.fit(300, Overflow::Fail), None);
+        assert_eq!(i5.fit(16, Overflow::Wrap), Some(-16));
+        assert_eq!(i5.fit(-17, Overflow::Sat), Some(-16));
+
+        assert!(FieldType::parse("u64").is_none());
+        assert!(FieldType::parse("i64").is_some());
+        assert!(FieldType::parse("x8").is_none());
+    }
+}
+// endregion: --- tests
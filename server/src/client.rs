@@ -0,0 +1,706 @@
+//! Per connection state, such as an open MULTI transaction, WATCHed keys and subscriptions
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use utils::prelude::*;
+use utils::serializer::serialize;
+use utils::DataType;
+
+use crate::command::{self, Command};
+use crate::dictionary::dictionary::{Dictionary, Migration};
+use crate::pubsub::{self, PubSub, Pushes};
+use crate::replication::{self, Wait};
+use crate::shards::{self, Locked, Shards, SHARDS};
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Commands a connection with subscriptions may still run.
+const SUBSCRIBE_MODE_COMMANDS: &[&str] = &[
+    "subscribe", "unsubscribe", "psubscribe", "punsubscribe", "ssubscribe", "sunsubscribe", "ping",
+];
+
+/// What a command left its connection blocked on, replying once it is done.
+pub enum Blocked {
+    /// WAIT or WAITAOF, see `replication::wait`.
+    Wait(Wait),
+    /// MIGRATE waiting on the target, see `Client::finish_migrate`.
+    Migrate(Migration),
+}
+
+#[derive(Clone, Copy)]
+enum Kind {
+    Channel,
+    Pattern,
+    Shard,
+}
+
+pub struct Client {
+    id: u64,
+    /// Queue for messages pushed to this connection outside of its replies.
+    pushes: Pushes,
+    /// Commands queued since MULTI, `None` outside a transaction.
+    multi: Option<Vec<DataType>>,
+    /// Set when a queued command was rejected, so EXEC must abort.
+    multi_error: bool,
+    /// The database selected with SELECT.
+    db: usize,
+    /// WATCHed keys with their database, the version seen and whether the key existed at the time.
+    watching: Vec<(usize, Vec<u8>, u64, bool)>,
+    /// Subscribed channels and patterns, while there are any only subscribe-mode commands run.
+    channels: Vec<Vec<u8>>,
+    patterns: Vec<Vec<u8>>,
+    shard_channels: Vec<Vec<u8>>,
+    /// Address of the peer, shown for replicas.
+    ip: String,
+    /// Port a replica connecting here listens on, from REPLCONF listening-port.
+    listening_port: u16,
+    /// What the connection blocks on before its next reply.
+    blocked: Option<Blocked>,
+    /// Set by ASKING, for the next command only.
+    asking: bool,
+}
+
+impl Client {
+    pub fn new(pushes: Pushes) -> Self {
+        Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            pushes,
+            multi: None,
+            multi_error: false,
+            db: 0,
+            watching: Vec::new(),
+            channels: Vec::new(),
+            patterns: Vec::new(),
+            shard_channels: Vec::new(),
+            ip: String::new(),
+            listening_port: 0,
+            blocked: None,
+            asking: false,
+        }
+    }
+
+    pub fn set_ip(&mut self, ip: String) {
+        self.ip = ip;
+    }
+
+    /// What the last command blocked on, whose reply comes once it is done instead of from
+    /// `handle`.
+    pub fn take_blocked(&mut self) -> Option<Blocked> {
+        self.blocked.take()
+    }
+
+    /// Validate and run one command, or queue it while a transaction is open.
+    pub fn handle(&mut self, d_command: DataType, redis: &Arc<Shards>) -> Vec<u8> {
+        let mut arr = match d_command {
+            DataType::Array(Some(arr)) if !arr.is_empty() => arr,
+            other => return self.lock(redis, vec![self.shard()]).handle_command(other),
+        };
+
+        let name = match &arr[0] {
+            DataType::BulkString(Some(val)) => String::from_utf8_lossy(val).to_lowercase(),
+            _ => return self.reject(error_resp("ERR invalid command name")),
+        };
+        arr[0] = DataType::BulkString(Some(name.clone().into_bytes()));
+
+        let Some(command) = command::lookup(&name) else {
+            let args: String = arr[1..].iter().map(|arg| f!("'{}' ", arg_preview(arg))).collect();
+            return self.reject(error_resp(&f!("ERR unknown command '{name}', with args beginning with: {args}")));
+        };
+        if !command.arity_ok(arr.len()) {
+            return self.reject(error_resp(&f!("ERR wrong number of arguments for '{name}' command")));
+        }
+        if self.subscribe_mode() && !SUBSCRIBE_MODE_COMMANDS.contains(&name.as_str()) {
+            return error_resp(&f!("ERR Can't execute '{name}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context"));
+        }
+        let asking = std::mem::take(&mut self.asking) || command.flags & command::ASKING != 0;
+        if let Err(oom) = redis.make_room(command.flags, self.db) {
+            return self.reject(oom);
+        }
+
+        match name.as_str() {
+            "multi" => {
+                if self.multi.is_some() {
+                    return error_resp("ERR MULTI calls can not be nested");
+                }
+                self.multi = Some(Vec::new());
+                self.multi_error = false;
+                b"+OK\r\n".to_vec()
+            },
+            "exec" => self.exec(redis, asking),
+            "discard" => {
+                if self.multi.take().is_none() {
+                    return error_resp("ERR DISCARD without MULTI");
+                }
+                self.unwatch_all(redis);
+                b"+OK\r\n".to_vec()
+            },
+            "watch" => {
+                if self.multi.is_some() {
+                    return error_resp("ERR WATCH inside MULTI is not allowed");
+                }
+                let mut dict = match self.lock_keys(redis, command, &arr, asking) {
+                    Ok(dict) => dict,
+                    Err(err) => return self.reject(err),
+                };
+                for each_arg in &arr[1..] {
+                    let DataType::BulkString(Some(key)) = each_arg else { continue };
+                    if self.watching.iter().any(|watched| watched.0 == self.db && watched.1 == *key) {
+                        continue;
+                    }
+                    let (version, existed) = dict.watch(key);
+                    self.watching.push((self.db, key.clone(), version, existed));
+                }
+                b"+OK\r\n".to_vec()
+            },
+            "unwatch" if self.multi.is_none() => {
+                self.unwatch_all(redis);
+                b"+OK\r\n".to_vec()
+            },
+            "subscribe" | "unsubscribe" | "psubscribe" | "punsubscribe" | "ssubscribe" | "sunsubscribe" => {
+                if self.multi.is_some() {
+                    return self.reject(error_resp("ERR Command not allowed inside a transaction"));
+                }
+                if redis.shared().cluster_enabled() {
+                    if let Err(err) = self.lock_keys(redis, command, &arr, asking) {
+                        return self.reject(err);
+                    }
+                }
+                self.subscription(&name, &arr[1..], &mut redis.shared().pubsub())
+            },
+            "replicaof" | "slaveof" if self.multi.is_none() => self.replicaof(&arr[1..], redis),
+            "migrate" if self.multi.is_none() => self.migrate(&arr[1..], redis),
+            // it would hold every shard while waiting on the target
+            "migrate" => self.reject(error_resp("ERR Command not allowed inside a transaction")),
+            "replconf" => self.replconf(&arr[1..], redis),
+            "asking" => {
+                if !redis.shared().cluster().is_enabled() {
+                    return error_resp("ERR This instance has cluster support disabled");
+                }
+                self.asking = true;
+                b"+OK\r\n".to_vec()
+            },
+            "wait" | "waitaof" if self.multi.is_none() => match self.lock(redis, vec![self.shard()]).wait(&arr[1..], name == "waitaof") {
+                Ok(wait) => {
+                    self.blocked = Some(Blocked::Wait(wait));
+                    Vec::new()
+                },
+                Err(err) => err,
+            },
+            "psync" | "sync" if self.multi.is_none() => {
+                // SYNC is PSYNC that never continues
+                let args = match name.as_str() {
+                    "sync" => vec![DataType::BulkString(Some(b"?".to_vec())), DataType::BulkString(Some(b"-1".to_vec()))],
+                    _ => arr.split_off(1),
+                };
+                redis.lock_all().psync(self.id, &self.pushes, self.ip.clone(), self.listening_port, &args)
+            },
+            "ping" if self.subscribe_mode() => {
+                let message = match arr.get(1) {
+                    Some(DataType::BulkString(Some(val))) => val.as_slice(),
+                    _ => b"",
+                };
+                pubsub::frame(&[b"pong", message])
+            },
+            _ if self.multi.is_some() => {
+                if redis.shared().cluster_enabled() {
+                    if let Err(err) = self.lock_keys(redis, command, &arr, asking) {
+                        return self.reject(err);
+                    }
+                }
+                self.multi.get_or_insert_default().push(DataType::Array(Some(arr)));
+                b"+QUEUED\r\n".to_vec()
+            },
+            _ => {
+                let mut dict = match self.lock_keys(redis, command, &arr, asking) {
+                    Ok(dict) => dict,
+                    Err(err) => return self.reject(err),
+                };
+                let response = dict.handle_command(DataType::Array(Some(arr)));
+                self.db = dict.selected();
+                response
+            },
+        }
+    }
+
+    /// Drop this connection's watches and subscriptions, for when it goes away.
+    pub fn disconnect(&mut self, redis: &Arc<Shards>) {
+        self.unwatch_all(redis);
+        let mut pubsub = redis.shared().pubsub();
+        for channel in self.channels.drain(..) {
+            pubsub.unsubscribe(self.id, &channel);
+        }
+        for pattern in self.patterns.drain(..) {
+            pubsub.punsubscribe(self.id, &pattern);
+        }
+        for channel in self.shard_channels.drain(..) {
+            pubsub.sunsubscribe(self.id, &channel);
+        }
+        drop(pubsub);
+        redis.shared().replication().remove_replica(self.id);
+    }
+
+    /// REPLICAOF host port, or REPLICAOF NO ONE, starting a link task for a new primary.
+    fn replicaof(&mut self, args: &[DataType], redis: &Arc<Shards>) -> Vec<u8> {
+        let (DataType::BulkString(Some(host)), DataType::BulkString(Some(port))) = (&args[0], &args[1]) else {
+            return error_resp("ERR syntax error");
+        };
+        let host = String::from_utf8_lossy(host).into_owned();
+        let port = String::from_utf8_lossy(port).into_owned();
+        let primary = if host.eq_ignore_ascii_case("no") && port.eq_ignore_ascii_case("one") {
+            None
+        } else {
+            match port.parse::<u16>() {
+                Ok(port) => Some((host, port)),
+                Err(_) => return error_resp("ERR Invalid master port"),
+            }
+        };
+
+        let following = primary.is_some();
+        let link_id = redis.lock_all().replicaof(primary);
+        match link_id {
+            Some(link_id) => {
+                tokio::spawn(replication::run_link(Arc::clone(redis), link_id));
+                b"+OK\r\n".to_vec()
+            },
+            None if following => b"+OK Already connected to specified master\r\n".to_vec(),
+            None => b"+OK\r\n".to_vec(),
+        }
+    }
+
+    /// MIGRATE, blocking the connection on the target, see `finish_migrate`.
+    fn migrate(&mut self, args: &[DataType], redis: &Shards) -> Vec<u8> {
+        match self.lock(redis, (0..SHARDS).collect()).migrate_start(args) {
+            Ok(migration) => {
+                self.blocked = Some(Blocked::Migrate(migration));
+                Vec::new()
+            },
+            Err(response) => response,
+        }
+    }
+
+    /// Send what MIGRATE moves to the target on a blocking thread, with no shard locked and
+    /// the other connections served meanwhile, then reply.
+    pub async fn finish_migrate(&mut self, migration: Migration, redis: &Shards) -> Vec<u8> {
+        let sent = tokio::task::spawn_blocking(move || {
+            let replies = migration.send();
+            (migration, replies)
+        }).await;
+        let (migration, replies) = sent.expect("MIGRATE panicked sending the keys");
+        self.lock(redis, (0..SHARDS).collect()).migrate_finish(migration, replies)
+    }
+
+    /// REPLCONF option value [option value ...], sent by replicas during and after the
+    /// handshake. ACK gets no reply.
+    fn replconf(&mut self, args: &[DataType], redis: &Arc<Shards>) -> Vec<u8> {
+        if !args.len().is_multiple_of(2) {
+            return error_resp("ERR syntax error");
+        }
+        for pair in args.chunks(2) {
+            let (DataType::BulkString(Some(option)), DataType::BulkString(Some(value))) = (&pair[0], &pair[1]) else {
+                return error_resp("ERR syntax error");
+            };
+            let value = String::from_utf8_lossy(value);
+            match String::from_utf8_lossy(option).to_ascii_lowercase().as_str() {
+                "listening-port" => match value.parse() {
+                    Ok(port) => self.listening_port = port,
+                    Err(_) => return error_resp("ERR value is not an integer or out of range"),
+                },
+                "ack" => {
+                    // ACK offset [FACK aof-offset]
+                    let fack = match args.get(2..4) {
+                        Some([DataType::BulkString(Some(option)), DataType::BulkString(Some(value))]) if option.eq_ignore_ascii_case(b"fack") => {
+                            String::from_utf8_lossy(value).parse().ok()
+                        },
+                        _ => None,
+                    };
+                    if let Ok(offset) = value.parse() {
+                        redis.shared().replication().ack(self.id, offset, fack);
+                    }
+                    return Vec::new();
+                },
+                "capa" | "ip-address" | "rdb-only" | "rdb-filter-only" => (),
+                other => return error_resp(&f!("ERR Unrecognized REPLCONF option: {other}")),
+            }
+        }
+        b"+OK\r\n".to_vec()
+    }
+
+    /// Channel and pattern subscriptions, the count SUBSCRIBE and PSUBSCRIBE report.
+    fn subscriptions(&self) -> usize {
+        self.channels.len() + self.patterns.len()
+    }
+
+    fn subscribe_mode(&self) -> bool {
+        self.subscriptions() + self.shard_channels.len() > 0
+    }
+
+    /// SUBSCRIBE, SSUBSCRIBE, PSUBSCRIBE and their UNSUBSCRIBE forms, confirming each channel or
+    /// pattern with the connection's subscription count after it. Unsubscribing with no
+    /// arguments drops every subscription of that kind.
+    fn subscription(&mut self, name: &str, args: &[DataType], pubsub: &mut PubSub) -> Vec<u8> {
+        let kind = match name {
+            "psubscribe" | "punsubscribe" => Kind::Pattern,
+            "ssubscribe" | "sunsubscribe" => Kind::Shard,
+            _ => Kind::Channel,
+        };
+        let mut targets: Vec<Vec<u8>> = args.iter()
+            .filter_map(|arg| match arg {
+                DataType::BulkString(Some(val)) => Some(val.clone()),
+                _ => None,
+            })
+            .collect();
+
+        let mut response = Vec::new();
+        if name.ends_with("unsubscribe") {
+            if targets.is_empty() {
+                targets = self.subscribed(kind).clone();
+                if targets.is_empty() {
+                    response.extend_from_slice(&confirmation(name, None, self.count(kind)));
+                }
+            }
+            for target in targets {
+                self.subscribed(kind).retain(|each| *each != target);
+                match kind {
+                    Kind::Channel => pubsub.unsubscribe(self.id, &target),
+                    Kind::Pattern => pubsub.punsubscribe(self.id, &target),
+                    Kind::Shard => pubsub.sunsubscribe(self.id, &target),
+                }
+                response.extend_from_slice(&confirmation(name, Some(&target), self.count(kind)));
+            }
+        } else {
+            for target in targets {
+                if !self.subscribed(kind).contains(&target) {
+                    self.subscribed(kind).push(target.clone());
+                    match kind {
+                        Kind::Channel => pubsub.subscribe(self.id, &self.pushes, &target),
+                        Kind::Pattern => pubsub.psubscribe(self.id, &self.pushes, &target),
+                        Kind::Shard => pubsub.ssubscribe(self.id, &self.pushes, &target),
+                    }
+                }
+                response.extend_from_slice(&confirmation(name, Some(&target), self.count(kind)));
+            }
+        }
+
+        response
+    }
+
+    fn subscribed(&mut self, kind: Kind) -> &mut Vec<Vec<u8>> {
+        match kind {
+            Kind::Channel => &mut self.channels,
+            Kind::Pattern => &mut self.patterns,
+            Kind::Shard => &mut self.shard_channels,
+        }
+    }
+
+    /// Shard subscriptions are counted apart from channels and patterns.
+    fn count(&self, kind: Kind) -> usize {
+        match kind {
+            Kind::Shard => self.shard_channels.len(),
+            _ => self.subscriptions(),
+        }
+    }
+
+    /// The shard commands without keys run on, spreading connections over the shards.
+    fn shard(&self) -> usize {
+        self.id as usize % SHARDS
+    }
+
+    /// Lock `shards` with this connection's database selected.
+    fn lock<'a>(&self, redis: &'a Shards, shards: Vec<usize>) -> Locked<'a> {
+        let mut dict = redis.lock(shards);
+        dict.select(self.db);
+        dict
+    }
+
+    /// Lock the shards of the keys of `arr`, or refuse it with a redirection when cluster mode
+    /// serves them elsewhere. The command runs under the same lock, so its slot can not be
+    /// migrated away between the check and the command.
+    fn lock_keys<'a>(&self, redis: &'a Shards, command: &Command, arr: &[DataType], asking: bool) -> std::result::Result<Locked<'a>, Vec<u8>> {
+        let mut dict = self.lock(redis, shards::route(arr, self.shard()));
+        match dict.check_cluster_keys(&command.key_args(arr), asking) {
+            Ok(()) => Ok(dict),
+            Err(err) => Err(error_resp(&err)),
+        }
+    }
+
+    /// The shards of the WATCHed keys.
+    fn watched_shards(&self) -> impl Iterator<Item = usize> + '_ {
+        self.watching.iter().map(|(_, key, _, _)| shards::shard_of(key))
+    }
+
+    fn unwatch_all(&mut self, redis: &Shards) {
+        if self.watching.is_empty() {
+            return;
+        }
+        let mut dict = redis.lock(self.watched_shards().collect());
+        self.drop_watches(&mut dict);
+    }
+
+    fn drop_watches(&mut self, dict: &mut Dictionary) {
+        for (db, key, _, _) in self.watching.drain(..) {
+            dict.unwatch(db, &key);
+        }
+    }
+
+    /// Run every queued command under a single acquisition of the shards they and the WATCHed
+    /// keys are in, unless a WATCHed key changed in the meantime.
+    fn exec(&mut self, redis: &Arc<Shards>, asking: bool) -> Vec<u8> {
+        let Some(queue) = self.multi.take() else {
+            return error_resp("ERR EXEC without MULTI");
+        };
+
+        let mut locked: Vec<usize> = self.watched_shards().collect();
+        for d_command in &queue {
+            if let DataType::Array(Some(arr)) = d_command {
+                locked.extend(shards::route(arr, self.shard()));
+            }
+        }
+        if locked.is_empty() {
+            locked.push(self.shard());
+        }
+        let mut dict = self.lock(redis, locked);
+        if self.multi_error {
+            self.drop_watches(&mut dict);
+            return error_resp("EXECABORT Transaction discarded because of previous errors.");
+        }
+
+        let dirty = self.watching.iter()
+            .any(|(db, key, version, existed)| dict.watched_key_changed(*db, key, *version, *existed));
+        self.drop_watches(&mut dict);
+        if dirty {
+            return b"*-1\r\n".to_vec();
+        }
+
+        // a slot may have moved since the commands were queued, so they are checked again
+        for d_command in &queue {
+            let DataType::Array(Some(arr)) = d_command else { continue };
+            let Some(DataType::BulkString(Some(name))) = arr.first() else { continue };
+            let Some(spec) = command::lookup(&String::from_utf8_lossy(name)) else { continue };
+            if let Err(err) = dict.check_cluster_keys(&spec.key_args(arr), asking) {
+                return error_resp(&err);
+            }
+        }
+
+        let mut response = f!("*{}\r\n", queue.len()).into_bytes();
+        for d_command in queue {
+            // UNWATCH queued in the transaction is moot, EXEC already dropped the watches
+            if matches!(&d_command, DataType::Array(Some(arr)) if arr[0] == "unwatch") {
+                response.extend_from_slice(b"+OK\r\n");
+                continue;
+            }
+            response.extend_from_slice(&dict.handle_command(d_command));
+        }
+        self.db = dict.selected();
+
+        response
+    }
+
+    /// Reply with `err`, flagging an open transaction so its EXEC aborts.
+    fn reject(&mut self, err: Vec<u8>) -> Vec<u8> {
+        if self.multi.is_some() {
+            self.multi_error = true;
+        }
+
+        err
+    }
+}
+
+fn arg_preview(arg: &DataType) -> String {
+    match arg {
+        DataType::BulkString(Some(val)) => String::from_utf8_lossy(val).chars().take(128).collect(),
+        _ => String::new(),
+    }
+}
+
+fn confirmation(name: &str, target: Option<&[u8]>, count: usize) -> Vec<u8> {
+    serialize(&DataType::Array(Some(vec![
+        DataType::BulkString(Some(name.as_bytes().to_vec())),
+        DataType::BulkString(target.map(|val| val.to_vec())),
+        DataType::Integer(count as i64),
+    ]))).unwrap()
+}
+
+fn error_resp(msg: &str) -> Vec<u8> {
+    serialize(&DataType::Error(msg.to_owned())).unwrap()
+}
+
+
+// region: --- tests
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::config::Config;
+    use tokio::sync::mpsc::unbounded_channel;
+
+    pub(crate) fn command(args: &[&str]) -> DataType {
+        DataType::Array(Some(args.iter().map(|arg| DataType::BulkString(Some(arg.as_bytes().to_vec()))).collect()))
+    }
+
+    /// A server with the default configuration, for one test.
+    pub(crate) fn server() -> Arc<Shards> {
+        Arc::new(Shards::new(Config::default()))
+    }
+
+    /// A connection whose pushed messages are dropped.
+    pub(crate) fn connect() -> Client {
+        Client::new(unbounded_channel().0)
+    }
+
+    #[test]
+    fn exec_runs_queued_commands() {
+        let redis = server();
+        let mut client = connect();
+
+        assert_eq!(client.handle(command(&["MULTI"]), &redis), b"+OK\r\n");
+        assert_eq!(client.handle(command(&["set", "a", "1"]), &redis), b"+QUEUED\r\n");
+        assert_eq!(client.handle(command(&["incr", "a"]), &redis), b"+QUEUED\r\n");
+        assert_eq!(client.handle(command(&["exec"]), &redis), b"*2\r\n+OK\r\n:2\r\n");
+        assert_eq!(client.handle(command(&["exec"]), &redis), b"-ERR EXEC without MULTI\r\n");
+    }
+
+    #[test]
+    fn queueing_errors_abort_exec() {
+        let redis = server();
+        let mut client = connect();
+
+        client.handle(command(&["multi"]), &redis);
+        client.handle(command(&["set", "a", "1"]), &redis);
+        let err = client.handle(command(&["get"]), &redis);
+        assert!(err.starts_with(b"-ERR wrong number of arguments"));
+        let resp = client.handle(command(&["exec"]), &redis);
+        assert!(resp.starts_with(b"-EXECABORT"));
+        assert_eq!(client.handle(command(&["get", "a"]), &redis), b"$-1\r\n");
+    }
+
+    #[test]
+    fn watched_key_change_aborts_exec() {
+        let redis = server();
+        let mut client = connect();
+        let mut other = connect();
+
+        client.handle(command(&["watch", "a"]), &redis);
+        other.handle(command(&["set", "a", "2"]), &redis);
+        client.handle(command(&["multi"]), &redis);
+        client.handle(command(&["set", "a", "1"]), &redis);
+        assert_eq!(client.handle(command(&["exec"]), &redis), b"*-1\r\n");
+        assert_eq!(client.handle(command(&["get", "a"]), &redis), b"$1\r\n2\r\n");
+
+        client.handle(command(&["watch", "a"]), &redis);
+        other.handle(command(&["get", "a"]), &redis);
+        client.handle(command(&["multi"]), &redis);
+        client.handle(command(&["set", "a", "1"]), &redis);
+        assert_eq!(client.handle(command(&["exec"]), &redis), b"*1\r\n+OK\r\n");
+    }
+
+    #[test]
+    fn watched_key_expiring_aborts_exec() {
+        let redis = server();
+        let mut client = connect();
+
+        client.handle(command(&["set", "a", "1", "PX", "20"]), &redis);
+        client.handle(command(&["watch", "a"]), &redis);
+        std::thread::sleep(std::time::Duration::from_millis(40));
+        client.handle(command(&["multi"]), &redis);
+        client.handle(command(&["set", "b", "1"]), &redis);
+        assert_eq!(client.handle(command(&["exec"]), &redis), b"*-1\r\n");
+    }
+
+    #[test]
+    fn discard_drops_queue() {
+        let redis = server();
+        let mut client = connect();
+
+        client.handle(command(&["multi"]), &redis);
+        client.handle(command(&["set", "a", "1"]), &redis);
+        assert_eq!(client.handle(command(&["discard"]), &redis), b"+OK\r\n");
+        assert_eq!(client.handle(command(&["get", "a"]), &redis), b"$-1\r\n");
+    }
+
+    #[test]
+    fn exec_checks_slots_again() {
+        let config = Config { cluster_enabled: true, ..Config::default() };
+        let redis = Arc::new(Shards::new(config));
+        let path = std::env::temp_dir().join(f!("nodes-{}-exec.conf", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        redis.shared().cluster().start(path, 7010, 15000).unwrap();
+        let mut client = connect();
+        let mut admin = connect();
+
+        admin.handle(command(&["cluster", "addslots", "5061"]), &redis);
+        client.handle(command(&["multi"]), &redis);
+        assert_eq!(client.handle(command(&["set", "bar", "1"]), &redis), b"+QUEUED\r\n");
+        // the slot is given up between queueing and EXEC
+        admin.handle(command(&["cluster", "delslots", "5061"]), &redis);
+        assert_eq!(client.handle(command(&["exec"]), &redis), b"-CLUSTERDOWN Hash slot not served\r\n");
+        admin.handle(command(&["cluster", "addslots", "5061"]), &redis);
+        assert_eq!(client.handle(command(&["get", "bar"]), &redis), b"$-1\r\n");
+    }
+
+    #[test]
+    fn subscribe_mode_restricts_commands() {
+        let redis = server();
+        let mut client = connect();
+
+        client.handle(command(&["subscribe", "a"]), &redis);
+        assert!(client.handle(command(&["get", "a"]), &redis).starts_with(b"-ERR Can't execute 'get'"));
+        assert_eq!(client.handle(command(&["ping"]), &redis), b"*2\r\n$4\r\npong\r\n$0\r\n\r\n");
+
+        let resp = client.handle(command(&["unsubscribe"]), &redis);
+        assert_eq!(resp, b"*3\r\n$11\r\nunsubscribe\r\n$1\r\na\r\n:0\r\n");
+        assert_eq!(client.handle(command(&["get", "a"]), &redis), b"$-1\r\n");
+    }
+
+    #[tokio::test]
+    async fn migrate_leaves_the_server_free_while_the_target_replies() {
+        use std::io::{Read, Write};
+        use tokio::sync::oneshot;
+
+        let redis = server();
+        let mut client = connect();
+        client.handle(command(&["set", "a", "1"]), &redis);
+        client.handle(command(&["set", "b", "2"]), &redis);
+
+        // where it would run with shards locked it is refused
+        client.handle(command(&["multi"]), &redis);
+        let refused = client.handle(command(&["migrate", "127.0.0.1", "1", "a", "0", "5000"]), &redis);
+        assert_eq!(refused, b"-ERR Command not allowed inside a transaction\r\n");
+        client.handle(command(&["discard"]), &redis);
+        let refused = client.handle(command(&["eval", "return redis.call('migrate', '127.0.0.1', '1', 'a', '0', '5000')", "0"]), &redis);
+        assert!(String::from_utf8_lossy(&refused).contains("not allowed from script"));
+
+        // a target that restores nothing until told to, then accepts both keys
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port().to_string();
+        let (in_flight, wait_in_flight) = oneshot::channel();
+        let (reply, wait_reply) = oneshot::channel::<()>();
+        let target = std::thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+            socket.read_exact(&mut [0; 1]).unwrap();
+            in_flight.send(()).unwrap();
+            wait_reply.blocking_recv().unwrap();
+            socket.write_all(b"+OK\r\n+OK\r\n").unwrap();
+        });
+
+        // the test runs on a single thread, which the other client gets while MIGRATE waits
+        let mut migrating = connect();
+        let started = migrating.handle(command(&["migrate", "127.0.0.1", &port, "", "0", "5000", "KEYS", "a", "b"]), &redis);
+        assert_eq!(started, b"");
+        let Some(Blocked::Migrate(migration)) = migrating.take_blocked() else { panic!("not migrating") };
+        let write = async {
+            wait_in_flight.await.unwrap();
+            let written = client.handle(command(&["set", "b", "3"]), &redis);
+            reply.send(()).unwrap();
+            written
+        };
+        let (migrated, written) = tokio::join!(migrating.finish_migrate(migration, &redis), write);
+        target.join().unwrap();
+        assert_eq!(written, b"+OK\r\n");
+
+        // the key written meanwhile is newer than what the target got, so it stays
+        assert_eq!(migrated, b"-ERR key changed during migration\r\n");
+        assert_eq!(client.handle(command(&["exists", "a"]), &redis), b":0\r\n");
+        assert_eq!(client.handle(command(&["get", "b"]), &redis), b"$1\r\n3\r\n");
+    }
+}
+// endregion: --- tests
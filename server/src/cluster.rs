@@ -0,0 +1,764 @@
+//! Cluster mode: keys map to one of 16384 hash slots by CRC16, honouring `{hashtags}`, and each
+//! node serves the slots it owns, redirecting the rest with MOVED. Nodes learn about each other
+//! and about slot ownership by gossip, sent once a second to every known node over the normal
+//! client port, and keep what they know in the cluster config file.
+//!
+//! A slot moves between nodes while both keep serving it: the source is MIGRATING it and sends
+//! keys it no longer has to the target with ASK, while the target is IMPORTING it and serves
+//! clients that say ASKING first. CLUSTER SETSLOT NODE then hands the slot over for good.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::ToSocketAddrs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use utils::deserializer::deserialize_partial;
+use utils::prelude::*;
+use utils::serializer::serialize;
+use utils::DataType;
+
+use crate::scripting;
+use crate::shards::Shards;
+
+pub const SLOTS: u16 = 16384;
+/// How often every known node is sent gossip.
+const GOSSIP_PERIOD: Duration = Duration::from_secs(1);
+/// How long a gossip exchange may take before it counts as failed.
+const GOSSIP_TIMEOUT: Duration = Duration::from_secs(1);
+/// The address nodes are told to reach this one at, the one the server listens on.
+const ANNOUNCE_IP: &str = "127.0.0.1";
+
+static NEXT_NODE_ID: AtomicU64 = AtomicU64::new(0);
+
+struct Node {
+    id: String,
+    ip: String,
+    port: u16,
+    /// Version of the node's slot claims; the higher one wins when two nodes claim a slot.
+    config_epoch: u64,
+    /// Unix time in ms of the last gossip heard from the node.
+    pong_received: u128,
+}
+
+pub struct Cluster {
+    /// Known nodes, this one first. Empty while cluster mode is off.
+    nodes: Vec<Node>,
+    /// Index into `nodes` of each slot's owner.
+    owners: Vec<Option<usize>>,
+    /// The highest config epoch seen in the cluster.
+    current_epoch: u64,
+    config_path: PathBuf,
+    node_timeout: u64,
+    last_gossip: Instant,
+    /// Addresses from CLUSTER MEET, greeted on the next cron tick.
+    meet: Vec<(String, u16)>,
+    /// Slots this node is moving out, with the index of the node they go to.
+    migrating: BTreeMap<u16, usize>,
+    /// Slots this node is taking in, with the index of the node they come from.
+    importing: BTreeMap<u16, usize>,
+}
+
+impl Cluster {
+    pub fn new() -> Self {
+        Self {
+            nodes: Vec::new(),
+            owners: vec![None; SLOTS as usize],
+            current_epoch: 0,
+            config_path: PathBuf::new(),
+            node_timeout: 15000,
+            last_gossip: Instant::now(),
+            meet: Vec::new(),
+            migrating: BTreeMap::new(),
+            importing: BTreeMap::new(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.nodes.is_empty()
+    }
+
+    /// Turn cluster mode on, loading the node table from `config_path`, or starting a new one
+    /// with just this node when the file does not exist yet.
+    pub fn start(&mut self, config_path: PathBuf, port: u16, node_timeout: u64) -> std::result::Result<(), String> {
+        self.config_path = config_path;
+        self.node_timeout = node_timeout;
+        match fs::read_to_string(&self.config_path) {
+            Ok(text) => self.parse_config(&text)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                self.nodes = vec![Node { id: new_node_id(), ip: ANNOUNCE_IP.to_owned(), port, config_epoch: 0, pong_received: 0 }];
+            },
+            Err(e) => return Err(f!("{}: {e}", self.config_path.display())),
+        }
+        self.nodes[0].port = port;
+        self.save_config();
+        Ok(())
+    }
+
+    pub fn set_node_timeout(&mut self, node_timeout: u64) {
+        self.node_timeout = node_timeout;
+    }
+
+    pub fn myid(&self) -> &str {
+        &self.nodes[0].id
+    }
+
+    /// Check that this node serves the keys of a command: the error to reply with when they
+    /// span slots, or belong to a slot served elsewhere or nowhere. `existing` tells which of
+    /// the keys are here, for slots being moved; `asking` is set after ASKING. Outside cluster
+    /// mode every key is served.
+    pub fn check_keys(&self, keys: &[&[u8]], existing: &[bool], asking: bool) -> std::result::Result<(), String> {
+        let Some(first) = keys.first().filter(|_| self.is_enabled()) else { return Ok(()) };
+        let slot = key_slot(first);
+        if keys[1..].iter().any(|key| key_slot(key) != slot) {
+            return Err("CROSSSLOT Keys in request don't hash to the same slot".to_owned());
+        }
+        let missing = existing.iter().filter(|exists| !**exists).count();
+        let try_again = || Err("TRYAGAIN Multiple keys request during rehashing of slot".to_owned());
+
+        match self.owners[slot as usize] {
+            Some(0) => match self.migrating.get(&slot) {
+                // keys not here any more, or not yet, are written and read on the target
+                Some(&target) if missing == keys.len() => Err(f!("ASK {slot} {}:{}", self.nodes[target].ip, self.nodes[target].port)),
+                Some(_) if missing > 0 => try_again(),
+                _ => Ok(()),
+            },
+            _ if asking && self.importing.contains_key(&slot) => match missing {
+                0 => Ok(()),
+                _ if keys.len() > 1 => try_again(),
+                _ => Ok(()),
+            },
+            Some(owner) => Err(f!("MOVED {slot} {}:{}", self.nodes[owner].ip, self.nodes[owner].port)),
+            None => Err("CLUSTERDOWN Hash slot not served".to_owned()),
+        }
+    }
+
+    /// CLUSTER SETSLOT slot IMPORTING|MIGRATING|NODE node-id, or STABLE with no node.
+    /// `has_keys` tells whether this node still holds keys in the slot.
+    pub fn set_slot(&mut self, slot: u16, action: &str, node_id: Option<&str>, has_keys: bool) -> std::result::Result<(), String> {
+        let node = match node_id {
+            Some(id) => Some(self.nodes.iter().position(|node| node.id == id).ok_or_else(|| f!("ERR I don't know about node {id}"))?),
+            None => None,
+        };
+        let owner = self.owners[slot as usize];
+        match (action, node) {
+            ("migrating", Some(target)) => {
+                if owner != Some(0) {
+                    return Err(f!("ERR I'm not the owner of hash slot {slot}"));
+                }
+                if target == 0 {
+                    return Err("ERR I can't migrate a slot to myself".to_owned());
+                }
+                self.migrating.insert(slot, target);
+            },
+            ("importing", Some(source)) => {
+                if owner == Some(0) {
+                    return Err(f!("ERR I'm already the owner of hash slot {slot}"));
+                }
+                if source == 0 {
+                    return Err("ERR I can't import a slot from myself".to_owned());
+                }
+                self.importing.insert(slot, source);
+            },
+            ("stable", None) => {
+                self.migrating.remove(&slot);
+                self.importing.remove(&slot);
+            },
+            ("node", Some(node)) => {
+                if owner == Some(0) && node != 0 && has_keys {
+                    return Err(f!("ERR Can't assign hashslot {slot} to a different node while I still hold keys for this hash slot."));
+                }
+                self.migrating.remove(&slot);
+                self.owners[slot as usize] = Some(node);
+                // the new owner's claim has to win over the old one's
+                if node == 0 && self.importing.remove(&slot).is_some() {
+                    self.claims_changed();
+                    return Ok(());
+                }
+            },
+            _ => return Err("ERR Invalid CLUSTER SETSLOT action or number of arguments. Try CLUSTER HELP".to_owned()),
+        }
+        self.save_config();
+        Ok(())
+    }
+
+    /// CLUSTER ADDSLOTS: take unassigned `slots`, all of them or none.
+    pub fn add_slots(&mut self, slots: &[u16]) -> std::result::Result<(), String> {
+        let mut seen = vec![false; SLOTS as usize];
+        for slot in slots {
+            if self.owners[*slot as usize].is_some() {
+                return Err(f!("ERR Slot {slot} is already busy"));
+            }
+            if std::mem::replace(&mut seen[*slot as usize], true) {
+                return Err(f!("ERR Slot {slot} specified multiple times"));
+            }
+        }
+        for slot in slots {
+            self.owners[*slot as usize] = Some(0);
+        }
+        self.claims_changed();
+        Ok(())
+    }
+
+    /// CLUSTER DELSLOTS: forget the owner of `slots`, all of them or none.
+    pub fn del_slots(&mut self, slots: &[u16]) -> std::result::Result<(), String> {
+        let mut seen = vec![false; SLOTS as usize];
+        for slot in slots {
+            if self.owners[*slot as usize].is_none() {
+                return Err(f!("ERR Slot {slot} is already unassigned"));
+            }
+            if std::mem::replace(&mut seen[*slot as usize], true) {
+                return Err(f!("ERR Slot {slot} specified multiple times"));
+            }
+        }
+        for slot in slots {
+            self.owners[*slot as usize] = None;
+        }
+        self.claims_changed();
+        Ok(())
+    }
+
+    /// This node's slots changed: a new config epoch makes its claims win over older gossip.
+    fn claims_changed(&mut self) {
+        self.current_epoch += 1;
+        self.nodes[0].config_epoch = self.current_epoch;
+        self.save_config();
+    }
+
+    /// CLUSTER MEET: greet the node at `ip:port`, which then joins the cluster.
+    pub fn meet(&mut self, ip: String, port: u16) {
+        self.meet.push((ip, port));
+    }
+
+    /// Addresses to send gossip to now, called from the server cron.
+    pub fn gossip_targets(&mut self) -> Vec<(String, u16)> {
+        let mut targets = std::mem::take(&mut self.meet);
+        if self.is_enabled() && self.last_gossip.elapsed() >= GOSSIP_PERIOD {
+            self.last_gossip = Instant::now();
+            targets.extend(self.nodes[1..].iter().map(|node| (node.ip.clone(), node.port)));
+        }
+        targets
+    }
+
+    /// What this node knows, as sent in gossip: the current epoch, then id, ip, port, config
+    /// epoch and slots of each node, this one first.
+    pub fn gossip(&self) -> Vec<Vec<u8>> {
+        let mut fields = vec![self.current_epoch.to_string().into_bytes()];
+        for (index, node) in self.nodes.iter().enumerate() {
+            let slots = self.ranges(index).iter()
+                .map(|(start, end)| if start == end { start.to_string() } else { f!("{start}-{end}") })
+                .collect::<Vec<_>>()
+                .join(",");
+            fields.push(node.id.clone().into_bytes());
+            fields.push(node.ip.clone().into_bytes());
+            fields.push(node.port.to_string().into_bytes());
+            fields.push(node.config_epoch.to_string().into_bytes());
+            fields.push(if slots.is_empty() { b"-".to_vec() } else { slots.into_bytes() });
+        }
+        fields
+    }
+
+    /// Take in gossip from another node. Its own entry, the first, proves it is alive; a slot
+    /// claim wins over the current owner's when its config epoch is newer, or equal from a node
+    /// with a lower id.
+    pub fn merge(&mut self, fields: &[Vec<u8>]) -> std::result::Result<(), String> {
+        let text = |field: &Vec<u8>| String::from_utf8_lossy(field).into_owned();
+        let bad = || "ERR Invalid gossip message".to_owned();
+        let (Some(epoch), true) = (fields.first().and_then(|epoch| text(epoch).parse::<u64>().ok()), fields.len() % 5 == 1) else {
+            return Err(bad());
+        };
+        let mut changed = epoch > self.current_epoch;
+        self.current_epoch = self.current_epoch.max(epoch);
+
+        for (position, entry) in fields[1..].chunks(5).enumerate() {
+            let (id, ip) = (text(&entry[0]), text(&entry[1]));
+            let (Ok(port), Ok(config_epoch)) = (text(&entry[2]).parse::<u16>(), text(&entry[3]).parse::<u64>()) else {
+                return Err(bad());
+            };
+            let claimed = parse_ranges(&text(&entry[4])).ok_or_else(bad)?;
+            if id == self.nodes[0].id {
+                continue;
+            }
+
+            let index = match self.nodes.iter().position(|node| node.id == id) {
+                Some(index) => index,
+                None => {
+                    self.nodes.push(Node { id, ip: ip.clone(), port, config_epoch, pong_received: now_ms() });
+                    changed = true;
+                    self.nodes.len() - 1
+                },
+            };
+            if position == 0 {
+                self.nodes[index].pong_received = now_ms();
+            }
+            let node = &mut self.nodes[index];
+            if config_epoch < node.config_epoch {
+                continue;
+            }
+            changed |= node.ip != ip || node.port != port || node.config_epoch != config_epoch;
+            (node.ip, node.port, node.config_epoch) = (ip, port, config_epoch);
+
+            let mut claims = vec![false; SLOTS as usize];
+            for (start, end) in claimed {
+                claims[start as usize..=end as usize].fill(true);
+            }
+            for (slot, claim) in claims.into_iter().enumerate() {
+                let owner = self.owners[slot];
+                let take = match owner {
+                    _ if !claim => false,
+                    None => true,
+                    // equal epochs are settled by node id, the same way on every node
+                    Some(owner) => owner != index && (self.nodes[owner].config_epoch, &self.nodes[index].id) < (config_epoch, &self.nodes[owner].id),
+                };
+                if take {
+                    self.owners[slot] = Some(index);
+                    changed = true;
+                } else if !claim && owner == Some(index) {
+                    self.owners[slot] = None;
+                    changed = true;
+                }
+            }
+        }
+
+        if changed {
+            self.save_config();
+        }
+        Ok(())
+    }
+
+    /// Contiguous slot ranges owned by node `index`.
+    fn ranges(&self, index: usize) -> Vec<(u16, u16)> {
+        let mut ranges: Vec<(u16, u16)> = Vec::new();
+        for slot in (0..SLOTS).filter(|slot| self.owners[*slot as usize] == Some(index)) {
+            match ranges.last_mut() {
+                Some((_, end)) if *end + 1 == slot => *end = slot,
+                _ => ranges.push((slot, slot)),
+            }
+        }
+        ranges
+    }
+
+    /// Whether node `index` has gone quiet for longer than the node timeout.
+    fn failing(&self, index: usize) -> bool {
+        index != 0 && now_ms().saturating_sub(self.nodes[index].pong_received) > self.node_timeout as u128
+    }
+
+    /// CLUSTER NODES, one line per node, also the format of the config file.
+    pub fn nodes(&self) -> String {
+        let mut text = String::new();
+        for (index, node) in self.nodes.iter().enumerate() {
+            let flags = match index {
+                0 => "myself,master",
+                _ if self.failing(index) => "master,fail?",
+                _ => "master",
+            };
+            let pong = if index == 0 { 0 } else { node.pong_received };
+            let link = if self.failing(index) { "disconnected" } else { "connected" };
+            text += &f!(
+                "{} {}:{}@{} {flags} - 0 {pong} {} {link}",
+                node.id, node.ip, node.port, node.port as u32 + 10000, node.config_epoch,
+            );
+            for (start, end) in self.ranges(index) {
+                text += &if start == end { f!(" {start}") } else { f!(" {start}-{end}") };
+            }
+            if index == 0 {
+                for (slot, target) in &self.migrating {
+                    text += &f!(" [{slot}->-{}]", self.nodes[*target].id);
+                }
+                for (slot, source) in &self.importing {
+                    text += &f!(" [{slot}-<-{}]", self.nodes[*source].id);
+                }
+            }
+            text += "\n";
+        }
+        text
+    }
+
+    /// CLUSTER SLOTS: each slot range with the address and id of the node serving it.
+    pub fn slots(&self) -> DataType {
+        let mut ranges: Vec<(u16, u16, usize)> = (0..self.nodes.len())
+            .flat_map(|index| self.ranges(index).into_iter().map(move |(start, end)| (start, end, index)))
+            .collect();
+        ranges.sort();
+        DataType::Array(Some(ranges.into_iter()
+            .map(|(start, end, index)| DataType::Array(Some(vec![
+                DataType::Integer(start as i64),
+                DataType::Integer(end as i64),
+                self.address(index),
+            ])))
+            .collect()))
+    }
+
+    /// CLUSTER SHARDS: every node with its slot ranges.
+    pub fn shards(&self) -> DataType {
+        let bulk = |text: &str| DataType::BulkString(Some(text.as_bytes().to_vec()));
+        DataType::Array(Some((0..self.nodes.len())
+            .map(|index| {
+                let node = &self.nodes[index];
+                let slots = self.ranges(index).into_iter()
+                    .flat_map(|(start, end)| [DataType::Integer(start as i64), DataType::Integer(end as i64)])
+                    .collect();
+                let health = if self.failing(index) { "fail" } else { "online" };
+                let description = vec![
+                    bulk("id"), bulk(&node.id),
+                    bulk("port"), DataType::Integer(node.port as i64),
+                    bulk("ip"), bulk(&node.ip),
+                    bulk("endpoint"), bulk(&node.ip),
+                    bulk("role"), bulk("master"),
+                    bulk("replication-offset"), DataType::Integer(0),
+                    bulk("health"), bulk(health),
+                ];
+                DataType::Array(Some(vec![
+                    bulk("slots"), DataType::Array(Some(slots)),
+                    bulk("nodes"), DataType::Array(Some(vec![DataType::Array(Some(description))])),
+                ]))
+            })
+            .collect()))
+    }
+
+    fn address(&self, index: usize) -> DataType {
+        let node = &self.nodes[index];
+        DataType::Array(Some(vec![
+            DataType::BulkString(Some(node.ip.clone().into_bytes())),
+            DataType::Integer(node.port as i64),
+            DataType::BulkString(Some(node.id.clone().into_bytes())),
+        ]))
+    }
+
+    /// CLUSTER INFO
+    pub fn info(&self) -> String {
+        let assigned = self.owners.iter().filter(|owner| owner.is_some()).count();
+        let pfail = self.owners.iter().filter(|owner| owner.is_some_and(|owner| self.failing(owner))).count();
+        let size = (0..self.nodes.len()).filter(|index| self.owners.contains(&Some(*index))).count();
+        let state = if assigned == SLOTS as usize { "ok" } else { "fail" };
+        let mut info = f!("cluster_state:{state}\r\n");
+        info += &f!("cluster_slots_assigned:{assigned}\r\ncluster_slots_ok:{}\r\n", assigned - pfail);
+        info += &f!("cluster_slots_pfail:{pfail}\r\ncluster_slots_fail:0\r\n");
+        info += &f!("cluster_known_nodes:{}\r\ncluster_size:{size}\r\n", self.nodes.len());
+        info += &f!("cluster_current_epoch:{}\r\ncluster_my_epoch:{}\r\n", self.current_epoch, self.nodes[0].config_epoch);
+        info
+    }
+
+    /// Read a config file written by `save_config`, or by Redis.
+    fn parse_config(&mut self, text: &str) -> std::result::Result<(), String> {
+        let bad = |line: &str| f!("{}: invalid line '{line}'", self.config_path.display());
+        let mut nodes = Vec::new();
+        let mut owners = vec![None; SLOTS as usize];
+        let mut moves = Vec::new();
+        for line in text.lines().filter(|line| !line.trim().is_empty()) {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields[0] == "vars" {
+                for pair in fields[1..].chunks(2) {
+                    if let [name, value] = pair {
+                        if *name == "currentEpoch" {
+                            self.current_epoch = value.parse().map_err(|_| bad(line))?;
+                        }
+                    }
+                }
+                continue;
+            }
+            if fields.len() < 8 {
+                return Err(bad(line));
+            }
+
+            // ip:port@cport, possibly followed by ,hostname
+            let address = fields[1].split(['@', ',']).next().unwrap_or_default();
+            let (ip, port) = address.rsplit_once(':').ok_or_else(|| bad(line))?;
+            let node = Node {
+                id: fields[0].to_owned(),
+                ip: ip.to_owned(),
+                port: port.parse().map_err(|_| bad(line))?,
+                config_epoch: fields[6].parse().map_err(|_| bad(line))?,
+                pong_received: now_ms(),
+            };
+            // this node first, so its index is 0
+            let index = if fields[2].split(',').any(|flag| flag == "myself") {
+                nodes.insert(0, node);
+                for owner in owners.iter_mut().flatten() {
+                    *owner += 1;
+                }
+                0
+            } else {
+                nodes.push(node);
+                nodes.len() - 1
+            };
+            // importing and migrating slots are listed in brackets, resolved once all nodes are known
+            for field in fields[8..].iter().filter(|field| field.starts_with('[')) {
+                let moving = field.trim_matches(['[', ']']);
+                let parsed = match (moving.split_once("->-"), moving.split_once("-<-")) {
+                    (Some((slot, id)), _) => slot.parse().ok().map(|slot: u16| (slot, true, id.to_owned())),
+                    (_, Some((slot, id))) => slot.parse().ok().map(|slot: u16| (slot, false, id.to_owned())),
+                    _ => None,
+                };
+                moves.push(parsed.filter(|(slot, _, _)| *slot < SLOTS).ok_or_else(|| bad(line))?);
+            }
+            for ranges in fields[8..].iter().filter(|field| !field.starts_with('[')) {
+                for (start, end) in parse_ranges(ranges).ok_or_else(|| bad(line))? {
+                    owners[start as usize..=end as usize].fill(Some(index));
+                }
+            }
+        }
+
+        if nodes.is_empty() || !text.lines().any(|line| line.contains("myself")) {
+            return Err(f!("{}: no node is flagged myself", self.config_path.display()));
+        }
+        for (slot, migrating, id) in moves {
+            let index = nodes.iter().position(|node| node.id == id)
+                .ok_or_else(|| f!("{}: unknown node {id}", self.config_path.display()))?;
+            match migrating {
+                true => self.migrating.insert(slot, index),
+                false => self.importing.insert(slot, index),
+            };
+        }
+        self.nodes = nodes;
+        self.owners = owners;
+        Ok(())
+    }
+
+    /// Write the node table to the config file, as Redis does after every change.
+    fn save_config(&self) {
+        let text = f!("{}vars currentEpoch {} lastVoteEpoch 0\n", self.nodes(), self.current_epoch);
+        let temp = self.config_path.with_extension("tmp");
+        if let Err(e) = fs::write(&temp, text).and_then(|_| fs::rename(&temp, &self.config_path)) {
+            println!("Can't save the cluster config to {}: {}", self.config_path.display(), e);
+        }
+    }
+}
+
+/// `a-b,c,...` slot ranges, where `-` is none.
+fn parse_ranges(text: &str) -> Option<Vec<(u16, u16)>> {
+    if text == "-" {
+        return Some(Vec::new());
+    }
+    text.split(',')
+        .map(|range| {
+            let (start, end) = range.split_once('-').unwrap_or((range, range));
+            let (start, end): (u16, u16) = (start.parse().ok()?, end.parse().ok()?);
+            (start <= end && end < SLOTS).then_some((start, end))
+        })
+        .collect()
+}
+
+/// Send gossip to the node at `host:port` and take in what it knows in return.
+pub async fn gossip(redis: Arc<Shards>, host: String, port: u16) {
+    let fields = redis.shared().cluster().gossip();
+    let reply = match tokio::time::timeout(GOSSIP_TIMEOUT, exchange(&host, port, fields)).await {
+        Ok(Ok(reply)) => reply,
+        // the node is flagged as failing once it has been silent for long enough
+        _ => return,
+    };
+    let merged = redis.shared().cluster().merge(&reply);
+    if let Err(e) = merged {
+        println!("Bad gossip from {}:{}: {}", host, port, e);
+    }
+}
+
+async fn exchange(host: &str, port: u16, fields: Vec<Vec<u8>>) -> std::result::Result<Vec<Vec<u8>>, String> {
+    let mut socket = TcpStream::connect((host, port)).await.map_err(|e| e.to_string())?;
+    let mut args = vec![DataType::BulkString(Some(b"cluster".to_vec())), DataType::BulkString(Some(b"gossip".to_vec()))];
+    args.extend(fields.into_iter().map(|field| DataType::BulkString(Some(field))));
+    socket.write_all(&serialize(&DataType::Array(Some(args))).unwrap()).await.map_err(|e| e.to_string())?;
+
+    let mut pending = Vec::new();
+    let reply = loop {
+        let mut buffer = vec![0; 16 * 1024];
+        let read = socket.read(&mut buffer).await.map_err(|e| e.to_string())?;
+        if read == 0 {
+            return Err("connection closed".to_owned());
+        }
+        pending.extend_from_slice(&buffer[..read]);
+        match deserialize_partial(&pending) {
+            Ok((reply, _)) => break reply,
+            Err(Error::Incomplete) | Err(Error::EmptyInput) => continue,
+            Err(e) => return Err(e.to_string()),
+        }
+    };
+    match reply {
+        DataType::Array(Some(fields)) => Ok(fields.into_iter()
+            .filter_map(|field| match field {
+                DataType::BulkString(Some(field)) => Some(field),
+                _ => None,
+            })
+            .collect()),
+        DataType::Error(e) => Err(e),
+        _ => Err("unexpected reply".to_owned()),
+    }
+}
+
+/// Send `commands` to the node at `host:port` and read a reply to each, for MIGRATE. This
+/// blocks the caller, with `timeout` for connecting and for each read.
+pub fn send_commands(host: &str, port: u16, timeout: Duration, commands: &[Vec<DataType>]) -> io::Result<Vec<DataType>> {
+    let addr = (host, port).to_socket_addrs()?.next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address for the target"))?;
+    let mut socket = std::net::TcpStream::connect_timeout(&addr, timeout)?;
+    socket.set_read_timeout(Some(timeout))?;
+    socket.set_write_timeout(Some(timeout))?;
+    let mut request = Vec::new();
+    for command in commands {
+        request.extend_from_slice(&serialize(&DataType::Array(Some(command.clone()))).unwrap());
+    }
+    socket.write_all(&request)?;
+
+    let mut replies = Vec::new();
+    let mut pending = Vec::new();
+    while replies.len() < commands.len() {
+        match deserialize_partial(&pending) {
+            Ok((reply, used)) => {
+                pending.drain(..used);
+                replies.push(reply);
+                continue;
+            },
+            Err(Error::Incomplete) | Err(Error::EmptyInput) => (),
+            Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e.to_string())),
+        }
+        let mut buffer = vec![0; 16 * 1024];
+        let read = socket.read(&mut buffer)?;
+        if read == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        pending.extend_from_slice(&buffer[..read]);
+    }
+    Ok(replies)
+}
+
+/// A fresh 40 character node id.
+fn new_node_id() -> String {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos();
+    let seed = f!("node-{nanos}-{}-{}", std::process::id(), NEXT_NODE_ID.fetch_add(1, Ordering::Relaxed));
+    scripting::sha1_hex(seed.as_bytes())
+}
+
+fn now_ms() -> u128 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis()
+}
+
+/// CRC16/XMODEM, the variant Redis Cluster hashes keys with.
+pub fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for byte in bytes {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+/// The slot of `key`. When the key has a non-empty `{...}` section only that part is hashed,
+/// so related keys can be kept in one slot.
+pub fn key_slot(key: &[u8]) -> u16 {
+    let tagged = key.iter().position(|byte| *byte == b'{').and_then(|open| {
+        let close = key[open + 1..].iter().position(|byte| *byte == b'}')?;
+        Some(&key[open + 1..open + 1 + close]).filter(|tag| !tag.is_empty())
+    });
+
+    crc16(tagged.unwrap_or(key)) % SLOTS
+}
+
+
+// region: --- tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc16_matches_redis() {
+        assert_eq!(crc16(b"123456789"), 0x31c3);
+        assert_eq!(key_slot(b"foo"), 12182);
+        assert_eq!(key_slot(b"bar"), 5061);
+    }
+
+    #[test]
+    fn hashtags_pick_the_hashed_part() {
+        assert_eq!(key_slot(b"{user1000}.following"), key_slot(b"user1000"));
+        assert_eq!(key_slot(b"foo{}{bar}"), crc16(b"foo{}{bar}") % SLOTS);
+        assert_eq!(key_slot(b"foo{{bar}}zap"), key_slot(b"{bar"));
+        assert_eq!(key_slot(b"foo{bar}{zap}"), key_slot(b"bar"));
+    }
+
+    fn node(name: &str, port: u16) -> Cluster {
+        let path = std::env::temp_dir().join(f!("nodes-{}-{name}.conf", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut cluster = Cluster::new();
+        cluster.start(path, port, 15000).unwrap();
+        cluster
+    }
+
+    #[test]
+    fn gossip_spreads_slot_ownership() {
+        let mut a = node("a", 7000);
+        let mut b = node("b", 7001);
+        a.add_slots(&(0..8192).collect::<Vec<_>>()).unwrap();
+        b.add_slots(&(8192..SLOTS).collect::<Vec<_>>()).unwrap();
+        assert!(a.add_slots(&[5]).unwrap_err().contains("busy"));
+
+        b.merge(&a.gossip()).unwrap();
+        a.merge(&b.gossip()).unwrap();
+        assert_eq!(a.check_keys(&[b"bar"], &[true], false), Ok(()));
+        assert_eq!(a.check_keys(&[b"foo"], &[true], false), Err("MOVED 12182 127.0.0.1:7001".to_owned()));
+        assert_eq!(b.check_keys(&[b"bar"], &[true], false), Err("MOVED 5061 127.0.0.1:7000".to_owned()));
+        assert!(a.check_keys(&[b"bar", b"foo"], &[true, true], false).unwrap_err().starts_with("CROSSSLOT"));
+        assert!(a.info().starts_with("cluster_state:ok"));
+
+        // a slot given up is free to claim once the newer claims are known
+        a.del_slots(&[0]).unwrap();
+        b.merge(&a.gossip()).unwrap();
+        b.add_slots(&[0]).unwrap();
+        a.merge(&b.gossip()).unwrap();
+        assert_eq!(a.ranges(1), vec![(0, 0), (8192, SLOTS - 1)]);
+        assert!(a.merge(&[b"1".to_vec(), b"x".to_vec()]).is_err());
+    }
+
+    #[test]
+    fn moving_slots_redirect_with_ask() {
+        let mut a = node("source", 7004);
+        let mut b = node("target", 7005);
+        a.add_slots(&[5061]).unwrap();
+        b.merge(&a.gossip()).unwrap();
+        a.merge(&b.gossip()).unwrap();
+        let (a_id, b_id) = (a.myid().to_owned(), b.myid().to_owned());
+
+        b.set_slot(5061, "importing", Some(&a_id), false).unwrap();
+        a.set_slot(5061, "migrating", Some(&b_id), true).unwrap();
+        assert!(a.set_slot(5061, "importing", Some(&b_id), true).unwrap_err().contains("already the owner"));
+        assert!(a.nodes().lines().next().unwrap().ends_with(&f!("5061 [5061->-{b_id}]")));
+
+        // keys still on the source are served there, the rest is asked of the target
+        assert_eq!(a.check_keys(&[b"bar"], &[true], false), Ok(()));
+        assert_eq!(a.check_keys(&[b"bar"], &[false], false), Err("ASK 5061 127.0.0.1:7005".to_owned()));
+        assert!(a.check_keys(&[b"bar", b"{bar}x"], &[true, false], false).unwrap_err().starts_with("TRYAGAIN"));
+        assert_eq!(b.check_keys(&[b"bar"], &[false], false), Err("MOVED 5061 127.0.0.1:7004".to_owned()));
+        assert_eq!(b.check_keys(&[b"bar"], &[false], true), Ok(()));
+
+        assert!(a.set_slot(5061, "node", Some(&b_id), true).unwrap_err().contains("still hold keys"));
+        b.set_slot(5061, "node", Some(&b_id), false).unwrap();
+        a.set_slot(5061, "node", Some(&b_id), false).unwrap();
+        a.merge(&b.gossip()).unwrap();
+        assert_eq!(a.check_keys(&[b"bar"], &[false], false), Err("MOVED 5061 127.0.0.1:7005".to_owned()));
+        assert_eq!(b.check_keys(&[b"bar"], &[false], false), Ok(()));
+        assert!(b.importing.is_empty() && a.migrating.is_empty());
+    }
+
+    #[test]
+    fn config_file_survives_a_restart() {
+        let mut a = node("restart", 7002);
+        a.add_slots(&[1, 2, 3, 10]).unwrap();
+        a.merge(&node("other", 7003).gossip()).unwrap();
+        let nodes = a.nodes();
+
+        let mut restarted = Cluster::new();
+        restarted.start(a.config_path.clone(), 7002, 15000).unwrap();
+        assert_eq!(restarted.myid(), a.myid());
+        assert_eq!(restarted.ranges(0), vec![(1, 3), (10, 10)]);
+        assert_eq!(restarted.current_epoch, a.current_epoch);
+        assert_eq!(restarted.nodes().lines().count(), 2);
+        assert!(nodes.lines().next().unwrap().ends_with("myself,master - 0 0 1 connected 1-3 10"));
+        fs::remove_file(&a.config_path).unwrap();
+    }
+}
+// endregion: --- tests
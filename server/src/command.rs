@@ -0,0 +1,207 @@
+//! Table of the commands the server understands, used to validate a command before it runs
+
+use utils::DataType;
+
+/// The command may modify the dataset.
+pub const WRITE: u8 = 1 << 0;
+/// The command can not be run from a Lua script.
+pub const NOSCRIPT: u8 = 1 << 1;
+/// The command is served for a slot being imported without ASKING first.
+pub const ASKING: u8 = 1 << 2;
+/// The command may grow the dataset, so it is refused while over `maxmemory`.
+pub const DENYOOM: u8 = 1 << 3;
+/// The command looks at its keys without counting as an access for eviction.
+pub const NOTOUCH: u8 = 1 << 4;
+/// The command works on the whole keyspace, or on state only the first shard keeps, so it
+/// runs with every shard locked.
+pub const GLOBAL: u8 = 1 << 5;
+
+/// Where a command's keys are among its arguments, counting the command name as 0.
+#[derive(Clone, Copy)]
+pub enum Keys {
+    None,
+    /// First and last key position and the step between keys, Redis style: a negative last
+    /// position counts from the end.
+    Range(usize, isize, usize),
+    /// The number of keys at this position, followed by the keys, as in EVAL.
+    Counted(usize),
+}
+
+pub struct Command {
+    pub name: &'static str,
+    /// Redis style arity counting the command name: positive is exact, negative is a minimum.
+    pub arity: i32,
+    /// Bitmask of `WRITE`, `NOSCRIPT`, `ASKING`, `DENYOOM`, `NOTOUCH`, `GLOBAL`.
+    pub flags: u8,
+    pub keys: Keys,
+}
+
+impl Command {
+    pub fn arity_ok(&self, len: usize) -> bool {
+        if self.arity >= 0 {
+            len == self.arity as usize
+        } else {
+            len >= self.arity.unsigned_abs() as usize
+        }
+    }
+
+    /// The key arguments of `args`, a whole command that passed the arity check.
+    pub fn key_args<'a>(&self, args: &'a [DataType]) -> Vec<&'a [u8]> {
+        let (first, last, step) = match self.keys {
+            Keys::None => return Vec::new(),
+            Keys::Range(first, last, step) => (first, if last < 0 { args.len() as isize + last } else { last }, step),
+            Keys::Counted(at) => {
+                let count: usize = match &args[at] {
+                    DataType::BulkString(Some(val)) => String::from_utf8_lossy(val).parse().unwrap_or(0),
+                    _ => 0,
+                };
+                // more keys than arguments is the command's own error to report
+                if count > args.len() - at - 1 {
+                    return Vec::new();
+                }
+                (at + 1, (at + count) as isize, 1)
+            },
+        };
+        (first..).step_by(step)
+            .take_while(|at| *at < args.len() && *at as isize <= last)
+            .filter_map(|at| match args.get(at) {
+                Some(DataType::BulkString(Some(key))) => Some(key.as_slice()),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+const fn command(name: &'static str, arity: i32, flags: u8) -> Command {
+    Command { name, arity, flags, keys: Keys::None }
+}
+
+/// A command whose keys are at positions `first` to `last`, `step` apart.
+const fn keyed(name: &'static str, arity: i32, flags: u8, first: usize, last: isize, step: usize) -> Command {
+    Command { name, arity, flags, keys: Keys::Range(first, last, step) }
+}
+
+/// A command with a key count argument at `at`, followed by the keys.
+const fn counted(name: &'static str, arity: i32, flags: u8, at: usize) -> Command {
+    Command { name, arity, flags, keys: Keys::Counted(at) }
+}
+
+const COMMANDS: &[Command] = &[
+    keyed("set", -3, WRITE | DENYOOM, 1, 1, 1),
+    keyed("get", 2, 0, 1, 1, 1),
+    keyed("exists", -2, 0, 1, -1, 1),
+    keyed("del", -2, WRITE, 1, -1, 1),
+    keyed("unlink", -2, WRITE, 1, -1, 1),
+    keyed("touch", -2, 0, 1, -1, 1),
+    keyed("type", 2, 0, 1, 1, 1),
+    keyed("rename", 3, WRITE, 1, 2, 1),
+    keyed("renamenx", 3, WRITE, 1, 2, 1),
+    keyed("copy", -3, WRITE | DENYOOM, 1, 2, 1),
+    command("randomkey", 1, GLOBAL),
+    command("dbsize", 1, GLOBAL),
+    command("flushdb", -1, WRITE | GLOBAL),
+    command("flushall", -1, WRITE | GLOBAL),
+    command("select", 2, 0),
+    command("swapdb", 3, WRITE | GLOBAL),
+    keyed("move", 3, WRITE, 1, 1, 1),
+    keyed("incr", 2, WRITE | DENYOOM, 1, 1, 1),
+    keyed("decr", 2, WRITE | DENYOOM, 1, 1, 1),
+    keyed("lpush", -3, WRITE | DENYOOM, 1, 1, 1),
+    keyed("rpush", -3, WRITE | DENYOOM, 1, 1, 1),
+    keyed("lrange", 2, 0, 1, 1, 1),
+    keyed("setbit", 4, WRITE | DENYOOM, 1, 1, 1),
+    keyed("getbit", 3, 0, 1, 1, 1),
+    keyed("bitcount", -2, 0, 1, 1, 1),
+    keyed("bitpos", -3, 0, 1, 1, 1),
+    keyed("bitop", -4, WRITE | DENYOOM, 2, -1, 1),
+    keyed("bitfield", -2, WRITE | DENYOOM, 1, 1, 1),
+    keyed("bitfield_ro", -2, 0, 1, 1, 1),
+    keyed("pfadd", -2, WRITE | DENYOOM, 1, 1, 1),
+    keyed("pfcount", -2, 0, 1, -1, 1),
+    keyed("pfmerge", -2, WRITE | DENYOOM, 1, -1, 1),
+    keyed("zadd", -4, WRITE | DENYOOM, 1, 1, 1),
+    keyed("zrem", -3, WRITE, 1, 1, 1),
+    keyed("zscore", 3, 0, 1, 1, 1),
+    keyed("zcard", 2, 0, 1, 1, 1),
+    keyed("zrange", -4, 0, 1, 1, 1),
+    keyed("zscan", -3, 0, 1, 1, 1),
+    keyed("geoadd", -5, WRITE | DENYOOM, 1, 1, 1),
+    keyed("geopos", -2, 0, 1, 1, 1),
+    keyed("geodist", -4, 0, 1, 1, 1),
+    keyed("geohash", -2, 0, 1, 1, 1),
+    keyed("geosearch", -7, 0, 1, 1, 1),
+    keyed("geosearchstore", -8, WRITE | DENYOOM, 1, 2, 1),
+    command("multi", 1, NOSCRIPT),
+    command("exec", 1, NOSCRIPT),
+    command("discard", 1, NOSCRIPT),
+    keyed("watch", -2, NOSCRIPT, 1, -1, 1),
+    command("unwatch", 1, NOSCRIPT),
+    counted("eval", -3, NOSCRIPT | DENYOOM | GLOBAL, 2),
+    counted("evalsha", -3, NOSCRIPT | DENYOOM | GLOBAL, 2),
+    counted("eval_ro", -3, NOSCRIPT | GLOBAL, 2),
+    counted("evalsha_ro", -3, NOSCRIPT | GLOBAL, 2),
+    command("script", -2, NOSCRIPT | GLOBAL),
+    counted("fcall", -3, NOSCRIPT | DENYOOM | GLOBAL, 2),
+    counted("fcall_ro", -3, NOSCRIPT | GLOBAL, 2),
+    command("function", -2, NOSCRIPT | GLOBAL),
+    command("ping", -1, 0),
+    command("subscribe", -2, NOSCRIPT),
+    command("unsubscribe", -1, NOSCRIPT),
+    command("psubscribe", -2, NOSCRIPT),
+    command("punsubscribe", -1, NOSCRIPT),
+    command("publish", 3, 0),
+    command("pubsub", -2, 0),
+    keyed("ssubscribe", -2, NOSCRIPT, 1, -1, 1),
+    keyed("sunsubscribe", -1, NOSCRIPT, 1, -1, 1),
+    keyed("spublish", 3, 0, 1, 1, 1),
+    command("config", -2, NOSCRIPT | GLOBAL),
+    command("save", 1, NOSCRIPT | GLOBAL),
+    command("bgsave", -1, NOSCRIPT | GLOBAL),
+    command("lastsave", 1, NOSCRIPT),
+    command("bgrewriteaof", 1, NOSCRIPT | GLOBAL),
+    keyed("dump", 2, 0, 1, 1, 1),
+    keyed("restore", -4, WRITE | DENYOOM | NOTOUCH, 1, 1, 1),
+    command("replicaof", 3, NOSCRIPT | GLOBAL),
+    command("slaveof", 3, NOSCRIPT | GLOBAL),
+    command("replconf", -1, NOSCRIPT),
+    command("psync", -3, NOSCRIPT | GLOBAL),
+    command("sync", 1, NOSCRIPT | GLOBAL),
+    command("role", 1, NOSCRIPT),
+    command("keys", 2, GLOBAL),
+    command("scan", -2, GLOBAL),
+    command("info", -1, GLOBAL),
+    command("wait", 3, NOSCRIPT),
+    command("waitaof", 4, NOSCRIPT),
+    command("cluster", -2, NOSCRIPT | GLOBAL),
+    command("asking", 1, 0),
+    command("migrate", -6, WRITE | NOSCRIPT | GLOBAL),
+    keyed("restore-asking", -4, WRITE | DENYOOM | ASKING, 1, 1, 1),
+    keyed("object", -2, NOTOUCH, 2, 2, 1),
+    keyed("memory", -2, NOTOUCH | GLOBAL, 2, 2, 1),
+];
+
+/// Look up a command by its lowercase name.
+pub fn lookup(name: &str) -> Option<&'static Command> {
+    COMMANDS.iter().find(|command| command.name == name)
+}
+
+
+// region: --- tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<DataType> {
+        args.iter().map(|arg| DataType::BulkString(Some(arg.as_bytes().to_vec()))).collect()
+    }
+
+    #[test]
+    fn counted_keys_stay_within_the_arguments() {
+        let eval = lookup("eval").unwrap();
+        assert_eq!(eval.key_args(&args(&["eval", "return 1", "2", "a", "b", "c"])), [b"a", b"b"]);
+        assert!(eval.key_args(&args(&["eval", "return 1", "3", "a", "b"])).is_empty());
+        assert!(eval.key_args(&args(&["eval", "return 1", "18446744073709551615"])).is_empty());
+        assert!(eval.key_args(&args(&["eval", "return 1", "-1", "a"])).is_empty());
+    }
+}
+// endregion: --- tests
@@ -0,0 +1,374 @@
+//! Runtime configuration, read and changed with CONFIG GET and CONFIG SET.
+
+use std::path::PathBuf;
+
+use utils::prelude::*;
+
+use crate::aof::Fsync;
+use crate::eviction::{Lfu, Policy};
+use crate::glob;
+use crate::notify;
+
+/// Parameter names, in the order CONFIG GET lists them.
+const PARAMETERS: &[&str] = &[
+    "notify-keyspace-events", "save", "dir", "dbfilename",
+    "appendonly", "appendfsync", "appendfilename", "appenddirname",
+    "port", "replicaof", "replica-read-only", "repl-backlog-size",
+    "cluster-enabled", "cluster-config-file", "cluster-node-timeout",
+    "maxmemory", "maxmemory-policy", "maxmemory-samples", "lfu-log-factor", "lfu-decay-time",
+    "databases",
+];
+
+/// Parameters only settable at startup.
+const IMMUTABLE: &[&str] = &[
+    "appendfilename", "appenddirname", "port", "replicaof", "cluster-enabled", "cluster-config-file",
+    "databases",
+];
+
+#[derive(Clone)]
+pub struct Config {
+    /// Enabled keyspace notification classes, see `notify`.
+    pub notify_keyspace_events: u16,
+    /// `(seconds, changes)` rules: save in the background once `changes` writes are at least
+    /// `seconds` old. Empty disables automatic saving.
+    pub save: Vec<(u64, u64)>,
+    pub dir: String,
+    pub dbfilename: String,
+    pub appendonly: bool,
+    pub appendfsync: Fsync,
+    /// Prefix of the AOF file names, fixed at startup.
+    pub appendfilename: String,
+    /// Directory under `dir` holding the AOF files, fixed at startup.
+    pub appenddirname: String,
+    pub port: u16,
+    /// The primary to replicate from; changed at runtime with REPLICAOF.
+    pub replicaof: Option<(String, u16)>,
+    pub replica_read_only: bool,
+    pub repl_backlog_size: usize,
+    pub cluster_enabled: bool,
+    /// Where the cluster node table is kept, under `dir`; fixed at startup.
+    pub cluster_config_file: String,
+    /// Milliseconds without a gossip reply before a node is flagged as failing.
+    pub cluster_node_timeout: u64,
+    /// Bytes the dataset may hold before keys are evicted, 0 for no limit.
+    pub maxmemory: usize,
+    pub maxmemory_policy: Policy,
+    /// Keys sampled per eviction by the approximate LRU, LFU and TTL policies.
+    pub maxmemory_samples: usize,
+    pub lfu_log_factor: u32,
+    /// Minutes for an LFU counter to decay by one, 0 for never.
+    pub lfu_decay_time: u32,
+    /// Number of logical databases, fixed at startup.
+    pub databases: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            notify_keyspace_events: 0,
+            save: vec![(3600, 1), (300, 100), (60, 10000)],
+            dir: ".".to_owned(),
+            dbfilename: "dump.rdb".to_owned(),
+            appendonly: false,
+            appendfsync: Fsync::EverySec,
+            appendfilename: "appendonly.aof".to_owned(),
+            appenddirname: "appendonlydir".to_owned(),
+            port: 6379,
+            replicaof: None,
+            replica_read_only: true,
+            repl_backlog_size: 1024 * 1024,
+            cluster_enabled: false,
+            cluster_config_file: "nodes.conf".to_owned(),
+            cluster_node_timeout: 15000,
+            maxmemory: 0,
+            maxmemory_policy: Policy::NoEviction,
+            maxmemory_samples: 5,
+            lfu_log_factor: 10,
+            lfu_decay_time: 1,
+            databases: 16,
+        }
+    }
+}
+
+impl Config {
+    /// Where snapshots are saved and loaded from.
+    pub fn rdb_path(&self) -> PathBuf {
+        PathBuf::from(&self.dir).join(&self.dbfilename)
+    }
+
+    pub fn aof_dir(&self) -> PathBuf {
+        PathBuf::from(&self.dir).join(&self.appenddirname)
+    }
+
+    pub fn cluster_config_path(&self) -> PathBuf {
+        PathBuf::from(&self.dir).join(&self.cluster_config_file)
+    }
+
+    pub fn lfu(&self) -> Lfu {
+        Lfu { log_factor: self.lfu_log_factor, decay_time: self.lfu_decay_time }
+    }
+
+    /// Apply `--name value` command line arguments, where a value may span several arguments
+    /// as in `--save 900 1`.
+    pub fn set_args(&mut self, args: impl Iterator<Item = String>) -> std::result::Result<(), String> {
+        let mut pairs: Vec<(String, String)> = Vec::new();
+        for arg in args {
+            if let Some(name) = arg.strip_prefix("--") {
+                pairs.push((name.to_owned(), String::new()));
+                continue;
+            }
+            let Some((_, value)) = pairs.last_mut() else {
+                return Err(f!("ERR unexpected argument '{arg}'"));
+            };
+            if !value.is_empty() {
+                value.push(' ');
+            }
+            value.push_str(&arg);
+        }
+        self.set_startup(&pairs)
+    }
+
+    /// Every parameter whose name matches the glob `pattern`, with its value.
+    pub fn get(&self, pattern: &str) -> Vec<(&'static str, String)> {
+        let pattern = pattern.to_ascii_lowercase();
+        PARAMETERS.iter()
+            .filter(|name| glob::matches(pattern.as_bytes(), name.as_bytes()))
+            .map(|name| (*name, self.value(name)))
+            .collect()
+    }
+
+    /// Set each `(name, value)` pair, changing nothing unless all of them are valid.
+    pub fn set(&mut self, pairs: &[(String, String)]) -> std::result::Result<(), String> {
+        for (name, _) in pairs {
+            let name = name.to_ascii_lowercase();
+            if IMMUTABLE.contains(&name.as_str()) {
+                return Err(invalid(&name, "can't set immutable config"));
+            }
+        }
+        self.set_startup(pairs)
+    }
+
+    /// Like `set`, but immutable parameters may be given too.
+    fn set_startup(&mut self, pairs: &[(String, String)]) -> std::result::Result<(), String> {
+        let mut next = self.clone();
+        for (name, value) in pairs {
+            let name = name.to_ascii_lowercase();
+            match name.as_str() {
+                "notify-keyspace-events" => {
+                    next.notify_keyspace_events = notify::parse(value).ok_or_else(|| {
+                        invalid(&name, "Invalid event class character. Use 'Ag$lshzxeKEtmdn'.")
+                    })?;
+                },
+                "save" => next.save = parse_save(value).ok_or_else(|| invalid(&name, "Invalid save parameters"))?,
+                "dir" => {
+                    if !std::path::Path::new(value).is_dir() {
+                        return Err(invalid(&name, "No such file or directory"));
+                    }
+                    next.dir = value.clone();
+                },
+                "dbfilename" => {
+                    if value.contains('/') || value.is_empty() {
+                        return Err(invalid(&name, "dbfilename can't be a path, just a filename"));
+                    }
+                    next.dbfilename = value.clone();
+                },
+                "appendonly" => next.appendonly = parse_bool(value).ok_or_else(|| invalid(&name, "argument must be 'yes' or 'no'"))?,
+                "appendfsync" => next.appendfsync = Fsync::parse(value).ok_or_else(|| invalid(&name, "argument(s) must be one of the following: always, everysec, no"))?,
+                "appendfilename" | "appenddirname" => {
+                    if value.contains('/') || value.is_empty() {
+                        return Err(invalid(&name, "appendfilename/appenddirname can't be a path, just a filename"));
+                    }
+                    if name == "appendfilename" {
+                        next.appendfilename = value.clone();
+                    } else {
+                        next.appenddirname = value.clone();
+                    }
+                },
+                "port" => next.port = value.parse().map_err(|_| invalid(&name, "argument couldn't be parsed into an integer"))?,
+                "replicaof" => next.replicaof = parse_replicaof(value).ok_or_else(|| invalid(&name, "Invalid master address"))?,
+                "replica-read-only" => next.replica_read_only = parse_bool(value).ok_or_else(|| invalid(&name, "argument must be 'yes' or 'no'"))?,
+                "repl-backlog-size" => {
+                    next.repl_backlog_size = parse_memory(value)
+                        .filter(|size| *size > 0)
+                        .ok_or_else(|| invalid(&name, "argument must be a memory value"))?;
+                },
+                "cluster-enabled" => next.cluster_enabled = parse_bool(value).ok_or_else(|| invalid(&name, "argument must be 'yes' or 'no'"))?,
+                "cluster-config-file" => {
+                    if value.is_empty() {
+                        return Err(invalid(&name, "cluster-config-file can't be empty"));
+                    }
+                    next.cluster_config_file = value.clone();
+                },
+                "cluster-node-timeout" => {
+                    next.cluster_node_timeout = value.parse()
+                        .ok()
+                        .filter(|timeout| *timeout > 0)
+                        .ok_or_else(|| invalid(&name, "argument couldn't be parsed into an integer"))?;
+                },
+                "maxmemory" => next.maxmemory = parse_memory(value).ok_or_else(|| invalid(&name, "argument must be a memory value"))?,
+                "maxmemory-policy" => next.maxmemory_policy = Policy::parse(value).ok_or_else(|| invalid(&name, "argument(s) must be one of the following: volatile-lru, allkeys-lru, volatile-lfu, allkeys-lfu, volatile-random, allkeys-random, volatile-ttl, noeviction"))?,
+                "maxmemory-samples" => {
+                    next.maxmemory_samples = value.parse()
+                        .ok()
+                        .filter(|samples| (1..=64).contains(samples))
+                        .ok_or_else(|| invalid(&name, "argument must be between 1 and 64 inclusive"))?;
+                },
+                "lfu-log-factor" | "lfu-decay-time" => {
+                    let parsed = value.parse().map_err(|_| invalid(&name, "argument couldn't be parsed into an integer"))?;
+                    if name == "lfu-log-factor" {
+                        next.lfu_log_factor = parsed;
+                    } else {
+                        next.lfu_decay_time = parsed;
+                    }
+                },
+                "databases" => {
+                    next.databases = value.parse()
+                        .ok()
+                        .filter(|databases| *databases > 0)
+                        .ok_or_else(|| invalid(&name, "argument must be between 1 and 2147483647 inclusive"))?;
+                },
+                _ => return Err(f!("ERR Unknown option or number of arguments for CONFIG SET - '{name}'")),
+            }
+        }
+
+        *self = next;
+        Ok(())
+    }
+
+    fn value(&self, name: &str) -> String {
+        match name {
+            "notify-keyspace-events" => notify::format(self.notify_keyspace_events),
+            "save" => self.save.iter().map(|(seconds, changes)| f!("{seconds} {changes}")).collect::<Vec<_>>().join(" "),
+            "dir" => self.dir.clone(),
+            "dbfilename" => self.dbfilename.clone(),
+            "appendonly" => if self.appendonly { "yes" } else { "no" }.to_owned(),
+            "appendfsync" => self.appendfsync.name().to_owned(),
+            "appendfilename" => self.appendfilename.clone(),
+            "appenddirname" => self.appenddirname.clone(),
+            "port" => self.port.to_string(),
+            "replicaof" => self.replicaof.as_ref().map(|(host, port)| f!("{host} {port}")).unwrap_or_default(),
+            "replica-read-only" => if self.replica_read_only { "yes" } else { "no" }.to_owned(),
+            "repl-backlog-size" => self.repl_backlog_size.to_string(),
+            "cluster-enabled" => if self.cluster_enabled { "yes" } else { "no" }.to_owned(),
+            "cluster-config-file" => self.cluster_config_file.clone(),
+            "cluster-node-timeout" => self.cluster_node_timeout.to_string(),
+            "maxmemory" => self.maxmemory.to_string(),
+            "maxmemory-policy" => self.maxmemory_policy.name().to_owned(),
+            "maxmemory-samples" => self.maxmemory_samples.to_string(),
+            "lfu-log-factor" => self.lfu_log_factor.to_string(),
+            "lfu-decay-time" => self.lfu_decay_time.to_string(),
+            "databases" => self.databases.to_string(),
+            _ => String::new(),
+        }
+    }
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value.to_ascii_lowercase().as_str() {
+        "yes" => Some(true),
+        "no" => Some(false),
+        _ => None,
+    }
+}
+
+/// Parse `<host> <port>`, or `no one` for none.
+fn parse_replicaof(value: &str) -> Option<Option<(String, u16)>> {
+    let fields: Vec<&str> = value.split_whitespace().collect();
+    match fields.as_slice() {
+        [] => Some(None),
+        [no, one] if no.eq_ignore_ascii_case("no") && one.eq_ignore_ascii_case("one") => Some(None),
+        [host, port] => Some(Some((host.to_string(), port.parse().ok()?))),
+        _ => None,
+    }
+}
+
+/// Parse a byte count such as `1048576`, `64kb` or `1gb`.
+pub fn parse_memory(value: &str) -> Option<usize> {
+    let value = value.to_ascii_lowercase();
+    let units: [(&str, usize); 6] = [("kb", 1024), ("mb", 1 << 20), ("gb", 1 << 30), ("k", 1000), ("m", 1_000_000), ("g", 1_000_000_000)];
+    for (suffix, unit) in units {
+        if let Some(number) = value.strip_suffix(suffix) {
+            return number.parse::<usize>().ok()?.checked_mul(unit);
+        }
+    }
+    value.parse().ok()
+}
+
+/// Parse `<seconds> <changes> [<seconds> <changes> ...]`, where an empty string means no rules.
+fn parse_save(value: &str) -> Option<Vec<(u64, u64)>> {
+    let numbers: Vec<u64> = value.split_whitespace().map(|each| each.parse().ok()).collect::<Option<_>>()?;
+    if !numbers.len().is_multiple_of(2) {
+        return None;
+    }
+    Some(numbers.chunks(2).map(|rule| (rule[0], rule[1])).collect())
+}
+
+fn invalid(name: &str, reason: &str) -> String {
+    f!("ERR CONFIG SET failed (possibly related to argument '{name}') - {reason}")
+}
+
+
+// region: --- tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_is_all_or_nothing() {
+        let mut config = Config::default();
+        assert!(config.set(&[("notify-keyspace-events".to_owned(), "KEA".to_owned())]).is_ok());
+        assert_eq!(config.get("notify-*"), vec![("notify-keyspace-events", "AKE".to_owned())]);
+
+        let bad = [
+            ("notify-keyspace-events".to_owned(), "E".to_owned()),
+            ("no-such-option".to_owned(), "1".to_owned()),
+        ];
+        assert!(config.set(&bad).is_err());
+        assert_eq!(config.get("notify-keyspace-events")[0].1, "AKE");
+    }
+
+    #[test]
+    fn save_rules_parse() {
+        let mut config = Config::default();
+        assert_eq!(config.get("save")[0].1, "3600 1 300 100 60 10000");
+        assert!(config.set(&[("save".to_owned(), "900 1 60 5".to_owned())]).is_ok());
+        assert_eq!(config.save, vec![(900, 1), (60, 5)]);
+        assert!(config.set(&[("save".to_owned(), "".to_owned())]).is_ok());
+        assert!(config.save.is_empty());
+        assert!(config.set(&[("save".to_owned(), "900".to_owned())]).is_err());
+        assert!(config.set(&[("dbfilename".to_owned(), "a/b.rdb".to_owned())]).is_err());
+    }
+
+    #[test]
+    fn startup_arguments() {
+        let mut config = Config::default();
+        let args = ["--appendonly", "yes", "--save", "900", "1", "--appendfilename", "x.aof"];
+        assert!(config.set_args(args.iter().map(|arg| arg.to_string())).is_ok());
+        assert!(config.appendonly);
+        assert_eq!(config.save, vec![(900, 1)]);
+        assert_eq!(config.appendfilename, "x.aof");
+
+        assert!(config.set(&[("appendfilename".to_owned(), "y.aof".to_owned())]).is_err());
+        assert!(config.set_args(["stray".to_owned()].into_iter()).is_err());
+    }
+
+    #[test]
+    fn memory_values() {
+        assert_eq!(parse_memory("1048576"), Some(1 << 20));
+        assert_eq!(parse_memory("64KB"), Some(64 * 1024));
+        assert_eq!(parse_memory("2m"), Some(2_000_000));
+        assert_eq!(parse_memory("lots"), None);
+
+        let mut config = Config::default();
+        let pairs = [("maxmemory".to_owned(), "100mb".to_owned()), ("maxmemory-policy".to_owned(), "ALLKEYS-LFU".to_owned())];
+        assert!(config.set(&pairs).is_ok());
+        assert_eq!(config.get("maxmemory*"), vec![
+            ("maxmemory", (100 << 20).to_string()),
+            ("maxmemory-policy", "allkeys-lfu".to_owned()),
+            ("maxmemory-samples", "5".to_owned()),
+        ]);
+        assert!(config.set(&[("maxmemory-policy".to_owned(), "lru".to_owned())]).is_err());
+        assert!(config.set(&[("maxmemory-samples".to_owned(), "0".to_owned())]).is_err());
+    }
+}
+// endregion: --- tests
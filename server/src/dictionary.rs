@@ -1,35 +1,145 @@
 use utils::DataType;
 use utils::serializer::serialize;
 use utils::prelude::*;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use crate::aof::{Aof, Fsync, Loaded};
+use crate::bitmap::{self, BitOp, FieldType, Overflow, RangeUnit};
+use crate::cluster;
+use crate::command;
+use crate::config::Config;
+use crate::eviction::{self, Keyspace};
+use crate::functions::{FunctionStore, RestorePolicy};
+use crate::geo::{self, Shape};
+use crate::glob;
+use crate::hyperloglog;
+use crate::lazyfree;
+use crate::notify;
+use crate::persistence;
+use crate::pubsub::Pushes;
+use crate::rdb::{self, Snapshot};
+use crate::scan;
+use crate::replication::{Replication, Wait};
+use crate::scripting::{self, ScriptCache};
+use crate::shards::{Shared, Sharded};
+use crate::sorted_set::SortedSet;
+
+#[allow(clippy::module_inception)]
 pub mod dictionary {
     use std::collections::{HashMap, LinkedList};
+    use std::mem::size_of;
 
     use super::*;
 
-    const SUCCESS_MSG: &'static str = "+OK\r\n";
+    const SUCCESS_MSG: &[u8] = b"+OK\r\n";
+    /// Elements sampled when estimating the size of a collection.
+    const SIZE_SAMPLES: usize = 5;
+    /// Limits under which Redis keeps values in its compact encodings, as OBJECT ENCODING
+    /// reports them.
+    const EMBSTR_MAX: usize = 44;
+    const LIST_LISTPACK_BYTES: usize = 8 * 1024;
+    const ZSET_LISTPACK_ENTRIES: usize = 128;
+    const ZSET_LISTPACK_VALUE: usize = 64;
+    /// Integers below this are shared objects in Redis, with an endless OBJECT REFCOUNT.
+    const SHARED_INTEGERS: i64 = 10000;
+    /// Below this MEMORY DOCTOR has nothing to say.
+    const EMPTY_INSTANCE_BYTES: usize = 5 << 20;
+    const BIG_KEY_AVERAGE: usize = 1 << 20;
 
     pub struct Dictionary {
-        dict: HashMap<String, ExpireValue>,
-        lists: HashMap<String, LinkedList<String>>,
+        /// The selected database. Its slot in `dbs` holds an empty one meanwhile.
+        db: Db,
+        dbs: Vec<Db>,
+        selected: usize,
+        /// The shard whose part of the keyspace this dictionary holds, see `shards`.
+        shard: usize,
+        /// The database the primary's stream is applying to.
+        replicated_db: usize,
+        /// Modification versions of keys some client is WATCHing, with the number of watchers,
+        /// by database.
+        watched_keys: Vec<WatchedKeys>,
+        /// Scripts and functions are kept by the first shard, which runs every command using
+        /// them with all the shards locked.
+        scripts: ScriptCache,
+        functions: FunctionStore,
+        config: Config,
+        /// Set when `config` changed, for the other shards to take the change.
+        config_changed: bool,
+        shared: Arc<Shared>,
+    }
+
+    /// Number of watchers and modification version of each WATCHed key of a database.
+    type WatchedKeys = Sharded<HashMap<Vec<u8>, (usize, u64)>>;
+
+    /// One logical database, as chosen with SELECT.
+    #[derive(Default)]
+    struct Db {
+        dict: Sharded<HashMap<Vec<u8>, ExpireValue>>,
+        lists: Sharded<HashMap<Vec<u8>, LinkedList<Vec<u8>>>>,
+        zsets: Sharded<HashMap<Vec<u8>, SortedSet>>,
+        /// Expiry times of the lists and sorted sets with a TTL. Strings keep theirs in their
+        /// `ExpireValue`.
+        expires: Sharded<HashMap<Vec<u8>, u128>>,
+        /// Estimated size and access metadata of every key, for `maxmemory`.
+        keyspace: Sharded<Keyspace>,
+    }
+
+    impl Db {
+        /// Trade the part of `shard` with `other`'s.
+        fn exchange(&mut self, other: &mut Db, shard: usize) {
+            self.dict.exchange(&mut other.dict, shard);
+            self.lists.exchange(&mut other.lists, shard);
+            self.zsets.exchange(&mut other.zsets, shard);
+            self.expires.exchange(&mut other.expires, shard);
+            self.keyspace.exchange(&mut other.keyspace, shard);
+        }
+
+        fn len(&self) -> usize {
+            let expired = self.expires.keys().filter(|key| self.collection_expired(key)).count();
+            self.dict.values().filter(|val| !val.is_expire()).count() + self.lists.len() + self.zsets.len() - expired
+        }
+
+        /// When `key` expires, whatever its type.
+        fn expire_at(&self, key: &[u8]) -> Option<u128> {
+            match self.dict.get(key) {
+                Some(val) => val.exp,
+                None => self.expires.get(key).copied(),
+            }
+        }
+
+        /// Whether `key` is a list or sorted set whose time has passed.
+        fn collection_expired(&self, key: &[u8]) -> bool {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+            self.expires.get(key).is_some_and(|exp| now > *exp)
+        }
+
+        /// Keys with a TTL, and the average milliseconds they have left.
+        fn expires(&self) -> (usize, u128) {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+            let ttls: Vec<u128> = self.dict.values().filter_map(|val| val.exp)
+                .chain(self.expires.values().copied())
+                .filter_map(|exp| exp.checked_sub(now))
+                .collect();
+            (ttls.len(), ttls.iter().sum::<u128>().checked_div(ttls.len() as u128).unwrap_or(0))
+        }
     }
 
     #[derive(Clone)]
     struct ExpireValue {
-        value: String,
+        value: Vec<u8>,
         exp: Option<u128>,
     }
 
     impl ExpireValue {
-        fn no_expire(value: String) -> Self {
+        fn no_expire(value: Vec<u8>) -> Self {
             Self {
                 value,
                 exp: None,
             }
         }
 
-        fn expire_seconds(value: String, exp: u128) -> Self {
+        fn expire_seconds(value: Vec<u8>, exp: u128) -> Self {
             let start = SystemTime::now();
             let since_the_epoch = start.duration_since(UNIX_EPOCH).unwrap();
             let unix_timestamp = since_the_epoch.as_millis();
@@ -40,7 +150,7 @@ pub mod dictionary {
             }
         }
 
-        fn expire_millis(value: String, exp: u128) -> Self {
+        fn expire_millis(value: Vec<u8>, exp: u128) -> Self {
             let start = SystemTime::now();
             let since_the_epoch = start.duration_since(UNIX_EPOCH).unwrap();
             let unix_timestamp = since_the_epoch.as_millis();
@@ -51,14 +161,14 @@ pub mod dictionary {
             }
         }
 
-        fn specific_expire_seconds(value: String, exp: u128) -> Self {
+        fn specific_expire_seconds(value: Vec<u8>, exp: u128) -> Self {
             Self {
                 value,
                 exp: Some(exp * 1000),
             }
         }
 
-        fn specific_expire_millis(value: String, exp: u128) -> Self {
+        fn specific_expire_millis(value: Vec<u8>, exp: u128) -> Self {
             Self {
                 value,
                 exp: Some(exp),
@@ -74,125 +184,2628 @@ pub mod dictionary {
                 return unix_timestamp > exp;
             }
 
-            return false;
+            false
+        }
+    }
+
+    /// A MIGRATE between reading the keys it moves and deleting them here, so the round trips
+    /// to the target can run with no shard locked.
+    pub struct Migration {
+        host: String,
+        port: u16,
+        timeout: Duration,
+        copy: bool,
+        commands: Vec<Vec<DataType>>,
+        /// The keys sent, with their WATCH version and whether they existed then.
+        moving: Vec<(Vec<u8>, u64, bool)>,
+    }
+
+    impl Migration {
+        /// Send the keys to the target and read its replies, blocking until it answers.
+        pub fn send(&self) -> std::io::Result<Vec<DataType>> {
+            cluster::send_commands(&self.host, self.port, self.timeout, &self.commands)
         }
     }
 
     impl Dictionary {
-        pub fn new() -> Self {
+        /// The dictionary of `shard`, see `Shards::new`.
+        pub fn new(shard: usize, config: Config, shared: Arc<Shared>) -> Self {
             Self {
-                dict: HashMap::new(),
-                lists:  HashMap::new(),
+                db: Db::default(),
+                dbs: (0..config.databases).map(|_| Db::default()).collect(),
+                selected: 0,
+                shard,
+                replicated_db: 0,
+                watched_keys: (0..config.databases).map(|_| Sharded::default()).collect(),
+                scripts: ScriptCache::default(),
+                functions: FunctionStore::default(),
+                config,
+                config_changed: false,
+                shared,
+            }
+        }
+
+        /// Trade the parts of `shard` of every database with `other`'s, see `Locked`.
+        pub fn exchange(&mut self, other: &mut Dictionary, shard: usize) {
+            for index in 0..self.dbs.len() {
+                self.db_mut(index).exchange(other.db_mut(index), shard);
+                self.watched_keys[index].exchange(&mut other.watched_keys[index], shard);
+            }
+        }
+
+        /// Estimated bytes held by this shard's own parts of the databases.
+        pub fn shard_memory(&self) -> usize {
+            self.databases().map(|(_, db)| db.keyspace.part(self.shard).used()).sum()
+        }
+
+        /// The configuration, when it changed since the last call.
+        pub fn take_changed_config(&mut self) -> Option<Config> {
+            std::mem::take(&mut self.config_changed).then(|| self.config.clone())
+        }
+
+        pub fn set_config(&mut self, config: Config) {
+            self.config = config;
+        }
+
+        /// Make database `index` the one commands run against.
+        pub fn select(&mut self, index: usize) {
+            if index != self.selected {
+                std::mem::swap(&mut self.db, &mut self.dbs[self.selected]);
+                self.selected = index;
+                std::mem::swap(&mut self.db, &mut self.dbs[self.selected]);
+            }
+        }
+
+        pub fn selected(&self) -> usize {
+            self.selected
+        }
+
+        /// Run `f` with database `index` selected, then select the current one again.
+        fn in_db<T>(&mut self, index: usize, f: impl FnOnce(&mut Self) -> T) -> T {
+            let selected = self.selected;
+            self.select(index);
+            let result = f(self);
+            self.select(selected);
+            result
+        }
+
+        fn db_mut(&mut self, index: usize) -> &mut Db {
+            if index == self.selected { &mut self.db } else { &mut self.dbs[index] }
+        }
+
+        /// Every database by index, the selected one included.
+        fn databases(&self) -> impl Iterator<Item = (usize, &Db)> {
+            (0..self.dbs.len()).map(|index| (index, if index == self.selected { &self.db } else { &self.dbs[index] }))
+        }
+
+        /// Estimated bytes held by all the databases.
+        fn used_memory(&self) -> usize {
+            self.databases().map(|(_, db)| db.keyspace.used()).sum()
+        }
+
+        /// Database index argument, checked against `databases`.
+        fn db_arg(&self, arg: &DataType) -> std::result::Result<usize, Vec<u8>> {
+            match int_arg(arg) {
+                Some(index) if (0..self.dbs.len() as i64).contains(&index) => Ok(index as usize),
+                Some(_) => Err(error_resp("ERR DB index is out of range")),
+                None => Err(not_integer()),
+            }
+        }
+
+        /// Record a modification of `key`, invalidating any WATCH on it.
+        fn signal_modified(&mut self, key: &[u8]) {
+            self.shared.save_status().modified();
+            if let Some((_, version)) = self.watched_keys[self.selected].get_mut(key) {
+                *version += 1;
+            }
+        }
+
+        /// Invalidate every WATCH on database `index`, for when it is replaced wholesale.
+        fn signal_flushed(&mut self, index: usize) {
+            self.shared.save_status().modified();
+            for (_, version) in self.watched_keys[index].values_mut() {
+                *version += 1;
+            }
+        }
+
+        fn remove_expired(&mut self, key: &[u8]) {
+            self.db.dict.remove(key);
+            self.db.lists.remove(key);
+            self.db.zsets.remove(key);
+            self.db.expires.remove(key);
+            self.db.keyspace.remove(key);
+            self.signal_modified(key);
+            self.propagate(&[DataType::BulkString(Some(b"del".to_vec())), DataType::BulkString(Some(key.to_vec()))]);
+            self.notify(notify::EXPIRED, "expired", key);
+        }
+
+        /// Drop every expired key, so keys nobody reads still expire and send their events. A
+        /// replica leaves this to its primary, which sends a DEL for each.
+        pub fn expire_keys(&mut self) {
+            if self.shared.replication().is_replica() {
+                return;
+            }
+            for index in 0..self.dbs.len() {
+                self.in_db(index, |dict| {
+                    let expired: Vec<Vec<u8>> = dict.db.dict.iter()
+                        .filter(|(_, val)| val.is_expire())
+                        .map(|(key, _)| key)
+                        .chain(dict.db.expires.keys().filter(|key| dict.db.collection_expired(key)))
+                        .cloned()
+                        .collect();
+                    for key in expired {
+                        dict.remove_expired(&key);
+                    }
+                });
+            }
+        }
+
+        /// Publish a keyspace notification of `event` on `key` when its `class` is enabled.
+        fn notify(&self, class: u16, event: &str, key: &[u8]) {
+            let flags = self.config.notify_keyspace_events;
+            if flags & class == 0 {
+                return;
+            }
+
+            let db = self.selected;
+            if flags & notify::KEYSPACE != 0 {
+                let mut channel = f!("__keyspace@{db}__:").into_bytes();
+                channel.extend_from_slice(key);
+                self.shared.pubsub().publish(&channel, event.as_bytes());
+            }
+            if flags & notify::KEYEVENT != 0 {
+                self.shared.pubsub().publish(f!("__keyevent@{db}__:{event}").as_bytes(), key);
+            }
+        }
+
+        /// Copy of the live dataset for writing to disk.
+        fn snapshot(&self) -> Snapshot {
+            let mut entries = Vec::new();
+            for (index, db) in self.databases() {
+                for (key, val) in db.dict.iter() {
+                    if !val.is_expire() {
+                        entries.push(rdb::Entry { db: index, key: key.clone(), value: rdb::Value::String(val.value.clone()), expire_at: val.exp });
+                    }
+                }
+                for (key, list) in db.lists.iter().filter(|(key, _)| !db.collection_expired(key)) {
+                    let value = rdb::Value::List(list.iter().cloned().collect());
+                    entries.push(rdb::Entry { db: index, key: key.clone(), value, expire_at: db.expire_at(key) });
+                }
+                for (key, zset) in db.zsets.iter().filter(|(key, _)| !db.collection_expired(key)) {
+                    let value = rdb::Value::SortedSet(zset.iter().map(|(member, score)| (member.to_vec(), score)).collect());
+                    entries.push(rdb::Entry { db: index, key: key.clone(), value, expire_at: db.expire_at(key) });
+                }
+            }
+
+            Snapshot {
+                entries,
+                functions: self.functions.libraries().map(|library| library.code.clone()).collect(),
+                skipped: Vec::new(),
+            }
+        }
+
+        /// Load the dataset at startup: from the AOF when it is enabled, otherwise from the RDB
+        /// snapshot. Returns how many keys were loaded.
+        pub fn load(&mut self) -> std::result::Result<usize, String> {
+            if !self.config.appendonly {
+                return self.load_rdb();
+            }
+
+            let mut aof = Aof::new(self.config.aof_dir(), &self.config.appendfilename);
+            let keys = match aof.load()? {
+                Some(files) => {
+                    for file in files {
+                        match file {
+                            Loaded::Snapshot(snapshot) => {
+                                self.load_snapshot(snapshot)?;
+                            },
+                            Loaded::Commands(commands) => {
+                                for d_command in commands {
+                                    self.handle_command(d_command);
+                                }
+                            },
+                        }
+                    }
+                    // the files SELECT where they write, which leaves the last one selected
+                    self.select(0);
+                    self.expire_keys();
+                    self.databases().map(|(_, db)| db.len()).sum()
+                },
+                // first start with the AOF on: begin it from the snapshot
+                None => self.load_rdb()?,
+            };
+
+            // the AOF counts its writes in replication offsets, for WAITAOF
+            let snapshot = self.snapshot();
+            let mut replication = self.shared.replication();
+            replication.start_backlog(self.config.repl_backlog_size);
+            aof.enable(&snapshot, replication.offset()).map_err(|e| f!("can't open the append only file: {e}"))?;
+            replication.set_selected_db(None);
+            *self.shared.aof() = Some(aof);
+            Ok(keys)
+        }
+
+        /// Load the snapshot at the configured path, if there is one.
+        fn load_rdb(&mut self) -> std::result::Result<usize, String> {
+            match persistence::load(&self.config.rdb_path())? {
+                Some(snapshot) => self.load_snapshot(snapshot),
+                None => Ok(0),
+            }
+        }
+
+        /// Add the contents of `snapshot`, returning how many keys it held. Keys that expired
+        /// while the server was down are dropped.
+        fn load_snapshot(&mut self, snapshot: Snapshot) -> std::result::Result<usize, String> {
+            for skipped in &snapshot.skipped {
+                println!("Skipped loading {}", skipped);
+            }
+            for code in &snapshot.functions {
+                self.functions.load(code, true)?;
+            }
+
+            let mut loaded = 0;
+            for entry in snapshot.entries {
+                if entry.db >= self.dbs.len() {
+                    println!("Skipped loading key '{}' in database {}, over the {} databases", String::from_utf8_lossy(&entry.key), entry.db, self.dbs.len());
+                    continue;
+                }
+                if self.in_db(entry.db, |dict| dict.insert_value(entry.key, entry.value, entry.expire_at)) {
+                    loaded += 1;
+                }
+            }
+            Ok(loaded)
+        }
+
+        /// Store a value read from RDB data, unless it has already expired. Returns whether it
+        /// was stored.
+        fn insert_value(&mut self, key: Vec<u8>, value: rdb::Value, expire_at: Option<u128>) -> bool {
+            match value {
+                rdb::Value::String(value) => {
+                    let val = match expire_at {
+                        Some(exp) => ExpireValue::specific_expire_millis(value, exp),
+                        None => ExpireValue::no_expire(value),
+                    };
+                    if val.is_expire() {
+                        return false;
+                    }
+                    self.db.dict.insert(key.clone(), val);
+                },
+                rdb::Value::List(items) => {
+                    self.db.lists.insert(key.clone(), items.into_iter().collect());
+                },
+                rdb::Value::SortedSet(members) => {
+                    let mut zset = SortedSet::new();
+                    for (member, score) in members {
+                        zset.insert(&member, score);
+                    }
+                    self.db.zsets.insert(key.clone(), zset);
+                },
+            }
+            if let Some(exp) = expire_at.filter(|_| !self.db.dict.contains_key(&key)) {
+                self.db.expires.insert(key.clone(), exp);
+                if self.db.collection_expired(&key) {
+                    self.db.lists.remove(&key);
+                    self.db.zsets.remove(&key);
+                    self.db.expires.remove(&key);
+                    return false;
+                }
+            }
+            self.track(&key, false);
+            true
+        }
+
+        /// The value of `key` in RDB terms, for DUMP.
+        fn rdb_value(&mut self, key: &[u8]) -> Option<rdb::Value> {
+            Some(match self.key_type(key)? {
+                "string" => rdb::Value::String(self.db.dict[key].value.clone()),
+                "list" => rdb::Value::List(self.db.lists[key].iter().cloned().collect()),
+                _ => rdb::Value::SortedSet(self.db.zsets[key].iter().map(|(member, score)| (member.to_vec(), score)).collect()),
+            })
+        }
+
+        fn dump(&mut self, args: &[DataType]) -> Vec<u8> {
+            let Some(key) = key_arg(&args[0]) else { return syntax_error() };
+            let payload = self.rdb_value(&key).map(|value| rdb::dump(&value));
+            serialize(&DataType::BulkString(payload)).unwrap()
+        }
+
+        /// RESTORE key ttl payload [REPLACE] [ABSTTL] [IDLETIME seconds] [FREQ frequency]
+        fn restore(&mut self, args: &[DataType]) -> Vec<u8> {
+            if args.len() < 3 {
+                return wrong_args("restore");
+            }
+            let Some(key) = key_arg(&args[0]) else { return syntax_error() };
+            let Some(ttl) = int_arg(&args[1]) else { return not_integer() };
+            if ttl < 0 {
+                return error_resp("ERR Invalid TTL value, must be >= 0");
+            }
+            let DataType::BulkString(Some(payload)) = &args[2] else {
+                return error_resp("ERR DUMP payload version or checksum are wrong");
+            };
+
+            let mut replace = false;
+            let mut absttl = false;
+            let mut idle = None;
+            let mut freq = None;
+            let mut options = args[3..].iter();
+            while let Some(option) = options.next() {
+                let Some(option) = text_arg(option) else { return syntax_error() };
+                match option.to_ascii_uppercase().as_str() {
+                    "REPLACE" => replace = true,
+                    "ABSTTL" => absttl = true,
+                    "IDLETIME" if idle.is_none() && freq.is_none() => {
+                        match options.next().and_then(int_arg) {
+                            Some(seconds) if seconds >= 0 => idle = Some(seconds as u64),
+                            _ => return error_resp("ERR Invalid IDLETIME value, must be >= 0"),
+                        }
+                    },
+                    "FREQ" if idle.is_none() && freq.is_none() => {
+                        match options.next().and_then(int_arg) {
+                            Some(counter) if (0..=255).contains(&counter) => freq = Some(counter as u8),
+                            _ => return error_resp("ERR Invalid FREQ value, must be >= 0 and <= 255"),
+                        }
+                    },
+                    _ => return syntax_error(),
+                }
+            }
+
+            if !replace && self.key_type(&key).is_some() {
+                return error_resp("BUSYKEY Target key name already exists.");
+            }
+            let value = match rdb::restore(payload) {
+                Ok(value) => value,
+                Err(err) => return error_resp(err),
+            };
+            let expire_at = match ttl {
+                0 => None,
+                _ if absttl => Some(ttl as u128),
+                _ => Some(SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() + ttl as u128),
+            };
+            let existed = self.delete_key(&key);
+            if self.insert_value(key.clone(), value, expire_at) {
+                self.signal_modified(&key);
+                // RESTORE is not counted as an access, the key starts from the given statistics
+                self.track(&key, false);
+                self.db.keyspace.set_access(&key, idle, freq);
+                self.notify(notify::GENERIC, "restore", &key);
+            } else if existed {
+                // restored with a time already past, which leaves the key deleted
+                self.notify(notify::GENERIC, "del", &key);
+            }
+            SUCCESS_MSG.to_vec()
+        }
+
+        /// Bring the memory accounting of `key` up to date, recording an access to it when
+        /// `accessed`.
+        fn track(&mut self, key: &[u8], accessed: bool) {
+            let lfu = self.config.lfu();
+            match self.entry_size(key, SIZE_SAMPLES) {
+                Some(size) => {
+                    let expire_at = self.db.expire_at(key);
+                    self.db.keyspace.update(key, size, expire_at, accessed, lfu);
+                },
+                None => self.db.keyspace.remove(key),
+            }
+        }
+
+        /// Estimated bytes held for `key`: the key, its value and the structures around them.
+        /// Lists and sorted sets are estimated from their first `samples` elements, or from
+        /// all of them when `samples` is 0.
+        fn entry_size(&self, key: &[u8], samples: usize) -> Option<usize> {
+            let value = if let Some(val) = self.db.dict.get(key) {
+                // the value's expiry sits in the slot next to it
+                size_of::<(String, ExpireValue)>() + val.value.capacity()
+            } else if let Some(list) = self.db.lists.get(key) {
+                // each node holds the item and two links
+                let items = list.iter().map(|item| size_of::<Vec<u8>>() + 2 * size_of::<usize>() + item.capacity());
+                size_of::<(String, LinkedList<Vec<u8>>)>() + eviction::sampled(items, list.len(), samples)
+            } else if let Some(zset) = self.db.zsets.get(key) {
+                // each member is held by both the score map and the ordered index
+                let members = zset.iter().map(|(member, _)| 2 * (size_of::<Vec<u8>>() + member.len() + size_of::<f64>()));
+                size_of::<(String, SortedSet)>() + eviction::sampled(members, zset.len(), samples)
+            } else {
+                return None;
+            };
+            // plus the control byte of the map slot
+            Some(key.len() + value + 1)
+        }
+
+        /// The encoding Redis would keep `key`'s value in, given its size.
+        fn encoding(&mut self, key: &[u8]) -> Option<&'static str> {
+            let encoding = match self.key_type(key)? {
+                "string" => {
+                    let value = &self.db.dict[key].value;
+                    let int = std::str::from_utf8(value).ok().and_then(|text| text.parse::<i64>().ok());
+                    if int.is_some_and(|int| int.to_string().as_bytes() == value.as_slice()) {
+                        "int"
+                    } else if value.len() <= EMBSTR_MAX {
+                        "embstr"
+                    } else {
+                        "raw"
+                    }
+                },
+                "list" => match self.db.lists[key].iter().map(|item| item.len()).sum::<usize>() <= LIST_LISTPACK_BYTES {
+                    true => "listpack",
+                    false => "quicklist",
+                },
+                _ => {
+                    let zset = &self.db.zsets[key];
+                    let small = zset.len() <= ZSET_LISTPACK_ENTRIES && zset.iter().all(|(member, _)| member.len() <= ZSET_LISTPACK_VALUE);
+                    if small { "listpack" } else { "skiplist" }
+                },
+            };
+            Some(encoding)
+        }
+
+        /// OBJECT subcommand [arg ...]
+        fn object(&mut self, args: &[DataType]) -> Vec<u8> {
+            let Some(sub) = text_arg(&args[0]) else { return syntax_error() };
+            let sub = sub.to_ascii_lowercase();
+            if sub == "help" && args.len() == 1 {
+                return help(&[
+                    "OBJECT <subcommand> [<arg> [value] [opt] ...]. Subcommands are:",
+                    "ENCODING <key>",
+                    "    Return the kind of internal representation used in order to store the value",
+                    "    associated with a <key>.",
+                    "FREQ <key>",
+                    "    Return the access frequency index of the <key>. The returned integer is",
+                    "    proportional to the logarithm of the recent access frequency of the key.",
+                    "IDLETIME <key>",
+                    "    Return the idle time of the <key>, that is the approximated number of",
+                    "    seconds elapsed since the last access to the key.",
+                    "REFCOUNT <key>",
+                    "    Return the number of references of the value associated with the specified",
+                    "    <key>.",
+                    "HELP",
+                    "    Print this help.",
+                ]);
+            }
+            if args.len() != 2 || !["encoding", "freq", "idletime", "refcount"].contains(&sub.as_str()) {
+                return error_resp(&f!("ERR unknown subcommand or wrong number of arguments for '{sub}'. Try OBJECT HELP."));
+            }
+            let Some(key) = key_arg(&args[1]) else { return syntax_error() };
+            let Some(encoding) = self.encoding(&key) else { return serialize(&DataType::BulkString(None)).unwrap() };
+            let lfu = self.config.maxmemory_policy.is_lfu();
+            let integer = |int: i64| serialize(&DataType::Integer(int)).unwrap();
+
+            match sub.as_str() {
+                "encoding" => serialize(&DataType::BulkString(Some(encoding.as_bytes().to_vec()))).unwrap(),
+                "refcount" => {
+                    // small integers are shared objects in Redis
+                    let shared = encoding == "int" && String::from_utf8_lossy(&self.db.dict[&key].value).parse::<i64>().is_ok_and(|int| (0..SHARED_INTEGERS).contains(&int));
+                    integer(if shared { i32::MAX as i64 } else { 1 })
+                },
+                "idletime" if lfu => error_resp("ERR An LFU maxmemory policy is selected, idle time not tracked. Please note that when switching between policies at runtime LRU and LFU data will take some time to adjust."),
+                "freq" if !lfu => error_resp("ERR An LFU maxmemory policy is not selected, access frequency not tracked. Please note that when switching between policies at runtime LRU and LFU data will take some time to adjust."),
+                _ => {
+                    if self.db.keyspace.get(&key).is_none() {
+                        self.track(&key, false);
+                    }
+                    let meta = self.db.keyspace.get(&key).unwrap();
+                    match sub.as_str() {
+                        "idletime" => integer(meta.idle() as i64),
+                        _ => integer(meta.frequency(self.config.lfu()) as i64),
+                    }
+                },
+            }
+        }
+
+        /// MEMORY subcommand [arg ...]
+        fn memory(&mut self, args: &[DataType]) -> Vec<u8> {
+            let Some(sub) = text_arg(&args[0]) else { return syntax_error() };
+            let sub = sub.to_ascii_lowercase();
+            let bulk = |text: &str| serialize(&DataType::BulkString(Some(text.as_bytes().to_vec()))).unwrap();
+
+            match (sub.as_str(), args.len()) {
+                ("usage", 2 | 4) => {
+                    let Some(key) = key_arg(&args[1]) else { return syntax_error() };
+                    let samples = match args.get(2..) {
+                        Some([option, count]) if text_arg(option).is_some_and(|option| option.eq_ignore_ascii_case("samples")) => match int_arg(count) {
+                            Some(count) if count >= 0 => count as usize,
+                            _ => return not_integer(),
+                        },
+                        Some([]) => SIZE_SAMPLES,
+                        _ => return syntax_error(),
+                    };
+                    if self.key_type(&key).is_none() {
+                        return serialize(&DataType::BulkString(None)).unwrap();
+                    }
+                    serialize(&DataType::Integer(self.entry_size(&key, samples).unwrap() as i64)).unwrap()
+                },
+                ("stats", 1) => {
+                    let (dataset, keys) = (self.used_memory(), self.databases().map(|(_, db)| db.keyspace.len()).sum::<usize>());
+                    let overhead = self.databases().map(|(_, db)| db.keyspace.overhead()).sum::<usize>();
+                    let backlog = self.shared.replication().backlog_len();
+                    let total = dataset + backlog + overhead;
+                    let fields = [
+                        ("peak.allocated", DataType::Integer(self.shared.peak_memory(dataset).max(total) as i64)),
+                        ("total.allocated", DataType::Integer(total as i64)),
+                        ("replication.backlog", DataType::Integer(backlog as i64)),
+                        ("overhead.total", DataType::Integer((backlog + overhead) as i64)),
+                        ("keys.count", DataType::Integer(keys as i64)),
+                        ("keys.bytes-per-key", DataType::Integer(dataset.checked_div(keys).unwrap_or(0) as i64)),
+                        ("dataset.bytes", DataType::Integer(dataset as i64)),
+                        ("dataset.percentage", DataType::BulkString(Some(f!("{:.2}", dataset as f64 * 100.0 / total.max(1) as f64).into_bytes()))),
+                    ];
+                    let stats = fields.into_iter()
+                        .flat_map(|(name, value)| [DataType::BulkString(Some(name.as_bytes().to_vec())), value])
+                        .collect();
+                    serialize(&DataType::Array(Some(stats))).unwrap()
+                },
+                ("doctor", 1) => bulk(&self.memory_doctor()),
+                ("malloc-stats", 1) => bulk("Stats not supported for the current allocator"),
+                ("purge", 1) => SUCCESS_MSG.to_vec(),
+                ("help", 1) => help(&[
+                    "MEMORY <subcommand> [<arg> [value] [opt] ...]. Subcommands are:",
+                    "DOCTOR",
+                    "    Return memory problems reports.",
+                    "MALLOC-STATS",
+                    "    Return internal statistics report from the memory allocator.",
+                    "PURGE",
+                    "    Attempt to purge dirty pages for reclamation by the allocator.",
+                    "STATS",
+                    "    Return information about the memory usage of the server.",
+                    "USAGE <key> [SAMPLES <count>]",
+                    "    Return memory in bytes used by <key> and its value. Nested values are",
+                    "    sampled up to <count> times (default: 5, 0 means sample all).",
+                    "HELP",
+                    "    Print this help.",
+                ]),
+                _ => error_resp(&f!("ERR unknown subcommand or wrong number of arguments for '{sub}'. Try MEMORY HELP.")),
+            }
+        }
+
+        fn memory_doctor(&self) -> String {
+            let used = self.used_memory();
+            let (peak, maxmemory) = (self.shared.peak_memory(used), self.config.maxmemory);
+            if used < EMPTY_INSTANCE_BYTES {
+                return "Nothing to report: this instance is empty or holds too little data for a diagnosis to mean anything. Run MEMORY DOCTOR again once it holds a real dataset.".to_owned();
+            }
+            let mut issues = Vec::new();
+            if peak > used * 3 / 2 {
+                issues.push(f!(" * Peak memory: the dataset once reached {}, more than half again the {} it holds now. Memory freed since then is usually kept by the allocator rather than returned to the system, so the process may look larger than its data.", human_bytes(peak), human_bytes(used)));
+            }
+            if maxmemory > 0 && used > maxmemory / 10 * 9 && self.config.maxmemory_policy == eviction::Policy::NoEviction {
+                issues.push(f!(" * Near maxmemory: the dataset holds {} of the {} allowed, and with the noeviction policy writes that grow it will be refused with OOM once it is full. Raise maxmemory or pick an eviction policy.", human_bytes(used), human_bytes(maxmemory)));
+            }
+            let keys: usize = self.databases().map(|(_, db)| db.keyspace.len()).sum();
+            if keys > 0 && used / keys > BIG_KEY_AVERAGE {
+                issues.push(f!(" * Big keys: the average key takes {}. MEMORY USAGE on keys picked with RANDOMKEY or SCAN will point at the largest ones.", human_bytes(used / keys)));
+            }
+            match issues.is_empty() {
+                true => "No memory issues found. The dataset, its peak and the maxmemory limit all look healthy.".to_owned(),
+                false => f!("Memory issues found:\n\n{}\n", issues.join("\n\n")),
+            }
+        }
+
+        /// Evict a key of the shards this dictionary holds per `maxmemory-policy`, from the
+        /// selected database first, then the others in order. Returns whether there was one.
+        pub fn evict_one(&mut self) -> bool {
+            let (policy, samples, lfu) = (self.config.maxmemory_policy, self.config.maxmemory_samples, self.config.lfu());
+            let selected = self.selected;
+            let victim = std::iter::once(selected).chain((0..self.dbs.len()).filter(|index| *index != selected))
+                .find_map(|index| self.in_db(index, |dict| dict.db.keyspace.victim(policy, samples, lfu)).map(|key| (index, key)));
+            let Some((index, key)) = victim else { return false };
+            self.in_db(index, |dict| dict.evict(&key));
+            true
+        }
+
+        fn evict(&mut self, key: &[u8]) {
+            self.delete_key(key);
+            self.db.keyspace.remove(key);
+            self.shared.evicted();
+            self.propagate(&[DataType::BulkString(Some(b"del".to_vec())), DataType::BulkString(Some(key.to_vec()))]);
+            self.notify(notify::EVICTED, "evicted", key);
+        }
+
+        /// Record a write that ran in the AOF and the replication stream. Replicas only pass on
+        /// what their primary sends, see `apply_replicated`.
+        fn propagate(&mut self, d_command: &[DataType]) {
+            let mut replication = self.shared.replication();
+            if replication.is_replica() {
+                return;
+            }
+            let mut aof = self.shared.aof();
+            if replication.selected_db() != Some(self.selected) {
+                let index = self.selected.to_string().into_bytes();
+                let select = [DataType::BulkString(Some(b"select".to_vec())), DataType::BulkString(Some(index))];
+                feed(&mut replication, &mut aof, self.config.appendfsync, &serialize(&DataType::Array(Some(select.to_vec()))).unwrap());
+                replication.set_selected_db(Some(self.selected));
+            }
+            let bytes = serialize(&DataType::Array(Some(d_command.to_vec()))).unwrap();
+            feed(&mut replication, &mut aof, self.config.appendfsync, &bytes);
+        }
+
+        /// Apply a command from the primary's stream, passing its exact bytes on.
+        pub fn apply_replicated(&mut self, d_command: DataType, raw: &[u8]) {
+            self.select(self.replicated_db);
+            self.execute(d_command);
+            // the stream SELECTs the database its writes go to
+            self.replicated_db = self.selected;
+            feed(&mut self.shared.replication(), &mut self.shared.aof(), self.config.appendfsync, raw);
+        }
+
+        /// Replace the dataset with the primary's snapshot, which the stream continues from
+        /// `offset`.
+        pub fn full_sync(&mut self, rdb: &[u8], replid: &str, offset: u64) -> std::result::Result<(), String> {
+            let snapshot = rdb::decode(rdb)?;
+            self.db = Db::default();
+            for index in 0..self.dbs.len() {
+                self.dbs[index] = Db::default();
+                self.signal_flushed(index);
+            }
+            self.functions.flush();
+            self.replicated_db = 0;
+            self.load_snapshot(snapshot)?;
+            let mut replication = self.shared.replication();
+            replication.synced(replid, offset, self.config.repl_backlog_size);
+
+            // the AOF no longer matches the dataset
+            let mut aof = self.shared.aof();
+            if let Some(aof) = aof.as_mut().filter(|aof| aof.is_enabled() && !aof.rewrite_in_progress()) {
+                replication.set_selected_db(None);
+                if let Err(e) = aof.rewrite(self.snapshot()) {
+                    println!("Can't rewrite the AOF after a full sync: {}", e);
+                }
+            }
+            Ok(())
+        }
+
+        /// PSYNC replid offset, from replica connection `id`. Continues from the backlog when
+        /// it can, otherwise replies with a snapshot. Either way `pushes` gets the stream after.
+        pub fn psync(&mut self, id: u64, pushes: &Pushes, ip: String, port: u16, args: &[DataType]) -> Vec<u8> {
+            let mut replication = self.shared.replication();
+            if replication.is_replica() && !replication.is_active() {
+                return error_resp("NOMASTERLINK Can't SYNC while not connected with my master");
+            }
+            let (Some(replid), Some(offset)) = (text_arg(&args[0]), int_arg(&args[1])) else { return syntax_error() };
+
+            replication.start_backlog(self.config.repl_backlog_size);
+            let stream = u64::try_from(offset).ok().and_then(|offset| replication.continue_from(&replid, offset));
+            let reply = match stream {
+                Some(stream) => {
+                    let mut reply = f!("+CONTINUE {}\r\n", replication.replid()).into_bytes();
+                    reply.extend_from_slice(&stream);
+                    reply
+                },
+                None => {
+                    // the replica starts from the snapshot in database 0, so the stream must SELECT
+                    replication.set_selected_db(None);
+                    let rdb = rdb::encode(&self.snapshot());
+                    let mut reply = f!("+FULLRESYNC {} {}\r\n${}\r\n", replication.replid(), replication.offset(), rdb.len()).into_bytes();
+                    reply.extend_from_slice(&rdb);
+                    reply
+                },
+            };
+            replication.add_replica(id, pushes, ip, port);
+            reply
+        }
+
+        /// Follow the primary at `host:port`, or none, returning the id for a link task to
+        /// start.
+        pub fn replicaof(&mut self, primary: Option<(String, u16)>) -> Option<u64> {
+            self.config.replicaof = primary.clone();
+            self.config_changed = true;
+            let mut replication = self.shared.replication();
+            match primary {
+                Some((host, port)) => replication.follow(&host, port),
+                None => {
+                    replication.promote();
+                    None
+                },
+            }
+        }
+
+        /// CLUSTER subcommand [arg ...]
+        fn cluster_command(&mut self, args: &[DataType]) -> Vec<u8> {
+            if !self.shared.cluster().is_enabled() {
+                return error_resp("ERR This instance has cluster support disabled");
+            }
+            let Some(sub) = text_arg(&args[0]) else { return syntax_error() };
+            let sub = sub.to_ascii_lowercase();
+            let bulk = |text: String| serialize(&DataType::BulkString(Some(text.into_bytes()))).unwrap();
+            let slot_arg = |arg: &DataType| int_arg(arg).filter(|slot| (0..cluster::SLOTS as i64).contains(slot)).map(|slot| slot as u16);
+
+            match (sub.as_str(), args.len()) {
+                ("myid", 1) => bulk(self.shared.cluster().myid().to_owned()),
+                ("nodes", 1) => bulk(self.shared.cluster().nodes()),
+                ("info", 1) => bulk(self.shared.cluster().info()),
+                ("slots", 1) => serialize(&self.shared.cluster().slots()).unwrap(),
+                ("shards", 1) => serialize(&self.shared.cluster().shards()).unwrap(),
+                ("keyslot", 2) => {
+                    let DataType::BulkString(Some(key)) = &args[1] else { return syntax_error() };
+                    serialize(&DataType::Integer(cluster::key_slot(key) as i64)).unwrap()
+                },
+                ("countkeysinslot", 2) => match slot_arg(&args[1]) {
+                    Some(slot) => serialize(&DataType::Integer(self.keys_in_slot(slot, usize::MAX).len() as i64)).unwrap(),
+                    None => error_resp("ERR Invalid slot"),
+                },
+                ("getkeysinslot", 3) => {
+                    let (Some(slot), Some(count)) = (slot_arg(&args[1]), int_arg(&args[2])) else {
+                        return error_resp("ERR Invalid slot or number of keys");
+                    };
+                    if count < 0 {
+                        return error_resp("ERR Invalid slot or number of keys");
+                    }
+                    let keys = self.keys_in_slot(slot, count as usize).into_iter()
+                        .map(|key| DataType::BulkString(Some(key)))
+                        .collect();
+                    serialize(&DataType::Array(Some(keys))).unwrap()
+                },
+                ("addslots" | "delslots", 2..) | ("addslotsrange" | "delslotsrange", 3..) => {
+                    let slots = match sub.ends_with("range") {
+                        true if args.len().is_multiple_of(2) => return syntax_error(),
+                        true => args[1..].chunks(2)
+                            .map(|range| Some((slot_arg(&range[0])?, slot_arg(&range[1])?)))
+                            .collect::<Option<Vec<_>>>()
+                            .and_then(|ranges| {
+                                let valid = ranges.iter().all(|(start, end)| start <= end);
+                                valid.then(|| ranges.into_iter().flat_map(|(start, end)| start..=end).collect::<Vec<_>>())
+                            }),
+                        false => args[1..].iter().map(slot_arg).collect(),
+                    };
+                    let Some(slots) = slots else { return error_resp("ERR Invalid or out of range slot") };
+                    let result = match sub.starts_with("add") {
+                        true => self.shared.cluster().add_slots(&slots),
+                        false => self.shared.cluster().del_slots(&slots),
+                    };
+                    match result {
+                        Ok(()) => SUCCESS_MSG.to_vec(),
+                        Err(err) => error_resp(&err),
+                    }
+                },
+                ("setslot", 3 | 4) => {
+                    let (Some(slot), Some(action)) = (slot_arg(&args[1]), text_arg(&args[2])) else {
+                        return error_resp("ERR Invalid or out of range slot");
+                    };
+                    let node_id = args.get(3).and_then(text_arg);
+                    let has_keys = !self.keys_in_slot(slot, 1).is_empty();
+                    let result = self.shared.cluster().set_slot(slot, &action.to_ascii_lowercase(), node_id.as_deref(), has_keys);
+                    match result {
+                        Ok(()) => SUCCESS_MSG.to_vec(),
+                        Err(err) => error_resp(&err),
+                    }
+                },
+                ("meet", 3 | 4) => {
+                    let (Some(ip), Some(port)) = (text_arg(&args[1]), int_arg(&args[2])) else { return syntax_error() };
+                    let Ok(port) = u16::try_from(port) else {
+                        return error_resp(&f!("ERR Invalid base port specified: {}", port));
+                    };
+                    self.shared.cluster().meet(ip, port);
+                    SUCCESS_MSG.to_vec()
+                },
+                // node to node gossip, see `cluster::gossip`
+                ("gossip", _) => {
+                    let fields: Vec<Vec<u8>> = args[1..].iter()
+                        .filter_map(|arg| match arg {
+                            DataType::BulkString(Some(field)) => Some(field.clone()),
+                            _ => None,
+                        })
+                        .collect();
+                    let mut cluster = self.shared.cluster();
+                    if let Err(err) = cluster.merge(&fields) {
+                        return error_resp(&err);
+                    }
+                    let reply = cluster.gossip().into_iter().map(|field| DataType::BulkString(Some(field))).collect();
+                    serialize(&DataType::Array(Some(reply))).unwrap()
+                },
+                _ => error_resp(&f!("ERR unknown subcommand or wrong number of arguments for '{sub}'. Try CLUSTER HELP.")),
+            }
+        }
+
+        /// Whether this node serves `keys` in cluster mode, see `Cluster::check_keys`.
+        pub fn check_cluster_keys(&mut self, keys: &[&[u8]], asking: bool) -> std::result::Result<(), String> {
+            if !self.shared.cluster_enabled() {
+                return Ok(());
+            }
+            let existing: Vec<bool> = keys.iter()
+                .map(|key| self.key_type(key).is_some())
+                .collect();
+            self.shared.cluster().check_keys(keys, &existing, asking)
+        }
+
+        /// MIGRATE host port key|"" destination-db timeout [COPY] [REPLACE] [AUTH password]
+        /// [AUTH2 username password] [KEYS key ...]
+        ///
+        /// Moves keys to another server with RESTORE-ASKING, deleting them here unless COPY is
+        /// given. It runs in steps so no shard is locked while the target restores the keys:
+        /// this reads them into the commands to send, `Migration::send` sends them, and
+        /// `migrate_finish` deletes them. The keys are watched in between, and one changed
+        /// meanwhile is kept. Fails with the reply for MIGRATE if there is nothing to send.
+        pub fn migrate_start(&mut self, args: &[DataType]) -> std::result::Result<Migration, Vec<u8>> {
+            let (Some(host), Some(port), Some(key), Some(db), Some(timeout)) =
+                (text_arg(&args[0]), int_arg(&args[1]), key_arg(&args[2]), int_arg(&args[3]), int_arg(&args[4])) else {
+                return Err(syntax_error());
+            };
+            let Ok(port) = u16::try_from(port) else { return Err(syntax_error()) };
+
+            let (mut copy, mut replace) = (false, false);
+            let mut auth: Option<Vec<DataType>> = None;
+            let mut keys = vec![key.clone()];
+            let mut rest = args[5..].iter();
+            while let Some(option) = rest.next() {
+                let Some(option) = text_arg(option) else { return Err(syntax_error()) };
+                match option.to_ascii_uppercase().as_str() {
+                    "COPY" => copy = true,
+                    "REPLACE" => replace = true,
+                    "AUTH" => match rest.next() {
+                        Some(password) => auth = Some(vec![DataType::BulkString(Some(b"auth".to_vec())), password.clone()]),
+                        None => return Err(syntax_error()),
+                    },
+                    "AUTH2" => match (rest.next(), rest.next()) {
+                        (Some(user), Some(password)) => auth = Some(vec![DataType::BulkString(Some(b"auth".to_vec())), user.clone(), password.clone()]),
+                        _ => return Err(syntax_error()),
+                    },
+                    "KEYS" => {
+                        if !key.is_empty() {
+                            return Err(error_resp("ERR When using MIGRATE KEYS option, the key argument must be set to the empty string"));
+                        }
+                        keys = rest.by_ref().filter_map(key_arg).collect();
+                    },
+                    _ => return Err(syntax_error()),
+                }
+            }
+
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+            let mut moving = Vec::new();
+            let mut commands: Vec<Vec<DataType>> = auth.into_iter().collect();
+            if db != 0 {
+                commands.push(vec![DataType::BulkString(Some(b"select".to_vec())), DataType::BulkString(Some(db.to_string().into_bytes()))]);
+            }
+            for key in keys {
+                let Some(value) = self.rdb_value(&key) else { continue };
+                let ttl = self.db.expire_at(&key).map_or(0, |exp| exp.saturating_sub(now).max(1));
+                let mut restore = vec![
+                    DataType::BulkString(Some(b"restore-asking".to_vec())),
+                    DataType::BulkString(Some(key.clone())),
+                    DataType::BulkString(Some(ttl.to_string().into_bytes())),
+                    DataType::BulkString(Some(rdb::dump(&value))),
+                ];
+                if replace {
+                    restore.push(DataType::BulkString(Some(b"REPLACE".to_vec())));
+                }
+                commands.push(restore);
+                let (version, existed) = self.watch(&key);
+                moving.push((key, version, existed));
+            }
+            if moving.is_empty() {
+                return Err(b"+NOKEY\r\n".to_vec());
+            }
+            let timeout = Duration::from_millis(if timeout <= 0 { 1000 } else { timeout as u64 });
+            Ok(Migration { host, port, timeout, copy, commands, moving })
+        }
+
+        /// Delete what `migration` moved, given the target's `replies`, and reply to MIGRATE.
+        pub fn migrate_finish(&mut self, migration: Migration, replies: std::io::Result<Vec<DataType>>) -> Vec<u8> {
+            // a key written while the target was restoring it is newer than the copy there
+            let selected = self.selected;
+            let unchanged: Vec<bool> = migration.moving.iter()
+                .map(|(key, version, existed)| !self.watched_key_changed(selected, key, *version, *existed))
+                .collect();
+            for (key, _, _) in &migration.moving {
+                self.unwatch(selected, key);
+            }
+            let replies = match replies {
+                Ok(replies) => replies,
+                Err(e) => return error_resp(&f!("IOERR error or timeout connecting or reading to target instance: {e}")),
+            };
+
+            // the first replies answer AUTH and SELECT, then one per key
+            let (setup, restored) = replies.split_at(migration.commands.len() - migration.moving.len());
+            if let Some(DataType::Error(err)) = setup.iter().find(|reply| matches!(reply, DataType::Error(_))) {
+                return error_resp(&f!("ERR Target instance replied with error: {err}"));
+            }
+            let (mut failure, mut changed) = (None, false);
+            for (((key, _, _), unchanged), reply) in migration.moving.iter().zip(unchanged).zip(restored) {
+                if let DataType::Error(err) = reply {
+                    failure.get_or_insert_with(|| err.clone());
+                    continue;
+                }
+                if !unchanged {
+                    changed = true;
+                    continue;
+                }
+                if !migration.copy {
+                    self.delete_key(key);
+                    self.db.keyspace.remove(key);
+                    self.notify(notify::GENERIC, "del", key);
+                    self.propagate(&[DataType::BulkString(Some(b"del".to_vec())), DataType::BulkString(Some(key.clone()))]);
+                }
+            }
+            match failure {
+                Some(err) => error_resp(&f!("ERR Target instance replied with error: {err}")),
+                // the target has an older copy, which a retry with REPLACE overwrites
+                None if changed => error_resp("ERR key changed during migration"),
+                None => SUCCESS_MSG.to_vec(),
+            }
+        }
+
+        /// KEYS pattern
+        fn keys(&mut self, args: &[DataType]) -> Vec<u8> {
+            let DataType::BulkString(Some(pattern)) = &args[0] else { return syntax_error() };
+            let keys: Vec<Vec<u8>> = self.db.dict.keys().chain(self.db.lists.keys()).chain(self.db.zsets.keys())
+                .filter(|key| glob::matches(pattern, key))
+                .cloned()
+                .collect();
+            let keys = keys.into_iter()
+                .filter(|key| self.key_type(key).is_some())
+                .map(|key| DataType::BulkString(Some(key)))
+                .collect();
+            serialize(&DataType::Array(Some(keys))).unwrap()
+        }
+
+        /// SCAN cursor [MATCH pattern] [COUNT count] [TYPE type]
+        fn scan(&mut self, args: &[DataType]) -> Vec<u8> {
+            let (cursor, options) = match scan_args(args, true) {
+                Ok(args) => args,
+                Err(resp) => return resp,
+            };
+            let (next, keys) = self.db.keyspace.scan(cursor, options.count);
+            let keys = keys.into_iter()
+                .filter(|key| options.matches(key))
+                .filter(|key| match self.key_type(key) {
+                    Some(kind) => options.kind.as_ref().is_none_or(|wanted| wanted.eq_ignore_ascii_case(kind)),
+                    None => false,
+                })
+                .map(|key| DataType::BulkString(Some(key)))
+                .collect();
+            scan_reply(next, keys)
+        }
+
+        /// Up to `count` live keys that hash to `slot`.
+        fn keys_in_slot(&self, slot: u16, count: usize) -> Vec<Vec<u8>> {
+            let strings = self.db.dict.iter().filter(|(_, value)| !value.is_expire()).map(|(key, _)| key);
+            strings.chain(self.db.lists.keys()).chain(self.db.zsets.keys())
+                .filter(|key| cluster::key_slot(key) == slot && !self.db.collection_expired(key))
+                .take(count)
+                .cloned()
+                .collect()
+        }
+
+        /// WAIT numreplicas timeout, or WAITAOF numlocal numreplicas timeout when `aof`, for
+        /// the writes made so far.
+        pub fn wait(&mut self, args: &[DataType], aof: bool) -> std::result::Result<Wait, Vec<u8>> {
+            if self.shared.replication().is_replica() {
+                let name = if aof { "WAITAOF" } else { "WAIT" };
+                return Err(error_resp(&f!("ERR {name} cannot be used with replica instances. Please also note that writes to replicas are just local and are not propagated.")));
+            }
+            let integer = |arg: &DataType| int_arg(arg).ok_or_else(|| error_resp("ERR value is not an integer or out of range"));
+            let (numlocal, numreplicas, timeout) = match aof {
+                true => (Some(integer(&args[0])?), integer(&args[1])?, &args[2]),
+                false => (None, integer(&args[0])?, &args[1]),
+            };
+            let timeout = match int_arg(timeout) {
+                Some(timeout) if timeout < 0 => return Err(error_resp("ERR timeout is negative")),
+                Some(timeout) => timeout as u64,
+                None => return Err(error_resp("ERR timeout is not an integer or out of range")),
+            };
+            if numlocal.is_some_and(|numlocal| numlocal > 0) && self.shared.aof_fsynced_offset().is_none() {
+                return Err(error_resp("ERR WAITAOF cannot be used when numlocal is set but appendonly is disabled."));
+            }
+            Ok(Wait::new(self.shared.replication().offset(), numlocal, numreplicas, timeout))
+        }
+
+        /// INFO [section ...]
+        fn info(&mut self, args: &[DataType]) -> Vec<u8> {
+            let mut sections: Vec<String> = args.iter().filter_map(text_arg).map(|arg| arg.to_ascii_lowercase()).collect();
+            if sections.is_empty() || sections.iter().any(|section| ["all", "default", "everything"].contains(&section.as_str())) {
+                sections = vec!["memory".to_owned(), "stats".to_owned(), "replication".to_owned(), "cluster".to_owned(), "keyspace".to_owned()];
+            }
+
+            let mut info = String::new();
+            for section in sections {
+                let text = match section.as_str() {
+                    "memory" => {
+                        let used = self.used_memory();
+                        let (peak, maxmemory) = (self.shared.peak_memory(used), self.config.maxmemory);
+                        f!(
+                            "# Memory\r\nused_memory:{used}\r\nused_memory_human:{}\r\nused_memory_peak:{peak}\r\nused_memory_peak_human:{}\r\nmaxmemory:{maxmemory}\r\nmaxmemory_human:{}\r\nmaxmemory_policy:{}\r\nlazyfree_pending_objects:{}\r\n",
+                            human_bytes(used), human_bytes(peak), human_bytes(maxmemory), self.config.maxmemory_policy.name(), lazyfree::pending(),
+                        )
+                    },
+                    "stats" => f!("# Stats\r\nevicted_keys:{}\r\n", self.shared.evicted_keys()),
+                    "replication" => self.shared.replication().info(self.config.replica_read_only),
+                    "cluster" => f!("# Cluster\r\ncluster_enabled:{}\r\n", self.shared.cluster().is_enabled() as u8),
+                    "keyspace" => {
+                        let mut text = "# Keyspace\r\n".to_owned();
+                        for (index, db) in self.databases().filter(|(_, db)| db.len() > 0) {
+                            let (expires, avg_ttl) = db.expires();
+                            text += &f!("db{index}:keys={},expires={expires},avg_ttl={avg_ttl}\r\n", db.len());
+                        }
+                        text
+                    },
+                    _ => continue,
+                };
+                if !info.is_empty() {
+                    info += "\r\n";
+                }
+                info += &text;
+            }
+            serialize(&DataType::BulkString(Some(info.into_bytes()))).unwrap()
+        }
+
+        /// BGREWRITEAOF
+        fn bgrewriteaof(&mut self) -> Vec<u8> {
+            let mut replication = self.shared.replication();
+            let mut aof = self.shared.aof();
+            if aof.as_ref().is_some_and(Aof::rewrite_in_progress) {
+                return error_resp("ERR Background append only file rewriting already in progress");
+            }
+            let snapshot = self.snapshot();
+            replication.set_selected_db(None);
+            let aof = aof.get_or_insert_with(|| Aof::new(self.config.aof_dir(), &self.config.appendfilename));
+            match aof.rewrite(snapshot) {
+                Ok(_) => b"+Background append only file rewriting started\r\n".to_vec(),
+                Err(e) => error_resp(&f!("ERR Can't rewrite append only file in background: {e}")),
+            }
+        }
+
+        /// Turn the AOF on or off after CONFIG SET appendonly. Turning it on rewrites it, as
+        /// writes made while it was off are missing from it.
+        fn set_appendonly(&mut self, on: bool) -> std::result::Result<(), String> {
+            let snapshot = self.snapshot();
+            let mut replication = self.shared.replication();
+            let mut aof = self.shared.aof();
+            let aof = aof.get_or_insert_with(|| Aof::new(self.config.aof_dir(), &self.config.appendfilename));
+            if !on {
+                aof.disable();
+                return Ok(());
+            }
+
+            replication.start_backlog(self.config.repl_backlog_size);
+            replication.set_selected_db(None);
+            let offset = replication.offset();
+            aof.enable(&snapshot, offset).and_then(|_| aof.rewrite(snapshot)).map(|_| ()).map_err(|e| f!("ERR {e}"))
+        }
+
+        /// Start a background save when BGSAVE SCHEDULE or a `save` rule asks for one.
+        pub fn save_if_due(&mut self) {
+            if self.shared.save_status().due(&self.config.save) {
+                persistence::bgsave(self.config.rdb_path(), self.snapshot(), self.shared.save_status());
+            }
+        }
+
+        /// SAVE: write the snapshot before replying.
+        fn save(&mut self) -> Vec<u8> {
+            if self.shared.save_status().in_progress() {
+                return error_resp("ERR Background save already in progress");
+            }
+            match persistence::save(&self.config.rdb_path(), &self.snapshot(), self.shared.save_status()) {
+                Ok(()) => SUCCESS_MSG.to_vec(),
+                Err(e) => {
+                    println!("Failed saving the DB: {}", e);
+                    error_resp("ERR")
+                },
+            }
+        }
+
+        /// BGSAVE [SCHEDULE]
+        fn bgsave(&mut self, args: &[DataType]) -> Vec<u8> {
+            let schedule = match args {
+                [] => false,
+                [flag] if text_arg(flag).is_some_and(|flag| flag.eq_ignore_ascii_case("schedule")) => true,
+                _ => return syntax_error(),
+            };
+
+            if self.shared.save_status().in_progress() {
+                if schedule {
+                    self.shared.save_status().schedule();
+                    return b"+Background saving scheduled\r\n".to_vec();
+                }
+                return error_resp("ERR Background save already in progress");
+            }
+            persistence::bgsave(self.config.rdb_path(), self.snapshot(), self.shared.save_status());
+            b"+Background saving started\r\n".to_vec()
+        }
+
+        pub fn config(&mut self) -> &mut Config {
+            &mut self.config
+        }
+
+        /// Start watching `key` in the selected database for one client, returning its current
+        /// version and whether it exists.
+        pub fn watch(&mut self, key: &[u8]) -> (u64, bool) {
+            let exists = self.key_type(key).is_some();
+            let entry = self.watched_keys[self.selected].entry(key.to_vec()).or_insert((0, 0));
+            entry.0 += 1;
+            (entry.1, exists)
+        }
+
+        pub fn unwatch(&mut self, db: usize, key: &[u8]) {
+            if let Some(entry) = self.watched_keys[db].get_mut(key) {
+                entry.0 -= 1;
+                if entry.0 == 0 {
+                    self.watched_keys[db].remove(key);
+                }
+            }
+        }
+
+        /// Whether `key` in database `db` was modified since a WATCH that saw `version`,
+        /// including expiring when it existed then.
+        pub fn watched_key_changed(&mut self, db: usize, key: &[u8], version: u64, existed: bool) -> bool {
+            let current = self.watched_keys[db].get(key).map_or(0, |entry| entry.1);
+            current != version || (existed && self.in_db(db, |dict| dict.key_type(key).is_none()))
+        }
+
+        fn get_value(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+            let o_val = self.db.dict.get(key);
+            match o_val {
+                Some(val) => {
+                    if val.is_expire() {
+                        self.remove_expired(key);
+                        return None;
+                    }
+
+                    Some(val.value.clone())
+                },
+                _ => None,
+            }
+        }
+
+        /// Live entry for `key`, created empty when missing, so in-place edits keep the TTL.
+        fn get_value_mut(&mut self, key: &[u8]) -> &mut ExpireValue {
+            if self.db.dict.get(key).is_some_and(|val| val.is_expire()) {
+                self.remove_expired(key);
+            }
+
+            self.db.dict.entry(key.to_vec()).or_insert(ExpireValue::no_expire(Vec::new()))
+        }
+
+        fn incr_decr_value(&mut self, key: &[u8], change_type: &DataType) -> std::result::Result<i64, Vec<u8>> {
+            if self.is_wrong_type(key, "string") {
+                return Err(wrong_type());
+            }
+            let is_incr = *change_type == "incr";
+
+            let mut error = None;
+            let mut new_val = if is_incr { 1 } else { -1 };
+            self.db.dict.entry(key.to_vec()).and_modify(|cur_exp| {
+                let Some(i_val) = std::str::from_utf8(&cur_exp.value).ok().and_then(|val| val.parse::<i64>().ok()) else {
+                    error = Some(not_integer());
+                    return;
+                };
+                match if is_incr { i_val.checked_add(1) } else { i_val.checked_sub(1) } {
+                    Some(val) => {
+                        new_val = val;
+                        cur_exp.value = f!("{new_val}").into_bytes();
+                    },
+                    None => error = Some(error_resp("ERR increment or decrement would overflow")),
+                }
+            }).or_insert(ExpireValue::no_expire(f!("{}", new_val).into_bytes()));
+
+            if let Some(error) = error {
+                return Err(error);
+            }
+            self.signal_modified(key);
+            self.notify(notify::STRING, "incrby", key);
+            Ok(new_val)
+        }
+
+        /// Store the string `val` at `key`, replacing whatever type was there.
+        fn set_string(&mut self, key: Vec<u8>, val: ExpireValue) {
+            self.db.lists.remove(&key);
+            self.db.zsets.remove(&key);
+            self.db.expires.remove(&key);
+            self.db.dict.insert(key, val);
+        }
+
+        fn delete_value(&mut self, key: &[u8]) -> Option<ExpireValue> {
+            self.db.dict.remove(key)
+        }
+
+        /// Remove `key` whatever type it holds, returning whether anything was there.
+        fn delete_key(&mut self, key: &[u8]) -> bool {
+            let had_value = self.delete_value(key).is_some_and(|val| !val.is_expire());
+            let expired = self.db.collection_expired(key);
+            self.db.expires.remove(key);
+            let had_list = self.db.lists.remove(key).is_some() && !expired;
+            let had_zset = self.db.zsets.remove(key).is_some() && !expired;
+            if had_value || had_list || had_zset {
+                self.signal_modified(key);
+            }
+            had_value || had_list || had_zset
+        }
+
+        /// The Redis type name of `key`, dropping it first if it has expired.
+        fn key_type(&mut self, key: &[u8]) -> Option<&'static str> {
+            if let Some(val) = self.db.dict.get(key) {
+                if !val.is_expire() {
+                    return Some("string");
+                }
+                self.remove_expired(key);
+            }
+            if self.db.collection_expired(key) {
+                self.remove_expired(key);
+                return None;
+            }
+            if self.db.lists.contains_key(key) {
+                return Some("list");
+            }
+            if self.db.zsets.contains_key(key) {
+                return Some("zset");
+            }
+
+            None
+        }
+
+        /// Remove `key` like `delete_key`, freeing a large collection in the background.
+        fn unlink_key(&mut self, key: &[u8]) -> bool {
+            if let Some(list) = self.db.lists.get_mut(key).filter(|list| list.len() > lazyfree::THRESHOLD) {
+                lazyfree::free(std::mem::take(list));
+            }
+            if let Some(zset) = self.db.zsets.get_mut(key).filter(|zset| zset.len() > lazyfree::THRESHOLD) {
+                lazyfree::free(std::mem::take(zset));
+            }
+            self.delete_key(key)
+        }
+
+        /// TYPE key
+        fn type_command(&mut self, args: &[DataType]) -> Vec<u8> {
+            let Some(key) = key_arg(&args[0]) else { return syntax_error() };
+            let kind = self.key_type(&key).unwrap_or("none");
+            serialize(&DataType::SimpleString(kind.to_owned())).unwrap()
+        }
+
+        /// RENAME key newkey, or RENAMENX key newkey when `nx`. The value keeps its TTL.
+        fn rename(&mut self, args: &[DataType], nx: bool) -> Vec<u8> {
+            let (Some(from), Some(to)) = (key_arg(&args[0]), key_arg(&args[1])) else { return syntax_error() };
+            if self.key_type(&from).is_none() {
+                return error_resp("ERR no such key");
+            }
+            let reply = |renamed: bool| match nx {
+                true => serialize(&DataType::Integer(renamed as i64)).unwrap(),
+                false => SUCCESS_MSG.to_vec(),
+            };
+            if from == to {
+                return reply(false);
+            }
+            if nx && self.key_type(&to).is_some() {
+                return reply(false);
+            }
+
+            self.delete_key(&to);
+            if let Some(val) = self.db.dict.remove(&from) {
+                self.db.dict.insert(to.clone(), val);
+            } else if let Some(list) = self.db.lists.remove(&from) {
+                self.db.lists.insert(to.clone(), list);
+            } else if let Some(zset) = self.db.zsets.remove(&from) {
+                self.db.zsets.insert(to.clone(), zset);
+            }
+            if let Some(exp) = self.db.expires.remove(&from) {
+                self.db.expires.insert(to.clone(), exp);
+            }
+            self.signal_modified(&from);
+            self.signal_modified(&to);
+            self.notify(notify::GENERIC, "rename_from", &from);
+            self.notify(notify::GENERIC, "rename_to", &to);
+            reply(true)
+        }
+
+        /// COPY source destination [DB destination-db] [REPLACE]. The copy keeps the TTL.
+        fn copy(&mut self, args: &[DataType]) -> Vec<u8> {
+            let (Some(from), Some(to)) = (key_arg(&args[0]), key_arg(&args[1])) else { return syntax_error() };
+            let mut replace = false;
+            let mut db = self.selected;
+            let mut options = args[2..].iter();
+            while let Some(option) = options.next() {
+                match text_arg(option).map(|option| option.to_ascii_lowercase()).as_deref() {
+                    Some("replace") => replace = true,
+                    Some("db") => match options.next().map(|arg| self.db_arg(arg)) {
+                        Some(Ok(index)) => db = index,
+                        Some(Err(resp)) => return resp,
+                        None => return syntax_error(),
+                    },
+                    _ => return syntax_error(),
+                }
+            }
+            if db != self.selected && self.shared.cluster_enabled() {
+                return error_resp("ERR Copying to another database is not allowed in cluster mode");
+            }
+            if from == to && db == self.selected {
+                return error_resp("ERR source and destination objects are the same");
+            }
+
+            let Some(value) = self.rdb_value(&from) else { return serialize(&DataType::Integer(0)).unwrap() };
+            let expire_at = self.db.expire_at(&from);
+            let copied = self.in_db(db, |dict| {
+                if dict.key_type(&to).is_some() {
+                    if !replace {
+                        return false;
+                    }
+                    dict.delete_key(&to);
+                }
+                dict.insert_value(to.clone(), value, expire_at);
+                dict.signal_modified(&to);
+                dict.notify(notify::GENERIC, "copy_to", &to);
+                true
+            });
+            serialize(&DataType::Integer(copied as i64)).unwrap()
+        }
+
+        /// SELECT index
+        fn select_command(&mut self, args: &[DataType]) -> Vec<u8> {
+            let index = match self.db_arg(&args[0]) {
+                Ok(index) => index,
+                Err(resp) => return resp,
+            };
+            if index != 0 && self.shared.cluster_enabled() {
+                return error_resp("ERR SELECT is not allowed in cluster mode");
+            }
+            self.select(index);
+            SUCCESS_MSG.to_vec()
+        }
+
+        /// SWAPDB index1 index2: every client of one database sees the other's keys from now on.
+        fn swapdb(&mut self, args: &[DataType]) -> Vec<u8> {
+            if self.shared.cluster_enabled() {
+                return error_resp("ERR SWAPDB is not allowed in cluster mode");
+            }
+            let (a, b) = match (self.db_arg(&args[0]), self.db_arg(&args[1])) {
+                (Ok(a), Ok(b)) => (a, b),
+                (Err(resp), _) | (_, Err(resp)) => return resp,
+            };
+            if a != b {
+                std::mem::swap(&mut self.db, &mut self.dbs[self.selected]);
+                self.dbs.swap(a, b);
+                std::mem::swap(&mut self.db, &mut self.dbs[self.selected]);
+                self.signal_flushed(a);
+                self.signal_flushed(b);
+            }
+            SUCCESS_MSG.to_vec()
+        }
+
+        /// MOVE key db, unless the key is already there. The value keeps its TTL.
+        fn move_key(&mut self, args: &[DataType]) -> Vec<u8> {
+            if self.shared.cluster_enabled() {
+                return error_resp("ERR MOVE is not allowed in cluster mode");
+            }
+            let Some(key) = key_arg(&args[0]) else { return syntax_error() };
+            let db = match self.db_arg(&args[1]) {
+                Ok(db) => db,
+                Err(resp) => return resp,
+            };
+            if db == self.selected {
+                return error_resp("ERR source and destination objects are the same");
+            }
+            if self.key_type(&key).is_none() || self.in_db(db, |dict| dict.key_type(&key).is_some()) {
+                return serialize(&DataType::Integer(0)).unwrap();
+            }
+
+            let (val, list, zset) = (self.db.dict.remove(&key), self.db.lists.remove(&key), self.db.zsets.remove(&key));
+            let exp = self.db.expires.remove(&key);
+            self.db.keyspace.remove(&key);
+            self.signal_modified(&key);
+            self.notify(notify::GENERIC, "move_from", &key);
+            self.in_db(db, |dict| {
+                if let Some(val) = val {
+                    dict.db.dict.insert(key.clone(), val);
+                } else if let Some(list) = list {
+                    dict.db.lists.insert(key.clone(), list);
+                } else if let Some(zset) = zset {
+                    dict.db.zsets.insert(key.clone(), zset);
+                }
+                if let Some(exp) = exp {
+                    dict.db.expires.insert(key.clone(), exp);
+                }
+                dict.track(&key, false);
+                dict.signal_modified(&key);
+                dict.notify(notify::GENERIC, "move_to", &key);
+            });
+            serialize(&DataType::Integer(1)).unwrap()
+        }
+
+        /// RANDOMKEY
+        fn random_key(&mut self) -> Vec<u8> {
+            // every miss forgets a key, expired or otherwise gone, so this ends
+            while let Some(key) = self.db.keyspace.random_key() {
+                if self.key_type(&key).is_some() {
+                    return serialize(&DataType::BulkString(Some(key))).unwrap();
+                }
+                self.db.keyspace.remove(&key);
+            }
+            serialize(&DataType::BulkString(None)).unwrap()
+        }
+
+        /// FLUSHDB [ASYNC | SYNC], or FLUSHALL [ASYNC | SYNC] for every database when `all`:
+        /// drop every key, in the background with ASYNC.
+        fn flush(&mut self, args: &[DataType], all: bool) -> Vec<u8> {
+            let lazy = match args {
+                [] => false,
+                [mode] => match text_arg(mode).map(|mode| mode.to_ascii_lowercase()).as_deref() {
+                    Some("async") => true,
+                    Some("sync") => false,
+                    _ => return syntax_error(),
+                },
+                _ => return syntax_error(),
+            };
+
+            let flushed = if all { 0..self.dbs.len() } else { self.selected..self.selected + 1 };
+            let mut datasets = Vec::new();
+            for index in flushed {
+                datasets.push(self.in_db(index, |dict| std::mem::take(&mut dict.db)));
+                self.signal_flushed(index);
+            }
+            if lazy {
+                lazyfree::free(datasets);
+            }
+            SUCCESS_MSG.to_vec()
+        }
+
+        fn is_wrong_type(&mut self, key: &[u8], expected: &str) -> bool {
+            self.key_type(key).is_some_and(|found| found != expected)
+        }
+
+        /// Fetch the sorted set at `key`, with the error reply when it holds another type.
+        fn get_zset(&mut self, key: &[u8]) -> std::result::Result<Option<&SortedSet>, Vec<u8>> {
+            if self.is_wrong_type(key, "zset") {
+                return Err(wrong_type());
+            }
+
+            Ok(self.db.zsets.get(key))
+        }
+
+        fn get_list(&self, key: &[u8]) -> DataType {
+            let mut values: Vec<DataType> = Vec::new();
+
+            if let Some(list) = self.db.lists.get(key) {
+                for each_val in list {
+                    values.push(DataType::BulkString(Some(each_val.clone())));
+                }
+            }
+
+            if values.is_empty() { DataType::Array(None) } else { DataType::Array(Some(values)) }
+        }
+
+        fn push_list(&mut self, key: &[u8], val: &[u8], push_dir: &DataType) -> usize {
+            let is_lpush = push_dir == &"lpush";
+            self.signal_modified(key);
+
+            let adj_list = self.db.lists.entry(key.to_vec())
+                .and_modify(|list| {
+                    if is_lpush {
+                        list.push_front(val.to_vec());
+                    } else {
+                        list.push_back(val.to_vec());
+                    }
+                }).or_insert(LinkedList::from([val.to_vec()]));
+
+            adj_list.len()
+        }
+
+        fn setbit(&mut self, args: &[DataType]) -> Vec<u8> {
+            if args.len() != 3 {
+                return wrong_args("setbit");
+            }
+
+            let Some(key) = key_arg(&args[0]) else { return syntax_error() };
+            let Some(offset) = bit_offset_arg(&args[1], 1) else {
+                return error_resp("ERR bit offset is not an integer or out of range");
+            };
+            let on = match int_arg(&args[2]) {
+                Some(0) => false,
+                Some(1) => true,
+                _ => return error_resp("ERR bit is not an integer or out of range"),
+            };
+            if self.is_wrong_type(&key, "string") {
+                return wrong_type();
+            }
+
+            self.signal_modified(&key);
+            let entry = self.get_value_mut(&key);
+            let previous = bitmap::set_bit(&mut entry.value, offset, on);
+            self.notify(notify::STRING, "setbit", &key);
+            serialize(&DataType::Integer(previous as i64)).unwrap()
+        }
+
+        fn getbit(&mut self, args: &[DataType]) -> Vec<u8> {
+            if args.len() != 2 {
+                return wrong_args("getbit");
+            }
+
+            let Some(key) = key_arg(&args[0]) else { return syntax_error() };
+            let Some(offset) = bit_offset_arg(&args[1], 1) else {
+                return error_resp("ERR bit offset is not an integer or out of range");
+            };
+            if self.is_wrong_type(&key, "string") {
+                return wrong_type();
+            }
+
+            let bit = self.get_value(&key).map_or(0, |val| bitmap::get_bit(&val, offset));
+            serialize(&DataType::Integer(bit as i64)).unwrap()
+        }
+
+        fn bitcount(&mut self, args: &[DataType]) -> Vec<u8> {
+            if args.is_empty() {
+                return wrong_args("bitcount");
+            }
+
+            let Some(key) = key_arg(&args[0]) else { return syntax_error() };
+            let (start, end, unit) = match args.len() {
+                1 => (0, -1, RangeUnit::Byte),
+                3 | 4 => {
+                    let (Some(start), Some(end)) = (int_arg(&args[1]), int_arg(&args[2])) else {
+                        return not_integer();
+                    };
+                    let unit = if args.len() == 4 { range_unit_arg(&args[3]) } else { Some(RangeUnit::Byte) };
+                    let Some(unit) = unit else { return syntax_error() };
+                    (start, end, unit)
+                },
+                _ => return syntax_error(),
+            };
+            if self.is_wrong_type(&key, "string") {
+                return wrong_type();
+            }
+
+            let count = self.get_value(&key).map_or(0, |val| bitmap::count(&val, start, end, unit));
+            serialize(&DataType::Integer(count)).unwrap()
+        }
+
+        fn bitpos(&mut self, args: &[DataType]) -> Vec<u8> {
+            if args.len() < 2 || args.len() > 5 {
+                return wrong_args("bitpos");
+            }
+
+            let Some(key) = key_arg(&args[0]) else { return syntax_error() };
+            let bit = match int_arg(&args[1]) {
+                Some(0) => 0,
+                Some(1) => 1,
+                _ => return error_resp("ERR The bit argument must be 1 or 0."),
+            };
+            let mut start = 0;
+            let mut end = None;
+            if args.len() > 2 {
+                let Some(val) = int_arg(&args[2]) else { return not_integer() };
+                start = val;
+            }
+            if args.len() > 3 {
+                let Some(val) = int_arg(&args[3]) else { return not_integer() };
+                end = Some(val);
+            }
+            let mut unit = RangeUnit::Byte;
+            if args.len() > 4 {
+                let Some(val) = range_unit_arg(&args[4]) else { return syntax_error() };
+                unit = val;
+            }
+            if self.is_wrong_type(&key, "string") {
+                return wrong_type();
+            }
+
+            let pos = match self.get_value(&key) {
+                Some(val) => bitmap::position(&val, bit, start, end, unit),
+                None => if bit == 1 { -1 } else { 0 },
+            };
+            serialize(&DataType::Integer(pos)).unwrap()
+        }
+
+        fn bitop(&mut self, args: &[DataType]) -> Vec<u8> {
+            if args.len() < 3 {
+                return wrong_args("bitop");
+            }
+
+            let op = match text_arg(&args[0]).map(|op| op.to_ascii_uppercase()).as_deref() {
+                Some("AND") => BitOp::And,
+                Some("OR") => BitOp::Or,
+                Some("XOR") => BitOp::Xor,
+                Some("NOT") => BitOp::Not,
+                _ => return syntax_error(),
+            };
+            if op == BitOp::Not && args.len() != 3 {
+                return error_resp("ERR BITOP NOT must be called with a single source key.");
+            }
+
+            let Some(dest) = key_arg(&args[1]) else { return syntax_error() };
+            let mut sources = Vec::new();
+            for each_arg in &args[2..] {
+                let Some(key) = key_arg(each_arg) else { return syntax_error() };
+                if self.is_wrong_type(&key, "string") {
+                    return wrong_type();
+                }
+                sources.push(self.get_value(&key).unwrap_or_default());
+            }
+
+            let result = bitmap::bit_op(op, &sources);
+            let len = result.len();
+            // the result replaces whatever `dest` held, of any type
+            let existed = self.delete_key(&dest);
+            if result.is_empty() {
+                if existed {
+                    self.notify(notify::GENERIC, "del", &dest);
+                }
+            } else {
+                self.db.dict.insert(dest.clone(), ExpireValue::no_expire(result));
+                self.notify(notify::STRING, "set", &dest);
+            }
+            self.signal_modified(&dest);
+
+            serialize(&DataType::Integer(len as i64)).unwrap()
+        }
+
+        fn bitfield(&mut self, args: &[DataType], read_only: bool) -> Vec<u8> {
+            let name = if read_only { "bitfield_ro" } else { "bitfield" };
+            if args.is_empty() {
+                return wrong_args(name);
+            }
+
+            let Some(key) = key_arg(&args[0]) else { return syntax_error() };
+            let mut ops: Vec<(FieldCommand, FieldType, u64, Overflow)> = Vec::new();
+            let mut overflow = Overflow::Wrap;
+            let mut i = 1;
+            while i < args.len() {
+                let Some(sub) = text_arg(&args[i]).map(|sub| sub.to_ascii_uppercase()) else { return syntax_error() };
+                if sub == "OVERFLOW" && !read_only {
+                    overflow = match args.get(i + 1).and_then(text_arg).map(|val| val.to_ascii_uppercase()).as_deref() {
+                        Some("WRAP") => Overflow::Wrap,
+                        Some("SAT") => Overflow::Sat,
+                        Some("FAIL") => Overflow::Fail,
+                        _ => return error_resp("ERR Invalid OVERFLOW type specified"),
+                    };
+                    i += 2;
+                    continue;
+                }
+
+                let arity = match sub.as_str() {
+                    "GET" => 3,
+                    "SET" | "INCRBY" if !read_only => 4,
+                    _ if read_only => return error_resp("ERR BITFIELD_RO only supports the GET subcommand"),
+                    _ => return syntax_error(),
+                };
+                if i + arity > args.len() {
+                    return syntax_error();
+                }
+
+                let Some(field) = text_arg(&args[i + 1]).and_then(|val| FieldType::parse(&val)) else {
+                    return error_resp("ERR Invalid bitfield type. Use something like i16 u8. Note that u64 is not supported but i64 is.");
+                };
+                let Some(offset) = field_offset_arg(&args[i + 2], field) else {
+                    return error_resp("ERR bit offset is not an integer or out of range");
+                };
+                let command = match sub.as_str() {
+                    "GET" => FieldCommand::Get,
+                    _ => {
+                        let Some(val) = int_arg(&args[i + 3]) else { return not_integer() };
+                        if sub == "SET" { FieldCommand::Set(val) } else { FieldCommand::IncrBy(val) }
+                    },
+                };
+
+                ops.push((command, field, offset, overflow));
+                i += arity;
+            }
+            if self.is_wrong_type(&key, "string") {
+                return wrong_type();
+            }
+
+            let has_writes = ops.iter().any(|op| op.0 != FieldCommand::Get);
+            let mut bytes = self.get_value(&key).unwrap_or_default();
+            let mut results = Vec::new();
+            for (command, field, offset, overflow) in ops {
+                let old = bitmap::get_field(&bytes, offset, field);
+                let result = match command {
+                    FieldCommand::Get => Some(old),
+                    FieldCommand::Set(val) => field.fit(val as i128, overflow).map(|new| {
+                        bitmap::set_field(&mut bytes, offset, field, new);
+                        old
+                    }),
+                    FieldCommand::IncrBy(incr) => field.fit(old as i128 + incr as i128, overflow).inspect(|&new| {
+                        bitmap::set_field(&mut bytes, offset, field, new);
+                    }),
+                };
+
+                results.push(match result {
+                    Some(val) => DataType::Integer(val),
+                    None => DataType::BulkString(None),
+                });
+            }
+
+            if has_writes {
+                self.get_value_mut(&key).value = bytes;
+                self.signal_modified(&key);
+                self.notify(notify::STRING, "setbit", &key);
+            }
+
+            serialize(&DataType::Array(Some(results))).unwrap()
+        }
+
+        /// Fetch `key` as a HyperLogLog, with the error reply when it holds something else.
+        fn get_hll(&mut self, key: &[u8]) -> std::result::Result<Option<Vec<u8>>, Vec<u8>> {
+            if self.is_wrong_type(key, "string") {
+                return Err(wrong_type());
+            }
+
+            match self.get_value(key) {
+                Some(val) if !hyperloglog::is_valid(&val) => {
+                    Err(error_resp("WRONGTYPE Key is not a valid HyperLogLog string value."))
+                },
+                o_val => Ok(o_val),
+            }
+        }
+
+        fn pfadd(&mut self, args: &[DataType]) -> Vec<u8> {
+            if args.is_empty() {
+                return wrong_args("pfadd");
+            }
+
+            let Some(key) = key_arg(&args[0]) else { return syntax_error() };
+            let existing = match self.get_hll(&key) {
+                Ok(val) => val,
+                Err(resp) => return resp,
+            };
+
+            let mut changed = existing.is_none();
+            let mut hll = existing.unwrap_or_else(hyperloglog::new_hll);
+            for each_arg in &args[1..] {
+                let DataType::BulkString(Some(element)) = each_arg else { return syntax_error() };
+                match hyperloglog::add(&mut hll, element) {
+                    Some(updated) => changed |= updated,
+                    None => return invalid_hll(),
+                }
+            }
+
+            if changed {
+                self.get_value_mut(&key).value = hll;
+                self.signal_modified(&key);
+                self.notify(notify::STRING, "pfadd", &key);
+            }
+
+            serialize(&DataType::Integer(changed as i64)).unwrap()
+        }
+
+        fn pfcount(&mut self, args: &[DataType]) -> Vec<u8> {
+            if args.is_empty() {
+                return wrong_args("pfcount");
+            }
+
+            if args.len() == 1 {
+                let Some(key) = key_arg(&args[0]) else { return syntax_error() };
+                let mut hll = match self.get_hll(&key) {
+                    Ok(Some(val)) => val,
+                    Ok(None) => return serialize(&DataType::Integer(0)).unwrap(),
+                    Err(resp) => return resp,
+                };
+
+                let cached = hll.clone();
+                let Some(card) = hyperloglog::count(&mut hll) else { return invalid_hll() };
+                // keep the refreshed cardinality cache, as Redis does
+                if hll != cached {
+                    self.get_value_mut(&key).value = hll;
+                    self.signal_modified(&key);
+                }
+                return serialize(&DataType::Integer(card as i64)).unwrap();
+            }
+
+            let mut max = vec![0; hyperloglog::HLL_REGISTERS];
+            for each_arg in args {
+                let Some(key) = key_arg(each_arg) else { return syntax_error() };
+                match self.get_hll(&key) {
+                    Ok(Some(hll)) => {
+                        if hyperloglog::merge_into(&mut max, &hll).is_none() {
+                            return invalid_hll();
+                        }
+                    },
+                    Ok(None) => (),
+                    Err(resp) => return resp,
+                }
+            }
+
+            serialize(&DataType::Integer(hyperloglog::estimate(&max) as i64)).unwrap()
+        }
+
+        fn pfmerge(&mut self, args: &[DataType]) -> Vec<u8> {
+            if args.is_empty() {
+                return wrong_args("pfmerge");
+            }
+
+            let mut max = vec![0; hyperloglog::HLL_REGISTERS];
+            let mut use_dense = false;
+            for each_arg in args {
+                let Some(key) = key_arg(each_arg) else { return syntax_error() };
+                match self.get_hll(&key) {
+                    Ok(Some(hll)) => {
+                        use_dense |= hll[4] == 0;
+                        if hyperloglog::merge_into(&mut max, &hll).is_none() {
+                            return invalid_hll();
+                        }
+                    },
+                    Ok(None) => (),
+                    Err(resp) => return resp,
+                }
+            }
+
+            let Some(dest) = key_arg(&args[0]) else { return syntax_error() };
+            self.get_value_mut(&dest).value = if use_dense {
+                hyperloglog::encode_dense(&max)
+            } else {
+                hyperloglog::encode(&max)
+            };
+            self.signal_modified(&dest);
+            self.notify(notify::STRING, "pfadd", &dest);
+
+            SUCCESS_MSG.to_vec()
+        }
+
+        fn zadd(&mut self, args: &[DataType]) -> Vec<u8> {
+            if args.len() < 3 {
+                return wrong_args("zadd");
+            }
+
+            let Some(key) = key_arg(&args[0]) else { return syntax_error() };
+            let mut flags = AddFlags::default();
+            let mut i = 1;
+            while let Some(flag) = args.get(i).and_then(text_arg) {
+                if !flags.parse(&flag) {
+                    break;
+                }
+                i += 1;
+            }
+            if flags.nx && flags.xx {
+                return error_resp("ERR XX and NX options at the same time are not compatible");
+            }
+            if i >= args.len() || !(args.len() - i).is_multiple_of(2) {
+                return syntax_error();
+            }
+
+            let mut pairs = Vec::new();
+            for pair in args[i..].chunks(2) {
+                let Some(score) = float_arg(&pair[0]) else { return error_resp("ERR value is not a valid float") };
+                let DataType::BulkString(Some(member)) = &pair[1] else { return syntax_error() };
+                pairs.push((score, member.clone()));
+            }
+
+            match self.add_to_zset(&key, pairs, flags) {
+                Ok(count) => serialize(&DataType::Integer(count)).unwrap(),
+                Err(resp) => resp,
+            }
+        }
+
+        /// Shared by ZADD and GEOADD, returning the number of added (or with CH, changed) members.
+        fn add_to_zset(&mut self, key: &[u8], pairs: Vec<(f64, Vec<u8>)>, flags: AddFlags) -> std::result::Result<i64, Vec<u8>> {
+            if self.is_wrong_type(key, "zset") {
+                return Err(wrong_type());
+            }
+
+            let zset = self.db.zsets.entry(key.to_vec()).or_default();
+            let mut count = 0;
+            let mut changed = false;
+            for (score, member) in pairs {
+                match zset.score(&member) {
+                    Some(_) if flags.nx => (),
+                    None if flags.xx => (),
+                    Some(old) => {
+                        if old != score {
+                            zset.insert(&member, score);
+                            changed = true;
+                            if flags.ch { count += 1; }
+                        }
+                    },
+                    None => {
+                        zset.insert(&member, score);
+                        changed = true;
+                        count += 1;
+                    },
+                }
+            }
+
+            if zset.is_empty() {
+                self.db.zsets.remove(key);
+                self.db.expires.remove(key);
+            }
+            if changed {
+                self.signal_modified(key);
+                self.notify(notify::ZSET, "zadd", key);
+            }
+
+            Ok(count)
+        }
+
+        fn zrem(&mut self, args: &[DataType]) -> Vec<u8> {
+            if args.len() < 2 {
+                return wrong_args("zrem");
+            }
+
+            let Some(key) = key_arg(&args[0]) else { return syntax_error() };
+            if self.is_wrong_type(&key, "zset") {
+                return wrong_type();
+            }
+
+            let mut count = 0;
+            let mut emptied = false;
+            if let Some(zset) = self.db.zsets.get_mut(&key) {
+                for each_arg in &args[1..] {
+                    if let DataType::BulkString(Some(member)) = each_arg {
+                        if zset.remove(member) { count += 1; }
+                    }
+                }
+                emptied = zset.is_empty();
+                if emptied {
+                    self.db.zsets.remove(&key);
+                    self.db.expires.remove(&key);
+                }
+            }
+            if count > 0 {
+                self.signal_modified(&key);
+                self.notify(notify::ZSET, "zrem", &key);
+            }
+            if emptied {
+                self.notify(notify::GENERIC, "del", &key);
+            }
+
+            serialize(&DataType::Integer(count)).unwrap()
+        }
+
+        fn zscore(&mut self, args: &[DataType]) -> Vec<u8> {
+            if args.len() != 2 {
+                return wrong_args("zscore");
+            }
+
+            let Some(key) = key_arg(&args[0]) else { return syntax_error() };
+            let DataType::BulkString(Some(member)) = &args[1] else { return syntax_error() };
+            match self.get_zset(&key) {
+                Ok(zset) => {
+                    let score = zset.and_then(|zset| zset.score(member));
+                    serialize(&DataType::BulkString(score.map(format_score))).unwrap()
+                },
+                Err(resp) => resp,
+            }
+        }
+
+        /// ZSCAN key cursor [MATCH pattern] [COUNT count]
+        fn zscan(&mut self, args: &[DataType]) -> Vec<u8> {
+            let Some(key) = key_arg(&args[0]) else { return syntax_error() };
+            let (cursor, options) = match scan_args(&args[1..], false) {
+                Ok(args) => args,
+                Err(resp) => return resp,
+            };
+            let zset = match self.get_zset(&key) {
+                Ok(zset) => zset,
+                Err(resp) => return resp,
+            };
+            let members = zset.into_iter().flat_map(|zset| zset.iter()).map(|(member, score)| (member, (member, score)));
+            let (next, members) = scan::page(members, cursor, options.count);
+            let members = members.into_iter()
+                .filter(|(member, _)| options.matches(member))
+                .flat_map(|(member, score)| [DataType::BulkString(Some(member.to_vec())), DataType::BulkString(Some(format_score(score)))])
+                .collect();
+            scan_reply(next, members)
+        }
+
+        fn zcard(&mut self, args: &[DataType]) -> Vec<u8> {
+            if args.len() != 1 {
+                return wrong_args("zcard");
+            }
+
+            let Some(key) = key_arg(&args[0]) else { return syntax_error() };
+            match self.get_zset(&key) {
+                Ok(zset) => serialize(&DataType::Integer(zset.map_or(0, |zset| zset.len() as i64))).unwrap(),
+                Err(resp) => resp,
+            }
+        }
+
+        fn zrange(&mut self, args: &[DataType]) -> Vec<u8> {
+            if args.len() != 3 && args.len() != 4 {
+                return wrong_args("zrange");
+            }
+
+            let Some(key) = key_arg(&args[0]) else { return syntax_error() };
+            let (Some(start), Some(stop)) = (int_arg(&args[1]), int_arg(&args[2])) else { return not_integer() };
+            let with_scores = match args.get(3).and_then(text_arg) {
+                Some(flag) if flag.eq_ignore_ascii_case("withscores") => true,
+                Some(_) => return syntax_error(),
+                None => false,
+            };
+
+            let zset = match self.get_zset(&key) {
+                Ok(zset) => zset,
+                Err(resp) => return resp,
+            };
+            let mut values = Vec::new();
+            for (member, score) in zset.map(|zset| zset.range(start, stop)).unwrap_or_default() {
+                values.push(DataType::BulkString(Some(member.to_vec())));
+                if with_scores {
+                    values.push(DataType::BulkString(Some(format_score(score))));
+                }
+            }
+
+            serialize(&DataType::Array(Some(values))).unwrap()
+        }
+
+        fn geoadd(&mut self, args: &[DataType]) -> Vec<u8> {
+            if args.len() < 4 {
+                return wrong_args("geoadd");
+            }
+
+            let Some(key) = key_arg(&args[0]) else { return syntax_error() };
+            let mut flags = AddFlags::default();
+            let mut i = 1;
+            while let Some(flag) = args.get(i).and_then(text_arg) {
+                match flag.to_ascii_uppercase().as_str() {
+                    "NX" | "XX" | "CH" => flags.parse(&flag),
+                    _ => break,
+                };
+                i += 1;
+            }
+            if flags.nx && flags.xx {
+                return error_resp("ERR XX and NX options at the same time are not compatible");
+            }
+            if i >= args.len() || !(args.len() - i).is_multiple_of(3) {
+                return error_resp("ERR syntax error. Try GEOADD key [x1] [y1] [name1] [x2] [y2] [name2] ... ");
+            }
+
+            let mut pairs = Vec::new();
+            for triple in args[i..].chunks(3) {
+                let (Some(lon), Some(lat)) = (float_arg(&triple[0]), float_arg(&triple[1])) else {
+                    return error_resp("ERR value is not a valid float");
+                };
+                if !geo::valid_coords(lon, lat) {
+                    return error_resp(&f!("ERR invalid longitude,latitude pair {lon:.6},{lat:.6}"));
+                }
+                let DataType::BulkString(Some(member)) = &triple[2] else { return syntax_error() };
+                pairs.push((geo::encode_score(lon, lat), member.clone()));
+            }
+
+            match self.add_to_zset(&key, pairs, flags) {
+                Ok(count) => serialize(&DataType::Integer(count)).unwrap(),
+                Err(resp) => resp,
+            }
+        }
+
+        /// Scores of each requested member, `None` for missing ones, for the GEO lookups.
+        fn member_scores(&mut self, key: &[u8], members: &[DataType]) -> std::result::Result<Vec<Option<f64>>, Vec<u8>> {
+            let zset = self.get_zset(key)?;
+            Ok(members.iter().map(|member| match (zset, member) {
+                (Some(zset), DataType::BulkString(Some(member))) => zset.score(member),
+                _ => None,
+            }).collect())
+        }
+
+        fn geopos(&mut self, args: &[DataType]) -> Vec<u8> {
+            if args.is_empty() {
+                return wrong_args("geopos");
+            }
+
+            let Some(key) = key_arg(&args[0]) else { return syntax_error() };
+            let scores = match self.member_scores(&key, &args[1..]) {
+                Ok(scores) => scores,
+                Err(resp) => return resp,
+            };
+
+            let values = scores.into_iter().map(|score| match score {
+                Some(score) => {
+                    let (lon, lat) = geo::decode_score(score);
+                    DataType::Array(Some(vec![
+                        DataType::BulkString(Some(format_coord(lon))),
+                        DataType::BulkString(Some(format_coord(lat))),
+                    ]))
+                },
+                None => DataType::Array(None),
+            }).collect();
+
+            serialize(&DataType::Array(Some(values))).unwrap()
+        }
+
+        fn geodist(&mut self, args: &[DataType]) -> Vec<u8> {
+            if args.len() != 3 && args.len() != 4 {
+                return wrong_args("geodist");
+            }
+
+            let Some(key) = key_arg(&args[0]) else { return syntax_error() };
+            let factor = match args.get(3).and_then(text_arg) {
+                Some(unit) => match geo::unit_factor(&unit) {
+                    Some(factor) => factor,
+                    None => return bad_unit(),
+                },
+                None => 1.0,
+            };
+
+            let scores = match self.member_scores(&key, &args[1..3]) {
+                Ok(scores) => scores,
+                Err(resp) => return resp,
+            };
+            let (Some(first), Some(second)) = (scores[0], scores[1]) else {
+                return serialize(&DataType::BulkString(None)).unwrap();
+            };
+
+            let (lon1, lat1) = geo::decode_score(first);
+            let (lon2, lat2) = geo::decode_score(second);
+            let dist = geo::distance(lon1, lat1, lon2, lat2) / factor;
+            serialize(&DataType::BulkString(Some(f!("{dist:.4}").into_bytes()))).unwrap()
+        }
+
+        fn geohash(&mut self, args: &[DataType]) -> Vec<u8> {
+            if args.is_empty() {
+                return wrong_args("geohash");
+            }
+
+            let Some(key) = key_arg(&args[0]) else { return syntax_error() };
+            let scores = match self.member_scores(&key, &args[1..]) {
+                Ok(scores) => scores,
+                Err(resp) => return resp,
+            };
+
+            let values = scores.into_iter()
+                .map(|score| DataType::BulkString(score.map(geo::hash_string)))
+                .collect();
+            serialize(&DataType::Array(Some(values))).unwrap()
+        }
+
+        /// GEOSEARCH, or GEOSEARCHSTORE when `store` is set and `args` starts with the destination.
+        fn geosearch(&mut self, args: &[DataType], store: bool) -> Vec<u8> {
+            let name = if store { "GEOSEARCHSTORE" } else { "GEOSEARCH" };
+            let first = if store { 2 } else { 1 };
+            if args.len() < first + 4 {
+                return wrong_args(&name.to_ascii_lowercase());
+            }
+
+            let Some(key) = key_arg(&args[first - 1]) else { return syntax_error() };
+            let query = match GeoQuery::parse(&args[first..], store, name) {
+                Ok(query) => query,
+                Err(resp) => return resp,
+            };
+
+            let zset = match self.get_zset(&key) {
+                Ok(zset) => zset,
+                Err(resp) => return resp,
+            };
+            let Some(zset) = zset else {
+                if store {
+                    let Some(dest) = key_arg(&args[0]) else { return syntax_error() };
+                    if self.delete_key(&dest) {
+                        self.notify(notify::GENERIC, "del", &dest);
+                    }
+                    return serialize(&DataType::Integer(0)).unwrap();
+                }
+                return serialize(&DataType::Array(Some(Vec::new()))).unwrap();
+            };
+
+            let center = match &query.from {
+                GeoFrom::LonLat(lon, lat) => (*lon, *lat),
+                GeoFrom::Member(member) => match zset.score(member) {
+                    Some(score) => geo::decode_score(score),
+                    None => return error_resp("ERR could not decode requested zset member"),
+                },
+            };
+
+            let limit = if query.any { query.count } else { None };
+            let mut matches = geo::search(zset, query.shape, center, limit);
+            match query.sort {
+                GeoSort::Asc => matches.sort_by(|a, b| a.dist.total_cmp(&b.dist)),
+                GeoSort::Desc => matches.sort_by(|a, b| b.dist.total_cmp(&a.dist)),
+                GeoSort::None => (),
+            }
+            if let Some(count) = query.count {
+                matches.truncate(count);
+            }
+
+            if store {
+                let Some(dest) = key_arg(&args[0]) else { return syntax_error() };
+                let count = matches.len();
+                let mut result = SortedSet::new();
+                for each_match in matches {
+                    let score = if query.store_dist { each_match.dist / query.factor } else { each_match.score };
+                    result.insert(&each_match.member, score);
+                }
+
+                let existed = self.delete_key(&dest);
+                if !result.is_empty() {
+                    self.db.zsets.insert(dest.clone(), result);
+                    self.notify(notify::ZSET, "geosearchstore", &dest);
+                } else if existed {
+                    self.notify(notify::GENERIC, "del", &dest);
+                }
+                self.signal_modified(&dest);
+                return serialize(&DataType::Integer(count as i64)).unwrap();
+            }
+
+            let values = matches.into_iter().map(|each_match| {
+                let member = DataType::BulkString(Some(each_match.member));
+                if !query.with_dist && !query.with_hash && !query.with_coord {
+                    return member;
+                }
+
+                let mut item = vec![member];
+                if query.with_dist {
+                    item.push(DataType::BulkString(Some(f!("{:.4}", each_match.dist / query.factor).into_bytes())));
+                }
+                if query.with_hash {
+                    item.push(DataType::Integer(each_match.score as i64));
+                }
+                if query.with_coord {
+                    item.push(DataType::Array(Some(vec![
+                        DataType::BulkString(Some(format_coord(each_match.lon))),
+                        DataType::BulkString(Some(format_coord(each_match.lat))),
+                    ])));
+                }
+                DataType::Array(Some(item))
+            }).collect();
+
+            serialize(&DataType::Array(Some(values))).unwrap()
+        }
+
+        /// EVAL, or EVALSHA when `by_sha` is set. The script runs while the caller holds the
+        /// dictionary lock, so no other command interleaves with it.
+        fn eval(&mut self, args: &[DataType], by_sha: bool, read_only: bool) -> Vec<u8> {
+            let DataType::BulkString(Some(script)) = &args[0] else { return syntax_error() };
+            let (keys, argv) = match script_args(&args[1..]) {
+                Ok(split) => split,
+                Err(resp) => return resp,
+            };
+
+            let (body, sha) = if by_sha {
+                let sha = String::from_utf8_lossy(script).to_ascii_lowercase();
+                match self.scripts.get(&sha) {
+                    Some(body) => (body, sha),
+                    None => return error_resp("NOSCRIPT No matching script. Please use EVAL."),
+                }
+            } else {
+                (script.clone(), self.scripts.load(script))
+            };
+
+            // a SELECT in the script does not outlast it
+            let selected = self.selected;
+            let reply = scripting::run(&body, &sha, &keys, &argv, read_only, |d_command| {
+                self.handle_command(DataType::Array(Some(d_command)))
+            });
+            self.select(selected);
+            serialize(&reply).unwrap()
+        }
+
+        /// FCALL, or FCALL_RO when `read_only` is set, which only runs `no-writes` functions.
+        fn fcall(&mut self, args: &[DataType], read_only: bool) -> Vec<u8> {
+            let Some(name) = text_arg(&args[0]) else { return syntax_error() };
+            let (keys, argv) = match script_args(&args[1..]) {
+                Ok(split) => split,
+                Err(resp) => return resp,
+            };
+
+            let Some((library, function)) = self.functions.find(&name) else {
+                return error_resp("ERR Function not found");
+            };
+            let no_writes = function.flags.iter().any(|flag| flag == "no-writes");
+            if read_only && !no_writes {
+                return error_resp("ERR Can not execute a script with write flag using *_ro command.");
+            }
+
+            let code = library.code.clone();
+            let selected = self.selected;
+            let reply = scripting::call_function(&code, &name, &keys, &argv, no_writes, |d_command| {
+                self.handle_command(DataType::Array(Some(d_command)))
+            });
+            self.select(selected);
+            serialize(&reply).unwrap()
+        }
+
+        fn function(&mut self, args: &[DataType]) -> Vec<u8> {
+            let Some(sub) = text_arg(&args[0]) else { return syntax_error() };
+            let sub = sub.to_ascii_lowercase();
+            let args = &args[1..];
+            match sub.as_str() {
+                "load" if !args.is_empty() && args.len() <= 2 => {
+                    let replace = match args.len() {
+                        2 if text_arg(&args[0]).is_some_and(|arg| arg.eq_ignore_ascii_case("replace")) => true,
+                        2 => return error_resp(&f!("ERR Unknown option given: {}", text_arg(&args[0]).unwrap_or_default())),
+                        _ => false,
+                    };
+                    let DataType::BulkString(Some(code)) = &args[args.len() - 1] else { return syntax_error() };
+                    match self.functions.load(code, replace) {
+                        Ok(name) => serialize(&DataType::BulkString(Some(name.into_bytes()))).unwrap(),
+                        Err(err) => error_resp(&err),
+                    }
+                },
+                "list" => self.function_list(args),
+                "delete" if args.len() == 1 => {
+                    let Some(name) = text_arg(&args[0]) else { return syntax_error() };
+                    if !self.functions.delete(&name) {
+                        return error_resp("ERR Library not found");
+                    }
+                    SUCCESS_MSG.to_vec()
+                },
+                "flush" if args.len() <= 1 => {
+                    if let Some(mode) = args.first().and_then(text_arg) {
+                        if !mode.eq_ignore_ascii_case("sync") && !mode.eq_ignore_ascii_case("async") {
+                            return syntax_error();
+                        }
+                    }
+                    self.functions.flush();
+                    SUCCESS_MSG.to_vec()
+                },
+                "dump" if args.is_empty() => serialize(&DataType::BulkString(Some(self.functions.dump()))).unwrap(),
+                "restore" if !args.is_empty() && args.len() <= 2 => {
+                    let DataType::BulkString(Some(payload)) = &args[0] else { return syntax_error() };
+                    let policy = match args.get(1).and_then(text_arg).map(|arg| arg.to_ascii_lowercase()).as_deref() {
+                        None | Some("append") => RestorePolicy::Append,
+                        Some("replace") => RestorePolicy::Replace,
+                        Some("flush") => RestorePolicy::Flush,
+                        Some(_) => return error_resp("ERR Wrong restore policy given, value should be either FLUSH, APPEND or REPLACE."),
+                    };
+                    match self.functions.restore(payload, policy) {
+                        Ok(()) => SUCCESS_MSG.to_vec(),
+                        Err(err) => error_resp(&err),
+                    }
+                },
+                "load" | "delete" | "flush" | "dump" | "restore" => wrong_args(&f!("function|{sub}")),
+                _ => error_resp(&f!("ERR unknown subcommand '{sub}'. Try FUNCTION HELP.")),
             }
         }
 
-        fn get_value(&mut self, key: &String) -> Option<String> {
-            let o_val = self.dict.get(key);
-            match o_val {
-                Some(val) => {
-                    if val.is_expire() {
-                        self.dict.remove(key);
-                        return None;
+        /// FUNCTION LIST [LIBRARYNAME pattern] [WITHCODE]
+        fn function_list(&self, args: &[DataType]) -> Vec<u8> {
+            let mut pattern = None;
+            let mut with_code = false;
+            let mut i = 0;
+            while i < args.len() {
+                let Some(option) = text_arg(&args[i]) else { return syntax_error() };
+                match option.to_ascii_lowercase().as_str() {
+                    "withcode" => with_code = true,
+                    "libraryname" if pattern.is_none() && i + 1 < args.len() => {
+                        let Some(val) = text_arg(&args[i + 1]) else { return syntax_error() };
+                        pattern = Some(val);
+                        i += 1;
+                    },
+                    _ => return error_resp(&f!("ERR Unknown argument {option}")),
+                }
+                i += 1;
+            }
+
+            let bulk = |val: &str| DataType::BulkString(Some(val.as_bytes().to_vec()));
+            let libraries = self.functions.libraries()
+                .filter(|library| pattern.as_ref().is_none_or(|pattern| glob::matches(pattern.as_bytes(), library.name.as_bytes())))
+                .map(|library| {
+                    let functions = library.functions.iter().map(|function| {
+                        DataType::Array(Some(vec![
+                            bulk("name"),
+                            bulk(&function.name),
+                            bulk("description"),
+                            DataType::BulkString(function.description.as_ref().map(|val| val.as_bytes().to_vec())),
+                            bulk("flags"),
+                            DataType::Array(Some(function.flags.iter().map(|flag| bulk(flag)).collect())),
+                        ]))
+                    }).collect();
+
+                    let mut item = vec![
+                        bulk("library_name"),
+                        bulk(&library.name),
+                        bulk("engine"),
+                        bulk("LUA"),
+                        bulk("functions"),
+                        DataType::Array(Some(functions)),
+                    ];
+                    if with_code {
+                        item.push(bulk("library_code"));
+                        item.push(DataType::BulkString(Some(library.code.clone())));
                     }
+                    DataType::Array(Some(item))
+                })
+                .collect();
 
-                    return Some(val.value.clone());
+            serialize(&DataType::Array(Some(libraries))).unwrap()
+        }
+
+        fn script(&mut self, args: &[DataType]) -> Vec<u8> {
+            let Some(sub) = text_arg(&args[0]) else { return syntax_error() };
+            match sub.to_ascii_lowercase().as_str() {
+                "load" if args.len() == 2 => {
+                    let DataType::BulkString(Some(body)) = &args[1] else { return syntax_error() };
+                    let sha = self.scripts.load(body);
+                    serialize(&DataType::BulkString(Some(sha.into_bytes()))).unwrap()
+                },
+                "exists" if args.len() >= 2 => {
+                    let found = args[1..].iter()
+                        .map(|arg| {
+                            let found = text_arg(arg).is_some_and(|sha| self.scripts.contains(&sha));
+                            DataType::Integer(found as i64)
+                        })
+                        .collect();
+                    serialize(&DataType::Array(Some(found))).unwrap()
+                },
+                "flush" if args.len() <= 2 => {
+                    if let Some(mode) = args.get(1).and_then(text_arg) {
+                        if !mode.eq_ignore_ascii_case("sync") && !mode.eq_ignore_ascii_case("async") {
+                            return syntax_error();
+                        }
+                    }
+                    self.scripts.flush();
+                    SUCCESS_MSG.to_vec()
                 },
-                _ => return None,
+                "load" | "exists" | "flush" => error_resp(&f!("ERR wrong number of arguments for 'script|{}' command", sub.to_ascii_lowercase())),
+                _ => error_resp(&f!("ERR unknown subcommand '{sub}'. Try SCRIPT HELP.")),
             }
         }
 
-        fn incr_decr_value(&mut self, key: &String, change_type: &DataType) -> Option<i64> {
-            let is_incr = if *change_type == "incr" { true } else { false };
+        /// PUBSUB CHANNELS and SHARDCHANNELS [pattern], NUMSUB and SHARDNUMSUB [channel ...], and
+        /// NUMPAT
+        fn pubsub_command(&self, args: &[DataType]) -> Vec<u8> {
+            let Some(sub) = text_arg(&args[0]) else { return syntax_error() };
+            let sub = sub.to_ascii_lowercase();
+            let names = args[1..].iter().filter_map(|arg| match arg {
+                DataType::BulkString(Some(val)) => Some(val.as_slice()),
+                _ => None,
+            });
 
-            let mut has_error = false;
-            let mut new_val = if is_incr { 1 } else { -1 };
-            self.dict.entry(key.to_string()).and_modify(|cur_exp| {
-                if let Ok(i_val) = cur_exp.value.parse::<i64>() {
-                    new_val = if is_incr { i_val + 1 } else { i_val - 1 };
-                    cur_exp.value = f!("{new_val}");
-                } else {
-                    has_error = true;
-                }
-            }).or_insert(ExpireValue::no_expire(f!("{}", new_val)));
+            let reply = match sub.as_str() {
+                "channels" | "shardchannels" if args.len() <= 2 => {
+                    let pattern = names.into_iter().next();
+                    let channels = if sub == "channels" {
+                        self.shared.pubsub().channels(pattern)
+                    } else {
+                        self.shared.pubsub().shard_channels(pattern)
+                    };
+                    DataType::Array(Some(channels.into_iter().map(|channel| DataType::BulkString(Some(channel))).collect()))
+                },
+                "numsub" | "shardnumsub" => {
+                    let counts = names.flat_map(|channel| {
+                        let count = if sub == "numsub" { self.shared.pubsub().numsub(channel) } else { self.shared.pubsub().shard_numsub(channel) };
+                        [DataType::BulkString(Some(channel.to_vec())), DataType::Integer(count as i64)]
+                    }).collect();
+                    DataType::Array(Some(counts))
+                },
+                "numpat" if args.len() == 1 => DataType::Integer(self.shared.pubsub().numpat() as i64),
+                "channels" | "shardchannels" | "numpat" => return wrong_args(&f!("pubsub|{sub}")),
+                _ => return error_resp(&f!("ERR unknown subcommand '{sub}'. Try PUBSUB HELP.")),
+            };
+            serialize(&reply).unwrap()
+        }
 
-            if !has_error {
-                return Some(new_val);
+        /// CONFIG GET pattern [pattern ...] and CONFIG SET name value [name value ...]
+        fn config_command(&mut self, args: &[DataType]) -> Vec<u8> {
+            let Some(sub) = text_arg(&args[0]) else { return syntax_error() };
+            let sub = sub.to_ascii_lowercase();
+            let mut params = Vec::new();
+            for each_arg in &args[1..] {
+                let Some(param) = text_arg(each_arg) else { return syntax_error() };
+                params.push(param);
             }
 
-            None
-        }
+            match sub.as_str() {
+                "get" if !params.is_empty() => {
+                    let mut found: Vec<(&str, String)> = Vec::new();
+                    for pattern in &params {
+                        for (name, value) in self.config.get(pattern) {
+                            if !found.iter().any(|(each_name, _)| *each_name == name) {
+                                found.push((name, value));
+                            }
+                        }
+                    }
 
-        fn delete_value(&mut self, key: &String) -> Option<ExpireValue> {
-            return self.dict.remove(key);
+                    let values = found.into_iter()
+                        .flat_map(|(name, value)| [
+                            DataType::BulkString(Some(name.as_bytes().to_vec())),
+                            DataType::BulkString(Some(value.into_bytes())),
+                        ])
+                        .collect();
+                    serialize(&DataType::Array(Some(values))).unwrap()
+                },
+                "set" if !params.is_empty() && params.len().is_multiple_of(2) => {
+                    let pairs: Vec<(String, String)> = params.chunks(2)
+                        .map(|pair| (pair[0].clone(), pair[1].clone()))
+                        .collect();
+                    let appendonly = self.config.appendonly;
+                    if let Err(err) = self.config.set(&pairs) {
+                        return error_resp(&err);
+                    }
+                    self.config_changed = true;
+                    self.shared.replication().resize_backlog(self.config.repl_backlog_size);
+                    self.shared.cluster().set_node_timeout(self.config.cluster_node_timeout);
+                    if self.config.appendonly != appendonly {
+                        if let Err(err) = self.set_appendonly(self.config.appendonly) {
+                            self.config.appendonly = appendonly;
+                            return error_resp(&err);
+                        }
+                    }
+                    SUCCESS_MSG.to_vec()
+                },
+                "get" | "set" => wrong_args(&f!("config|{sub}")),
+                _ => error_resp(&f!("ERR unknown subcommand '{sub}'. Try CONFIG HELP.")),
+            }
         }
 
-        fn get_list(&self, key: &String) -> DataType {
-            let mut values: Vec<DataType> = Vec::new();
-
-            if let Some(list) = self.lists.get(key) {
-                for each_val in list {
-                    values.push(DataType::BulkString(Some(each_val.clone())));
-                }
+        /// Run one command, propagating it when it is a write that succeeded.
+        pub fn handle_command(&mut self, d_command: DataType) -> Vec<u8> {
+            let write = matches!(&d_command, DataType::Array(Some(arr)) if !arr.is_empty() && propagates(arr));
+            if write && self.shared.replication().is_replica() && self.config.replica_read_only {
+                return error_resp("READONLY You can't write against a read only replica.");
             }
 
-            return if values.len() == 0 { DataType::Array(None) } else { DataType::Array(Some(values)) };
+            let recorded = write && (self.shared.replication().is_active() || self.shared.aof().as_ref().is_some_and(Aof::is_enabled));
+            let mut logged = match &d_command {
+                DataType::Array(Some(arr)) if write && recorded => arr.clone(),
+                _ => return self.execute(d_command),
+            };
+            let response = self.execute(d_command);
+            if response.starts_with(b"-") {
+                return response;
+            }
+            self.absolute_expiry(&mut logged);
+            self.propagate(&logged);
+            response
         }
 
-        fn push_list(&mut self, key: &String, val: &String, push_dir: &DataType) -> usize {
-            let is_lpush = if push_dir == &"lpush" { true } else { false };
+        /// Rewrite a relative expiry in `logged` to the absolute time it was set to, since it
+        /// would otherwise restart when the command is replayed.
+        fn absolute_expiry(&self, logged: &mut Vec<DataType>) {
+            let exp = logged.get(1).and_then(key_arg).and_then(|key| self.db.expire_at(&key));
+            let Some(exp) = exp else { return };
 
-            let adj_list = self.lists.entry(key.clone())
-                .and_modify(|list| {
-                    if is_lpush {
-                        list.push_front(val.clone());
-                    } else {
-                        list.push_back(val.clone());
-                    }
-                }).or_insert(LinkedList::from([val.clone()]));
+            if logged[0] == "set" && logged.len() == 5 && text_arg(&logged[3]).is_some_and(|option| option == "EX" || option == "PX") {
+                logged[3] = DataType::BulkString(Some(b"PXAT".to_vec()));
+                logged[4] = DataType::BulkString(Some(exp.to_string().into_bytes()));
+            }
+            if (logged[0] == "restore" || logged[0] == "restore-asking") && !logged[4..].iter().any(|option| text_arg(option).is_some_and(|option| option.eq_ignore_ascii_case("absttl"))) {
+                logged[2] = DataType::BulkString(Some(exp.to_string().into_bytes()));
+                logged.push(DataType::BulkString(Some(b"ABSTTL".to_vec())));
+            }
+        }
 
-            return adj_list.len();
+        /// Run a command, then update the sizes and access times of the keys it named.
+        fn execute(&mut self, d_command: DataType) -> Vec<u8> {
+            let (keys, accessed) = match &d_command {
+                DataType::Array(Some(arr)) => command_keys(arr),
+                _ => (Vec::new(), false),
+            };
+            // lists and sorted sets are read in place, so those past their TTL go first
+            for key in &keys {
+                if self.db.collection_expired(key) {
+                    self.remove_expired(key);
+                }
+            }
+            let response = self.dispatch(d_command);
+            for key in keys {
+                self.track(&key, accessed);
+            }
+            response
         }
 
-        pub fn handle_command(&mut self, d_command: DataType) -> String {
+        fn dispatch(&mut self, d_command: DataType) -> Vec<u8> {
             let err_resp = serialize(&DataType::Error("ERR command no recognized".to_owned())).unwrap();
-            let response: String = match d_command {
+            let response: Vec<u8> = match d_command {
                 DataType::Array(o_arr) => {
                     if let Some(arr) = o_arr {
-                        if arr.len() == 0 {
+                        if arr.is_empty() {
                             return err_resp;
                         }
                         if arr[0] == "set" {
                             if arr.len() == 3 {
-                                if let DataType::BulkString(Some(key)) = &arr[1] {
+                                if let Some(key) = key_arg(&arr[1]) {
                                     if let DataType::BulkString(Some(val)) = &arr[2] {
-                                        self.dict.insert(key.clone(), ExpireValue::no_expire(val.clone()));
-                                        return f!("{SUCCESS_MSG}");
+                                        self.signal_modified(&key);
+                                        self.set_string(key.clone(), ExpireValue::no_expire(val.clone()));
+                                        self.notify(notify::STRING, "set", &key);
+                                        return SUCCESS_MSG.to_vec();
                                     }
                                 }
                             }
 
                             if arr.len() == 5 {
-                                if let DataType::BulkString(Some(key)) = &arr[1] {
+                                if let Some(key) = key_arg(&arr[1]) {
                                     if let DataType::BulkString(Some(val)) = &arr[2] {
-                                        if let DataType::BulkString(Some(exp_com)) = &arr[3] {
-                                            if let DataType::BulkString(Some(exp_time)) = &arr[4] {
-                                                let exp_time: u128 = exp_time.parse().unwrap();
+                                        if let Some(exp_com) = text_arg(&arr[3]) {
+                                            if let Some(exp_time) = int_arg(&arr[4]) {
+                                                let exp_time: u128 = exp_time.max(0) as u128;
                                                 match exp_com.as_str() {
                                                     "EX" => {
-                                                        self.dict.insert(key.clone(), ExpireValue::expire_seconds(val.clone(), exp_time));
-                                                        return f!("{SUCCESS_MSG}");
+                                                        self.signal_modified(&key);
+                                                        self.set_string(key.clone(), ExpireValue::expire_seconds(val.clone(), exp_time));
+                                                        self.notify(notify::STRING, "set", &key);
+                                                        self.notify(notify::GENERIC, "expire", &key);
+                                                        return SUCCESS_MSG.to_vec();
                                                     },
                                                     "PX" => {
-                                                        self.dict.insert(key.clone(), ExpireValue::expire_millis(val.clone(), exp_time));
-                                                        return f!("{SUCCESS_MSG}");
+                                                        self.signal_modified(&key);
+                                                        self.set_string(key.clone(), ExpireValue::expire_millis(val.clone(), exp_time));
+                                                        self.notify(notify::STRING, "set", &key);
+                                                        self.notify(notify::GENERIC, "expire", &key);
+                                                        return SUCCESS_MSG.to_vec();
                                                     },
                                                     "EXAT" => {
-                                                        self.dict.insert(key.clone(), ExpireValue::specific_expire_seconds(val.clone(), exp_time));
-                                                        return f!("{SUCCESS_MSG}");
+                                                        self.signal_modified(&key);
+                                                        self.set_string(key.clone(), ExpireValue::specific_expire_seconds(val.clone(), exp_time));
+                                                        self.notify(notify::STRING, "set", &key);
+                                                        self.notify(notify::GENERIC, "expire", &key);
+                                                        return SUCCESS_MSG.to_vec();
                                                     },
                                                     "PXAT" => {
-                                                        self.dict.insert(key.clone(), ExpireValue::specific_expire_millis(val.clone(), exp_time));
-                                                        return f!("{SUCCESS_MSG}");
+                                                        self.signal_modified(&key);
+                                                        self.set_string(key.clone(), ExpireValue::specific_expire_millis(val.clone(), exp_time));
+                                                        self.notify(notify::STRING, "set", &key);
+                                                        self.notify(notify::GENERIC, "expire", &key);
+                                                        return SUCCESS_MSG.to_vec();
                                                     },
                                                     _ => (),
                                                 }
@@ -210,14 +2823,54 @@ pub mod dictionary {
                                 return err_resp;
                             }
 
-                            if let DataType::BulkString(Some(key)) = &arr[1] {
-                                    let o_val = self.get_value(key);
+                            if let Some(key) = key_arg(&arr[1]) {
+                                    let o_val = self.get_value(&key);
                                     match o_val {
                                         Some(val) => return serialize(&DataType::BulkString(Some(val))).unwrap(),
                                         _ => return serialize(&DataType::BulkString(None)).unwrap()
                                     }
                             }
                         }
+                        if arr[0] == "unlink" || arr[0] == "touch" {
+                            let mut count = 0;
+                            for key in arr[1..].iter().filter_map(key_arg) {
+                                if arr[0] == "touch" && self.key_type(&key).is_some() {
+                                    count += 1;
+                                }
+                                if arr[0] == "unlink" && self.unlink_key(&key) {
+                                    self.notify(notify::GENERIC, "del", &key);
+                                    count += 1;
+                                }
+                            }
+                            return serialize(&DataType::Integer(count)).unwrap();
+                        }
+                        if arr[0] == "type" {
+                            return self.type_command(&arr[1..]);
+                        }
+                        if arr[0] == "rename" || arr[0] == "renamenx" {
+                            return self.rename(&arr[1..], arr[0] == "renamenx");
+                        }
+                        if arr[0] == "copy" {
+                            return self.copy(&arr[1..]);
+                        }
+                        if arr[0] == "randomkey" {
+                            return self.random_key();
+                        }
+                        if arr[0] == "dbsize" {
+                            return serialize(&DataType::Integer(self.db.len() as i64)).unwrap();
+                        }
+                        if arr[0] == "flushdb" || arr[0] == "flushall" {
+                            return self.flush(&arr[1..], arr[0] == "flushall");
+                        }
+                        if arr[0] == "select" {
+                            return self.select_command(&arr[1..]);
+                        }
+                        if arr[0] == "swapdb" {
+                            return self.swapdb(&arr[1..]);
+                        }
+                        if arr[0] == "move" {
+                            return self.move_key(&arr[1..]);
+                        }
                         if arr[0] == "exists" || arr[0] == "del" {
                             if arr.len() == 1 {
                                 return err_resp;
@@ -225,17 +2878,15 @@ pub mod dictionary {
 
                             let mut count = 0;
                             for each_val in &arr[1..] {
-                                match each_val {
-                                    DataType::BulkString(Some(key)) => {
-                                        if arr[0] == "exists" {
-                                            if let Some(_) = self.get_value(key) { count += 1; }
-                                        }
+                                if let Some(key) = key_arg(each_val) {
+                                    if arr[0] == "exists" && self.key_type(&key).is_some() {
+                                        count += 1;
+                                    }
 
-                                        if arr[0] == "del" {
-                                            if let Some(_) = self.delete_value(key) { count += 1; }
-                                        }
-                                    },
-                                    _ => (),
+                                    if arr[0] == "del" && self.delete_key(&key) {
+                                        self.notify(notify::GENERIC, "del", &key);
+                                        count += 1;
+                                    }
                                 }
                             }
 
@@ -246,12 +2897,11 @@ pub mod dictionary {
                                 return err_resp;
                             }
 
-                            if let DataType::BulkString(Some(key)) = &arr[1] {
-                                    if let Some(i_val) = self.incr_decr_value(key, &arr[0]) {
-                                        return serialize(&DataType::Integer(i_val)).unwrap();
-                                    } else {
-                                        return serialize(&DataType::Error(f!("ERR value is not an integer or out of range"))).unwrap();
-                                    }
+                            if let Some(key) = key_arg(&arr[1]) {
+                                    return match self.incr_decr_value(&key, &arr[0]) {
+                                        Ok(i_val) => serialize(&DataType::Integer(i_val)).unwrap(),
+                                        Err(e) => e,
+                                    };
                             }
 
                             return err_resp;
@@ -261,14 +2911,18 @@ pub mod dictionary {
                                 return err_resp;
                             }
 
-                            if let DataType::BulkString(Some(key)) = &arr[1] {
+                            if let Some(key) = key_arg(&arr[1]) {
+                                if self.is_wrong_type(&key, "list") {
+                                    return wrong_type();
+                                }
                                 let mut size = 0;
                                 for each_val in &arr[2..] {
                                     match each_val {
-                                        DataType::BulkString(Some(val)) => { size = self.push_list(key, val, &arr[0]); },
+                                        DataType::BulkString(Some(val)) => { size = self.push_list(&key, val, &arr[0]); },
                                         _ => return err_resp,
                                     }
                                 }
+                                self.notify(notify::LIST, if arr[0] == "lpush" { "lpush" } else { "rpush" }, &key);
 
                                 return serialize(&DataType::Integer(size as i64)).unwrap();
                             }
@@ -280,13 +2934,158 @@ pub mod dictionary {
                                 return err_resp;
                             }
 
-                            if let DataType::BulkString(Some(key)) = &arr[1] {
-                                let list = self.get_list(key);
+                            if let Some(key) = key_arg(&arr[1]) {
+                                let list = self.get_list(&key);
                                 return serialize(&list).unwrap();
                             }
 
                             return err_resp;
                         }
+                        if arr[0] == "setbit" {
+                            return self.setbit(&arr[1..]);
+                        }
+                        if arr[0] == "getbit" {
+                            return self.getbit(&arr[1..]);
+                        }
+                        if arr[0] == "bitcount" {
+                            return self.bitcount(&arr[1..]);
+                        }
+                        if arr[0] == "bitpos" {
+                            return self.bitpos(&arr[1..]);
+                        }
+                        if arr[0] == "bitop" {
+                            return self.bitop(&arr[1..]);
+                        }
+                        if arr[0] == "bitfield" || arr[0] == "bitfield_ro" {
+                            return self.bitfield(&arr[1..], arr[0] == "bitfield_ro");
+                        }
+                        if arr[0] == "pfadd" {
+                            return self.pfadd(&arr[1..]);
+                        }
+                        if arr[0] == "pfcount" {
+                            return self.pfcount(&arr[1..]);
+                        }
+                        if arr[0] == "pfmerge" {
+                            return self.pfmerge(&arr[1..]);
+                        }
+                        if arr[0] == "zadd" {
+                            return self.zadd(&arr[1..]);
+                        }
+                        if arr[0] == "zrem" {
+                            return self.zrem(&arr[1..]);
+                        }
+                        if arr[0] == "zscan" {
+                            return self.zscan(&arr[1..]);
+                        }
+                        if arr[0] == "zscore" {
+                            return self.zscore(&arr[1..]);
+                        }
+                        if arr[0] == "zcard" {
+                            return self.zcard(&arr[1..]);
+                        }
+                        if arr[0] == "zrange" {
+                            return self.zrange(&arr[1..]);
+                        }
+                        if arr[0] == "geoadd" {
+                            return self.geoadd(&arr[1..]);
+                        }
+                        if arr[0] == "geopos" {
+                            return self.geopos(&arr[1..]);
+                        }
+                        if arr[0] == "geodist" {
+                            return self.geodist(&arr[1..]);
+                        }
+                        if arr[0] == "geohash" {
+                            return self.geohash(&arr[1..]);
+                        }
+                        if arr[0] == "geosearch" || arr[0] == "geosearchstore" {
+                            return self.geosearch(&arr[1..], arr[0] == "geosearchstore");
+                        }
+                        if arr[0] == "eval" || arr[0] == "eval_ro" {
+                            return self.eval(&arr[1..], false, arr[0] == "eval_ro");
+                        }
+                        if arr[0] == "evalsha" || arr[0] == "evalsha_ro" {
+                            return self.eval(&arr[1..], true, arr[0] == "evalsha_ro");
+                        }
+                        if arr[0] == "script" {
+                            return self.script(&arr[1..]);
+                        }
+                        if arr[0] == "fcall" || arr[0] == "fcall_ro" {
+                            return self.fcall(&arr[1..], arr[0] == "fcall_ro");
+                        }
+                        if arr[0] == "function" {
+                            return self.function(&arr[1..]);
+                        }
+                        if arr[0] == "ping" {
+                            return match arr.get(1) {
+                                Some(message) if arr.len() == 2 => serialize(message).unwrap(),
+                                None => b"+PONG\r\n".to_vec(),
+                                _ => wrong_args("ping"),
+                            };
+                        }
+                        if arr[0] == "publish" || arr[0] == "spublish" {
+                            let (DataType::BulkString(Some(channel)), DataType::BulkString(Some(message))) = (&arr[1], &arr[2]) else {
+                                return syntax_error();
+                            };
+                            let receivers = if arr[0] == "publish" {
+                                self.shared.pubsub().publish(channel, message)
+                            } else {
+                                self.shared.pubsub().spublish(channel, message)
+                            };
+                            return serialize(&DataType::Integer(receivers as i64)).unwrap();
+                        }
+                        if arr[0] == "pubsub" {
+                            return self.pubsub_command(&arr[1..]);
+                        }
+                        if arr[0] == "config" {
+                            return self.config_command(&arr[1..]);
+                        }
+                        if arr[0] == "cluster" {
+                            return self.cluster_command(&arr[1..]);
+                        }
+                        if arr[0] == "keys" {
+                            return self.keys(&arr[1..]);
+                        }
+                        if arr[0] == "scan" {
+                            return self.scan(&arr[1..]);
+                        }
+                        if arr[0] == "object" {
+                            return self.object(&arr[1..]);
+                        }
+                        if arr[0] == "memory" {
+                            return self.memory(&arr[1..]);
+                        }
+                        if arr[0] == "save" {
+                            return self.save();
+                        }
+                        if arr[0] == "bgsave" {
+                            return self.bgsave(&arr[1..]);
+                        }
+                        if arr[0] == "dump" {
+                            return self.dump(&arr[1..]);
+                        }
+                        if arr[0] == "restore" || arr[0] == "restore-asking" {
+                            return self.restore(&arr[1..]);
+                        }
+                        if arr[0] == "info" {
+                            return self.info(&arr[1..]);
+                        }
+                        if arr[0] == "wait" || arr[0] == "waitaof" {
+                            // inside a transaction there is no blocking, only the count so far
+                            return match self.wait(&arr[1..], arr[0] == "waitaof") {
+                                Ok(wait) => wait.progress(&self.shared).0,
+                                Err(err) => err,
+                            };
+                        }
+                        if arr[0] == "role" {
+                            return serialize(&self.shared.replication().role()).unwrap();
+                        }
+                        if arr[0] == "bgrewriteaof" {
+                            return self.bgrewriteaof();
+                        }
+                        if arr[0] == "lastsave" {
+                            return serialize(&DataType::Integer(self.shared.save_status().last_save() as i64)).unwrap();
+                        }
                     }
         
                     err_resp
@@ -294,12 +3093,617 @@ pub mod dictionary {
                 _ => err_resp,
             };
         
-            return response;
+            response
+        }
+    }
+
+    /// Add `bytes` to the replication stream, then to the AOF, which counts its writes in
+    /// replication offsets.
+    fn feed(replication: &mut Replication, aof: &mut Option<Aof>, fsync: Fsync, bytes: &[u8]) {
+        replication.feed(bytes);
+        if let Some(aof) = aof {
+            aof.feed(bytes, fsync, replication.offset());
+        }
+    }
+
+    /// Whether `arr` is a command that changes the dataset when it succeeds.
+    fn propagates(arr: &[DataType]) -> bool {
+        let Some(name) = text_arg(&arr[0]) else { return false };
+        if name == "function" {
+            return arr.get(1).and_then(text_arg)
+                .is_some_and(|sub| ["load", "delete", "flush", "restore"].contains(&sub.to_ascii_lowercase().as_str()));
+        }
+        // MIGRATE propagates the deletion of what it moved itself
+        if name == "migrate" {
+            return false;
+        }
+        command::lookup(&name).is_some_and(|spec| spec.flags & command::WRITE != 0)
+    }
+
+    #[derive(PartialEq, Eq)]
+    enum FieldCommand {
+        Get,
+        Set(i64),
+        IncrBy(i64),
+    }
+
+    /// ZADD/GEOADD update conditions.
+    #[derive(Clone, Copy, Default)]
+    struct AddFlags {
+        nx: bool,
+        xx: bool,
+        ch: bool,
+    }
+
+    impl AddFlags {
+        fn parse(&mut self, flag: &str) -> bool {
+            match flag.to_ascii_uppercase().as_str() {
+                "NX" => self.nx = true,
+                "XX" => self.xx = true,
+                "CH" => self.ch = true,
+                _ => return false,
+            }
+
+            true
+        }
+    }
+
+    enum GeoFrom {
+        Member(Vec<u8>),
+        LonLat(f64, f64),
+    }
+
+    enum GeoSort {
+        None,
+        Asc,
+        Desc,
+    }
+
+    /// Parsed GEOSEARCH/GEOSEARCHSTORE options; the shape is kept in meters.
+    struct GeoQuery {
+        from: GeoFrom,
+        shape: Shape,
+        factor: f64,
+        sort: GeoSort,
+        count: Option<usize>,
+        any: bool,
+        with_coord: bool,
+        with_dist: bool,
+        with_hash: bool,
+        store_dist: bool,
+    }
+
+    impl GeoQuery {
+        fn parse(args: &[DataType], store: bool, name: &str) -> std::result::Result<Self, Vec<u8>> {
+            let mut from = None;
+            let mut by = None;
+            let mut query = GeoQuery {
+                from: GeoFrom::LonLat(0.0, 0.0),
+                shape: Shape::Radius(0.0),
+                factor: 1.0,
+                sort: GeoSort::None,
+                count: None,
+                any: false,
+                with_coord: false,
+                with_dist: false,
+                with_hash: false,
+                store_dist: false,
+            };
+
+            let mut i = 0;
+            while i < args.len() {
+                let Some(opt) = text_arg(&args[i]) else { return Err(syntax_error()) };
+                let left = args.len() - i - 1;
+                match opt.to_ascii_uppercase().as_str() {
+                    "FROMMEMBER" if left >= 1 && from.is_none() => {
+                        let DataType::BulkString(Some(member)) = &args[i + 1] else { return Err(syntax_error()) };
+                        from = Some(GeoFrom::Member(member.clone()));
+                        i += 2;
+                    },
+                    "FROMLONLAT" if left >= 2 && from.is_none() => {
+                        let (Some(lon), Some(lat)) = (float_arg(&args[i + 1]), float_arg(&args[i + 2])) else {
+                            return Err(error_resp("ERR value is not a valid float"));
+                        };
+                        if !geo::valid_coords(lon, lat) {
+                            return Err(error_resp(&f!("ERR invalid longitude,latitude pair {lon:.6},{lat:.6}")));
+                        }
+                        from = Some(GeoFrom::LonLat(lon, lat));
+                        i += 3;
+                    },
+                    "FROMMEMBER" | "FROMLONLAT" if from.is_some() => {
+                        return Err(error_resp(&f!("ERR exactly one of FROMMEMBER or FROMLONLAT can be specified for '{name}' command")));
+                    },
+                    "BYRADIUS" if left >= 2 && by.is_none() => {
+                        let Some(radius) = float_arg(&args[i + 1]).filter(|val| *val >= 0.0) else {
+                            return Err(error_resp("ERR radius cannot be negative"));
+                        };
+                        let Some(factor) = text_arg(&args[i + 2]).and_then(|unit| geo::unit_factor(&unit)) else {
+                            return Err(bad_unit());
+                        };
+                        by = Some(Shape::Radius(radius * factor));
+                        query.factor = factor;
+                        i += 3;
+                    },
+                    "BYBOX" if left >= 3 && by.is_none() => {
+                        let (Some(width), Some(height)) = (float_arg(&args[i + 1]), float_arg(&args[i + 2])) else {
+                            return Err(error_resp("ERR value is not a valid float"));
+                        };
+                        if width < 0.0 || height < 0.0 {
+                            return Err(error_resp("ERR height or width cannot be negative"));
+                        }
+                        let Some(factor) = text_arg(&args[i + 3]).and_then(|unit| geo::unit_factor(&unit)) else {
+                            return Err(bad_unit());
+                        };
+                        by = Some(Shape::Box(width * factor, height * factor));
+                        query.factor = factor;
+                        i += 4;
+                    },
+                    "BYRADIUS" | "BYBOX" if by.is_some() => {
+                        return Err(error_resp(&f!("ERR exactly one of BYRADIUS and BYBOX arguments must be provided for '{name}' command")));
+                    },
+                    "ASC" => { query.sort = GeoSort::Asc; i += 1; },
+                    "DESC" => { query.sort = GeoSort::Desc; i += 1; },
+                    "COUNT" if left >= 1 => {
+                        let Some(count) = int_arg(&args[i + 1]) else { return Err(not_integer()) };
+                        if count <= 0 {
+                            return Err(error_resp("ERR COUNT must be > 0"));
+                        }
+                        query.count = Some(count as usize);
+                        i += 2;
+                        if args.get(i).and_then(text_arg).is_some_and(|val| val.eq_ignore_ascii_case("any")) {
+                            query.any = true;
+                            i += 1;
+                        }
+                    },
+                    "WITHCOORD" if !store => { query.with_coord = true; i += 1; },
+                    "WITHDIST" if !store => { query.with_dist = true; i += 1; },
+                    "WITHHASH" if !store => { query.with_hash = true; i += 1; },
+                    "STOREDIST" if store => { query.store_dist = true; i += 1; },
+                    _ => return Err(syntax_error()),
+                }
+            }
+
+            let Some(from) = from else {
+                return Err(error_resp(&f!("ERR exactly one of FROMMEMBER or FROMLONLAT can be specified for '{name}' command")));
+            };
+            let Some(shape) = by else {
+                return Err(error_resp(&f!("ERR exactly one of BYRADIUS and BYBOX arguments must be provided for '{name}' command")));
+            };
+            // a bounded result set is returned nearest first unless any match will do
+            if query.count.is_some() && !query.any && matches!(query.sort, GeoSort::None) {
+                query.sort = GeoSort::Asc;
+            }
+
+            query.from = from;
+            query.shape = shape;
+            Ok(query)
+        }
+    }
+
+    /// The keys named by a command, per the command table, and whether running it counts
+    /// as an access to them.
+    fn command_keys(arr: &[DataType]) -> (Vec<Vec<u8>>, bool) {
+        let Some(name) = arr.first().and_then(text_arg) else { return (Vec::new(), false) };
+        match command::lookup(&name.to_ascii_lowercase()) {
+            Some(command) if command.arity_ok(arr.len()) => {
+                let keys = command.key_args(arr).into_iter().map(<[u8]>::to_vec).collect();
+                (keys, command.flags & command::NOTOUCH == 0)
+            },
+            _ => (Vec::new(), false),
+        }
+    }
+
+    /// A HELP reply, one line per entry.
+    fn help(lines: &[&str]) -> Vec<u8> {
+        let lines = lines.iter().map(|line| DataType::SimpleString((*line).to_owned())).collect();
+        serialize(&DataType::Array(Some(lines))).unwrap()
+    }
+
+    /// A key, as the bytes the client sent: keys need not be UTF-8.
+    fn key_arg(arg: &DataType) -> Option<Vec<u8>> {
+        match arg {
+            DataType::BulkString(Some(val)) => Some(val.clone()),
+            _ => None,
+        }
+    }
+
+    fn text_arg(arg: &DataType) -> Option<String> {
+        match arg {
+            DataType::BulkString(Some(val)) => Some(String::from_utf8_lossy(val).into_owned()),
+            _ => None,
+        }
+    }
+
+    fn int_arg(arg: &DataType) -> Option<i64> {
+        text_arg(arg)?.parse().ok()
+    }
+
+    fn float_arg(arg: &DataType) -> Option<f64> {
+        text_arg(arg)?.parse().ok().filter(|val: &f64| !val.is_nan())
+    }
+
+    /// The options of the SCAN family.
+    struct ScanOptions {
+        pattern: Option<Vec<u8>>,
+        count: usize,
+        kind: Option<String>,
+    }
+
+    impl ScanOptions {
+        fn matches(&self, element: &[u8]) -> bool {
+            self.pattern.as_ref().is_none_or(|pattern| glob::matches(pattern, element))
+        }
+    }
+
+    /// cursor [MATCH pattern] [COUNT count], then [TYPE type] when `with_type`.
+    fn scan_args(args: &[DataType], with_type: bool) -> std::result::Result<(u64, ScanOptions), Vec<u8>> {
+        let cursor = text_arg(&args[0]).and_then(|cursor| cursor.parse().ok()).ok_or_else(|| error_resp("ERR invalid cursor"))?;
+        let mut options = ScanOptions { pattern: None, count: 10, kind: None };
+        let mut rest = &args[1..];
+        while let [option, value, tail @ ..] = rest {
+            let Some(option) = text_arg(option) else { return Err(syntax_error()) };
+            match option.to_ascii_lowercase().as_str() {
+                "match" => {
+                    let DataType::BulkString(Some(pattern)) = value else { return Err(syntax_error()) };
+                    options.pattern = Some(pattern.clone());
+                },
+                "count" => match int_arg(value) {
+                    Some(count) if count >= 1 => options.count = count as usize,
+                    Some(_) => return Err(syntax_error()),
+                    None => return Err(not_integer()),
+                },
+                "type" if with_type => options.kind = text_arg(value),
+                _ => return Err(syntax_error()),
+            }
+            rest = tail;
+        }
+        if !rest.is_empty() {
+            return Err(syntax_error());
+        }
+        Ok((cursor, options))
+    }
+
+    fn scan_reply(cursor: u64, elements: Vec<DataType>) -> Vec<u8> {
+        let cursor = DataType::BulkString(Some(cursor.to_string().into_bytes()));
+        serialize(&DataType::Array(Some(vec![cursor, DataType::Array(Some(elements))]))).unwrap()
+    }
+
+    fn format_score(score: f64) -> Vec<u8> {
+        f!("{score}").into_bytes()
+    }
+
+    /// Coordinates are printed like Redis' human readable long doubles.
+    fn format_coord(val: f64) -> Vec<u8> {
+        let formatted = f!("{val:.17}");
+        formatted.trim_end_matches('0').trim_end_matches('.').as_bytes().to_vec()
+    }
+
+    /// Parse a bit offset, checking that `width` bits starting there stay addressable.
+    fn bit_offset_arg(arg: &DataType, width: u32) -> Option<u64> {
+        let offset: u64 = text_arg(arg)?.parse().ok()?;
+        if offset + width as u64 - 1 > bitmap::MAX_BIT_OFFSET {
+            return None;
+        }
+
+        Some(offset)
+    }
+
+    /// BITFIELD offsets may be prefixed with `#` to count in units of the field width.
+    fn field_offset_arg(arg: &DataType, field: FieldType) -> Option<u64> {
+        let raw = text_arg(arg)?;
+        match raw.strip_prefix('#') {
+            Some(index) => {
+                let index: u64 = index.parse().ok()?;
+                let offset = index.checked_mul(field.bits as u64)?;
+                bit_offset_arg(&DataType::BulkString(Some(offset.to_string().into_bytes())), field.bits)
+            },
+            None => bit_offset_arg(arg, field.bits),
+        }
+    }
+
+    fn range_unit_arg(arg: &DataType) -> Option<RangeUnit> {
+        match text_arg(arg)?.to_ascii_uppercase().as_str() {
+            "BYTE" => Some(RangeUnit::Byte),
+            "BIT" => Some(RangeUnit::Bit),
+            _ => None,
+        }
+    }
+
+    /// The KEYS and ARGV of a script or function call.
+    type ScriptArgs = (Vec<Vec<u8>>, Vec<Vec<u8>>);
+
+    /// Split `numkeys key [key ...] arg [arg ...]` into the keys and the arguments.
+    fn script_args(args: &[DataType]) -> std::result::Result<ScriptArgs, Vec<u8>> {
+        let Some(numkeys) = int_arg(&args[0]) else { return Err(not_integer()) };
+        if numkeys < 0 {
+            return Err(error_resp("ERR Number of keys can't be negative"));
         }
+        if numkeys as usize > args.len() - 1 {
+            return Err(error_resp("ERR Number of keys can't be greater than number of args"));
+        }
+
+        let bytes = |arg: &DataType| match arg {
+            DataType::BulkString(Some(val)) => val.clone(),
+            _ => Vec::new(),
+        };
+        let split = 1 + numkeys as usize;
+        Ok((args[1..split].iter().map(bytes).collect(), args[split..].iter().map(bytes).collect()))
+    }
+
+    /// Bytes as INFO's `_human` fields show them, such as `1.50M`.
+    fn human_bytes(bytes: usize) -> String {
+        let units = [("G", 1 << 30), ("M", 1 << 20), ("K", 1 << 10)];
+        match units.iter().find(|(_, unit)| bytes >= *unit) {
+            Some((suffix, unit)) => f!("{:.2}{suffix}", bytes as f64 / *unit as f64),
+            None => f!("{bytes}B"),
+        }
+    }
+
+    fn error_resp(msg: &str) -> Vec<u8> {
+        serialize(&DataType::Error(msg.to_owned())).unwrap()
+    }
+
+    fn wrong_args(command: &str) -> Vec<u8> {
+        error_resp(&f!("ERR wrong number of arguments for '{command}' command"))
+    }
+
+    fn syntax_error() -> Vec<u8> {
+        error_resp("ERR syntax error")
+    }
+
+    fn not_integer() -> Vec<u8> {
+        error_resp("ERR value is not an integer or out of range")
+    }
+
+    fn invalid_hll() -> Vec<u8> {
+        error_resp("INVALIDOBJ Corrupted HLL object detected")
     }
 
+    fn bad_unit() -> Vec<u8> {
+        error_resp("ERR unsupported unit provided. please use M, KM, FT, MI")
+    }
 
-    
+    fn wrong_type() -> Vec<u8> {
+        error_resp("WRONGTYPE Operation against a key holding the wrong kind of value")
+    }
 }
 
 
+// region: --- tests
+#[cfg(test)]
+mod tests {
+    use crate::client::tests::{command, connect, server};
+    use utils::deserializer::deserialize;
+    use utils::prelude::*;
+    use utils::DataType;
+
+    #[test]
+    fn dump_restores_under_another_key() {
+        let redis = server();
+        let mut client = connect();
+
+        client.handle(command(&["rpush", "list", "a", "b"]), &redis);
+        let dumped = client.handle(command(&["dump", "list"]), &redis);
+        let Ok(DataType::BulkString(Some(payload))) = utils::deserializer::deserialize(&dumped) else { panic!("no payload") };
+        let restore = |key: &str, option: Option<&str>| {
+            let mut args = vec![DataType::BulkString(Some(b"restore".to_vec())), DataType::BulkString(Some(key.as_bytes().to_vec()))];
+            args.push(DataType::BulkString(Some(b"0".to_vec())));
+            args.push(DataType::BulkString(Some(payload.clone())));
+            args.extend(option.map(|option| DataType::BulkString(Some(option.as_bytes().to_vec()))));
+            DataType::Array(Some(args))
+        };
+
+        assert_eq!(client.handle(restore("copy", None), &redis), b"+OK\r\n");
+        assert_eq!(client.handle(command(&["lrange", "copy"]), &redis), client.handle(command(&["lrange", "list"]), &redis));
+        assert!(client.handle(restore("copy", None), &redis).starts_with(b"-BUSYKEY"));
+        assert_eq!(client.handle(restore("copy", Some("REPLACE")), &redis), b"+OK\r\n");
+        assert_eq!(client.handle(command(&["dump", "missing"]), &redis), b"$-1\r\n");
+        assert_eq!(client.handle(restore("other", Some("BOGUS")), &redis), b"-ERR syntax error\r\n");
+        let resp = client.handle(command(&["restore", "other", "0", "garbage"]), &redis);
+        assert_eq!(resp, b"-ERR DUMP payload version or checksum are wrong\r\n");
+        let resp = client.handle(command(&["restore", "other", "-1", "garbage"]), &redis);
+        assert_eq!(resp, b"-ERR Invalid TTL value, must be >= 0\r\n");
+    }
+
+    #[test]
+    fn restore_sets_access_statistics() {
+        let redis = server();
+        let mut client = connect();
+
+        client.handle(command(&["set", "a", "1"]), &redis);
+        let dumped = client.handle(command(&["dump", "a"]), &redis);
+        let Ok(DataType::BulkString(Some(payload))) = utils::deserializer::deserialize(&dumped) else { panic!("no payload") };
+        let restore = |key: &str, option: &str, value: &str| {
+            let args = [b"restore".as_slice(), key.as_bytes(), b"0", &payload, option.as_bytes(), value.as_bytes()];
+            DataType::Array(Some(args.iter().map(|arg| DataType::BulkString(Some(arg.to_vec()))).collect()))
+        };
+
+        assert_eq!(client.handle(restore("idle", "IDLETIME", "1000"), &redis), b"+OK\r\n");
+        assert_eq!(client.handle(command(&["object", "idletime", "idle"]), &redis), b":1000\r\n");
+        client.handle(command(&["config", "set", "maxmemory-policy", "allkeys-lfu"]), &redis);
+        assert_eq!(client.handle(restore("freq", "FREQ", "42"), &redis), b"+OK\r\n");
+        assert_eq!(client.handle(command(&["object", "freq", "freq"]), &redis), b":42\r\n");
+        assert!(client.handle(restore("bad", "FREQ", "256"), &redis).starts_with(b"-ERR Invalid FREQ value"));
+    }
+
+    #[test]
+    fn restore_keeps_ttls_on_every_type() {
+        let redis = server();
+        let mut client = connect();
+        let mut restore_with_ttl = |from: &str, to: &str| {
+            let dumped = client.handle(command(&["dump", from]), &redis);
+            let Ok(DataType::BulkString(Some(payload))) = deserialize(&dumped) else { panic!("no payload") };
+            let args = [b"restore".as_slice(), to.as_bytes(), b"40", &payload];
+            let restore = DataType::Array(Some(args.iter().map(|arg| DataType::BulkString(Some(arg.to_vec()))).collect()));
+            client.handle(restore, &redis)
+        };
+
+        redis.lock_all().handle_command(command(&["rpush", "list", "a", "b"]));
+        redis.lock_all().handle_command(command(&["zadd", "zset", "1", "m"]));
+        assert_eq!(restore_with_ttl("list", "l"), b"+OK\r\n");
+        assert_eq!(restore_with_ttl("zset", "z"), b"+OK\r\n");
+        assert_eq!(client.handle(command(&["lrange", "l"]), &redis), b"*2\r\n$1\r\na\r\n$1\r\nb\r\n");
+        assert_eq!(client.handle(command(&["rename", "z", "z2"]), &redis), b"+OK\r\n");
+        assert_eq!(client.handle(command(&["dbsize"]), &redis), b":4\r\n");
+
+        // once the TTL passes they are gone, and a new key of the same name has none
+        std::thread::sleep(std::time::Duration::from_millis(80));
+        assert_eq!(client.handle(command(&["exists", "l", "z2"]), &redis), b":0\r\n");
+        assert_eq!(client.handle(command(&["dbsize"]), &redis), b":2\r\n");
+        assert_eq!(client.handle(command(&["rpush", "l", "c"]), &redis), b":1\r\n");
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        assert_eq!(client.handle(command(&["lrange", "l"]), &redis), b"*1\r\n$1\r\nc\r\n");
+    }
+
+    #[test]
+    fn generic_key_commands() {
+        let redis = server();
+        let mut client = connect();
+        let mut run = |args: &[&str]| client.handle(command(args), &redis);
+
+        run(&["set", "s", "1"]);
+        run(&["rpush", "l", "a"]);
+        run(&["zadd", "z", "1", "m"]);
+        assert_eq!(run(&["type", "s"]), b"+string\r\n");
+        assert_eq!(run(&["type", "l"]), b"+list\r\n");
+        assert_eq!(run(&["type", "z"]), b"+zset\r\n");
+        assert_eq!(run(&["type", "nope"]), b"+none\r\n");
+        assert_eq!(run(&["dbsize"]), b":3\r\n");
+        assert_eq!(run(&["touch", "s", "l", "nope"]), b":2\r\n");
+
+        assert_eq!(run(&["rename", "l", "l2"]), b"+OK\r\n");
+        assert_eq!(run(&["type", "l"]), b"+none\r\n");
+        assert_eq!(run(&["lrange", "l2"]), b"*1\r\n$1\r\na\r\n");
+        assert_eq!(run(&["rename", "nope", "x"]), b"-ERR no such key\r\n");
+        assert_eq!(run(&["renamenx", "s", "z"]), b":0\r\n");
+        assert_eq!(run(&["renamenx", "s", "s2"]), b":1\r\n");
+        // RENAME replaces whatever the destination held
+        assert_eq!(run(&["rename", "s2", "z"]), b"+OK\r\n");
+        assert_eq!(run(&["get", "z"]), b"$1\r\n1\r\n");
+
+        assert_eq!(run(&["copy", "z", "l2"]), b":0\r\n");
+        assert_eq!(run(&["copy", "z", "l2", "replace"]), b":1\r\n");
+        assert_eq!(run(&["get", "l2"]), b"$1\r\n1\r\n");
+        assert_eq!(run(&["copy", "z", "z"]), b"-ERR source and destination objects are the same\r\n");
+
+        // TTLs move with the value
+        run(&["set", "t", "v", "PX", "50"]);
+        assert_eq!(run(&["rename", "t", "t2"]), b"+OK\r\n");
+        assert_eq!(run(&["copy", "t2", "t3"]), b":1\r\n");
+        std::thread::sleep(std::time::Duration::from_millis(80));
+        assert_eq!(run(&["exists", "t2", "t3"]), b":0\r\n");
+
+        let key = run(&["randomkey"]);
+        assert!(key == b"$1\r\nz\r\n" || key == b"$2\r\nl2\r\n");
+        for i in 0..200 {
+            run(&["rpush", "big", &i.to_string()]);
+        }
+        assert_eq!(run(&["unlink", "big", "z", "nope"]), b":2\r\n");
+        assert_eq!(run(&["dbsize"]), b":1\r\n");
+        assert_eq!(run(&["flushall", "async"]), b"+OK\r\n");
+        assert_eq!(run(&["dbsize"]), b":0\r\n");
+        assert_eq!(run(&["randomkey"]), b"$-1\r\n");
+        assert_eq!(run(&["flushdb", "later"]), b"-ERR syntax error\r\n");
+    }
+
+    #[test]
+    fn databases_are_independent_keyspaces() {
+        let redis = server();
+        let mut first = connect();
+        let mut second = connect();
+
+        first.handle(command(&["set", "k", "zero"]), &redis);
+        assert_eq!(second.handle(command(&["select", "1"]), &redis), b"+OK\r\n");
+        assert_eq!(second.handle(command(&["get", "k"]), &redis), b"$-1\r\n");
+        second.handle(command(&["set", "k", "one"]), &redis);
+        assert_eq!(first.handle(command(&["get", "k"]), &redis), b"$4\r\nzero\r\n");
+        assert_eq!(second.handle(command(&["select", "16"]), &redis), b"-ERR DB index is out of range\r\n");
+        assert_eq!(second.handle(command(&["select", "one"]), &redis), b"-ERR value is not an integer or out of range\r\n");
+
+        // SELECT inside a transaction applies to the commands after it and sticks
+        first.handle(command(&["multi"]), &redis);
+        first.handle(command(&["select", "1"]), &redis);
+        first.handle(command(&["get", "k"]), &redis);
+        assert_eq!(first.handle(command(&["exec"]), &redis), b"*2\r\n+OK\r\n$3\r\none\r\n");
+        assert_eq!(first.handle(command(&["get", "k"]), &redis), b"$3\r\none\r\n");
+        first.handle(command(&["select", "0"]), &redis);
+
+        first.handle(command(&["set", "t", "v", "EX", "100"]), &redis);
+        assert_eq!(first.handle(command(&["move", "t", "1"]), &redis), b":1\r\n");
+        assert_eq!(first.handle(command(&["move", "k", "1"]), &redis), b":0\r\n");
+        assert_eq!(first.handle(command(&["move", "k", "0"]), &redis), b"-ERR source and destination objects are the same\r\n");
+        assert_eq!(first.handle(command(&["exists", "t"]), &redis), b":0\r\n");
+        assert_eq!(second.handle(command(&["exists", "t"]), &redis), b":1\r\n");
+        assert_eq!(first.handle(command(&["copy", "k", "k2", "db", "2"]), &redis), b":1\r\n");
+        assert_eq!(first.handle(command(&["copy", "k", "k", "db", "0"]), &redis), b"-ERR source and destination objects are the same\r\n");
+
+        let DataType::BulkString(Some(info)) = deserialize(&first.handle(command(&["info", "keyspace"]), &redis)).unwrap() else { panic!() };
+        let info = String::from_utf8(info).unwrap();
+        assert!(info.contains("db0:keys=1,expires=0,avg_ttl=0\r\n"));
+        // the moved key kept its TTL
+        assert!(info.contains("db1:keys=2,expires=1,"));
+        assert!(info.contains("db2:keys=1,expires=0,"));
+        assert!(!info.contains("db3:"));
+
+        // a swap invalidates watches on both databases and is seen by every connection
+        first.handle(command(&["watch", "k"]), &redis);
+        assert_eq!(second.handle(command(&["swapdb", "0", "1"]), &redis), b"+OK\r\n");
+        assert_eq!(first.handle(command(&["get", "k"]), &redis), b"$3\r\none\r\n");
+        assert_eq!(second.handle(command(&["get", "k"]), &redis), b"$4\r\nzero\r\n");
+        first.handle(command(&["multi"]), &redis);
+        first.handle(command(&["set", "k", "x"]), &redis);
+        assert_eq!(first.handle(command(&["exec"]), &redis), b"*-1\r\n");
+
+        assert_eq!(second.handle(command(&["flushdb"]), &redis), b"+OK\r\n");
+        assert_eq!(second.handle(command(&["dbsize"]), &redis), b":0\r\n");
+        assert_eq!(first.handle(command(&["dbsize"]), &redis), b":2\r\n");
+        first.handle(command(&["flushall"]), &redis);
+        first.handle(command(&["select", "2"]), &redis);
+        assert_eq!(first.handle(command(&["dbsize"]), &redis), b":0\r\n");
+    }
+
+    #[test]
+    fn string_writes_respect_other_types() {
+        let redis = server();
+        let mut client = connect();
+
+        client.handle(command(&["lpush", "list", "a"]), &redis);
+        client.handle(command(&["zadd", "zset", "1", "a"]), &redis);
+        assert!(client.handle(command(&["incr", "list"]), &redis).starts_with(b"-WRONGTYPE"));
+        assert!(client.handle(command(&["rpush", "zset", "a"]), &redis).starts_with(b"-WRONGTYPE"));
+
+        client.handle(command(&["set", "a", "x"]), &redis);
+        assert_eq!(client.handle(command(&["bitop", "not", "list", "a"]), &redis), b":1\r\n");
+        assert_eq!(client.handle(command(&["type", "list"]), &redis), b"+string\r\n");
+        assert_eq!(client.handle(command(&["bitop", "and", "zset", "missing"]), &redis), b":0\r\n");
+        assert_eq!(client.handle(command(&["exists", "zset"]), &redis), b":0\r\n");
+    }
+
+    #[test]
+    fn incr_refuses_to_overflow() {
+        let redis = server();
+        let mut client = connect();
+
+        client.handle(command(&["set", "max", &i64::MAX.to_string()]), &redis);
+        assert_eq!(client.handle(command(&["incr", "max"]), &redis), b"-ERR increment or decrement would overflow\r\n");
+        client.handle(command(&["set", "min", &i64::MIN.to_string()]), &redis);
+        assert_eq!(client.handle(command(&["decr", "min"]), &redis), b"-ERR increment or decrement would overflow\r\n");
+        assert_eq!(client.handle(command(&["get", "max"]), &redis), f!("$19\r\n{}\r\n", i64::MAX).into_bytes());
+    }
+
+    #[test]
+    fn set_replaces_any_type() {
+        let redis = server();
+        let mut client = connect();
+
+        client.handle(command(&["lpush", "k", "a"]), &redis);
+        assert_eq!(client.handle(command(&["set", "k", "v"]), &redis), b"+OK\r\n");
+        assert_eq!(client.handle(command(&["dbsize"]), &redis), b":1\r\n");
+        assert_eq!(client.handle(command(&["type", "k"]), &redis), b"+string\r\n");
+        client.handle(command(&["zadd", "z", "1", "a"]), &redis);
+        assert_eq!(client.handle(command(&["set", "z", "v", "EX", "100"]), &redis), b"+OK\r\n");
+        assert_eq!(client.handle(command(&["type", "z"]), &redis), b"+string\r\n");
+        assert_eq!(client.handle(command(&["dbsize"]), &redis), b":2\r\n");
+    }
+}
+// endregion: --- tests
@@ -0,0 +1,593 @@
+//! Memory accounting and eviction under `maxmemory`. Every key has an estimated size and the
+//! access metadata the policies need: an LRU clock of the last access, and a logarithmic LFU
+//! counter that decays over time. As in Redis, LRU, LFU and TTL order are approximated by
+//! sampling a few random keys into a pool of the best candidates seen, instead of keeping the
+//! keyspace sorted.
+
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeSet, HashMap};
+use std::hash::{BuildHasher, Hasher};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::scan;
+use crate::shards::{shard_of, Sharded};
+
+/// `maxmemory-policy`: which keys to evict once the dataset outgrows `maxmemory`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Policy {
+    NoEviction,
+    AllKeysLru,
+    AllKeysLfu,
+    AllKeysRandom,
+    VolatileLru,
+    VolatileLfu,
+    VolatileRandom,
+    VolatileTtl,
+}
+
+const POLICIES: &[(&str, Policy)] = &[
+    ("noeviction", Policy::NoEviction),
+    ("allkeys-lru", Policy::AllKeysLru),
+    ("allkeys-lfu", Policy::AllKeysLfu),
+    ("allkeys-random", Policy::AllKeysRandom),
+    ("volatile-lru", Policy::VolatileLru),
+    ("volatile-lfu", Policy::VolatileLfu),
+    ("volatile-random", Policy::VolatileRandom),
+    ("volatile-ttl", Policy::VolatileTtl),
+];
+
+impl Policy {
+    pub fn parse(name: &str) -> Option<Self> {
+        POLICIES.iter().find(|(each, _)| each.eq_ignore_ascii_case(name)).map(|(_, policy)| *policy)
+    }
+
+    pub fn name(self) -> &'static str {
+        POLICIES.iter().find(|(_, policy)| *policy == self).unwrap().0
+    }
+
+    /// Only keys with a TTL are evicted.
+    fn volatile(self) -> bool {
+        matches!(self, Policy::VolatileLru | Policy::VolatileLfu | Policy::VolatileRandom | Policy::VolatileTtl)
+    }
+
+    pub fn is_lfu(self) -> bool {
+        matches!(self, Policy::AllKeysLfu | Policy::VolatileLfu)
+    }
+}
+
+/// Candidates kept between evictions.
+const POOL_SIZE: usize = 16;
+/// The LRU clock counts seconds and wraps at 24 bits, as in Redis.
+const LRU_CLOCK_MAX: u64 = (1 << 24) - 1;
+/// The LFU counter of a new key, so it is not evicted before it had a chance to be used.
+pub const LFU_INIT: u8 = 5;
+
+/// `lfu-log-factor` and `lfu-decay-time`: how many accesses it takes to grow an LFU counter,
+/// and the minutes it takes to decay by one.
+#[derive(Clone, Copy)]
+pub struct Lfu {
+    pub log_factor: u32,
+    pub decay_time: u32,
+}
+
+pub struct Meta {
+    pub size: usize,
+    pub expire_at: Option<u128>,
+    /// LRU clock of the last access.
+    lru: u64,
+    counter: u8,
+    /// Minute clock the counter was last decayed at.
+    decayed_at: u16,
+    /// Positions in `Keyspace::keys` and `Keyspace::volatile`.
+    at: usize,
+    volatile_at: Option<usize>,
+}
+
+impl Meta {
+    /// Seconds since the last access.
+    pub fn idle(&self) -> u64 {
+        let now = lru_clock();
+        if now >= self.lru {
+            now - self.lru
+        } else {
+            LRU_CLOCK_MAX - self.lru + now
+        }
+    }
+
+    /// The LFU counter, decayed for the time since the last access.
+    pub fn frequency(&self, lfu: Lfu) -> u8 {
+        if lfu.decay_time == 0 {
+            return self.counter;
+        }
+        let periods = minute_clock().wrapping_sub(self.decayed_at) as u32 / lfu.decay_time;
+        self.counter.saturating_sub(periods.min(u8::MAX as u32) as u8)
+    }
+}
+
+/// Size and access metadata of every key, indexed for sampling.
+pub struct Keyspace {
+    meta: HashMap<Vec<u8>, Meta>,
+    /// Every key, then the keys with a TTL, in no order: for picking keys at random.
+    keys: Vec<Vec<u8>>,
+    volatile: Vec<Vec<u8>>,
+    /// Every key by its SCAN hash, so a SCAN page is found without going over the rest.
+    order: BTreeSet<(u64, Vec<u8>)>,
+    used: usize,
+    /// Eviction candidates by score, best last, and the policy that scored them.
+    pool: Vec<(u64, Vec<u8>)>,
+    pool_policy: Option<Policy>,
+    random: u64,
+}
+
+impl Default for Keyspace {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Keyspace {
+    pub fn new() -> Self {
+        Self {
+            meta: HashMap::new(),
+            keys: Vec::new(),
+            volatile: Vec::new(),
+            order: BTreeSet::new(),
+            used: 0,
+            pool: Vec::new(),
+            pool_policy: None,
+            random: RandomState::new().build_hasher().finish() | 1,
+        }
+    }
+
+    /// Estimated bytes held by the dataset.
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// Bytes spent on this bookkeeping itself.
+    pub fn overhead(&self) -> usize {
+        self.meta.capacity() * (size_of::<(Vec<u8>, Meta)>() + 1)
+            + (self.keys.capacity() + self.volatile.capacity()) * size_of::<Vec<u8>>()
+            + self.pool.capacity() * size_of::<(u64, Vec<u8>)>()
+            + self.order.len() * size_of::<(u64, Vec<u8>)>()
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&Meta> {
+        self.meta.get(key)
+    }
+
+    /// Record the current `size` and expiry of `key`, and an access to it when `accessed`.
+    pub fn update(&mut self, key: &[u8], size: usize, expire_at: Option<u128>, accessed: bool, lfu: Lfu) {
+        if !self.meta.contains_key(key) {
+            self.keys.push(key.to_vec());
+            self.order.insert((scan::hash(key), key.to_vec()));
+            let meta = Meta {
+                size: 0,
+                expire_at: None,
+                lru: lru_clock(),
+                counter: LFU_INIT,
+                decayed_at: minute_clock(),
+                at: self.keys.len() - 1,
+                volatile_at: None,
+            };
+            self.meta.insert(key.to_vec(), meta);
+        }
+        let meta = self.meta.get_mut(key).unwrap();
+        self.used = self.used - meta.size + size;
+        meta.size = size;
+        meta.expire_at = expire_at;
+        if accessed {
+            let chance = next_random(&mut self.random) as f64 / u64::MAX as f64;
+            meta.counter = log_increment(meta.frequency(lfu), lfu.log_factor, chance);
+            meta.decayed_at = minute_clock();
+            meta.lru = lru_clock();
+        }
+
+        match (expire_at.is_some(), meta.volatile_at) {
+            (true, None) => {
+                meta.volatile_at = Some(self.volatile.len());
+                self.volatile.push(key.to_vec());
+            },
+            (false, Some(at)) => {
+                meta.volatile_at = None;
+                self.volatile.swap_remove(at);
+                if let Some(moved) = self.volatile.get(at) {
+                    self.meta.get_mut(moved).unwrap().volatile_at = Some(at);
+                }
+            },
+            _ => (),
+        }
+    }
+
+    /// Make `key` look last accessed `idle` seconds ago, or give it the LFU counter `freq`, as
+    /// RESTORE does with the statistics of a migrated key.
+    pub fn set_access(&mut self, key: &[u8], idle: Option<u64>, freq: Option<u8>) {
+        let Some(meta) = self.meta.get_mut(key) else {
+            return;
+        };
+        if let Some(idle) = idle {
+            let (now, idle) = (lru_clock(), idle.min(LRU_CLOCK_MAX));
+            meta.lru = if idle <= now { now - idle } else { LRU_CLOCK_MAX - (idle - now) };
+        }
+        if let Some(freq) = freq {
+            meta.counter = freq;
+            meta.decayed_at = minute_clock();
+        }
+    }
+
+    pub fn remove(&mut self, key: &[u8]) {
+        let Some(meta) = self.meta.remove(key) else {
+            return;
+        };
+        self.used -= meta.size;
+        self.order.remove(&(scan::hash(key), key.to_vec()));
+        self.keys.swap_remove(meta.at);
+        if let Some(moved) = self.keys.get(meta.at) {
+            self.meta.get_mut(moved).unwrap().at = meta.at;
+        }
+        if let Some(at) = meta.volatile_at {
+            self.volatile.swap_remove(at);
+            if let Some(moved) = self.volatile.get(at) {
+                self.meta.get_mut(moved).unwrap().volatile_at = Some(at);
+            }
+        }
+    }
+
+    /// The keys a SCAN page of `count` at `cursor` may take, with their hash, see
+    /// `scan::candidates`.
+    pub fn scan_candidates(&self, cursor: u64, count: usize) -> Vec<&(u64, Vec<u8>)> {
+        scan::candidates(&self.order, cursor, count)
+    }
+
+    /// Any key, picked at random.
+    pub fn random_key(&mut self) -> Option<Vec<u8>> {
+        if self.keys.is_empty() {
+            return None;
+        }
+        let at = next_random(&mut self.random) as usize % self.keys.len();
+        Some(self.keys[at].clone())
+    }
+
+    /// The key to evict next under `policy`, looking at `samples` random keys, or `None` when
+    /// there is nothing the policy may evict.
+    pub fn victim(&mut self, policy: Policy, samples: usize, lfu: Lfu) -> Option<Vec<u8>> {
+        let (_, key) = self.candidate(policy, samples, lfu)?;
+        if self.pool.last().is_some_and(|(_, pooled)| *pooled == key) {
+            self.pool.pop();
+        }
+        Some(key)
+    }
+
+    /// The key `victim` would pick with its score, the higher the better, left in the pool.
+    fn candidate(&mut self, policy: Policy, samples: usize, lfu: Lfu) -> Option<(u64, Vec<u8>)> {
+        let candidates = if policy.volatile() { &self.volatile } else { &self.keys };
+        if candidates.is_empty() {
+            return None;
+        }
+        match policy {
+            Policy::NoEviction => None,
+            Policy::AllKeysRandom | Policy::VolatileRandom => {
+                let at = next_random(&mut self.random) as usize % candidates.len();
+                Some((next_random(&mut self.random), candidates[at].clone()))
+            },
+            _ => {
+                if self.pool_policy != Some(policy) {
+                    self.pool.clear();
+                    self.pool_policy = Some(policy);
+                }
+                self.populate(policy, samples, lfu);
+                // the pool may hold keys deleted, or no longer volatile, since they were sampled
+                while let Some((score, key)) = self.pool.last() {
+                    if self.meta.get(key).is_some_and(|meta| !policy.volatile() || meta.volatile_at.is_some()) {
+                        return Some((*score, key.clone()));
+                    }
+                    self.pool.pop();
+                }
+                None
+            },
+        }
+    }
+
+    /// Add `samples` random keys to the pool, scored so that the best candidate is the
+    /// highest: the longest idle, the least frequently used, or the closest to expiring.
+    fn populate(&mut self, policy: Policy, samples: usize, lfu: Lfu) {
+        for _ in 0..samples.max(1) {
+            let candidates = if policy.volatile() { &self.volatile } else { &self.keys };
+            let key = &candidates[next_random(&mut self.random) as usize % candidates.len()];
+            if self.pool.iter().any(|(_, pooled)| pooled == key) {
+                continue;
+            }
+            let meta = &self.meta[key];
+            let score = match policy {
+                Policy::AllKeysLfu | Policy::VolatileLfu => (u8::MAX - meta.frequency(lfu)) as u64,
+                Policy::VolatileTtl => u64::MAX - meta.expire_at.unwrap_or(0).min(u64::MAX as u128) as u64,
+                _ => meta.idle(),
+            };
+
+            let mut at = self.pool.partition_point(|(pooled, _)| *pooled <= score);
+            if self.pool.len() == POOL_SIZE {
+                if at == 0 {
+                    continue;
+                }
+                self.pool.remove(0);
+                at -= 1;
+            }
+            let key = key.clone();
+            self.pool.insert(at, (score, key));
+        }
+    }
+}
+
+/// The keyspace of a database split by shard. Each part samples its own keys, and the part
+/// with the best candidate gives the victim.
+impl Sharded<Keyspace> {
+    pub fn used(&self) -> usize {
+        self.parts().map(Keyspace::used).sum()
+    }
+
+    pub fn len(&self) -> usize {
+        self.parts().map(Keyspace::len).sum()
+    }
+
+    pub fn overhead(&self) -> usize {
+        self.parts().map(Keyspace::overhead).sum()
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&Meta> {
+        self.part(shard_of(key)).get(key)
+    }
+
+    pub fn update(&mut self, key: &[u8], size: usize, expire_at: Option<u128>, accessed: bool, lfu: Lfu) {
+        self.part_mut(shard_of(key)).update(key, size, expire_at, accessed, lfu);
+    }
+
+    pub fn set_access(&mut self, key: &[u8], idle: Option<u64>, freq: Option<u8>) {
+        self.part_mut(shard_of(key)).set_access(key, idle, freq);
+    }
+
+    pub fn remove(&mut self, key: &[u8]) {
+        self.part_mut(shard_of(key)).remove(key);
+    }
+
+    /// The next `count` keys at or after `cursor` in hash order, with the cursor following
+    /// them, from the first few of each part.
+    pub fn scan(&self, cursor: u64, count: usize) -> (u64, Vec<Vec<u8>>) {
+        let candidates = self.parts().flat_map(|part| part.scan_candidates(cursor, count));
+        scan::page_hashed(candidates.map(|(hash, key)| (*hash, key.clone())), cursor, count)
+    }
+
+    /// Any key, picked at random: from a part chosen by its share of the keys.
+    pub fn random_key(&mut self) -> Option<Vec<u8>> {
+        let len = self.len();
+        if len == 0 {
+            return None;
+        }
+        let mut at = next_random(&mut self.part_mut(0).random) as usize % len;
+        for part in self.parts_mut() {
+            if at < part.len() {
+                return part.random_key();
+            }
+            at -= part.len();
+        }
+        None
+    }
+
+    pub fn victim(&mut self, policy: Policy, samples: usize, lfu: Lfu) -> Option<Vec<u8>> {
+        let (_, shard) = self.parts_mut().enumerate()
+            .filter_map(|(shard, part)| Some((part.candidate(policy, samples, lfu)?.0, shard)))
+            .max()?;
+        self.part_mut(shard).victim(policy, samples, lfu)
+    }
+}
+
+/// Estimate the bytes of a collection of `len` elements from the sizes of its first
+/// `samples` elements, or of all of them when `samples` is 0.
+pub fn sampled(sizes: impl Iterator<Item = usize>, len: usize, samples: usize) -> usize {
+    let samples = if samples == 0 { usize::MAX } else { samples };
+    let (total, seen) = sizes.take(samples).fold((0, 0), |(total, seen), size| (total + size, seen + 1));
+    (total * len).checked_div(seen).unwrap_or(0)
+}
+
+/// Grow `counter` with a chance that falls as it grows, so it takes about `log_factor` times
+/// as many accesses for each step as it took for the previous one.
+fn log_increment(counter: u8, log_factor: u32, chance: f64) -> u8 {
+    if counter == u8::MAX {
+        return counter;
+    }
+    let base = counter.saturating_sub(LFU_INIT) as f64;
+    if chance < 1.0 / (base * log_factor as f64 + 1.0) {
+        counter + 1
+    } else {
+        counter
+    }
+}
+
+fn unix_seconds() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+fn lru_clock() -> u64 {
+    unix_seconds() & LRU_CLOCK_MAX
+}
+
+fn minute_clock() -> u16 {
+    (unix_seconds() / 60) as u16
+}
+
+/// xorshift64*, plenty for sampling keys.
+fn next_random(state: &mut u64) -> u64 {
+    *state ^= *state >> 12;
+    *state ^= *state << 25;
+    *state ^= *state >> 27;
+    state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+}
+
+
+// region: --- tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::tests::{command, connect, server};
+    use crate::client::Client;
+
+    const LFU: Lfu = Lfu { log_factor: 10, decay_time: 1 };
+
+    fn keyspace(keys: &[&str]) -> Keyspace {
+        let mut keyspace = Keyspace::new();
+        for key in keys {
+            keyspace.update(key.as_bytes(), 10, None, false, LFU);
+        }
+        keyspace
+    }
+
+    #[test]
+    fn sizes_and_indexes_follow_updates() {
+        let mut keyspace = keyspace(&["a", "b", "c"]);
+        assert_eq!(keyspace.used(), 30);
+        keyspace.update(b"b", 25, Some(1), false, LFU);
+        keyspace.update(b"c", 5, Some(2), false, LFU);
+        assert_eq!(keyspace.used(), 40);
+        assert_eq!(keyspace.volatile, vec![b"b".to_vec(), b"c".to_vec()]);
+
+        keyspace.remove(b"a");
+        keyspace.update(b"b", 25, None, false, LFU);
+        assert_eq!(keyspace.used(), 30);
+        for (at, key) in keyspace.keys.iter().enumerate() {
+            assert_eq!(keyspace.meta[key].at, at);
+        }
+        assert_eq!(keyspace.volatile, vec![b"c".to_vec()]);
+        assert_eq!(keyspace.meta[b"c".as_slice()].volatile_at, Some(0));
+
+        keyspace.remove(b"c");
+        assert!(keyspace.volatile.is_empty());
+        assert_eq!(keyspace.victim(Policy::VolatileRandom, 5, LFU), None);
+        assert_eq!(keyspace.victim(Policy::AllKeysRandom, 5, LFU), Some(b"b".to_vec()));
+        assert_eq!(keyspace.victim(Policy::NoEviction, 5, LFU), None);
+    }
+
+    #[test]
+    fn pool_prefers_idle_and_rare_keys() {
+        let keys: Vec<String> = (0..50).map(|i| i.to_string()).collect();
+        let mut keyspace = keyspace(&keys.iter().map(String::as_str).collect::<Vec<_>>());
+        let idle = keyspace.meta.get_mut(b"7".as_slice()).unwrap();
+        idle.lru = (lru_clock() + LRU_CLOCK_MAX - 1000) % LRU_CLOCK_MAX;
+        idle.counter = 0;
+        // enough samples to see every key
+        assert_eq!(keyspace.victim(Policy::AllKeysLru, 500, LFU), Some(b"7".to_vec()));
+        assert_eq!(keyspace.victim(Policy::AllKeysLfu, 500, LFU), Some(b"7".to_vec()));
+
+        keyspace.update(b"20", 10, Some(5_000), false, LFU);
+        keyspace.update(b"30", 10, Some(1_000), false, LFU);
+        assert_eq!(keyspace.victim(Policy::VolatileTtl, 500, LFU), Some(b"30".to_vec()));
+        keyspace.remove(b"30");
+        assert_eq!(keyspace.victim(Policy::VolatileTtl, 500, LFU), Some(b"20".to_vec()));
+    }
+
+    #[test]
+    fn lfu_counters_grow_logarithmically_and_decay() {
+        assert_eq!(log_increment(LFU_INIT, 10, 0.99), LFU_INIT + 1);
+        assert_eq!(log_increment(LFU_INIT + 1, 10, 0.5), LFU_INIT + 1);
+        assert_eq!(log_increment(LFU_INIT + 1, 10, 0.05), LFU_INIT + 2);
+        assert_eq!(log_increment(u8::MAX, 0, 0.0), u8::MAX);
+
+        let mut keyspace = keyspace(&["a"]);
+        for _ in 0..200 {
+            keyspace.update(b"a", 10, None, true, LFU);
+        }
+        let meta = keyspace.meta.get_mut(b"a".as_slice()).unwrap();
+        assert!(meta.counter > LFU_INIT + 2 && meta.counter < 20, "counter {}", meta.counter);
+        meta.decayed_at = minute_clock().wrapping_sub(3);
+        assert_eq!(meta.frequency(LFU), meta.counter - 3);
+        assert_eq!(meta.frequency(Lfu { log_factor: 10, decay_time: 0 }), meta.counter);
+    }
+
+    #[test]
+    fn policies_parse() {
+        for (name, policy) in POLICIES {
+            assert_eq!(Policy::parse(&name.to_ascii_uppercase()), Some(*policy));
+            assert_eq!(policy.name(), *name);
+        }
+        assert_eq!(Policy::parse("allkeys-fifo"), None);
+    }
+
+    #[test]
+    fn maxmemory_evicts_or_refuses_writes() {
+        let redis = server();
+        let mut client = connect();
+        let info = |client: &mut Client, field: &str| {
+            let info = client.handle(command(&["info", "memory", "stats"]), &redis);
+            let info = String::from_utf8(info).unwrap();
+            let line = info.lines().find(|line| line.starts_with(field)).unwrap();
+            line[field.len() + 1..].to_owned()
+        };
+
+        client.handle(command(&["set", "a", "1"]), &redis);
+        client.handle(command(&["set", "b", "2", "PX", "100000"]), &redis);
+        client.handle(command(&["set", "c", "3", "PX", "50000"]), &redis);
+        let used = info(&mut client, "used_memory");
+        assert_eq!(client.handle(command(&["config", "set", "maxmemory", &used]), &redis), b"+OK\r\n");
+
+        // writes run until the dataset is over the limit, then only those freeing memory do
+        assert_eq!(client.handle(command(&["set", "d", "4"]), &redis), b"+OK\r\n");
+        let oom = client.handle(command(&["set", "e", "5"]), &redis);
+        assert!(oom.starts_with(b"-OOM command not allowed"));
+        assert_eq!(client.handle(command(&["get", "a"]), &redis), b"$1\r\n1\r\n");
+        assert_eq!(client.handle(command(&["del", "d"]), &redis), b":1\r\n");
+        assert_eq!(info(&mut client, "used_memory"), used);
+
+        client.handle(command(&["config", "set", "maxmemory-policy", "volatile-ttl"]), &redis);
+        assert_eq!(client.handle(command(&["set", "d", "4"]), &redis), b"+OK\r\n");
+        // keys with a TTL make room, and only them
+        assert_eq!(client.handle(command(&["set", "e", "5"]), &redis), b"+OK\r\n");
+        // then any command evicts again while over the limit, here the last key with a TTL
+        assert_eq!(client.handle(command(&["exists", "a", "b", "c"]), &redis), b":1\r\n");
+        assert_eq!(info(&mut client, "evicted_keys"), "2");
+        assert_eq!(info(&mut client, "maxmemory_policy"), "volatile-ttl");
+    }
+
+    #[test]
+    fn memory_usage_and_object_introspection() {
+        let redis = server();
+        let mut client = connect();
+        let integer = |reply: Vec<u8>| String::from_utf8(reply).unwrap()[1..].trim_end().parse::<i64>().unwrap();
+
+        client.handle(command(&["set", "int", "123"]), &redis);
+        client.handle(command(&["set", "short", "hello"]), &redis);
+        client.handle(command(&["set", "long", &"x".repeat(100)]), &redis);
+        client.handle(command(&["rpush", "list", "a", "b"]), &redis);
+        assert_eq!(client.handle(command(&["object", "encoding", "int"]), &redis), b"$3\r\nint\r\n");
+        assert_eq!(client.handle(command(&["object", "encoding", "short"]), &redis), b"$6\r\nembstr\r\n");
+        assert_eq!(client.handle(command(&["object", "encoding", "long"]), &redis), b"$3\r\nraw\r\n");
+        assert_eq!(client.handle(command(&["object", "encoding", "list"]), &redis), b"$8\r\nlistpack\r\n");
+        assert_eq!(client.handle(command(&["object", "encoding", "missing"]), &redis), b"$-1\r\n");
+        assert_eq!(integer(client.handle(command(&["object", "refcount", "int"]), &redis)), i32::MAX as i64);
+        assert_eq!(integer(client.handle(command(&["object", "refcount", "long"]), &redis)), 1);
+        assert_eq!(integer(client.handle(command(&["object", "idletime", "int"]), &redis)), 0);
+        assert!(client.handle(command(&["object", "freq", "int"]), &redis).starts_with(b"-ERR An LFU maxmemory policy is not selected"));
+
+        // a TTL does not grow the key, its metadata is part of the value slot
+        let short = integer(client.handle(command(&["memory", "usage", "short"]), &redis));
+        client.handle(command(&["set", "short", "hello", "EX", "100"]), &redis);
+        assert_eq!(integer(client.handle(command(&["memory", "usage", "short"]), &redis)), short);
+        let long = integer(client.handle(command(&["memory", "usage", "long"]), &redis));
+        assert!(long > short + 90);
+        assert_eq!(client.handle(command(&["memory", "usage", "missing"]), &redis), b"$-1\r\n");
+        for i in 0..20 {
+            client.handle(command(&["rpush", "list", &"y".repeat(i * 10)]), &redis);
+        }
+        let sampled = integer(client.handle(command(&["memory", "usage", "list", "samples", "5"]), &redis));
+        let exact = integer(client.handle(command(&["memory", "usage", "list", "samples", "0"]), &redis));
+        assert!(exact > sampled);
+
+        let stats = client.handle(command(&["memory", "stats"]), &redis);
+        let stats = String::from_utf8(stats).unwrap();
+        assert!(stats.contains("keys.count\r\n:4\r\n"));
+        let doctor = client.handle(command(&["memory", "doctor"]), &redis);
+        assert!(String::from_utf8(doctor).unwrap().contains("this instance is empty"));
+    }
+}
+// endregion: --- tests
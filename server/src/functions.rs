@@ -0,0 +1,164 @@
+//! Function libraries registered with FUNCTION LOAD and run by FCALL. Unlike the EVAL script
+//! cache these are part of the dataset, so they can be dumped and restored.
+
+use std::collections::BTreeMap;
+
+use utils::prelude::*;
+
+use crate::rdb;
+use crate::scripting::{self, FunctionInfo};
+
+#[derive(Clone)]
+pub struct Library {
+    pub name: String,
+    pub code: Vec<u8>,
+    pub functions: Vec<FunctionInfo>,
+}
+
+impl Library {
+    fn from_code(code: &[u8]) -> std::result::Result<Self, String> {
+        let (name, functions) = scripting::load_library(code)?;
+        Ok(Self {
+            name,
+            code: code.to_vec(),
+            functions,
+        })
+    }
+}
+
+/// How FUNCTION RESTORE treats libraries that already exist.
+#[derive(Clone, Copy)]
+pub enum RestorePolicy {
+    /// Fail on a library name that already exists.
+    Append,
+    /// Replace libraries with the same name.
+    Replace,
+    /// Drop every existing library first.
+    Flush,
+}
+
+#[derive(Clone, Default)]
+pub struct FunctionStore {
+    libraries: BTreeMap<String, Library>,
+}
+
+impl FunctionStore {
+    /// Load a library from its code, returning the library name.
+    pub fn load(&mut self, code: &[u8], replace: bool) -> std::result::Result<String, String> {
+        let library = Library::from_code(code)?;
+        self.add(library, replace)
+    }
+
+    fn add(&mut self, library: Library, replace: bool) -> std::result::Result<String, String> {
+        if !replace && self.libraries.contains_key(&library.name) {
+            return Err(f!("ERR Library '{}' already exists", library.name));
+        }
+        for function in &library.functions {
+            if let Some((owner, _)) = self.find(&function.name) {
+                if owner.name != library.name {
+                    return Err(f!("ERR Function {} already exists", function.name));
+                }
+            }
+        }
+
+        let name = library.name.clone();
+        self.libraries.insert(name.clone(), library);
+        Ok(name)
+    }
+
+    /// The library defining `function`, and the function itself.
+    pub fn find(&self, function: &str) -> Option<(&Library, &FunctionInfo)> {
+        self.libraries.values().find_map(|library| {
+            library.functions.iter()
+                .find(|each_function| each_function.name == function)
+                .map(|each_function| (library, each_function))
+        })
+    }
+
+    pub fn delete(&mut self, name: &str) -> bool {
+        self.libraries.remove(name).is_some()
+    }
+
+    pub fn flush(&mut self) {
+        self.libraries.clear();
+    }
+
+    pub fn libraries(&self) -> impl Iterator<Item = &Library> {
+        self.libraries.values()
+    }
+
+    /// Every library's code as a DUMP style payload.
+    pub fn dump(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        for library in self.libraries.values() {
+            payload.push(rdb::OPCODE_FUNCTION2);
+            rdb::write_string(&mut payload, &library.code);
+        }
+        rdb::seal_payload(payload)
+    }
+
+    /// Load the libraries in a payload from `dump`. Nothing changes unless all of them load.
+    pub fn restore(&mut self, payload: &[u8], policy: RestorePolicy) -> std::result::Result<(), String> {
+        let Some(mut body) = rdb::open_payload(payload) else {
+            return Err("ERR payload version or checksum are wrong".to_owned());
+        };
+
+        let mut next = self.clone();
+        if let RestorePolicy::Flush = policy {
+            next.flush();
+        }
+        while let Some((&opcode, rest)) = body.split_first() {
+            if opcode != rdb::OPCODE_FUNCTION2 {
+                return Err("ERR given type is not a function".to_owned());
+            }
+            body = rest;
+            let Some(code) = rdb::read_string(&mut body) else {
+                return Err("ERR payload version or checksum are wrong".to_owned());
+            };
+            next.add(Library::from_code(&code)?, matches!(policy, RestorePolicy::Replace))?;
+        }
+
+        *self = next;
+        Ok(())
+    }
+}
+
+
+// region: --- tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIB: &[u8] = b"#!lua name=mylib\nredis.register_function('hello', function() return 'hi' end)";
+
+    #[test]
+    fn load_rejects_conflicts() {
+        let mut store = FunctionStore::default();
+        assert_eq!(store.load(LIB, false), Ok("mylib".to_owned()));
+        assert_eq!(store.load(LIB, false), Err("ERR Library 'mylib' already exists".to_owned()));
+        assert_eq!(store.load(LIB, true), Ok("mylib".to_owned()));
+
+        let other = b"#!lua name=other\nredis.register_function('hello', function() return 1 end)";
+        assert_eq!(store.load(other, false), Err("ERR Function hello already exists".to_owned()));
+        assert_eq!(store.load(b"return 1", false), Err("ERR Missing library metadata".to_owned()));
+        assert_eq!(store.load(b"#!lua name=empty\nlocal x = 1", false), Err("ERR No functions registered".to_owned()));
+    }
+
+    #[test]
+    fn dump_restores_libraries() {
+        let mut store = FunctionStore::default();
+        store.load(LIB, false).unwrap();
+        let payload = store.dump();
+
+        let mut restored = FunctionStore::default();
+        restored.restore(&payload, RestorePolicy::Append).unwrap();
+        assert!(restored.find("hello").is_some());
+        assert!(restored.restore(&payload, RestorePolicy::Append).is_err());
+        assert!(restored.restore(&payload, RestorePolicy::Replace).is_ok());
+
+        let mut corrupt = payload.clone();
+        corrupt[2] ^= 1;
+        assert_eq!(restored.restore(&corrupt, RestorePolicy::Flush), Err("ERR payload version or checksum are wrong".to_owned()));
+    }
+}
+// endregion: --- tests
@@ -0,0 +1,308 @@
+//! 52 bit geohash encoding and distance math behind the GEO commands, following Redis so scores
+//! and GEOHASH strings match.
+
+use crate::sorted_set::SortedSet;
+
+const GEO_STEP_MAX: u32 = 26;
+pub const GEO_LAT_MIN: f64 = -85.051_128_78;
+pub const GEO_LAT_MAX: f64 = 85.051_128_78;
+pub const GEO_LONG_MIN: f64 = -180.0;
+pub const GEO_LONG_MAX: f64 = 180.0;
+
+const EARTH_RADIUS_IN_METERS: f64 = 6_372_797.560_856;
+const MERCATOR_MAX: f64 = 20_037_726.37;
+const GEO_ALPHABET: &[u8] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+
+#[derive(Clone, Copy)]
+pub enum Shape {
+    Radius(f64),
+    /// Width and height in meters.
+    Box(f64, f64),
+}
+
+pub struct Match {
+    pub member: Vec<u8>,
+    pub dist: f64,
+    pub score: f64,
+    pub lon: f64,
+    pub lat: f64,
+}
+
+/// Meters per unit for the GEO unit arguments.
+pub fn unit_factor(unit: &str) -> Option<f64> {
+    match unit.to_ascii_lowercase().as_str() {
+        "m" => Some(1.0),
+        "km" => Some(1000.0),
+        "ft" => Some(0.3048),
+        "mi" => Some(1609.34),
+        _ => None,
+    }
+}
+
+pub fn valid_coords(lon: f64, lat: f64) -> bool {
+    (GEO_LONG_MIN..=GEO_LONG_MAX).contains(&lon) && (GEO_LAT_MIN..=GEO_LAT_MAX).contains(&lat)
+}
+
+fn interleave(lat: u32, lon: u32) -> u64 {
+    let spread = |val: u32| {
+        let mut x = val as u64;
+        x = (x | (x << 16)) & 0x0000_ffff_0000_ffff;
+        x = (x | (x << 8)) & 0x00ff_00ff_00ff_00ff;
+        x = (x | (x << 4)) & 0x0f0f_0f0f_0f0f_0f0f;
+        x = (x | (x << 2)) & 0x3333_3333_3333_3333;
+        (x | (x << 1)) & 0x5555_5555_5555_5555
+    };
+
+    spread(lat) | (spread(lon) << 1)
+}
+
+fn deinterleave(bits: u64) -> (u32, u32) {
+    let squash = |val: u64| {
+        let mut x = val & 0x5555_5555_5555_5555;
+        x = (x | (x >> 1)) & 0x3333_3333_3333_3333;
+        x = (x | (x >> 2)) & 0x0f0f_0f0f_0f0f_0f0f;
+        x = (x | (x >> 4)) & 0x00ff_00ff_00ff_00ff;
+        x = (x | (x >> 8)) & 0x0000_ffff_0000_ffff;
+        ((x | (x >> 16)) & 0x0000_0000_ffff_ffff) as u32
+    };
+
+    (squash(bits), squash(bits >> 1))
+}
+
+fn encode_range(lon: f64, lat: f64, lat_min: f64, lat_max: f64, step: u32) -> u64 {
+    let lat_offset = (lat - lat_min) / (lat_max - lat_min) * (1u64 << step) as f64;
+    let lon_offset = (lon - GEO_LONG_MIN) / (GEO_LONG_MAX - GEO_LONG_MIN) * (1u64 << step) as f64;
+    let cells = (1u64 << step) - 1;
+    interleave((lat_offset as u64).min(cells) as u32, (lon_offset as u64).min(cells) as u32)
+}
+
+/// Geohash of the coordinates at `step` bits per axis, in the Mercator latitude range.
+pub fn encode(lon: f64, lat: f64, step: u32) -> u64 {
+    encode_range(lon, lat, GEO_LAT_MIN, GEO_LAT_MAX, step)
+}
+
+/// Full precision geohash, the score stored in the sorted set.
+pub fn encode_score(lon: f64, lat: f64) -> f64 {
+    encode(lon, lat, GEO_STEP_MAX) as f64
+}
+
+/// Bounds of the cell `bits` names, as (lon_min, lon_max, lat_min, lat_max).
+fn area(bits: u64, step: u32) -> (f64, f64, f64, f64) {
+    let (lat_cell, lon_cell) = deinterleave(bits);
+    let cells = (1u64 << step) as f64;
+    let lat_scale = GEO_LAT_MAX - GEO_LAT_MIN;
+    let lon_scale = GEO_LONG_MAX - GEO_LONG_MIN;
+
+    (
+        GEO_LONG_MIN + (lon_cell as f64 / cells) * lon_scale,
+        GEO_LONG_MIN + ((lon_cell as f64 + 1.0) / cells) * lon_scale,
+        GEO_LAT_MIN + (lat_cell as f64 / cells) * lat_scale,
+        GEO_LAT_MIN + ((lat_cell as f64 + 1.0) / cells) * lat_scale,
+    )
+}
+
+/// Center of the cell a score points at, the coordinates GEOPOS reports.
+pub fn decode_score(score: f64) -> (f64, f64) {
+    let (lon_min, lon_max, lat_min, lat_max) = area(score as u64, GEO_STEP_MAX);
+    let lon = ((lon_min + lon_max) / 2.0).clamp(GEO_LONG_MIN, GEO_LONG_MAX);
+    let lat = ((lat_min + lat_max) / 2.0).clamp(GEO_LAT_MIN, GEO_LAT_MAX);
+    (lon, lat)
+}
+
+/// The standard 11 character base32 geohash, which uses the full -90..90 latitude range.
+pub fn hash_string(score: f64) -> Vec<u8> {
+    let (lon, lat) = decode_score(score);
+    let bits = encode_range(lon, lat, -90.0, 90.0, GEO_STEP_MAX);
+
+    (0..11)
+        .map(|i| {
+            let idx = if i == 10 { 0 } else { (bits >> (52 - (i + 1) * 5)) & 0x1f };
+            GEO_ALPHABET[idx as usize]
+        })
+        .collect()
+}
+
+fn deg_rad(deg: f64) -> f64 {
+    deg.to_radians()
+}
+
+/// Haversine distance in meters.
+pub fn distance(lon1: f64, lat1: f64, lon2: f64, lat2: f64) -> f64 {
+    let (lat1r, lon1r) = (deg_rad(lat1), deg_rad(lon1));
+    let (lat2r, lon2r) = (deg_rad(lat2), deg_rad(lon2));
+    let v = ((lon2r - lon1r) / 2.0).sin();
+    let u = ((lat2r - lat1r) / 2.0).sin();
+    let a = u * u + lat1r.cos() * lat2r.cos() * v * v;
+    2.0 * EARTH_RADIUS_IN_METERS * a.sqrt().asin()
+}
+
+/// Distance from the center when the point falls inside `shape`.
+fn distance_in_shape(shape: Shape, center: (f64, f64), lon: f64, lat: f64) -> Option<f64> {
+    match shape {
+        Shape::Radius(radius) => {
+            let dist = distance(center.0, center.1, lon, lat);
+            (dist <= radius).then_some(dist)
+        },
+        Shape::Box(width, height) => {
+            let lat_dist = EARTH_RADIUS_IN_METERS * (deg_rad(lat) - deg_rad(center.1)).abs();
+            if lat_dist > height / 2.0 {
+                return None;
+            }
+            if distance(center.0, lat, lon, lat) > width / 2.0 {
+                return None;
+            }
+            Some(distance(center.0, center.1, lon, lat))
+        },
+    }
+}
+
+/// (lon_min, lat_min, lon_max, lat_max) enclosing the shape, possibly past +/-180.
+fn bounding_box(shape: Shape, center: (f64, f64)) -> (f64, f64, f64, f64) {
+    let (half_width, half_height) = match shape {
+        Shape::Radius(radius) => (radius, radius),
+        Shape::Box(width, height) => (width / 2.0, height / 2.0),
+    };
+
+    let lat_delta = (half_height / EARTH_RADIUS_IN_METERS).to_degrees();
+    let lon_delta_top = (half_width / EARTH_RADIUS_IN_METERS / deg_rad(center.1 + lat_delta).cos()).to_degrees();
+    let lon_delta_bottom = (half_width / EARTH_RADIUS_IN_METERS / deg_rad(center.1 - lat_delta).cos()).to_degrees();
+    let lon_delta = if center.1 < 0.0 { lon_delta_bottom } else { lon_delta_top };
+
+    (center.0 - lon_delta, center.1 - lat_delta, center.0 + lon_delta, center.1 + lat_delta)
+}
+
+fn estimate_step(mut range: f64, lat: f64) -> u32 {
+    if range == 0.0 {
+        return GEO_STEP_MAX;
+    }
+
+    let mut step: i32 = 1;
+    while range < MERCATOR_MAX {
+        range *= 2.0;
+        step += 1;
+    }
+    step -= 2;
+    if !(-66.0..=66.0).contains(&lat) {
+        step -= 1;
+        if !(-80.0..=80.0).contains(&lat) {
+            step -= 1;
+        }
+    }
+
+    step.clamp(1, GEO_STEP_MAX as i32) as u32
+}
+
+/// Score ranges of the center cell and its eight neighbours at a step coarse enough that
+/// they cover the whole shape.
+fn search_ranges(shape: Shape, center: (f64, f64)) -> Vec<(f64, f64)> {
+    let radius = match shape {
+        Shape::Radius(radius) => radius,
+        Shape::Box(width, height) => ((width / 2.0).powi(2) + (height / 2.0).powi(2)).sqrt(),
+    };
+    let bounds = bounding_box(shape, center);
+
+    let mut step = estimate_step(radius, center.1);
+    let (lat_cell, lon_cell) = loop {
+        let bits = encode(center.0, center.1.clamp(GEO_LAT_MIN, GEO_LAT_MAX), step);
+        let (lon_min, lon_max, lat_min, lat_max) = area(bits, step);
+        let (lon_width, lat_height) = (lon_max - lon_min, lat_max - lat_min);
+
+        let covered = bounds.0 >= lon_min - lon_width && bounds.2 <= lon_max + lon_width
+            && bounds.1 >= lat_min - lat_height && bounds.3 <= lat_max + lat_height;
+        if covered || step == 1 {
+            if !covered {
+                return vec![(f64::NEG_INFINITY, f64::INFINITY)];
+            }
+            break deinterleave(bits);
+        }
+        step -= 1;
+    };
+
+    let cells = 1i64 << step;
+    let shift = 52 - 2 * step;
+    let mut ranges: Vec<(f64, f64)> = Vec::new();
+    for lat_move in [-1i64, 0, 1] {
+        let lat_i = lat_cell as i64 + lat_move;
+        if lat_i < 0 || lat_i >= cells {
+            continue;
+        }
+        for lon_move in [-1i64, 0, 1] {
+            let lon_i = (lon_cell as i64 + lon_move).rem_euclid(cells);
+            let bits = interleave(lat_i as u32, lon_i as u32);
+            let range = ((bits << shift) as f64, ((bits + 1) << shift) as f64);
+            if !ranges.contains(&range) {
+                ranges.push(range);
+            }
+        }
+    }
+
+    ranges
+}
+
+/// Members of `zset` inside `shape` around `center`, stopping after `limit` when given.
+pub fn search(zset: &SortedSet, shape: Shape, center: (f64, f64), limit: Option<usize>) -> Vec<Match> {
+    let mut matches = Vec::new();
+    for (min, max) in search_ranges(shape, center) {
+        for (member, score) in zset.range_by_score(min, max) {
+            let (lon, lat) = decode_score(score);
+            if let Some(dist) = distance_in_shape(shape, center, lon, lat) {
+                matches.push(Match { member: member.to_vec(), dist, score, lon, lat });
+                if limit.is_some_and(|limit| matches.len() >= limit) {
+                    return matches;
+                }
+            }
+        }
+    }
+
+    matches
+}
+
+
+// region: --- tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use utils::prelude::*;
+
+    const PALERMO: (f64, f64) = (13.361389, 38.115556);
+    const CATANIA: (f64, f64) = (15.087269, 37.502669);
+
+    #[test]
+    fn scores_and_hashes_match_redis() {
+        assert_eq!(encode_score(PALERMO.0, PALERMO.1), 3479099956230698.0);
+        assert_eq!(encode_score(CATANIA.0, CATANIA.1), 3479447370796909.0);
+        assert_eq!(hash_string(encode_score(PALERMO.0, PALERMO.1)), b"sqc8b49rny0".to_vec());
+        assert_eq!(hash_string(encode_score(CATANIA.0, CATANIA.1)), b"sqdtr74hyu0".to_vec());
+    }
+
+    #[test]
+    fn decode_is_close_to_input() {
+        let (lon, lat) = decode_score(encode_score(PALERMO.0, PALERMO.1));
+        assert!((lon - PALERMO.0).abs() < 1e-5);
+        assert!((lat - PALERMO.1).abs() < 1e-5);
+    }
+
+    #[test]
+    fn distance_between_palermo_and_catania() {
+        let (lon1, lat1) = decode_score(encode_score(PALERMO.0, PALERMO.1));
+        let (lon2, lat2) = decode_score(encode_score(CATANIA.0, CATANIA.1));
+        assert_eq!(f!("{:.4}", distance(lon1, lat1, lon2, lat2)), "166274.1516");
+    }
+
+    #[test]
+    fn search_finds_members_in_radius_and_box() {
+        let mut zset = SortedSet::new();
+        zset.insert(b"Palermo", encode_score(PALERMO.0, PALERMO.1));
+        zset.insert(b"Catania", encode_score(CATANIA.0, CATANIA.1));
+        zset.insert(b"Paris", encode_score(2.35, 48.85));
+
+        let found = search(&zset, Shape::Radius(200_000.0), (15.0, 37.0), None);
+        assert_eq!(found.len(), 2);
+        let found = search(&zset, Shape::Radius(100_000.0), (15.0, 37.0), None);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].member, b"Catania".to_vec());
+        let found = search(&zset, Shape::Box(400_000.0, 400_000.0), (15.0, 37.0), None);
+        assert_eq!(found.len(), 2);
+    }
+}
+// endregion: --- tests
@@ -0,0 +1,93 @@
+//! Redis style glob patterns: `*`, `?`, `[abc]`, `[^abc]`, `[a-z]` and `\` escapes.
+
+pub fn matches(pattern: &[u8], text: &[u8]) -> bool {
+    let Some((&first, rest)) = pattern.split_first() else {
+        return text.is_empty();
+    };
+
+    match first {
+        b'*' => {
+            // Collapse runs of stars, then try every split point
+            let rest = match rest.iter().position(|byte| *byte != b'*') {
+                Some(start) => &rest[start..],
+                None => return true,
+            };
+            (0..=text.len()).any(|skip| matches(rest, &text[skip..]))
+        },
+        b'?' => !text.is_empty() && matches(rest, &text[1..]),
+        b'[' => {
+            let Some((&byte, text_rest)) = text.split_first() else { return false };
+            let (matched, rest) = match_class(rest, byte);
+            matched && matches(rest, text_rest)
+        },
+        b'\\' if !rest.is_empty() => {
+            text.first() == Some(&rest[0]) && matches(&rest[1..], &text[1..])
+        },
+        _ => text.first() == Some(&first) && matches(rest, &text[1..]),
+    }
+}
+
+/// Match `byte` against the class after an opening `[`, returning the pattern after the class.
+fn match_class(mut pattern: &[u8], byte: u8) -> (bool, &[u8]) {
+    let negate = pattern.first() == Some(&b'^');
+    if negate {
+        pattern = &pattern[1..];
+    }
+
+    let mut matched = false;
+    loop {
+        match pattern {
+            [] => break,
+            [b']', rest @ ..] => {
+                pattern = rest;
+                break;
+            },
+            [b'\\', escaped, rest @ ..] => {
+                matched |= *escaped == byte;
+                pattern = rest;
+            },
+            [start, b'-', end, rest @ ..] if *end != b']' => {
+                let (low, high) = if start <= end { (*start, *end) } else { (*end, *start) };
+                matched |= (low..=high).contains(&byte);
+                pattern = rest;
+            },
+            [other, rest @ ..] => {
+                matched |= *other == byte;
+                pattern = rest;
+            },
+        }
+    }
+
+    (matched != negate, pattern)
+}
+
+
+// region: --- tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcards() {
+        assert!(matches(b"*", b""));
+        assert!(matches(b"h?llo", b"hello"));
+        assert!(matches(b"h*llo", b"heeeello"));
+        assert!(matches(b"h*llo", b"hllo"));
+        assert!(!matches(b"h?llo", b"hllo"));
+        assert!(matches(b"news.*", b"news.art"));
+        assert!(!matches(b"news.*", b"new"));
+    }
+
+    #[test]
+    fn classes_and_escapes() {
+        assert!(matches(b"h[ae]llo", b"hallo"));
+        assert!(!matches(b"h[ae]llo", b"hillo"));
+        assert!(matches(b"h[^e]llo", b"hallo"));
+        assert!(!matches(b"h[^e]llo", b"hello"));
+        assert!(matches(b"h[a-b]llo", b"hbllo"));
+        assert!(!matches(b"h[a-b]llo", b"hcllo"));
+        assert!(matches(b"h\\*llo", b"h*llo"));
+        assert!(!matches(b"h\\*llo", b"hello"));
+    }
+}
+// endregion: --- tests
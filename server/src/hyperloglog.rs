@@ -0,0 +1,353 @@
+//! HyperLogLog values stored in the Redis string layout: a 16 byte `HYLL` header followed by
+//! either sparse run-length opcodes or 16384 dense 6 bit registers.
+
+const MAGIC: &[u8] = b"HYLL";
+const HEADER_LEN: usize = 16;
+const ENCODING_DENSE: u8 = 0;
+const ENCODING_SPARSE: u8 = 1;
+
+const HLL_P: u32 = 14;
+const HLL_Q: usize = 64 - HLL_P as usize;
+pub const HLL_REGISTERS: usize = 1 << HLL_P;
+const HLL_BITS: usize = 6;
+const DENSE_LEN: usize = HEADER_LEN + (HLL_REGISTERS * HLL_BITS).div_ceil(8);
+const ALPHA_INF: f64 = 0.721_347_520_444_481_7;
+
+/// Matches the `hll-sparse-max-bytes` default; larger sparse values are promoted to dense.
+const SPARSE_MAX_BYTES: usize = 3000;
+const SPARSE_VAL_MAX_VALUE: u8 = 32;
+const SPARSE_VAL_MAX_LEN: usize = 4;
+const SPARSE_ZERO_MAX_LEN: usize = 64;
+const SPARSE_XZERO_MAX_LEN: usize = 16384;
+
+/// Whether `bytes` carries a well formed HLL header and, when dense, the right length.
+pub fn is_valid(bytes: &[u8]) -> bool {
+    if bytes.len() < HEADER_LEN || &bytes[..4] != MAGIC {
+        return false;
+    }
+
+    match bytes[4] {
+        ENCODING_DENSE => bytes.len() == DENSE_LEN,
+        ENCODING_SPARSE => true,
+        _ => false,
+    }
+}
+
+/// An empty HyperLogLog, sparse encoded like a fresh PFADD in Redis.
+pub fn new_hll() -> Vec<u8> {
+    encode(&vec![0; HLL_REGISTERS])
+}
+
+/// Add `element`, returning whether any register changed, or `None` for a corrupted value.
+pub fn add(hll: &mut Vec<u8>, element: &[u8]) -> Option<bool> {
+    let (index, count) = pattern_len(element);
+
+    if hll[4] == ENCODING_DENSE {
+        if dense_get(&hll[HEADER_LEN..], index) >= count {
+            return Some(false);
+        }
+        dense_set(&mut hll[HEADER_LEN..], index, count);
+    } else {
+        let mut registers = registers(hll)?;
+        if registers[index] >= count {
+            return Some(false);
+        }
+        registers[index] = count;
+        *hll = encode(&registers);
+    }
+
+    invalidate_cache(hll);
+    Some(true)
+}
+
+/// Cardinality of a single HLL, served from and stored into the header cache.
+pub fn count(hll: &mut [u8]) -> Option<u64> {
+    if hll[15] & 0x80 == 0 {
+        let mut cached = [0u8; 8];
+        cached.copy_from_slice(&hll[8..16]);
+        return Some(u64::from_le_bytes(cached));
+    }
+
+    let card = estimate(&registers(hll)?);
+    hll[8..16].copy_from_slice(&card.to_le_bytes());
+    Some(card)
+}
+
+/// Fold `hll` into `max` by keeping the larger of each register.
+pub fn merge_into(max: &mut [u8], hll: &[u8]) -> Option<()> {
+    for (reg, val) in max.iter_mut().zip(registers(hll)?) {
+        *reg = (*reg).max(val);
+    }
+
+    Some(())
+}
+
+/// Decode every register, or `None` when the sparse opcodes do not cover exactly 16384 registers.
+pub fn registers(hll: &[u8]) -> Option<Vec<u8>> {
+    let body = &hll[HEADER_LEN..];
+    if hll[4] == ENCODING_DENSE {
+        return Some((0..HLL_REGISTERS).map(|index| dense_get(body, index)).collect());
+    }
+
+    let mut registers = Vec::with_capacity(HLL_REGISTERS);
+    let mut i = 0;
+    while i < body.len() {
+        let op = body[i];
+        let (value, len) = if op & 0xc0 == 0x00 {
+            i += 1;
+            (0, (op & 0x3f) as usize + 1)
+        } else if op & 0xc0 == 0x40 {
+            let next = *body.get(i + 1)? as usize;
+            i += 2;
+            (0, (((op & 0x3f) as usize) << 8 | next) + 1)
+        } else {
+            i += 1;
+            (((op >> 2) & 0x1f) + 1, (op & 0x03) as usize + 1)
+        };
+
+        if registers.len() + len > HLL_REGISTERS {
+            return None;
+        }
+        registers.extend(std::iter::repeat_n(value, len));
+    }
+
+    if registers.len() != HLL_REGISTERS {
+        return None;
+    }
+
+    Some(registers)
+}
+
+/// Encode registers as sparse when they fit, falling back to dense like Redis does.
+pub fn encode(registers: &[u8]) -> Vec<u8> {
+    let mut hll = header(ENCODING_SPARSE);
+    let mut i = 0;
+    while i < registers.len() {
+        let value = registers[i];
+        let run = registers[i..].iter().take_while(|&&reg| reg == value).count();
+        if value > SPARSE_VAL_MAX_VALUE {
+            return encode_dense(registers);
+        }
+
+        let mut left = run;
+        while left > 0 {
+            if value == 0 && left > SPARSE_ZERO_MAX_LEN {
+                let len = left.min(SPARSE_XZERO_MAX_LEN);
+                hll.push(0x40 | ((len - 1) >> 8) as u8);
+                hll.push(((len - 1) & 0xff) as u8);
+                left -= len;
+            } else if value == 0 {
+                hll.push((left - 1) as u8);
+                left = 0;
+            } else {
+                let len = left.min(SPARSE_VAL_MAX_LEN);
+                hll.push(0x80 | ((value - 1) << 2) | (len - 1) as u8);
+                left -= len;
+            }
+        }
+
+        if hll.len() > SPARSE_MAX_BYTES {
+            return encode_dense(registers);
+        }
+        i += run;
+    }
+
+    hll
+}
+
+pub fn encode_dense(registers: &[u8]) -> Vec<u8> {
+    let mut hll = header(ENCODING_DENSE);
+    hll.resize(DENSE_LEN, 0);
+    for (index, &value) in registers.iter().enumerate() {
+        dense_set(&mut hll[HEADER_LEN..], index, value);
+    }
+
+    hll
+}
+
+fn header(encoding: u8) -> Vec<u8> {
+    let mut hll = Vec::with_capacity(HEADER_LEN);
+    hll.extend_from_slice(MAGIC);
+    hll.extend_from_slice(&[encoding, 0, 0, 0]);
+    hll.extend_from_slice(&[0; 8]);
+    invalidate_cache(&mut hll);
+    hll
+}
+
+fn invalidate_cache(hll: &mut [u8]) {
+    hll[15] |= 0x80;
+}
+
+fn dense_get(body: &[u8], index: usize) -> u8 {
+    let byte = index * HLL_BITS / 8;
+    let fb = (index * HLL_BITS) & 7;
+    let b0 = body[byte] as u16;
+    let b1 = body.get(byte + 1).copied().unwrap_or(0) as u16;
+    (((b0 >> fb) | (b1 << (8 - fb))) & 0x3f) as u8
+}
+
+fn dense_set(body: &mut [u8], index: usize, value: u8) {
+    let byte = index * HLL_BITS / 8;
+    let fb = (index * HLL_BITS) & 7;
+    let value = value as u16;
+
+    body[byte] &= !(0x3fu16 << fb) as u8;
+    body[byte] |= (value << fb) as u8;
+    if fb > 2 {
+        body[byte + 1] &= !(0x3fu16 >> (8 - fb)) as u8;
+        body[byte + 1] |= (value >> (8 - fb)) as u8;
+    }
+}
+
+/// Register index and run length of zeros (plus one) for `element`.
+fn pattern_len(element: &[u8]) -> (usize, u8) {
+    let hash = murmur_hash64a(element, 0xadc8_3b19);
+    let index = (hash & (HLL_REGISTERS as u64 - 1)) as usize;
+    let hash = (hash >> HLL_P) | (1 << HLL_Q);
+    (index, hash.trailing_zeros() as u8 + 1)
+}
+
+fn murmur_hash64a(key: &[u8], seed: u64) -> u64 {
+    const M: u64 = 0xc6a4_a793_5bd1_e995;
+    const R: u32 = 47;
+
+    let mut h = seed ^ (key.len() as u64).wrapping_mul(M);
+    let mut chunks = key.chunks_exact(8);
+    for chunk in &mut chunks {
+        let mut k = u64::from_le_bytes(chunk.try_into().unwrap());
+        k = k.wrapping_mul(M);
+        k ^= k >> R;
+        k = k.wrapping_mul(M);
+        h ^= k;
+        h = h.wrapping_mul(M);
+    }
+
+    let tail = chunks.remainder();
+    if !tail.is_empty() {
+        for (i, &byte) in tail.iter().enumerate() {
+            h ^= (byte as u64) << (8 * i);
+        }
+        h = h.wrapping_mul(M);
+    }
+
+    h ^= h >> R;
+    h = h.wrapping_mul(M);
+    h ^= h >> R;
+    h
+}
+
+/// Ertl's improved estimator, as used by Redis since 5.0.
+pub fn estimate(registers: &[u8]) -> u64 {
+    let mut histogram = [0u32; 64];
+    for &reg in registers {
+        histogram[reg as usize] += 1;
+    }
+
+    let m = HLL_REGISTERS as f64;
+    let mut z = m * tau((m - histogram[HLL_Q + 1] as f64) / m);
+    for j in (1..=HLL_Q).rev() {
+        z += histogram[j] as f64;
+        z *= 0.5;
+    }
+    z += m * sigma(histogram[0] as f64 / m);
+
+    (ALPHA_INF * m * m / z).round() as u64
+}
+
+fn sigma(mut x: f64) -> f64 {
+    if x == 1.0 {
+        return f64::INFINITY;
+    }
+
+    let mut y = 1.0;
+    let mut z = x;
+    loop {
+        x *= x;
+        let z_prime = z;
+        z += x * y;
+        y += y;
+        if z_prime == z {
+            return z;
+        }
+    }
+}
+
+fn tau(mut x: f64) -> f64 {
+    if x == 0.0 || x == 1.0 {
+        return 0.0;
+    }
+
+    let mut y = 1.0;
+    let mut z = 1.0 - x;
+    loop {
+        x = x.sqrt();
+        let z_prime = z;
+        y *= 0.5;
+        z -= (1.0 - x).powi(2) * y;
+        if z_prime == z {
+            return z / 3.0;
+        }
+    }
+}
+
+
+// region: --- tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use utils::prelude::*;
+
+    #[test]
+    fn empty_hll_is_sparse() {
+        let hll = new_hll();
+        assert!(is_valid(&hll));
+        assert_eq!(&hll[..5], b"HYLL\x01");
+        // a single XZERO opcode covering all 16384 registers
+        assert_eq!(&hll[HEADER_LEN..], &[0x7f, 0xff]);
+        assert_eq!(count(&mut hll.clone()), Some(0));
+    }
+
+    #[test]
+    fn small_counts_are_exact() {
+        let mut hll = new_hll();
+        for element in ["a", "b", "c", "d", "e", "f", "g"] {
+            assert_eq!(add(&mut hll, element.as_bytes()), Some(true));
+        }
+        assert_eq!(add(&mut hll, b"a"), Some(false));
+        assert_eq!(count(&mut hll), Some(7));
+    }
+
+    #[test]
+    fn large_counts_promote_to_dense_within_error() {
+        let mut hll = new_hll();
+        for i in 0..100_000 {
+            add(&mut hll, f!("element:{i}").as_bytes()).unwrap();
+        }
+        assert_eq!(hll[4], ENCODING_DENSE);
+        assert_eq!(hll.len(), DENSE_LEN);
+
+        let card = count(&mut hll).unwrap() as f64;
+        assert!((card - 100_000.0).abs() / 100_000.0 < 0.02, "estimate {card}");
+    }
+
+    #[test]
+    fn dense_and_sparse_agree() {
+        let mut regs = vec![0; HLL_REGISTERS];
+        for i in (0..HLL_REGISTERS).step_by(97) {
+            regs[i] = (i % 30) as u8 + 1;
+        }
+        let sparse = encode(&regs);
+        let dense = encode_dense(&regs);
+        assert_eq!(sparse[4], ENCODING_SPARSE);
+        assert_eq!(registers(&sparse).unwrap(), regs);
+        assert_eq!(registers(&dense).unwrap(), regs);
+    }
+
+    #[test]
+    fn corrupted_sparse_is_rejected() {
+        let mut hll = new_hll();
+        hll.push(0x00);
+        assert!(registers(&hll).is_none());
+    }
+}
+// endregion: --- tests
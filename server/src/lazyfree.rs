@@ -0,0 +1,38 @@
+//! Freeing large values on a background thread, so UNLINK and FLUSHALL ASYNC return without
+//! holding the dictionary for as long as dropping the values takes.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::OnceLock;
+use std::thread;
+
+/// Collections with more elements than this are freed in the background, smaller ones are
+/// cheaper to drop right away.
+pub const THRESHOLD: usize = 64;
+
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+static WORKER: OnceLock<Sender<Box<dyn Send>>> = OnceLock::new();
+
+/// Drop `value` on the background thread, started on first use.
+pub fn free<T: Send + 'static>(value: T) {
+    let worker = WORKER.get_or_init(|| {
+        let (sender, receiver) = mpsc::channel::<Box<dyn Send>>();
+        thread::spawn(move || {
+            for value in receiver {
+                drop(value);
+                PENDING.fetch_sub(1, Ordering::Relaxed);
+            }
+        });
+        sender
+    });
+    PENDING.fetch_add(1, Ordering::Relaxed);
+    if worker.send(Box::new(value)).is_err() {
+        // the value came back in the error and is dropped here instead
+        PENDING.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Values handed over and not freed yet.
+pub fn pending() -> usize {
+    PENDING.load(Ordering::Relaxed)
+}
@@ -0,0 +1,153 @@
+//! The compact containers Redis stores small lists, hashes and sorted sets in: ziplists and
+//! listpacks. Both decode to their elements, with integers as decimal strings.
+
+/// Elements of a ziplist, `None` when it is malformed.
+pub fn ziplist(bytes: &[u8]) -> Option<Vec<Vec<u8>>> {
+    // zlbytes, zltail and zllen come first; zllen saturates, so count up to the end marker instead
+    let mut pos = 10;
+    let mut elements = Vec::new();
+    loop {
+        if *bytes.get(pos)? == 0xff {
+            return Some(elements);
+        }
+        // the previous entry's length, 1 or 5 bytes
+        pos += if bytes[pos] == 0xfe { 5 } else { 1 };
+
+        let encoding = *bytes.get(pos)?;
+        pos += 1;
+        let element = match encoding >> 6 {
+            0 => string(bytes, &mut pos, (encoding & 0x3f) as usize)?,
+            1 => {
+                let len = (((encoding & 0x3f) as usize) << 8) | *bytes.get(pos)? as usize;
+                pos += 1;
+                string(bytes, &mut pos, len)?
+            },
+            2 => {
+                let len = u32::from_be_bytes(bytes.get(pos..pos + 4)?.try_into().ok()?) as usize;
+                pos += 4;
+                string(bytes, &mut pos, len)?
+            },
+            _ => {
+                let value = match encoding {
+                    0xc0 => int_le(bytes, &mut pos, 2)?,
+                    0xd0 => int_le(bytes, &mut pos, 4)?,
+                    0xe0 => int_le(bytes, &mut pos, 8)?,
+                    0xf0 => int_le(bytes, &mut pos, 3)?,
+                    0xfe => int_le(bytes, &mut pos, 1)?,
+                    // 0xf1 to 0xfd hold 0 to 12 in the encoding byte itself
+                    0xf1..=0xfd => (encoding & 0x0f) as i64 - 1,
+                    _ => return None,
+                };
+                value.to_string().into_bytes()
+            },
+        };
+        elements.push(element);
+    }
+}
+
+/// Elements of a listpack, `None` when it is malformed.
+pub fn listpack(bytes: &[u8]) -> Option<Vec<Vec<u8>>> {
+    // total bytes and element count come first
+    let mut pos = 6;
+    let mut elements = Vec::new();
+    loop {
+        let encoding = *bytes.get(pos)?;
+        if encoding == 0xff {
+            return Some(elements);
+        }
+        let start = pos;
+        pos += 1;
+
+        let element = if encoding & 0x80 == 0 {
+            (encoding as i64).to_string().into_bytes()
+        } else if encoding & 0xc0 == 0x80 {
+            string(bytes, &mut pos, (encoding & 0x3f) as usize)?
+        } else if encoding & 0xe0 == 0xc0 {
+            let raw = (((encoding & 0x1f) as i64) << 8) | *bytes.get(pos)? as i64;
+            pos += 1;
+            // 13 bit two's complement
+            let value = if raw >= 1 << 12 { raw - (1 << 13) } else { raw };
+            value.to_string().into_bytes()
+        } else if encoding & 0xf0 == 0xe0 {
+            let len = (((encoding & 0x0f) as usize) << 8) | *bytes.get(pos)? as usize;
+            pos += 1;
+            string(bytes, &mut pos, len)?
+        } else {
+            match encoding {
+                0xf0 => {
+                    let len = u32::from_le_bytes(bytes.get(pos..pos + 4)?.try_into().ok()?) as usize;
+                    pos += 4;
+                    string(bytes, &mut pos, len)?
+                },
+                0xf1 => int_le(bytes, &mut pos, 2)?.to_string().into_bytes(),
+                0xf2 => int_le(bytes, &mut pos, 3)?.to_string().into_bytes(),
+                0xf3 => int_le(bytes, &mut pos, 4)?.to_string().into_bytes(),
+                0xf4 => int_le(bytes, &mut pos, 8)?.to_string().into_bytes(),
+                _ => return None,
+            }
+        };
+        elements.push(element);
+
+        // each entry ends with its own length, in as many 7 bit groups as it needs
+        pos += match pos - start {
+            len if len < 1 << 7 => 1,
+            len if len < 1 << 14 => 2,
+            len if len < 1 << 21 => 3,
+            len if len < 1 << 28 => 4,
+            _ => 5,
+        };
+    }
+}
+
+fn string(bytes: &[u8], pos: &mut usize, len: usize) -> Option<Vec<u8>> {
+    let string = bytes.get(*pos..*pos + len)?.to_vec();
+    *pos += len;
+    Some(string)
+}
+
+/// A little endian, sign extended integer `width` bytes wide.
+fn int_le(bytes: &[u8], pos: &mut usize, width: usize) -> Option<i64> {
+    let raw = bytes.get(*pos..*pos + width)?;
+    *pos += width;
+    let mut buf = [0; 8];
+    buf[..width].copy_from_slice(raw);
+    let shift = 64 - 8 * width as u32;
+    Some((i64::from_le_bytes(buf) << shift) >> shift)
+}
+
+
+// region: --- tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ziplist_strings_and_ints() {
+        // "a", 5 as an immediate, 300 as int16, then the end marker
+        let bytes = [
+            0, 0, 0, 0, 0, 0, 0, 0, 3, 0,
+            0x00, 0x01, b'a',
+            0x03, 0xf6,
+            0x02, 0xc0, 0x2c, 0x01,
+            0xff,
+        ];
+        assert_eq!(ziplist(&bytes), Some(vec![b"a".to_vec(), b"5".to_vec(), b"300".to_vec()]));
+        assert_eq!(ziplist(&bytes[..bytes.len() - 1]), None);
+    }
+
+    #[test]
+    fn listpack_strings_and_ints() {
+        // "hi", 7, -1 as a 13 bit int, 1000 as int16, then the end marker
+        let bytes = [
+            0, 0, 0, 0, 4, 0,
+            0x82, b'h', b'i', 3,
+            0x07, 1,
+            0xdf, 0xff, 2,
+            0xf1, 0xe8, 0x03, 3,
+            0xff,
+        ];
+        let expected = vec![b"hi".to_vec(), b"7".to_vec(), b"-1".to_vec(), b"1000".to_vec()];
+        assert_eq!(listpack(&bytes), Some(expected));
+    }
+}
+// endregion: --- tests
@@ -0,0 +1,65 @@
+//! LZF decompression, for the compressed strings Redis writes into RDB files.
+
+/// The most one input byte can expand to: a 3 byte back reference copies at most 264 bytes.
+const MAX_EXPANSION: usize = 88;
+
+/// Decompress `input` into exactly `len` bytes, `None` when the data is corrupt.
+pub fn decompress(input: &[u8], len: usize) -> Option<Vec<u8>> {
+    // the length comes from the file, so it is only trusted as far as the input can reach it
+    if len > input.len().saturating_mul(MAX_EXPANSION) {
+        return None;
+    }
+    let mut out = Vec::with_capacity(len);
+    let mut pos = 0;
+    while pos < input.len() {
+        let ctrl = input[pos] as usize;
+        pos += 1;
+
+        if ctrl < 1 << 5 {
+            // a run of ctrl + 1 literal bytes
+            let literal = input.get(pos..pos + ctrl + 1)?;
+            out.extend_from_slice(literal);
+            pos += ctrl + 1;
+            continue;
+        }
+
+        // a back reference: copy run_len bytes starting distance bytes back
+        let mut run_len = ctrl >> 5;
+        if run_len == 7 {
+            run_len += *input.get(pos)? as usize;
+            pos += 1;
+        }
+        let distance = ((ctrl & 0x1f) << 8) + *input.get(pos)? as usize + 1;
+        pos += 1;
+        let start = out.len().checked_sub(distance)?;
+        // the source may overlap what is being written, so copy byte by byte
+        for offset in 0..run_len + 2 {
+            out.push(out[start + offset]);
+        }
+    }
+
+    (out.len() == len).then_some(out)
+}
+
+
+// region: --- tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn literals_and_back_references() {
+        // "aaaaaaaaaa": one literal 'a', then a 9 byte run starting one byte back
+        assert_eq!(decompress(&[0x00, b'a', 0xe0, 0x00, 0x00], 10), Some(b"aaaaaaaaaa".to_vec()));
+        assert_eq!(decompress(&[0x01, b'h', b'i'], 2), Some(b"hi".to_vec()));
+        assert_eq!(decompress(&[0x01, b'h', b'i'], 3), None);
+        assert_eq!(decompress(&[0x20, 0x05], 3), None);
+    }
+
+    #[test]
+    fn refuses_lengths_the_input_can_not_reach() {
+        assert_eq!(decompress(&[0x00, b'a'], 1 << 45), None);
+        assert_eq!(decompress(&[0x00, b'a'], usize::MAX), None);
+    }
+}
+// endregion: --- tests
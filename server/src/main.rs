@@ -1,51 +1,101 @@
+mod aof;
+mod bitmap;
+mod client;
+mod cluster;
+mod command;
+mod config;
 mod dictionary;
+mod eviction;
+mod functions;
+mod geo;
+mod glob;
+mod hyperloglog;
+mod lazyfree;
+mod listpack;
+mod lzf;
+mod notify;
+mod persistence;
+mod pubsub;
+mod rdb;
+mod replication;
+mod scan;
+mod scripting;
+mod shards;
+mod sorted_set;
 
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
+use std::time::Duration;
 
-use dictionary::dictionary::Dictionary;
+use client::{Blocked, Client};
+use config::Config;
+use shards::Shards;
 use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
 use tokio::net::{TcpListener, TcpStream};
-use utils::deserializer::deserialize;
+use tokio::sync::mpsc::unbounded_channel;
+use utils::deserializer::Decoder;
+use utils::prelude::*;
 use utils::serializer::serialize;
 use utils::DataType;
 
 
-async fn handle_client(mut socket: TcpStream, redis: &Arc<Mutex<Dictionary>>) {
+async fn handle_client(mut socket: TcpStream, redis: &Arc<Shards>) {
+    let peer = socket.peer_addr();
     let (reader, mut writer) = socket.split();
     let mut reader = BufReader::new(reader);
+    let mut decoder = Decoder::default();
+    let (pushes, mut pushed) = unbounded_channel();
+    let mut client = Client::new(pushes);
+    if let Ok(addr) = peer {
+        client.set_ip(addr.ip().to_string());
+    }
 
-    loop {
+    'client: loop {
         let mut buffer = vec![0; 1024];
-        match reader.read(&mut buffer).await {
+        // published messages are written whenever the connection is not busy with a command
+        let read = tokio::select! {
+            read = reader.read(&mut buffer) => read,
+            Some(message) = pushed.recv() => {
+                if let Err(e) = writer.write_all(&message).await {
+                    println!("Failed to write to client: {}", e);
+                    break;
+                }
+                continue;
+            },
+        };
+
+        match read {
             Ok(0) => {
                 println!("Client disconnected.");
                 break;
             },
-            Ok(_) => {
-                let command = String::from_utf8(buffer).unwrap();
-                println!("received: {}", command);
+            Ok(n) => {
+                decoder.extend(&buffer[..n]);
 
-                let response = {
-                    let mut d_command: Option<DataType> = None;
-                    match deserialize(&command) {
-                        Ok(val) => d_command = Some(val),
+                // a read may hold several pipelined commands, or only part of one
+                loop {
+                    let response = match decoder.decode() {
+                        Ok(Some(d_command)) => {
+                            let response = client.handle(d_command, redis);
+                            match client.take_blocked() {
+                                Some(Blocked::Wait(wait)) => replication::wait(redis, wait).await,
+                                Some(Blocked::Migrate(migration)) => client.finish_migrate(migration, redis).await,
+                                None => response,
+                            }
+                        },
+                        Ok(None) => break,
+                        // what follows can not be told apart from the rest of the bad value
                         Err(e) => {
                             println!("Error: {}", e);
-                            let response = serialize(&DataType::Error("ERR error parsing data".to_owned())).unwrap();
-                            if let Err(e) = writer.write_all(response.as_bytes()).await {
-                                println!("Failed to write to client: {}", e);
-                                break;
-                            }
+                            let response = serialize(&DataType::Error(f!("ERR Protocol error: {e}"))).unwrap();
+                            let _ = writer.write_all(&response).await;
+                            break 'client;
                         },
-                    }
-                    
-                    let mut dict = redis.lock().unwrap();
-                    dict.handle_command(d_command.unwrap())
-                };
+                    };
 
-                if let Err(e) = writer.write_all(response.as_bytes()).await {
-                    println!("Failed to write to client: {}", e);
-                    break;
+                    if let Err(e) = writer.write_all(&response).await {
+                        println!("Failed to write to client: {}", e);
+                        break 'client;
+                    }
                 }
             },
             Err(e) => {
@@ -54,13 +104,61 @@ async fn handle_client(mut socket: TcpStream, redis: &Arc<Mutex<Dictionary>>) {
             },
         }
     }
+
+    client.disconnect(redis);
 }
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
-    let listener = TcpListener::bind("127.0.0.1:6379").await?;
-    println!("Redis Lite server listening on 127.0.0.1:6379");
-    let redis: Arc<Mutex<Dictionary>> = Arc::new(Mutex::new(Dictionary::new()));
+    let mut config = Config::default();
+    if let Err(e) = config.set_args(std::env::args().skip(1)) {
+        println!("Bad command line arguments: {}", e);
+        std::process::exit(1);
+    }
+    let port = config.port;
+    let listener = TcpListener::bind(("127.0.0.1", port)).await?;
+    println!("Redis Lite server listening on 127.0.0.1:{}", port);
+    let (cluster_enabled, cluster_path, node_timeout) = (config.cluster_enabled, config.cluster_config_path(), config.cluster_node_timeout);
+    let primary = config.replicaof.clone();
+    let redis = Arc::new(Shards::new(config));
+    if cluster_enabled {
+        let mut cluster = redis.shared().cluster();
+        if let Err(e) = cluster.start(cluster_path, port, node_timeout) {
+            println!("Can't start cluster mode: {}", e);
+            std::process::exit(1);
+        }
+        println!("Cluster node {} started", cluster.myid());
+    }
+    let link_id = {
+        let mut dictionary = redis.lock_all();
+        match dictionary.load() {
+            Ok(keys) => println!("DB loaded from disk: {} keys", keys),
+            Err(e) => {
+                println!("Failed loading the DB: {}", e);
+                std::process::exit(1);
+            },
+        }
+        primary.and_then(|primary| dictionary.replicaof(Some(primary)))
+    };
+    if let Some(link_id) = link_id {
+        tokio::spawn(replication::run_link(Arc::clone(&redis), link_id));
+    }
+
+    // expire keys in the background too, not only when they are next read, and save when due
+    let cron_redis = Arc::clone(&redis);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_millis(100));
+        loop {
+            interval.tick().await;
+            cron_redis.expire_keys();
+            cron_redis.persistence_cron();
+            cron_redis.shared().replication().ping_replicas();
+            let targets = cron_redis.shared().cluster().gossip_targets();
+            for (host, port) in targets {
+                tokio::spawn(cluster::gossip(Arc::clone(&cron_redis), host, port));
+            }
+        }
+    });
 
     loop {
         let (socket, addr) = listener.accept().await?;
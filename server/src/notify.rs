@@ -0,0 +1,111 @@
+//! Keyspace notification classes, as set by the `notify-keyspace-events` flag string.
+
+/// `K`: publish to `__keyspace@<db>__:<key>` with the event as the message.
+pub const KEYSPACE: u16 = 1 << 0;
+/// `E`: publish to `__keyevent@<db>__:<event>` with the key as the message.
+pub const KEYEVENT: u16 = 1 << 1;
+pub const GENERIC: u16 = 1 << 2;
+pub const STRING: u16 = 1 << 3;
+pub const LIST: u16 = 1 << 4;
+pub const SET: u16 = 1 << 5;
+pub const HASH: u16 = 1 << 6;
+pub const ZSET: u16 = 1 << 7;
+pub const EXPIRED: u16 = 1 << 8;
+pub const EVICTED: u16 = 1 << 9;
+pub const STREAM: u16 = 1 << 10;
+pub const KEY_MISS: u16 = 1 << 11;
+pub const MODULE: u16 = 1 << 12;
+pub const NEW: u16 = 1 << 13;
+
+/// What `A` stands for, every class except key misses and new keys.
+pub const ALL: u16 = GENERIC | STRING | LIST | SET | HASH | ZSET | EXPIRED | EVICTED | STREAM | MODULE;
+
+const CLASSES: &[(char, u16)] = &[
+    ('g', GENERIC),
+    ('$', STRING),
+    ('l', LIST),
+    ('s', SET),
+    ('h', HASH),
+    ('z', ZSET),
+    ('x', EXPIRED),
+    ('e', EVICTED),
+    ('t', STREAM),
+    ('d', MODULE),
+];
+
+/// Parse a flag string such as `KEA` or `Elg`, `None` on an unknown character.
+pub fn parse(flags: &str) -> Option<u16> {
+    let mut parsed = 0;
+    for flag in flags.chars() {
+        parsed |= match flag {
+            'A' => ALL,
+            'K' => KEYSPACE,
+            'E' => KEYEVENT,
+            'm' => KEY_MISS,
+            'n' => NEW,
+            _ => CLASSES.iter().find(|(each, _)| *each == flag)?.1,
+        };
+    }
+    Some(parsed)
+}
+
+/// The flag string for `flags`, in the form CONFIG GET reports it.
+pub fn format(flags: u16) -> String {
+    let mut formatted = String::new();
+    if flags & ALL == ALL {
+        formatted.push('A');
+    } else {
+        formatted.extend(CLASSES.iter().filter(|(_, class)| flags & class != 0).map(|(flag, _)| flag));
+    }
+    for (flag, class) in [('K', KEYSPACE), ('E', KEYEVENT), ('m', KEY_MISS), ('n', NEW)] {
+        if flags & class != 0 {
+            formatted.push(flag);
+        }
+    }
+    formatted
+}
+
+
+// region: --- tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::tests::{command, connect, server};
+    use crate::client::Client;
+    use tokio::sync::mpsc::unbounded_channel;
+
+    #[test]
+    fn flags_round_trip() {
+        assert_eq!(parse(""), Some(0));
+        assert_eq!(parse("KEA"), Some(KEYSPACE | KEYEVENT | ALL));
+        assert_eq!(format(parse("KEA").unwrap()), "AKE");
+        assert_eq!(format(parse("Elx").unwrap()), "lxE");
+        assert_eq!(parse("Kq"), None);
+    }
+
+    #[test]
+    fn keyspace_events_follow_config() {
+        let redis = server();
+        let (pushes, mut received) = unbounded_channel();
+        let mut subscriber = Client::new(pushes);
+        let mut client = connect();
+
+        subscriber.handle(command(&["psubscribe", "__key*__:*"]), &redis);
+        client.handle(command(&["set", "a", "1"]), &redis);
+        assert!(received.try_recv().is_err());
+
+        assert_eq!(client.handle(command(&["config", "set", "notify-keyspace-events", "Eg$x"]), &redis), b"+OK\r\n");
+        client.handle(command(&["set", "a", "1", "PX", "20"]), &redis);
+        let events = [received.try_recv().unwrap(), received.try_recv().unwrap()];
+        assert!(events[0].ends_with(b"$18\r\n__keyevent@0__:set\r\n$1\r\na\r\n"));
+        assert!(events[1].ends_with(b"$21\r\n__keyevent@0__:expire\r\n$1\r\na\r\n"));
+
+        std::thread::sleep(std::time::Duration::from_millis(40));
+        client.handle(command(&["get", "a"]), &redis);
+        assert!(received.try_recv().unwrap().ends_with(b"$22\r\n__keyevent@0__:expired\r\n$1\r\na\r\n"));
+
+        client.handle(command(&["lpush", "l", "x"]), &redis);
+        assert!(received.try_recv().is_err());
+    }
+}
+// endregion: --- tests
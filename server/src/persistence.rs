@@ -0,0 +1,149 @@
+//! RDB snapshots on disk: writing them for SAVE and BGSAVE, reading them back at startup, and the
+//! bookkeeping behind the `save` rules.
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use utils::prelude::*;
+
+use crate::rdb::{self, Snapshot};
+
+/// State shared between the dictionary and a background save in progress.
+pub struct SaveStatus {
+    /// Writes since the last successful save.
+    dirty: AtomicU64,
+    /// Unix time in seconds of the last successful save, or of startup.
+    last_save: AtomicU64,
+    in_progress: AtomicBool,
+    /// Set by BGSAVE SCHEDULE while another save was running.
+    scheduled: AtomicBool,
+}
+
+impl SaveStatus {
+    pub fn new() -> Self {
+        Self {
+            dirty: AtomicU64::new(0),
+            last_save: AtomicU64::new(now()),
+            in_progress: AtomicBool::new(false),
+            scheduled: AtomicBool::new(false),
+        }
+    }
+
+    pub fn modified(&self) {
+        self.dirty.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn last_save(&self) -> u64 {
+        self.last_save.load(Ordering::Relaxed)
+    }
+
+    pub fn in_progress(&self) -> bool {
+        self.in_progress.load(Ordering::Acquire)
+    }
+
+    pub fn schedule(&self) {
+        self.scheduled.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether a scheduled save or one of the `(seconds, changes)` rules calls for a save now.
+    pub fn due(&self, rules: &[(u64, u64)]) -> bool {
+        if self.in_progress() {
+            return false;
+        }
+        if self.scheduled.load(Ordering::Relaxed) {
+            return true;
+        }
+
+        let dirty = self.dirty.load(Ordering::Relaxed);
+        let elapsed = now().saturating_sub(self.last_save());
+        dirty > 0 && rules.iter().any(|(seconds, changes)| dirty >= *changes && elapsed >= *seconds)
+    }
+
+    /// Record a successful save of a snapshot taken when `dirty` writes were pending.
+    fn saved(&self, dirty: u64) {
+        self.dirty.fetch_sub(dirty, Ordering::Relaxed);
+        self.last_save.store(now(), Ordering::Relaxed);
+    }
+}
+
+/// Save `snapshot` to `path` before returning, as SAVE does.
+pub fn save(path: &Path, snapshot: &Snapshot, status: &SaveStatus) -> io::Result<()> {
+    let dirty = status.dirty.load(Ordering::Relaxed);
+    write(path, snapshot)?;
+    status.saved(dirty);
+    Ok(())
+}
+
+/// Save `snapshot` to `path` on another thread, returning false when a background save is
+/// already running.
+pub fn bgsave(path: PathBuf, snapshot: Snapshot, status: &Arc<SaveStatus>) -> bool {
+    if status.in_progress.swap(true, Ordering::AcqRel) {
+        return false;
+    }
+    status.scheduled.store(false, Ordering::Relaxed);
+
+    let dirty = status.dirty.load(Ordering::Relaxed);
+    let status = Arc::clone(status);
+    thread::spawn(move || {
+        match write(&path, &snapshot) {
+            Ok(()) => {
+                status.saved(dirty);
+                println!("Background saving terminated with success");
+            },
+            Err(e) => println!("Background saving error: {}", e),
+        }
+        status.in_progress.store(false, Ordering::Release);
+    });
+    true
+}
+
+/// Read the snapshot at `path`, `None` when there is no file yet.
+pub fn load(path: &Path) -> std::result::Result<Option<Snapshot>, String> {
+    match fs::read(path) {
+        Ok(bytes) => rdb::decode(&bytes).map(Some),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Write through a temporary file renamed into place, so a crash never leaves half a snapshot.
+fn write(path: &Path, snapshot: &Snapshot) -> io::Result<()> {
+    let temp = path.with_file_name(f!("temp-{}.rdb", std::process::id()));
+    let mut file = File::create(&temp)?;
+    file.write_all(&rdb::encode(snapshot))?;
+    file.sync_all()?;
+    fs::rename(&temp, path)
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+
+// region: --- tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rules_need_enough_changes() {
+        let status = SaveStatus::new();
+        assert!(!status.due(&[(0, 1)]));
+        status.modified();
+        assert!(status.due(&[(0, 1)]));
+        assert!(!status.due(&[(0, 2)]));
+        assert!(!status.due(&[(3600, 1)]));
+        assert!(!status.due(&[]));
+
+        status.saved(1);
+        assert!(!status.due(&[(0, 1)]));
+        status.schedule();
+        assert!(status.due(&[]));
+    }
+}
+// endregion: --- tests
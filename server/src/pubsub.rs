@@ -0,0 +1,248 @@
+//! Channel, pattern and shard channel subscriptions. Published messages go to each subscribed
+//! connection's push queue, which its task writes out between replies.
+
+use std::collections::HashMap;
+
+use tokio::sync::mpsc::UnboundedSender;
+use utils::serializer::serialize;
+use utils::DataType;
+
+use crate::cluster;
+use crate::glob;
+
+/// Queue of serialized messages for one connection to write out of band.
+pub type Pushes = UnboundedSender<Vec<u8>>;
+
+/// Subscribers to a channel or pattern, by client id.
+type Subscribers = HashMap<u64, Pushes>;
+
+#[derive(Default)]
+pub struct PubSub {
+    channels: HashMap<Vec<u8>, Subscribers>,
+    patterns: HashMap<Vec<u8>, Subscribers>,
+    /// Shard channels by hash slot, so a slot's subscribers stay with the node serving it.
+    shard_channels: HashMap<u16, HashMap<Vec<u8>, Subscribers>>,
+}
+
+impl PubSub {
+    pub fn subscribe(&mut self, id: u64, pushes: &Pushes, channel: &[u8]) {
+        add(&mut self.channels, channel, id, pushes);
+    }
+
+    pub fn unsubscribe(&mut self, id: u64, channel: &[u8]) {
+        remove(&mut self.channels, channel, id);
+    }
+
+    pub fn psubscribe(&mut self, id: u64, pushes: &Pushes, pattern: &[u8]) {
+        add(&mut self.patterns, pattern, id, pushes);
+    }
+
+    pub fn punsubscribe(&mut self, id: u64, pattern: &[u8]) {
+        remove(&mut self.patterns, pattern, id);
+    }
+
+    pub fn ssubscribe(&mut self, id: u64, pushes: &Pushes, channel: &[u8]) {
+        let slot = cluster::key_slot(channel);
+        add(self.shard_channels.entry(slot).or_default(), channel, id, pushes);
+    }
+
+    pub fn sunsubscribe(&mut self, id: u64, channel: &[u8]) {
+        let slot = cluster::key_slot(channel);
+        if let Some(channels) = self.shard_channels.get_mut(&slot) {
+            remove(channels, channel, id);
+            if channels.is_empty() {
+                self.shard_channels.remove(&slot);
+            }
+        }
+    }
+
+    /// Send `message` to subscribers of `channel` and of patterns matching it, returning how
+    /// many received it.
+    pub fn publish(&self, channel: &[u8], message: &[u8]) -> usize {
+        let mut receivers = 0;
+
+        if let Some(subscribers) = self.channels.get(channel) {
+            let push = frame(&[b"message", channel, message]);
+            for pushes in subscribers.values() {
+                // A closed queue belongs to a connection that is going away
+                let _ = pushes.send(push.clone());
+            }
+            receivers += subscribers.len();
+        }
+
+        for (pattern, subscribers) in &self.patterns {
+            if !glob::matches(pattern, channel) {
+                continue;
+            }
+            let push = frame(&[b"pmessage", pattern, channel, message]);
+            for pushes in subscribers.values() {
+                let _ = pushes.send(push.clone());
+            }
+            receivers += subscribers.len();
+        }
+
+        receivers
+    }
+
+    /// Send `message` to subscribers of the shard channel `channel`, returning how many received
+    /// it. Patterns never match shard channels.
+    pub fn spublish(&self, channel: &[u8], message: &[u8]) -> usize {
+        let slot = cluster::key_slot(channel);
+        let Some(subscribers) = self.shard_channels.get(&slot).and_then(|channels| channels.get(channel)) else {
+            return 0;
+        };
+
+        let push = frame(&[b"smessage", channel, message]);
+        for pushes in subscribers.values() {
+            let _ = pushes.send(push.clone());
+        }
+        subscribers.len()
+    }
+
+    /// Channels with at least one subscriber, optionally only those matching `pattern`.
+    pub fn channels(&self, pattern: Option<&[u8]>) -> Vec<Vec<u8>> {
+        matching(self.channels.keys(), pattern)
+    }
+
+    pub fn numsub(&self, channel: &[u8]) -> usize {
+        self.channels.get(channel).map_or(0, |subscribers| subscribers.len())
+    }
+
+    /// Shard channels with at least one subscriber, optionally only those matching `pattern`.
+    pub fn shard_channels(&self, pattern: Option<&[u8]>) -> Vec<Vec<u8>> {
+        matching(self.shard_channels.values().flat_map(|channels| channels.keys()), pattern)
+    }
+
+    pub fn shard_numsub(&self, channel: &[u8]) -> usize {
+        self.shard_channels.get(&cluster::key_slot(channel))
+            .and_then(|channels| channels.get(channel))
+            .map_or(0, |subscribers| subscribers.len())
+    }
+
+    pub fn numpat(&self) -> usize {
+        self.patterns.len()
+    }
+}
+
+fn matching<'a>(channels: impl Iterator<Item = &'a Vec<u8>>, pattern: Option<&[u8]>) -> Vec<Vec<u8>> {
+    channels
+        .filter(|channel| pattern.is_none_or(|pattern| glob::matches(pattern, channel)))
+        .cloned()
+        .collect()
+}
+
+fn add(map: &mut HashMap<Vec<u8>, Subscribers>, name: &[u8], id: u64, pushes: &Pushes) {
+    map.entry(name.to_vec()).or_default().insert(id, pushes.clone());
+}
+
+fn remove(map: &mut HashMap<Vec<u8>, Subscribers>, name: &[u8], id: u64) {
+    if let Some(subscribers) = map.get_mut(name) {
+        subscribers.remove(&id);
+        if subscribers.is_empty() {
+            map.remove(name);
+        }
+    }
+}
+
+/// A push message as an array of bulk strings.
+pub fn frame(parts: &[&[u8]]) -> Vec<u8> {
+    let parts = parts.iter().map(|part| DataType::BulkString(Some(part.to_vec()))).collect();
+    serialize(&DataType::Array(Some(parts))).unwrap()
+}
+
+
+// region: --- tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::tests::{command, connect, server};
+    use crate::client::Client;
+    use tokio::sync::mpsc::unbounded_channel;
+
+    #[test]
+    fn publish_reaches_channels_and_patterns() {
+        let mut pubsub = PubSub::default();
+        let (first, mut first_rx) = unbounded_channel();
+        let (second, mut second_rx) = unbounded_channel();
+
+        pubsub.subscribe(1, &first, b"news.art");
+        pubsub.psubscribe(2, &second, b"news.*");
+        assert_eq!(pubsub.publish(b"news.art", b"hi"), 2);
+        assert_eq!(pubsub.publish(b"news.tech", b"yo"), 1);
+        assert_eq!(pubsub.publish(b"sports", b"no"), 0);
+
+        assert_eq!(first_rx.try_recv().unwrap(), b"*3\r\n$7\r\nmessage\r\n$8\r\nnews.art\r\n$2\r\nhi\r\n");
+        assert!(first_rx.try_recv().is_err());
+        assert_eq!(second_rx.try_recv().unwrap(), b"*4\r\n$8\r\npmessage\r\n$6\r\nnews.*\r\n$8\r\nnews.art\r\n$2\r\nhi\r\n");
+        assert!(second_rx.try_recv().is_ok());
+    }
+
+    #[test]
+    fn unsubscribe_drops_empty_channels() {
+        let mut pubsub = PubSub::default();
+        let (pushes, _rx) = unbounded_channel();
+
+        pubsub.subscribe(1, &pushes, b"a");
+        pubsub.subscribe(2, &pushes, b"a");
+        pubsub.unsubscribe(1, b"a");
+        assert_eq!(pubsub.numsub(b"a"), 1);
+        pubsub.unsubscribe(2, b"a");
+        assert_eq!(pubsub.numsub(b"a"), 0);
+        assert!(pubsub.channels(None).is_empty());
+    }
+
+    #[test]
+    fn shard_channels_are_separate() {
+        let mut pubsub = PubSub::default();
+        let (pushes, mut received) = unbounded_channel();
+
+        pubsub.ssubscribe(1, &pushes, b"orders");
+        pubsub.psubscribe(1, &pushes, b"*");
+        assert_eq!(pubsub.spublish(b"orders", b"new"), 1);
+        assert_eq!(received.try_recv().unwrap(), b"*3\r\n$8\r\nsmessage\r\n$6\r\norders\r\n$3\r\nnew\r\n");
+        assert!(received.try_recv().is_err());
+        assert_eq!(pubsub.shard_numsub(b"orders"), 1);
+        assert!(pubsub.channels(None).is_empty());
+
+        pubsub.sunsubscribe(1, b"orders");
+        assert!(pubsub.shard_channels(None).is_empty());
+    }
+
+    #[test]
+    fn publish_pushes_to_subscribers() {
+        let redis = server();
+        let (pushes, mut received) = unbounded_channel();
+        let mut subscriber = Client::new(pushes);
+        let mut publisher = connect();
+
+        let resp = subscriber.handle(command(&["subscribe", "a", "b"]), &redis);
+        assert_eq!(resp, b"*3\r\n$9\r\nsubscribe\r\n$1\r\na\r\n:1\r\n*3\r\n$9\r\nsubscribe\r\n$1\r\nb\r\n:2\r\n");
+        subscriber.handle(command(&["psubscribe", "a*"]), &redis);
+        assert_eq!(publisher.handle(command(&["publish", "a", "hi"]), &redis), b":2\r\n");
+        assert_eq!(received.try_recv().unwrap(), b"*3\r\n$7\r\nmessage\r\n$1\r\na\r\n$2\r\nhi\r\n");
+        assert_eq!(received.try_recv().unwrap(), b"*4\r\n$8\r\npmessage\r\n$2\r\na*\r\n$1\r\na\r\n$2\r\nhi\r\n");
+
+        subscriber.disconnect(&redis);
+        assert_eq!(publisher.handle(command(&["publish", "a", "hi"]), &redis), b":0\r\n");
+    }
+
+    #[test]
+    fn shard_subscriptions_count_separately() {
+        let redis = server();
+        let (pushes, mut received) = unbounded_channel();
+        let mut subscriber = Client::new(pushes);
+        let mut publisher = connect();
+
+        subscriber.handle(command(&["subscribe", "a"]), &redis);
+        let resp = subscriber.handle(command(&["ssubscribe", "a"]), &redis);
+        assert_eq!(resp, b"*3\r\n$10\r\nssubscribe\r\n$1\r\na\r\n:1\r\n");
+        assert_eq!(publisher.handle(command(&["spublish", "a", "hi"]), &redis), b":1\r\n");
+        assert_eq!(received.try_recv().unwrap(), b"*3\r\n$8\r\nsmessage\r\n$1\r\na\r\n$2\r\nhi\r\n");
+
+        subscriber.handle(command(&["unsubscribe"]), &redis);
+        assert!(subscriber.handle(command(&["get", "a"]), &redis).starts_with(b"-ERR Can't execute"));
+        subscriber.handle(command(&["sunsubscribe"]), &redis);
+        assert_eq!(subscriber.handle(command(&["get", "a"]), &redis), b"$-1\r\n");
+    }
+}
+// endregion: --- tests
@@ -0,0 +1,671 @@
+//! The Redis RDB encoding, for snapshot files and DUMP style payloads: opcodes, length prefixed
+//! strings, value types and the CRC64 checksum that closes both.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use utils::prelude::*;
+
+use crate::listpack;
+use crate::lzf;
+
+/// RDB format version written into files and payloads.
+pub const RDB_VERSION: u16 = 11;
+
+/// Opcode that introduces a function library's code.
+pub const OPCODE_FUNCTION2: u8 = 245;
+const OPCODE_MODULE_AUX: u8 = 247;
+const OPCODE_IDLE: u8 = 248;
+const OPCODE_FREQ: u8 = 249;
+const OPCODE_AUX: u8 = 250;
+const OPCODE_RESIZEDB: u8 = 251;
+const OPCODE_EXPIRETIME_MS: u8 = 252;
+const OPCODE_EXPIRETIME: u8 = 253;
+const OPCODE_SELECTDB: u8 = 254;
+const OPCODE_EOF: u8 = 255;
+
+/// Newest format loaded, that of Redis 7.4.
+const MAX_LOAD_VERSION: u16 = 12;
+
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_SET: u8 = 2;
+const TYPE_ZSET: u8 = 3;
+const TYPE_HASH: u8 = 4;
+const TYPE_ZSET_2: u8 = 5;
+const TYPE_MODULE_2: u8 = 7;
+const TYPE_HASH_ZIPMAP: u8 = 9;
+const TYPE_LIST_ZIPLIST: u8 = 10;
+const TYPE_SET_INTSET: u8 = 11;
+const TYPE_ZSET_ZIPLIST: u8 = 12;
+const TYPE_HASH_ZIPLIST: u8 = 13;
+const TYPE_LIST_QUICKLIST: u8 = 14;
+const TYPE_STREAM_LISTPACKS: u8 = 15;
+const TYPE_HASH_LISTPACK: u8 = 16;
+const TYPE_ZSET_LISTPACK: u8 = 17;
+const TYPE_LIST_QUICKLIST_2: u8 = 18;
+const TYPE_STREAM_LISTPACKS_2: u8 = 19;
+const TYPE_SET_LISTPACK: u8 = 20;
+const TYPE_STREAM_LISTPACKS_3: u8 = 21;
+
+const QUICKLIST_NODE_PLAIN: u64 = 1;
+const QUICKLIST_NODE_PACKED: u64 = 2;
+
+const MODULE_OPCODE_EOF: u64 = 0;
+const MODULE_OPCODE_SINT: u64 = 1;
+const MODULE_OPCODE_UINT: u64 = 2;
+const MODULE_OPCODE_FLOAT: u64 = 3;
+const MODULE_OPCODE_DOUBLE: u64 = 4;
+const MODULE_OPCODE_STRING: u64 = 5;
+
+/// Length prefix bits marking a specially encoded string.
+const ENCODED: u8 = 0xc0;
+const ENCODED_INT8: u8 = 0;
+const ENCODED_INT16: u8 = 1;
+const ENCODED_INT32: u8 = 2;
+const ENCODED_LZF: u8 = 3;
+
+pub enum Value {
+    String(Vec<u8>),
+    List(Vec<Vec<u8>>),
+    SortedSet(Vec<(Vec<u8>, f64)>),
+}
+
+pub struct Entry {
+    /// The database the key is in.
+    pub db: usize,
+    pub key: Vec<u8>,
+    pub value: Value,
+    /// Unix time in milliseconds the key expires at.
+    pub expire_at: Option<u128>,
+}
+
+/// The contents of an RDB file.
+#[derive(Default)]
+pub struct Snapshot {
+    pub entries: Vec<Entry>,
+    /// Code of each function library.
+    pub functions: Vec<Vec<u8>>,
+    /// What the file held that could not be loaded, one description each.
+    pub skipped: Vec<String>,
+}
+
+pub fn encode(snapshot: &Snapshot) -> Vec<u8> {
+    let mut out = f!("REDIS{RDB_VERSION:04}").into_bytes();
+    let ctime = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    for (field, value) in [("redis-ver", "7.2.0".to_owned()), ("redis-bits", "64".to_owned()), ("ctime", ctime.to_string())] {
+        out.push(OPCODE_AUX);
+        write_string(&mut out, field.as_bytes());
+        write_string(&mut out, value.as_bytes());
+    }
+    for code in &snapshot.functions {
+        out.push(OPCODE_FUNCTION2);
+        write_string(&mut out, code);
+    }
+
+    let mut entries: Vec<&Entry> = snapshot.entries.iter().collect();
+    entries.sort_by_key(|entry| entry.db);
+    for db in entries.chunk_by(|a, b| a.db == b.db) {
+        out.push(OPCODE_SELECTDB);
+        write_len(&mut out, db[0].db as u64);
+        out.push(OPCODE_RESIZEDB);
+        write_len(&mut out, db.len() as u64);
+        write_len(&mut out, db.iter().filter(|entry| entry.expire_at.is_some()).count() as u64);
+        for entry in db {
+            if let Some(expire_at) = entry.expire_at {
+                out.push(OPCODE_EXPIRETIME_MS);
+                out.extend_from_slice(&(expire_at as u64).to_le_bytes());
+            }
+            out.push(value_type(&entry.value));
+            write_string(&mut out, &entry.key);
+            write_value(&mut out, &entry.value);
+        }
+    }
+
+    out.push(OPCODE_EOF);
+    let crc = crc64(0, &out);
+    out.extend_from_slice(&crc.to_le_bytes());
+    out
+}
+
+fn value_type(value: &Value) -> u8 {
+    match value {
+        Value::String(_) => TYPE_STRING,
+        Value::List(_) => TYPE_LIST,
+        Value::SortedSet(_) => TYPE_ZSET_2,
+    }
+}
+
+/// Write the body of `value`, which follows its type and key.
+fn write_value(out: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::String(val) => write_string(out, val),
+        Value::List(items) => {
+            write_len(out, items.len() as u64);
+            for item in items {
+                write_string(out, item);
+            }
+        },
+        Value::SortedSet(members) => {
+            write_len(out, members.len() as u64);
+            for (member, score) in members {
+                write_string(out, member);
+                out.extend_from_slice(&score.to_le_bytes());
+            }
+        },
+    }
+}
+
+/// A DUMP payload: the value's type and body, then the footer.
+pub fn dump(value: &Value) -> Vec<u8> {
+    let mut payload = vec![value_type(value)];
+    write_value(&mut payload, value);
+    seal_payload(payload)
+}
+
+/// The value in a DUMP payload. Fails with the Redis error for a bad footer or a body this
+/// server can not store.
+pub fn restore(payload: &[u8]) -> std::result::Result<Value, &'static str> {
+    let mut body = open_payload(payload).ok_or("ERR DUMP payload version or checksum are wrong")?;
+    let bad_format = "ERR Bad data format";
+    let value_type = take(&mut body, 1).ok_or(bad_format)?[0];
+    let value = read_value(&mut body, value_type).flatten().ok_or(bad_format)?;
+    if !body.is_empty() {
+        return Err(bad_format);
+    }
+    Ok(value)
+}
+
+pub fn decode(bytes: &[u8]) -> std::result::Result<Snapshot, String> {
+    let version = bytes.strip_prefix(b"REDIS")
+        .and_then(|rest| rest.get(..4))
+        .and_then(|digits| std::str::from_utf8(digits).ok()?.parse::<u16>().ok())
+        .ok_or("not an RDB file")?;
+    if version > MAX_LOAD_VERSION {
+        return Err(f!("RDB version {version} is newer than the supported {MAX_LOAD_VERSION}"));
+    }
+
+    // checked up front, so a value that cannot be read is known to be unsupported, not corrupt
+    if version >= 5 {
+        let (body, crc) = bytes.split_at(bytes.len().saturating_sub(8).max(9));
+        let crc = u64::from_le_bytes(crc.try_into().map_err(|_| "truncated file")?);
+        // a zero checksum means the writer had checksums turned off
+        if crc != 0 && crc != crc64(0, body) {
+            return Err("checksum mismatch".to_owned());
+        }
+    }
+
+    let corrupt = || "truncated or corrupt data".to_owned();
+    let mut input = &bytes[9..];
+    let mut snapshot = Snapshot::default();
+    let mut expire_at = None;
+    let mut db = 0;
+    loop {
+        let opcode = take(&mut input, 1).ok_or_else(corrupt)?[0];
+        match opcode {
+            OPCODE_EOF => break,
+            OPCODE_SELECTDB => db = read_len(&mut input).ok_or_else(corrupt)?,
+            OPCODE_RESIZEDB => {
+                read_len(&mut input).ok_or_else(corrupt)?;
+                read_len(&mut input).ok_or_else(corrupt)?;
+            },
+            OPCODE_AUX => {
+                read_string(&mut input).ok_or_else(corrupt)?;
+                read_string(&mut input).ok_or_else(corrupt)?;
+            },
+            OPCODE_EXPIRETIME_MS => {
+                let millis = u64::from_le_bytes(take(&mut input, 8).ok_or_else(corrupt)?.try_into().unwrap());
+                expire_at = Some(millis as u128);
+            },
+            OPCODE_EXPIRETIME => {
+                let secs = u32::from_le_bytes(take(&mut input, 4).ok_or_else(corrupt)?.try_into().unwrap());
+                expire_at = Some(secs as u128 * 1000);
+            },
+            OPCODE_IDLE => {
+                read_len(&mut input).ok_or_else(corrupt)?;
+            },
+            OPCODE_FREQ => {
+                take(&mut input, 1).ok_or_else(corrupt)?;
+            },
+            OPCODE_FUNCTION2 => snapshot.functions.push(read_string(&mut input).ok_or_else(corrupt)?),
+            OPCODE_MODULE_AUX => {
+                // module id, when opcode and when, then the module's own data
+                for _ in 0..3 {
+                    read_len(&mut input).ok_or_else(corrupt)?;
+                }
+                skip_module_data(&mut input).ok_or_else(corrupt)?;
+                snapshot.skipped.push("module auxiliary data".to_owned());
+            },
+            value_type => {
+                let Some(type_name) = type_name(value_type) else {
+                    // without knowing the layout there is no finding the next key
+                    snapshot.skipped.push(f!("value type {value_type} and everything after it"));
+                    return Ok(snapshot);
+                };
+                let key = read_string(&mut input).ok_or_else(corrupt)?;
+                let name = String::from_utf8_lossy(&key).into_owned();
+                let value = read_value(&mut input, value_type).ok_or_else(|| f!("{type_name} key '{name}': {}", corrupt()))?;
+                match value {
+                    Some(value) => snapshot.entries.push(Entry { db: db as usize, key, value, expire_at }),
+                    None => snapshot.skipped.push(f!("{type_name} key '{name}'")),
+                }
+                expire_at = None;
+            },
+        }
+    }
+
+    Ok(snapshot)
+}
+
+/// What each value type holds and how it is encoded, `None` for types with no known layout.
+fn type_name(value_type: u8) -> Option<&'static str> {
+    Some(match value_type {
+        TYPE_STRING => "string",
+        TYPE_LIST => "list",
+        TYPE_SET => "set",
+        TYPE_ZSET => "zset",
+        TYPE_HASH => "hash",
+        TYPE_ZSET_2 => "zset",
+        TYPE_MODULE_2 => "module",
+        TYPE_HASH_ZIPMAP => "hash (zipmap)",
+        TYPE_LIST_ZIPLIST => "list (ziplist)",
+        TYPE_SET_INTSET => "set (intset)",
+        TYPE_ZSET_ZIPLIST => "zset (ziplist)",
+        TYPE_HASH_ZIPLIST => "hash (ziplist)",
+        TYPE_LIST_QUICKLIST => "list (quicklist)",
+        TYPE_STREAM_LISTPACKS => "stream",
+        TYPE_HASH_LISTPACK => "hash (listpack)",
+        TYPE_ZSET_LISTPACK => "zset (listpack)",
+        TYPE_LIST_QUICKLIST_2 => "list (quicklist)",
+        TYPE_STREAM_LISTPACKS_2 => "stream",
+        TYPE_SET_LISTPACK => "set (listpack)",
+        TYPE_STREAM_LISTPACKS_3 => "stream",
+        _ => return None,
+    })
+}
+
+/// Read a value of a type `type_name` knows. The inner `None` is a value read past but of a
+/// type this server does not store.
+fn read_value(input: &mut &[u8], value_type: u8) -> Option<Option<Value>> {
+    let value = match value_type {
+        TYPE_STRING => Value::String(read_string(input)?),
+        TYPE_LIST => {
+            let len = read_len(input)?;
+            let mut items = Vec::new();
+            for _ in 0..len {
+                items.push(read_string(input)?);
+            }
+            Value::List(items)
+        },
+        TYPE_LIST_ZIPLIST => Value::List(listpack::ziplist(&read_string(input)?)?),
+        TYPE_LIST_QUICKLIST | TYPE_LIST_QUICKLIST_2 => {
+            let nodes = read_len(input)?;
+            let mut items = Vec::new();
+            for _ in 0..nodes {
+                if value_type == TYPE_LIST_QUICKLIST {
+                    items.extend(listpack::ziplist(&read_string(input)?)?);
+                    continue;
+                }
+                // a plain node is one large element, a packed node a listpack of them
+                match read_len(input)? {
+                    QUICKLIST_NODE_PLAIN => items.push(read_string(input)?),
+                    QUICKLIST_NODE_PACKED => items.extend(listpack::listpack(&read_string(input)?)?),
+                    _ => return None,
+                }
+            }
+            Value::List(items)
+        },
+        TYPE_ZSET | TYPE_ZSET_2 => {
+            let len = read_len(input)?;
+            let mut members = Vec::new();
+            for _ in 0..len {
+                let member = read_string(input)?;
+                let score = if value_type == TYPE_ZSET_2 {
+                    f64::from_le_bytes(take(input, 8)?.try_into().ok()?)
+                } else {
+                    read_string_double(input)?
+                };
+                members.push((member, score));
+            }
+            Value::SortedSet(members)
+        },
+        TYPE_ZSET_ZIPLIST | TYPE_ZSET_LISTPACK => {
+            let blob = read_string(input)?;
+            let elements = if value_type == TYPE_ZSET_ZIPLIST { listpack::ziplist(&blob)? } else { listpack::listpack(&blob)? };
+            let mut members = Vec::new();
+            for pair in elements.chunks_exact(2) {
+                let score = std::str::from_utf8(&pair[1]).ok()?.parse().ok()?;
+                members.push((pair[0].clone(), score));
+            }
+            Value::SortedSet(members)
+        },
+        TYPE_SET | TYPE_HASH => {
+            let len = read_len(input)?;
+            let strings = if value_type == TYPE_HASH { len.checked_mul(2)? } else { len };
+            for _ in 0..strings {
+                read_string(input)?;
+            }
+            return Some(None);
+        },
+        TYPE_HASH_ZIPMAP | TYPE_SET_INTSET | TYPE_HASH_ZIPLIST | TYPE_HASH_LISTPACK | TYPE_SET_LISTPACK => {
+            read_string(input)?;
+            return Some(None);
+        },
+        TYPE_STREAM_LISTPACKS | TYPE_STREAM_LISTPACKS_2 | TYPE_STREAM_LISTPACKS_3 => {
+            skip_stream(input, value_type)?;
+            return Some(None);
+        },
+        TYPE_MODULE_2 => {
+            read_len(input)?;
+            skip_module_data(input)?;
+            return Some(None);
+        },
+        _ => return None,
+    };
+    Some(Some(value))
+}
+
+/// Read a score in the old string form: a length byte then ASCII, with 253 to 255 standing for
+/// NaN and the infinities.
+fn read_string_double(input: &mut &[u8]) -> Option<f64> {
+    match take(input, 1)?[0] {
+        253 => Some(f64::NAN),
+        254 => Some(f64::INFINITY),
+        255 => Some(f64::NEG_INFINITY),
+        len => std::str::from_utf8(take(input, len as usize)?).ok()?.parse().ok(),
+    }
+}
+
+/// Read past a stream: its listpacks, metadata and consumer groups.
+fn skip_stream(input: &mut &[u8], value_type: u8) -> Option<()> {
+    let listpacks = read_len(input)?;
+    for _ in 0..listpacks {
+        read_string(input)?;
+        read_string(input)?;
+    }
+    // length and last id, then first id, max deleted id and entries added
+    let lens = if value_type == TYPE_STREAM_LISTPACKS { 3 } else { 8 };
+    for _ in 0..lens {
+        read_len(input)?;
+    }
+
+    let groups = read_len(input)?;
+    for _ in 0..groups {
+        read_string(input)?;
+        // last delivered id, and entries read since version 2
+        let lens = if value_type == TYPE_STREAM_LISTPACKS { 2 } else { 3 };
+        for _ in 0..lens {
+            read_len(input)?;
+        }
+        // pending entries: raw id, delivery time and delivery count
+        let pending = read_len(input)?;
+        for _ in 0..pending {
+            take(input, 16 + 8)?;
+            read_len(input)?;
+        }
+        let consumers = read_len(input)?;
+        for _ in 0..consumers {
+            read_string(input)?;
+            // seen time, and active time since version 3
+            take(input, if value_type == TYPE_STREAM_LISTPACKS_3 { 16 } else { 8 })?;
+            let pending = read_len(input)?;
+            take(input, usize::try_from(pending).ok()?.checked_mul(16)?)?;
+        }
+    }
+    Some(())
+}
+
+/// Read past module data saved with typed opcodes, up to its end marker.
+fn skip_module_data(input: &mut &[u8]) -> Option<()> {
+    loop {
+        match read_len(input)? {
+            MODULE_OPCODE_EOF => return Some(()),
+            MODULE_OPCODE_SINT | MODULE_OPCODE_UINT => {
+                read_len(input)?;
+            },
+            MODULE_OPCODE_FLOAT => {
+                take(input, 4)?;
+            },
+            MODULE_OPCODE_DOUBLE => {
+                take(input, 8)?;
+            },
+            MODULE_OPCODE_STRING => {
+                read_string(input)?;
+            },
+            _ => return None,
+        }
+    }
+}
+
+const CRC64_POLY: u64 = 0x95ac_9329_ac4b_c9b5;
+
+/// CRC-64/Jones as used by Redis: reflected, zero initial value, no final xor.
+pub fn crc64(mut crc: u64, bytes: &[u8]) -> u64 {
+    for byte in bytes {
+        crc ^= *byte as u64;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ CRC64_POLY } else { crc >> 1 };
+        }
+    }
+    crc
+}
+
+pub fn write_len(out: &mut Vec<u8>, len: u64) {
+    if len < 1 << 6 {
+        out.push(len as u8);
+    } else if len < 1 << 14 {
+        out.push(0x40 | (len >> 8) as u8);
+        out.push(len as u8);
+    } else if len <= u32::MAX as u64 {
+        out.push(0x80);
+        out.extend_from_slice(&(len as u32).to_be_bytes());
+    } else {
+        out.push(0x81);
+        out.extend_from_slice(&len.to_be_bytes());
+    }
+}
+
+pub fn write_string(out: &mut Vec<u8>, bytes: &[u8]) {
+    write_len(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+/// Read a length, advancing `input` past it.
+pub fn read_len(input: &mut &[u8]) -> Option<u64> {
+    let first = take(input, 1)?[0];
+    match first >> 6 {
+        0 => Some((first & 0x3f) as u64),
+        1 => Some((((first & 0x3f) as u64) << 8) | take(input, 1)?[0] as u64),
+        _ if first == 0x80 => Some(u32::from_be_bytes(take(input, 4)?.try_into().ok()?) as u64),
+        _ if first == 0x81 => Some(u64::from_be_bytes(take(input, 8)?.try_into().ok()?)),
+        _ => None,
+    }
+}
+
+/// Read a string, plain, integer or LZF encoded, advancing `input` past it.
+pub fn read_string(input: &mut &[u8]) -> Option<Vec<u8>> {
+    let first = *input.first()?;
+    if first & ENCODED != ENCODED {
+        let len = read_len(input)?;
+        return take(input, usize::try_from(len).ok()?).map(|bytes| bytes.to_vec());
+    }
+
+    take(input, 1)?;
+    let int = match first & 0x3f {
+        ENCODED_INT8 => take(input, 1)?[0] as i8 as i64,
+        ENCODED_INT16 => i16::from_le_bytes(take(input, 2)?.try_into().ok()?) as i64,
+        ENCODED_INT32 => i32::from_le_bytes(take(input, 4)?.try_into().ok()?) as i64,
+        ENCODED_LZF => {
+            let compressed_len = usize::try_from(read_len(input)?).ok()?;
+            let len = usize::try_from(read_len(input)?).ok()?;
+            return lzf::decompress(take(input, compressed_len)?, len);
+        },
+        _ => return None,
+    };
+    Some(int.to_string().into_bytes())
+}
+
+/// Append the RDB version and CRC64 footer of a DUMP style payload.
+pub fn seal_payload(mut payload: Vec<u8>) -> Vec<u8> {
+    payload.extend_from_slice(&RDB_VERSION.to_le_bytes());
+    let crc = crc64(0, &payload);
+    payload.extend_from_slice(&crc.to_le_bytes());
+    payload
+}
+
+/// Check the footer of a DUMP style payload, returning the body without it.
+pub fn open_payload(payload: &[u8]) -> Option<&[u8]> {
+    if payload.len() < 10 {
+        return None;
+    }
+    let (body, crc) = payload.split_at(payload.len() - 8);
+    if crc64(0, body) != u64::from_le_bytes(crc.try_into().ok()?) {
+        return None;
+    }
+    let (body, version) = body.split_at(body.len() - 2);
+    if u16::from_le_bytes(version.try_into().ok()?) > MAX_LOAD_VERSION {
+        return None;
+    }
+    Some(body)
+}
+
+fn take<'a>(input: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if input.len() < len {
+        return None;
+    }
+    let (head, rest) = input.split_at(len);
+    *input = rest;
+    Some(head)
+}
+
+
+// region: --- tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc64_matches_redis() {
+        assert_eq!(crc64(0, b"123456789"), 0xe9c6_d914_c4b8_d9ca);
+    }
+
+    #[test]
+    fn lengths_round_trip() {
+        for len in [0, 63, 64, 16383, 16384, u32::MAX as u64, u32::MAX as u64 + 1] {
+            let mut out = Vec::new();
+            write_len(&mut out, len);
+            let mut input = out.as_slice();
+            assert_eq!(read_len(&mut input), Some(len));
+            assert!(input.is_empty());
+        }
+    }
+
+    #[test]
+    fn snapshots_round_trip() {
+        let snapshot = Snapshot {
+            entries: vec![
+                Entry { db: 0, key: b"s".to_vec(), value: Value::String(b"v".to_vec()), expire_at: Some(1_700_000_000_000) },
+                Entry { db: 0, key: b"l".to_vec(), value: Value::List(vec![b"a".to_vec(), b"b".to_vec()]), expire_at: None },
+                Entry { db: 3, key: b"z".to_vec(), value: Value::SortedSet(vec![(b"m".to_vec(), 1.5)]), expire_at: None },
+            ],
+            functions: vec![b"#!lua name=lib".to_vec()],
+            skipped: Vec::new(),
+        };
+
+        let bytes = encode(&snapshot);
+        assert!(bytes.starts_with(b"REDIS0011"));
+        let decoded = decode(&bytes).unwrap();
+        assert_eq!(decoded.entries.len(), 3);
+        assert_eq!(decoded.functions, snapshot.functions);
+        assert!(matches!(&decoded.entries[0].value, Value::String(val) if val == b"v"));
+        assert_eq!(decoded.entries[0].expire_at, Some(1_700_000_000_000));
+        assert!(matches!(&decoded.entries[1].value, Value::List(items) if items.len() == 2));
+        assert!(matches!(&decoded.entries[2].value, Value::SortedSet(members) if members[0].1 == 1.5));
+        assert_eq!(decoded.entries.iter().map(|entry| entry.db).collect::<Vec<_>>(), [0, 0, 3]);
+
+        let mut corrupt = bytes.clone();
+        corrupt[20] ^= 1;
+        assert!(decode(&corrupt).is_err());
+    }
+
+    #[test]
+    fn encoded_strings() {
+        let mut input: &[u8] = &[0xc0, 0xfe, 0xc1, 0x39, 0x30, 0xc2, 0x00, 0x00, 0x00, 0x80];
+        assert_eq!(read_string(&mut input), Some(b"-2".to_vec()));
+        assert_eq!(read_string(&mut input), Some(b"12345".to_vec()));
+        assert_eq!(read_string(&mut input), Some(b"-2147483648".to_vec()));
+
+        let mut input: &[u8] = &[0xc3, 0x05, 0x0a, 0x00, b'a', 0xe0, 0x00, 0x00];
+        assert_eq!(read_string(&mut input), Some(b"aaaaaaaaaa".to_vec()));
+        assert!(input.is_empty());
+    }
+
+    #[test]
+    fn restore_refuses_a_huge_claimed_length() {
+        // a string of one compressed literal claiming to expand to 2^45 bytes
+        let mut body = vec![0x00, 0xc3, 0x02];
+        write_len(&mut body, 1 << 45);
+        body.extend_from_slice(&[0x00, b'a']);
+        assert_eq!(restore(&seal_payload(body)).err(), Some("ERR Bad data format"));
+    }
+
+    #[test]
+    fn redis_snapshots_load_what_they_can() {
+        let mut bytes = b"REDIS0011".to_vec();
+        bytes.extend_from_slice(&[OPCODE_AUX, 0x0a]);
+        bytes.extend_from_slice(b"redis-bits");
+        bytes.extend_from_slice(&[0xc0, 64, OPCODE_SELECTDB, 0, OPCODE_EXPIRETIME_MS]);
+        bytes.extend_from_slice(&1_700_000_000_000u64.to_le_bytes());
+        // an int encoded string, then a quicklist with one packed listpack node
+        bytes.extend_from_slice(&[TYPE_STRING, 1, b'n', 0xc1, 0xe8, 0x03]);
+        bytes.extend_from_slice(&[TYPE_LIST_QUICKLIST_2, 1, b'l', 1, 2, 12, 0, 0, 0, 0, 2, 0, 0x81, b'x', 2, 0x05, 1, 0xff]);
+        // a set of two, which this server does not store
+        bytes.extend_from_slice(&[TYPE_SET, 1, b's', 2, 1, b'a', 1, b'b']);
+        bytes.extend_from_slice(&[TYPE_ZSET_LISTPACK, 1, b'z', 12, 0, 0, 0, 0, 2, 0, 0x81, b'm', 2, 0x03, 1, 0xff]);
+        bytes.push(OPCODE_EOF);
+        bytes.extend_from_slice(&0u64.to_le_bytes());
+
+        let snapshot = decode(&bytes).unwrap();
+        assert_eq!(snapshot.entries.len(), 3);
+        assert!(matches!(&snapshot.entries[0].value, Value::String(val) if val == b"1000"));
+        assert_eq!(snapshot.entries[0].expire_at, Some(1_700_000_000_000));
+        assert!(matches!(&snapshot.entries[1].value, Value::List(items) if *items == [b"x".to_vec(), b"5".to_vec()]));
+        assert_eq!(snapshot.entries[1].expire_at, None);
+        assert!(matches!(&snapshot.entries[2].value, Value::SortedSet(members) if members[0] == (b"m".to_vec(), 3.0)));
+        assert_eq!(snapshot.skipped, vec!["set key 's'".to_owned()]);
+
+        // an unknown type stops loading but keeps what came before it
+        let unknown = [&bytes[..bytes.len() - 9], &[42, 1, b'k', OPCODE_EOF, 0, 0, 0, 0, 0, 0, 0, 0]].concat();
+        let snapshot = decode(&unknown).unwrap();
+        assert_eq!(snapshot.entries.len(), 3);
+        assert_eq!(snapshot.skipped.last().unwrap(), "value type 42 and everything after it");
+    }
+
+    #[test]
+    fn dump_payloads_match_redis() {
+        // DUMP of the string "bar" as Redis 7.2 returns it
+        let mut expected = vec![0x00, 0x03, b'b', b'a', b'r', 0x0b, 0x00];
+        expected.extend_from_slice(&crc64(0, &expected).to_le_bytes());
+        assert_eq!(dump(&Value::String(b"bar".to_vec())), expected);
+
+        let list = dump(&Value::List(vec![b"a".to_vec(), b"b".to_vec()]));
+        assert!(matches!(restore(&list), Ok(Value::List(items)) if items.len() == 2));
+        // a set, which this server does not store
+        let set = seal_payload(vec![TYPE_SET, 1, 1, b'a']);
+        assert_eq!(restore(&set).err(), Some("ERR Bad data format"));
+        let mut corrupt = list.clone();
+        corrupt[2] ^= 1;
+        assert_eq!(restore(&corrupt).err(), Some("ERR DUMP payload version or checksum are wrong"));
+    }
+
+    #[test]
+    fn payload_footer_is_checked() {
+        let mut body = Vec::new();
+        write_string(&mut body, b"hello");
+        let mut payload = seal_payload(body.clone());
+        assert_eq!(open_payload(&payload), Some(body.as_slice()));
+
+        payload[0] ^= 1;
+        assert_eq!(open_payload(&payload), None);
+    }
+}
+// endregion: --- tests
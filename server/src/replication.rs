@@ -0,0 +1,665 @@
+//! Primary/replica replication. A primary sends its stream of writes to every replica and keeps
+//! the recent part of it in a backlog, so a replica that reconnects can continue from its offset
+//! (PSYNC) instead of taking a whole new snapshot. A replica runs a link task that syncs with its
+//! primary and then applies the stream.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+use utils::deserializer::deserialize_partial;
+use utils::prelude::*;
+use utils::serializer::serialize;
+use utils::DataType;
+
+use crate::pubsub::{self, Pushes};
+use crate::scripting;
+use crate::shards::{Shards, Shared};
+
+/// How often a primary pings its replicas, so they can tell a quiet link from a dead one.
+const PING_PERIOD: Duration = Duration::from_secs(10);
+/// How long a replica waits on a silent primary before reconnecting.
+const TIMEOUT: Duration = Duration::from_secs(60);
+/// How often a replica reports its offset with REPLCONF ACK.
+const ACK_PERIOD: Duration = Duration::from_secs(1);
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+static NEXT_REPLID: AtomicU64 = AtomicU64::new(0);
+
+/// Progress of a replica's link to its primary, as ROLE names it.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum LinkState {
+    Connect,
+    Connecting,
+    Sync,
+    Connected,
+}
+
+impl LinkState {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Connect => "connect",
+            Self::Connecting => "connecting",
+            Self::Sync => "sync",
+            Self::Connected => "connected",
+        }
+    }
+}
+
+struct Primary {
+    host: String,
+    port: u16,
+    /// Identifies the link task serving this primary; a task whose id is stale exits.
+    link_id: u64,
+    state: LinkState,
+    last_io: Instant,
+}
+
+struct Replica {
+    pushes: Pushes,
+    ip: String,
+    /// The port the replica listens on, from REPLCONF listening-port.
+    port: u16,
+    ack_offset: u64,
+    /// How far the replica's AOF is fsynced, from REPLCONF ACK offset FACK offset.
+    fack_offset: u64,
+    last_ack: Instant,
+}
+
+/// A WAIT or WAITAOF blocked until enough replicas, and for WAITAOF the local AOF, reach
+/// `offset`.
+pub struct Wait {
+    offset: u64,
+    /// Set for WAITAOF.
+    numlocal: Option<i64>,
+    numreplicas: i64,
+    deadline: Option<Instant>,
+}
+
+/// The recent part of the replication stream, ending at the current offset.
+struct Backlog {
+    bytes: VecDeque<u8>,
+    size: usize,
+}
+
+pub struct Replication {
+    replid: String,
+    /// The previous replication id, still valid up to `second_offset` for replicas that
+    /// followed the old primary.
+    replid2: String,
+    second_offset: i64,
+    /// Bytes of replication stream produced, or applied on a replica.
+    offset: u64,
+    /// Created once the first replica attaches; no stream is kept before that.
+    backlog: Option<Backlog>,
+    replicas: BTreeMap<u64, Replica>,
+    primary: Option<Primary>,
+    next_link_id: u64,
+    last_ping: Instant,
+    /// Signalled on every ACK and local fsync, for blocked WAIT and WAITAOF calls.
+    waiters: watch::Sender<()>,
+    /// The database the last write fed in was in, `None` when the next one must be preceded
+    /// by a SELECT regardless.
+    selected_db: Option<usize>,
+}
+
+impl Replication {
+    pub fn new() -> Self {
+        Self {
+            replid: new_replid(),
+            replid2: "0".repeat(40),
+            second_offset: -1,
+            offset: 0,
+            backlog: None,
+            replicas: BTreeMap::new(),
+            primary: None,
+            next_link_id: 1,
+            last_ping: Instant::now(),
+            waiters: watch::channel(()).0,
+            selected_db: None,
+        }
+    }
+
+    pub fn is_replica(&self) -> bool {
+        self.primary.is_some()
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Whether writes are being recorded into a stream.
+    pub fn is_active(&self) -> bool {
+        self.backlog.is_some()
+    }
+
+    pub fn selected_db(&self) -> Option<usize> {
+        self.selected_db
+    }
+
+    pub fn set_selected_db(&mut self, db: Option<usize>) {
+        self.selected_db = db;
+    }
+
+    /// Add `bytes` to the stream: the backlog, and every attached replica.
+    pub fn feed(&mut self, bytes: &[u8]) {
+        let Some(backlog) = &mut self.backlog else { return };
+        self.offset += bytes.len() as u64;
+        backlog.bytes.extend(bytes);
+        let excess = backlog.bytes.len().saturating_sub(backlog.size);
+        backlog.bytes.drain(..excess);
+
+        for replica in self.replicas.values() {
+            // A closed queue belongs to a replica that is disconnecting
+            let _ = replica.pushes.send(bytes.to_vec());
+        }
+    }
+
+    /// Keep replica links from looking idle, called from the server cron.
+    pub fn ping_replicas(&mut self) {
+        if self.last_ping.elapsed() < PING_PERIOD {
+            return;
+        }
+        self.last_ping = Instant::now();
+        if !self.replicas.is_empty() && !self.is_replica() {
+            self.feed(&pubsub::frame(&[b"ping"]));
+        }
+    }
+
+    /// The stream from `offset` on, when a replica that followed `replid` up to just before
+    /// `offset` can continue from the backlog.
+    pub fn continue_from(&self, replid: &str, offset: u64) -> Option<Vec<u8>> {
+        let backlog = self.backlog.as_ref()?;
+        let known = replid == self.replid || (replid == self.replid2 && offset as i64 <= self.second_offset);
+        let first = self.offset + 1 - backlog.bytes.len() as u64;
+        if !known || offset < first || offset > self.offset + 1 {
+            return None;
+        }
+        Some(backlog.bytes.iter().skip((offset - first) as usize).copied().collect())
+    }
+
+    /// Bytes of the stream held in the backlog.
+    pub fn backlog_len(&self) -> usize {
+        self.backlog.as_ref().map_or(0, |backlog| backlog.bytes.len())
+    }
+
+    /// Start the backlog if there is none, for a replica about to attach.
+    pub fn start_backlog(&mut self, size: usize) {
+        if self.backlog.is_none() {
+            self.backlog = Some(Backlog { bytes: VecDeque::new(), size });
+        }
+    }
+
+    /// Set the backlog size, dropping the oldest part of the stream when it shrinks.
+    pub fn resize_backlog(&mut self, size: usize) {
+        if let Some(backlog) = &mut self.backlog {
+            backlog.size = size;
+            let excess = backlog.bytes.len().saturating_sub(size);
+            backlog.bytes.drain(..excess);
+        }
+    }
+
+    pub fn replid(&self) -> &str {
+        &self.replid
+    }
+
+    pub fn add_replica(&mut self, id: u64, pushes: &Pushes, ip: String, port: u16) {
+        let replica = Replica { pushes: pushes.clone(), ip, port, ack_offset: 0, fack_offset: 0, last_ack: Instant::now() };
+        self.replicas.insert(id, replica);
+    }
+
+    pub fn remove_replica(&mut self, id: u64) {
+        self.replicas.remove(&id);
+    }
+
+    /// Record REPLCONF ACK from replica `id`.
+    pub fn ack(&mut self, id: u64, offset: u64, fack: Option<u64>) {
+        if let Some(replica) = self.replicas.get_mut(&id) {
+            replica.ack_offset = offset;
+            if let Some(fack) = fack {
+                replica.fack_offset = fack;
+            }
+            replica.last_ack = Instant::now();
+            self.wake_waiters();
+        }
+    }
+
+    /// Replicas that acknowledged `offset`, or that fsynced it to their AOF when `fsynced`.
+    pub fn acked(&self, offset: u64, fsynced: bool) -> usize {
+        self.replicas.values()
+            .filter(|replica| if fsynced { replica.fack_offset >= offset } else { replica.ack_offset >= offset })
+            .count()
+    }
+
+    /// Ask every replica to ACK right away with REPLCONF GETACK.
+    pub fn request_acks(&mut self) {
+        if !self.replicas.is_empty() && !self.is_replica() {
+            self.feed(&pubsub::frame(&[b"replconf", b"getack", b"*"]));
+        }
+    }
+
+    pub fn wake_waiters(&self) {
+        self.waiters.send_modify(|_| ());
+    }
+
+    /// Follow the primary at `host:port`, returning the id for its new link task, or `None`
+    /// when already following it.
+    pub fn follow(&mut self, host: &str, port: u16) -> Option<u64> {
+        if self.primary.as_ref().is_some_and(|primary| primary.host == host && primary.port == port) {
+            return None;
+        }
+
+        let link_id = self.next_link_id;
+        self.next_link_id += 1;
+        self.primary = Some(Primary {
+            host: host.to_owned(),
+            port,
+            link_id,
+            state: LinkState::Connect,
+            last_io: Instant::now(),
+        });
+        Some(link_id)
+    }
+
+    /// Stop following a primary and take writes. A new replication id starts here, while
+    /// replicas of the old primary may still continue with the old one up to this offset.
+    pub fn promote(&mut self) {
+        if self.primary.take().is_none() {
+            return;
+        }
+        self.replid2 = std::mem::replace(&mut self.replid, new_replid());
+        self.second_offset = self.offset as i64 + 1;
+    }
+
+    /// The primary a link task should connect to, `None` once the link is no longer wanted.
+    fn link_target(&self, link_id: u64) -> Option<(String, u16)> {
+        let primary = self.primary.as_ref().filter(|primary| primary.link_id == link_id)?;
+        Some((primary.host.clone(), primary.port))
+    }
+
+    fn set_link_state(&mut self, link_id: u64, state: LinkState) {
+        if let Some(primary) = self.primary.as_mut().filter(|primary| primary.link_id == link_id) {
+            primary.state = state;
+            primary.last_io = Instant::now();
+        }
+    }
+
+    /// After a full sync: the stream continues `replid` from `offset`.
+    pub fn synced(&mut self, replid: &str, offset: u64, backlog_size: usize) {
+        self.replid = replid.to_owned();
+        self.replid2 = "0".repeat(40);
+        self.second_offset = -1;
+        self.offset = offset;
+        self.backlog = Some(Backlog { bytes: VecDeque::new(), size: backlog_size });
+    }
+
+    /// After a partial sync the primary may have moved to a new replication id.
+    fn continued(&mut self, replid: Option<&str>) {
+        match replid {
+            Some(replid) if replid != self.replid => {
+                self.replid2 = std::mem::replace(&mut self.replid, replid.to_owned());
+                self.second_offset = self.offset as i64 + 1;
+            },
+            _ => (),
+        }
+    }
+
+    /// ROLE reply.
+    pub fn role(&self) -> DataType {
+        let bulk = |text: &str| DataType::BulkString(Some(text.as_bytes().to_vec()));
+        match &self.primary {
+            Some(primary) => DataType::Array(Some(vec![
+                bulk("slave"),
+                bulk(&primary.host),
+                DataType::Integer(primary.port as i64),
+                bulk(primary.state.name()),
+                DataType::Integer(self.offset as i64),
+            ])),
+            None => {
+                let replicas = self.replicas.values()
+                    .map(|replica| DataType::Array(Some(vec![
+                        bulk(&replica.ip),
+                        bulk(&replica.port.to_string()),
+                        bulk(&replica.ack_offset.to_string()),
+                    ])))
+                    .collect();
+                DataType::Array(Some(vec![bulk("master"), DataType::Integer(self.offset as i64), DataType::Array(Some(replicas))]))
+            },
+        }
+    }
+
+    /// The `# Replication` section of INFO.
+    pub fn info(&self, read_only: bool) -> String {
+        let mut info = String::from("# Replication\r\n");
+        match &self.primary {
+            Some(primary) => {
+                let up = primary.state == LinkState::Connected;
+                info += "role:slave\r\n";
+                info += &f!("master_host:{}\r\nmaster_port:{}\r\n", primary.host, primary.port);
+                info += &f!("master_link_status:{}\r\n", if up { "up" } else { "down" });
+                info += &f!("master_last_io_seconds_ago:{}\r\n", if up { primary.last_io.elapsed().as_secs() as i64 } else { -1 });
+                info += &f!("master_sync_in_progress:{}\r\n", (primary.state == LinkState::Sync) as u8);
+                info += &f!("slave_read_repl_offset:{}\r\nslave_repl_offset:{}\r\n", self.offset, self.offset);
+                info += &f!("slave_read_only:{}\r\n", read_only as u8);
+            },
+            None => info += "role:master\r\n",
+        }
+
+        info += &f!("connected_slaves:{}\r\n", self.replicas.len());
+        for (index, replica) in self.replicas.values().enumerate() {
+            info += &f!(
+                "slave{index}:ip={},port={},state=online,offset={},lag={}\r\n",
+                replica.ip, replica.port, replica.ack_offset, replica.last_ack.elapsed().as_secs(),
+            );
+        }
+
+        let (first, histlen) = match &self.backlog {
+            Some(backlog) => (self.offset + 1 - backlog.bytes.len() as u64, backlog.bytes.len()),
+            None => (0, 0),
+        };
+        info += &f!("master_replid:{}\r\nmaster_replid2:{}\r\n", self.replid, self.replid2);
+        info += &f!("master_repl_offset:{}\r\nsecond_repl_offset:{}\r\n", self.offset, self.second_offset);
+        info += &f!("repl_backlog_active:{}\r\n", self.backlog.is_some() as u8);
+        info += &f!("repl_backlog_size:{}\r\n", self.backlog.as_ref().map_or(0, |backlog| backlog.size));
+        info += &f!("repl_backlog_first_byte_offset:{first}\r\nrepl_backlog_histlen:{histlen}\r\n");
+        info
+    }
+}
+
+impl Wait {
+    /// Wait for `numreplicas` replicas to reach the current offset, and with `numlocal` for
+    /// the local AOF too, WAITAOF style. A timeout of 0 waits for good.
+    pub fn new(offset: u64, numlocal: Option<i64>, numreplicas: i64, timeout_ms: u64) -> Self {
+        let deadline = (timeout_ms > 0).then(|| Instant::now() + Duration::from_millis(timeout_ms));
+        Self { offset, numlocal, numreplicas, deadline }
+    }
+
+    /// The reply as things stand, and whether it can be sent yet.
+    pub fn progress(&self, shared: &Shared) -> (Vec<u8>, bool) {
+        match self.numlocal {
+            None => {
+                let replicas = shared.replication().acked(self.offset, false);
+                let reply = serialize(&DataType::Integer(replicas as i64)).unwrap();
+                (reply, replicas as i64 >= self.numreplicas)
+            },
+            Some(numlocal) => {
+                let local = shared.aof_fsynced_offset().is_some_and(|fsynced| fsynced >= self.offset) as i64;
+                let replicas = shared.replication().acked(self.offset, true) as i64;
+                let reply = serialize(&DataType::Array(Some(vec![DataType::Integer(local), DataType::Integer(replicas)]))).unwrap();
+                (reply, local >= numlocal && replicas >= self.numreplicas)
+            },
+        }
+    }
+}
+
+/// Block on `wait` until it is satisfied or times out, then reply.
+pub async fn wait(redis: &Arc<Shards>, wait: Wait) -> Vec<u8> {
+    let mut waiters = {
+        let (reply, done) = wait.progress(redis.shared());
+        if done {
+            return reply;
+        }
+        let mut replication = redis.shared().replication();
+        replication.request_acks();
+        replication.waiters.subscribe()
+    };
+
+    loop {
+        let changed = match wait.deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline.into(), waiters.changed()).await.ok(),
+            None => Some(waiters.changed().await),
+        };
+        let (reply, done) = wait.progress(redis.shared());
+        if done || changed.is_none() {
+            return reply;
+        }
+    }
+}
+
+/// A fresh 40 character replication id.
+fn new_replid() -> String {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos();
+    let seed = f!("{nanos}-{}-{}", std::process::id(), NEXT_REPLID.fetch_add(1, Ordering::Relaxed));
+    scripting::sha1_hex(seed.as_bytes())
+}
+
+/// A replica's link to its primary: connect, sync, then apply the stream, reconnecting after
+/// errors until the link is replaced by another REPLICAOF.
+pub async fn run_link(redis: Arc<Shards>, link_id: u64) {
+    loop {
+        let target = redis.shared().replication().link_target(link_id);
+        let Some((host, port)) = target else { return };
+        redis.shared().replication().set_link_state(link_id, LinkState::Connecting);
+
+        match sync_with(&redis, link_id, &host, port).await {
+            Ok(()) => return,
+            Err(e) => println!("Replication link to {}:{} failed: {}", host, port, e),
+        }
+        redis.shared().replication().set_link_state(link_id, LinkState::Connect);
+        tokio::time::sleep(RETRY_DELAY).await;
+    }
+}
+
+/// One connection to the primary. Returns `Ok` only when the link is no longer wanted.
+async fn sync_with(redis: &Arc<Shards>, link_id: u64, host: &str, port: u16) -> std::result::Result<(), String> {
+    let mut socket = TcpStream::connect((host, port)).await.map_err(|e| e.to_string())?;
+    let (reader, mut writer) = socket.split();
+    let mut reader = BufReader::new(reader);
+
+    let listening_port = redis.lock(vec![0]).config().port;
+    let (replid, offset) = {
+        let replication = redis.shared().replication();
+        (replication.replid.clone(), replication.offset + 1)
+    };
+    let handshake: [&[&str]; 3] = [
+        &["ping"],
+        &["replconf", "listening-port", &listening_port.to_string()],
+        &["replconf", "capa", "psync2"],
+    ];
+    for args in handshake {
+        send(&mut writer, args).await?;
+        let reply = read_line(&mut reader).await?;
+        if reply.starts_with('-') && !reply.starts_with("-NOAUTH") {
+            return Err(f!("handshake refused: {reply}"));
+        }
+    }
+
+    redis.shared().replication().set_link_state(link_id, LinkState::Sync);
+    send(&mut writer, &["psync", &replid, &offset.to_string()]).await?;
+    let reply = read_line(&mut reader).await?;
+    let fields: Vec<&str> = reply.split_whitespace().collect();
+    match fields.as_slice() {
+        ["+FULLRESYNC", replid, offset] => {
+            let offset: u64 = offset.parse().map_err(|_| f!("bad FULLRESYNC reply: {reply}"))?;
+            let header = read_line(&mut reader).await?;
+            let len: usize = header.strip_prefix('$').and_then(|len| len.parse().ok())
+                .ok_or_else(|| f!("bad snapshot header: {header}"))?;
+            let mut rdb = vec![0; len];
+            reader.read_exact(&mut rdb).await.map_err(|e| e.to_string())?;
+
+            let mut dict = redis.lock_all();
+            if redis.shared().replication().link_target(link_id).is_none() {
+                return Ok(());
+            }
+            dict.full_sync(&rdb, replid, offset)?;
+            println!("MASTER <-> REPLICA sync: Finished with success");
+        },
+        ["+CONTINUE", rest @ ..] => {
+            redis.shared().replication().continued(rest.first().copied());
+            println!("MASTER <-> REPLICA sync: Master accepted a Partial Resynchronization.");
+        },
+        _ => return Err(f!("PSYNC refused: {reply}")),
+    }
+    redis.shared().replication().set_link_state(link_id, LinkState::Connected);
+
+    let mut pending: Vec<u8> = Vec::new();
+    let mut ack = tokio::time::interval(ACK_PERIOD);
+    let mut last_io = Instant::now();
+    loop {
+        let mut buffer = vec![0; 16 * 1024];
+        let read = tokio::select! {
+            read = reader.read(&mut buffer) => read.map_err(|e| e.to_string())?,
+            _ = ack.tick() => {
+                if last_io.elapsed() > TIMEOUT {
+                    return Err("timeout, no data from the primary".to_owned());
+                }
+                if !send_ack(redis, link_id, &mut writer).await? {
+                    return Ok(());
+                }
+                continue;
+            },
+        };
+        if read == 0 {
+            return Err("connection closed by the primary".to_owned());
+        }
+        last_io = Instant::now();
+        pending.extend_from_slice(&buffer[..read]);
+
+        let getack = {
+            // the stream may write to any key, so a batch is applied with every shard locked
+            let mut dict = redis.lock_all();
+            if redis.shared().replication().link_target(link_id).is_none() {
+                return Ok(());
+            }
+            redis.shared().replication().set_link_state(link_id, LinkState::Connected);
+            let mut getack = false;
+            loop {
+                match deserialize_partial(&pending) {
+                    Ok((d_command, used)) => {
+                        let raw: Vec<u8> = pending.drain(..used).collect();
+                        if is_getack(&d_command) {
+                            // counts toward the offset, but is no write to keep
+                            redis.shared().replication().feed(&raw);
+                            getack = true;
+                        } else {
+                            dict.apply_replicated(d_command, &raw);
+                        }
+                    },
+                    Err(Error::Incomplete) | Err(Error::EmptyInput) => break,
+                    Err(e) => return Err(f!("bad replication stream: {e}")),
+                }
+            }
+            getack
+        };
+        if getack && !send_ack(redis, link_id, &mut writer).await? {
+            return Ok(());
+        }
+    }
+}
+
+/// REPLCONF ACK with the offset applied and the offset fsynced to the AOF. Returns false when
+/// the link is no longer wanted.
+async fn send_ack<W: AsyncWriteExt + Unpin>(redis: &Arc<Shards>, link_id: u64, writer: &mut W) -> std::result::Result<bool, String> {
+    let (offset, fsynced) = {
+        let replication = redis.shared().replication();
+        if replication.link_target(link_id).is_none() {
+            return Ok(false);
+        }
+        let offset = replication.offset();
+        drop(replication);
+        (offset, redis.shared().aof_fsynced_offset().unwrap_or(0))
+    };
+    send(writer, &["replconf", "ack", &offset.to_string(), "fack", &fsynced.to_string()]).await?;
+    Ok(true)
+}
+
+fn is_getack(d_command: &DataType) -> bool {
+    let DataType::Array(Some(arr)) = d_command else { return false };
+    let arg = |index: usize| match arr.get(index) {
+        Some(DataType::BulkString(Some(val))) => String::from_utf8_lossy(val).to_ascii_lowercase(),
+        _ => String::new(),
+    };
+    arg(0) == "replconf" && arg(1) == "getack"
+}
+
+async fn send<W: AsyncWriteExt + Unpin>(writer: &mut W, args: &[&str]) -> std::result::Result<(), String> {
+    let args = args.iter().map(|arg| DataType::BulkString(Some(arg.as_bytes().to_vec()))).collect();
+    let bytes = serialize(&DataType::Array(Some(args))).unwrap();
+    writer.write_all(&bytes).await.map_err(|e| e.to_string())
+}
+
+async fn read_line<R: AsyncBufReadExt + Unpin>(reader: &mut R) -> std::result::Result<String, String> {
+    let mut line = String::new();
+    if reader.read_line(&mut line).await.map_err(|e| e.to_string())? == 0 {
+        return Err("connection closed by the primary".to_owned());
+    }
+    Ok(line.trim_end().to_owned())
+}
+
+
+// region: --- tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::tests::{command, connect, server};
+    use crate::client::Blocked;
+    use tokio::sync::mpsc::unbounded_channel;
+
+    #[test]
+    fn backlog_serves_recent_offsets() {
+        let mut replication = Replication::new();
+        let replid = replication.replid().to_owned();
+        replication.feed(b"lost");
+        assert_eq!(replication.offset(), 0);
+
+        replication.start_backlog(8);
+        let (pushes, mut received) = unbounded_channel();
+        replication.add_replica(7, &pushes, "127.0.0.1".to_owned(), 6380);
+        replication.feed(b"abcdef");
+        replication.feed(b"ghij");
+        assert_eq!(replication.offset(), 10);
+        assert_eq!(received.try_recv().unwrap(), b"abcdef");
+
+        assert_eq!(replication.continue_from(&replid, 9), Some(b"ij".to_vec()));
+        assert_eq!(replication.continue_from(&replid, 11), Some(Vec::new()));
+        // the first two bytes no longer fit in the backlog
+        assert_eq!(replication.continue_from(&replid, 2), None);
+        assert_eq!(replication.continue_from("other", 9), None);
+    }
+
+    #[test]
+    fn promotion_keeps_the_old_id_valid() {
+        let mut replication = Replication::new();
+        replication.synced("a".repeat(40).as_str(), 100, 64);
+        replication.follow("127.0.0.1", 6379);
+        replication.promote();
+
+        assert!(!replication.is_replica());
+        assert_ne!(replication.replid(), "a".repeat(40));
+        assert!(replication.continue_from(&"a".repeat(40), 101).is_some());
+        assert!(replication.continue_from(&"a".repeat(40), 102).is_none());
+    }
+
+    #[test]
+    fn wait_blocks_until_replicas_ack() {
+        let redis = server();
+        let mut client = connect();
+        let mut replica = connect();
+
+        assert_eq!(client.handle(command(&["wait", "0", "0"]), &redis), b"");
+        let Some(Blocked::Wait(wait)) = client.take_blocked() else { panic!("not waiting") };
+        assert_eq!(wait.progress(redis.shared()), (b":0\r\n".to_vec(), true));
+
+        assert!(replica.handle(command(&["psync", "?", "-1"]), &redis).starts_with(b"+FULLRESYNC"));
+        client.handle(command(&["set", "a", "1"]), &redis);
+        client.handle(command(&["wait", "1", "100"]), &redis);
+        let Some(Blocked::Wait(wait)) = client.take_blocked() else { panic!("not waiting") };
+        assert_eq!(wait.progress(redis.shared()), (b":0\r\n".to_vec(), false));
+
+        let offset = redis.shared().replication().offset().to_string();
+        assert_eq!(replica.handle(command(&["replconf", "ack", &offset, "fack", "0"]), &redis), b"");
+        assert_eq!(wait.progress(redis.shared()), (b":1\r\n".to_vec(), true));
+
+        // without an AOF nothing is fsynced locally, and the replica's AOF is behind
+        assert!(client.handle(command(&["waitaof", "1", "0", "0"]), &redis).starts_with(b"-ERR WAITAOF cannot be used"));
+        client.handle(command(&["waitaof", "0", "1", "0"]), &redis);
+        let Some(Blocked::Wait(wait)) = client.take_blocked() else { panic!("not waiting") };
+        assert_eq!(wait.progress(redis.shared()), (b"*2\r\n:0\r\n:0\r\n".to_vec(), false));
+        assert_eq!(client.handle(command(&["wait", "1", "-1"]), &redis), b"-ERR timeout is negative\r\n");
+    }
+}
+// endregion: --- tests
@@ -0,0 +1,185 @@
+//! Stateless cursors for SCAN and its per-collection variants.
+//!
+//! A cursor is a position in the 64 bit hash space of the elements. Each call returns the
+//! elements hashing at or after the cursor, in hash order, along with the cursor to go on
+//! from. The order depends only on the elements themselves, not on the table holding them,
+//! so an element present for the whole iteration is returned exactly once however the table
+//! grows, shrinks or rehashes between calls. The iteration is over when the cursor is 0.
+
+use std::collections::BTreeSet;
+
+/// FNV-1a, fixed so cursors stay valid across calls and restarts.
+pub fn hash(element: &[u8]) -> u64 {
+    element.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3))
+}
+
+/// The next `count` of `elements` at or after `cursor`, with the cursor following them.
+/// Elements sharing a hash are returned together, so a page may hold a few more.
+pub fn page<'a, T>(elements: impl Iterator<Item = (&'a [u8], T)>, cursor: u64, count: usize) -> (u64, Vec<T>) {
+    page_hashed(elements.map(|(element, item)| (hash(element), item)), cursor, count)
+}
+
+/// `page` over elements given with their hash.
+pub fn page_hashed<T>(elements: impl Iterator<Item = (u64, T)>, cursor: u64, count: usize) -> (u64, Vec<T>) {
+    let count = count.max(1);
+    let mut found: Vec<(u64, T)> = elements.filter(|(hash, _)| *hash >= cursor).collect();
+
+    let mut next = 0;
+    if found.len() > count {
+        found.select_nth_unstable_by_key(count - 1, |(hash, _)| *hash);
+        let last = found[count - 1].0;
+        found.retain(|(hash, _)| *hash <= last);
+        next = last.checked_add(1).unwrap_or(0);
+    }
+    found.sort_unstable_by_key(|(hash, _)| *hash);
+    (next, found.into_iter().map(|(_, item)| item).collect())
+}
+
+/// The elements of `ordered`, kept by hash, that a page of `count` at `cursor` may take: the
+/// first `count` + 1 at or after it, so `page_hashed` can tell whether more follow, and any
+/// sharing a hash with the last. Found in O(count + log n) however large the set. `T`'s
+/// default must be its least value, as for strings.
+pub fn candidates<T: Ord + Default>(ordered: &BTreeSet<(u64, T)>, cursor: u64, count: usize) -> Vec<&(u64, T)> {
+    let take = count.max(1) + 1;
+    let mut found: Vec<&(u64, T)> = Vec::new();
+    for entry in ordered.range((cursor, T::default())..) {
+        if found.len() >= take && found.last().is_some_and(|last| last.0 != entry.0) {
+            break;
+        }
+        found.push(entry);
+    }
+    found
+}
+
+// region: --- tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::tests::{command, connect, server};
+    use utils::deserializer::deserialize;
+    use utils::DataType;
+
+    fn scan_all(elements: &[String], count: usize) -> Vec<String> {
+        let mut cursor = 0;
+        let mut seen = Vec::new();
+        loop {
+            let (next, page) = page(elements.iter().map(|element| (element.as_bytes(), element.clone())), cursor, count);
+            seen.extend(page);
+            if next == 0 {
+                return seen;
+            }
+            cursor = next;
+        }
+    }
+
+    #[test]
+    fn every_element_once() {
+        let elements: Vec<String> = (0..500).map(|i| format!("key:{i}")).collect();
+        for count in [1, 7, 10, 499, 500, 1000] {
+            let mut seen = scan_all(&elements, count);
+            seen.sort();
+            let mut expected = elements.clone();
+            expected.sort();
+            assert_eq!(seen, expected);
+        }
+        assert_eq!(page(std::iter::empty::<(&[u8], ())>(), 0, 10), (0, Vec::new()));
+    }
+
+    #[test]
+    fn survives_changes_between_calls() {
+        let mut elements: Vec<String> = (0..100).map(|i| format!("stable:{i}")).collect();
+        let (mut cursor, mut seen) = page(elements.iter().map(|element| (element.as_bytes(), element.clone())), 0, 10);
+        let mut added = 0;
+        while cursor != 0 {
+            // the collection grows tenfold and loses other elements while the iteration runs
+            elements.extend((0..100).map(|i| format!("added:{added}:{i}")));
+            elements.retain(|element| !element.starts_with("added:") || !element.ends_with('7'));
+            added += 1;
+            let (next, page) = page(elements.iter().map(|element| (element.as_bytes(), element.clone())), cursor, 10);
+            seen.extend(page);
+            cursor = next;
+        }
+        for i in 0..100 {
+            assert_eq!(seen.iter().filter(|element| **element == format!("stable:{i}")).count(), 1);
+        }
+    }
+
+    #[test]
+    fn candidates_page_like_a_full_pass() {
+        let elements: Vec<String> = (0..500).map(|i| format!("key:{i}")).collect();
+        // the elements split over a few ordered sets, as the shards keep them
+        let mut parts = vec![BTreeSet::new(); 4];
+        for (i, element) in elements.iter().enumerate() {
+            parts[i % 4].insert((hash(element.as_bytes()), element.clone()));
+        }
+        for count in [1, 10, 200, 1000] {
+            let mut cursor = 0;
+            loop {
+                let candidates = parts.iter().flat_map(|part| candidates(part, cursor, count));
+                let (next, page) = page_hashed(candidates.map(|(hash, element)| (*hash, element.clone())), cursor, count);
+                let expected = super::page(elements.iter().map(|element| (element.as_bytes(), element.clone())), cursor, count);
+                assert_eq!((next, page), expected);
+                if next == 0 {
+                    break;
+                }
+                cursor = next;
+            }
+        }
+    }
+
+    #[test]
+    fn keys_and_scan_enumerate_the_keyspace() {
+        let redis = server();
+        let mut client = connect();
+        let elements = |reply: DataType| match reply {
+            DataType::Array(Some(elements)) => elements,
+            reply => panic!("unexpected reply {reply:?}"),
+        };
+        let text = |element: &DataType| match element {
+            DataType::BulkString(Some(bytes)) => String::from_utf8(bytes.clone()).unwrap(),
+            element => panic!("unexpected element {element:?}"),
+        };
+
+        for i in 0..30 {
+            client.handle(command(&["set", &format!("user:{i}"), "1"]), &redis);
+        }
+        client.handle(command(&["rpush", "queue", "a"]), &redis);
+        client.handle(command(&["zadd", "board", "1", "x", "2", "y"]), &redis);
+        client.handle(command(&["set", "gone", "1", "PX", "1"]), &redis);
+        std::thread::sleep(std::time::Duration::from_millis(5));
+
+        let mut keys: Vec<String> = elements(deserialize(&client.handle(command(&["keys", "user:?"]), &redis)).unwrap()).iter().map(text).collect();
+        keys.sort();
+        assert_eq!(keys, (0..10).map(|i| format!("user:{i}")).collect::<Vec<_>>());
+        assert_eq!(elements(deserialize(&client.handle(command(&["keys", "*"]), &redis)).unwrap()).len(), 32);
+
+        let mut scan = |args: &[&str]| {
+            let mut cursor = "0".to_owned();
+            let mut seen = Vec::new();
+            loop {
+                let mut call = vec!["scan", &cursor];
+                call.extend_from_slice(args);
+                let reply = elements(deserialize(&client.handle(command(&call), &redis)).unwrap());
+                seen.extend(elements(reply[1].clone()).iter().map(text));
+                cursor = text(&reply[0]);
+                if cursor == "0" {
+                    return seen;
+                }
+            }
+        };
+        let mut all = scan(&["count", "3"]);
+        all.sort();
+        all.dedup();
+        assert_eq!(all.len(), 32);
+        assert_eq!(scan(&["match", "user:1*", "count", "5"]).len(), 11);
+        assert_eq!(scan(&["type", "zset"]), ["board"]);
+        assert_eq!(scan(&["type", "LIST", "match", "q*"]), ["queue"]);
+
+        assert_eq!(client.handle(command(&["scan", "x"]), &redis), b"-ERR invalid cursor\r\n");
+        assert_eq!(client.handle(command(&["scan", "0", "count", "0"]), &redis), b"-ERR syntax error\r\n");
+        let zscan = elements(deserialize(&client.handle(command(&["zscan", "board", "0", "match", "y"]), &redis)).unwrap());
+        assert_eq!(elements(zscan[1].clone()).iter().map(text).collect::<Vec<_>>(), ["y", "2"]);
+        assert!(client.handle(command(&["zscan", "queue", "0"]), &redis).starts_with(b"-WRONGTYPE"));
+    }
+}
+// endregion: --- tests
@@ -0,0 +1,462 @@
+//! Lua scripting for EVAL/EVALSHA: a sandboxed Lua 5.1 state per call, with `redis.call` and
+//! `redis.pcall` routed back through the command dispatch and replies converted like Redis does.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+
+use mlua::{Function, Lua, LuaOptions, StdLib, Table, Value, Variadic};
+use utils::deserializer::deserialize;
+use utils::prelude::*;
+use utils::DataType;
+
+use crate::command;
+
+/// Scripts seen by EVAL or SCRIPT LOAD, keyed by the lowercase hex SHA1 of their body.
+#[derive(Default)]
+pub struct ScriptCache {
+    scripts: HashMap<String, Vec<u8>>,
+}
+
+impl ScriptCache {
+    pub fn load(&mut self, body: &[u8]) -> String {
+        let sha = sha1_hex(body);
+        self.scripts.entry(sha.clone()).or_insert_with(|| body.to_vec());
+        sha
+    }
+
+    pub fn get(&self, sha: &str) -> Option<Vec<u8>> {
+        self.scripts.get(&sha.to_ascii_lowercase()).cloned()
+    }
+
+    pub fn contains(&self, sha: &str) -> bool {
+        self.scripts.contains_key(&sha.to_ascii_lowercase())
+    }
+
+    pub fn flush(&mut self) {
+        self.scripts.clear();
+    }
+}
+
+pub fn sha1_hex(body: &[u8]) -> String {
+    sha1_smol::Sha1::from(body).digest().to_string()
+}
+
+/// An error reply, such as one from a command run by `redis.call`, raised through the script
+/// unchanged.
+#[derive(Debug)]
+struct CallError(String);
+
+impl fmt::Display for CallError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for CallError {}
+
+/// A function registered by a library through `redis.register_function`.
+#[derive(Clone)]
+pub struct FunctionInfo {
+    pub name: String,
+    pub description: Option<String>,
+    pub flags: Vec<String>,
+}
+
+const FUNCTION_FLAGS: &[&str] = &["no-writes", "allow-oom", "allow-stale", "no-cluster", "allow-cross-slot-keys"];
+
+/// Registry slot holding the callbacks a library registered, by function name.
+const FUNCTIONS_KEY: &str = "registered_functions";
+
+/// What the code being run is, which decides what the sandbox offers.
+#[derive(Clone, Copy)]
+enum Mode<'a> {
+    /// An EVAL script, the chunk itself is the entry point.
+    Script,
+    /// FUNCTION LOAD, only `redis.register_function` is usable and nothing is called.
+    Load,
+    /// FCALL, run the library and then the named function it registered.
+    Call(&'a str),
+}
+
+/// Run `body` with KEYS and ARGV set. `call` executes one command and returns its serialized
+/// reply; `read_only` rejects commands flagged as writes.
+pub fn run<F>(body: &[u8], sha: &str, keys: &[Vec<u8>], argv: &[Vec<u8>], read_only: bool, call: F) -> DataType
+where
+    F: FnMut(Vec<DataType>) -> Vec<u8>,
+{
+    let registered = RefCell::new(Vec::new());
+    match execute(body, Mode::Script, keys, argv, read_only, call, &registered) {
+        Ok(reply) => reply,
+        Err(e) => script_error(&e, sha),
+    }
+}
+
+/// Run a library's code for the functions it registers, returning the library name and them.
+pub fn load_library(code: &[u8]) -> std::result::Result<(String, Vec<FunctionInfo>), String> {
+    let (name, body) = parse_metadata(code)?;
+    let registered = RefCell::new(Vec::new());
+    if let Err(e) = execute(&body, Mode::Load, &[], &[], true, |_| Vec::new(), &registered) {
+        return Err(reply_message(&e).unwrap_or_else(|| f!("ERR Error registering functions: {}", error_message(&e))));
+    }
+
+    let functions = registered.into_inner();
+    if functions.is_empty() {
+        return Err("ERR No functions registered".to_owned());
+    }
+    Ok((name, functions))
+}
+
+/// Call `function` from the library `code`, with the keys and arguments passed as its two
+/// parameters.
+pub fn call_function<F>(code: &[u8], function: &str, keys: &[Vec<u8>], argv: &[Vec<u8>], read_only: bool, call: F) -> DataType
+where
+    F: FnMut(Vec<DataType>) -> Vec<u8>,
+{
+    let body = match parse_metadata(code) {
+        Ok((_, body)) => body,
+        Err(err) => return DataType::Error(err),
+    };
+    let registered = RefCell::new(Vec::new());
+    match execute(&body, Mode::Call(function), keys, argv, read_only, call, &registered) {
+        Ok(reply) => reply,
+        Err(e) => script_error(&e, function),
+    }
+}
+
+fn execute<F>(
+    code: &[u8],
+    mode: Mode,
+    keys: &[Vec<u8>],
+    argv: &[Vec<u8>],
+    read_only: bool,
+    call: F,
+    registered: &RefCell<Vec<FunctionInfo>>,
+) -> mlua::Result<DataType>
+where
+    F: FnMut(Vec<DataType>) -> Vec<u8>,
+{
+    let lua = Lua::new_with(StdLib::TABLE | StdLib::STRING | StdLib::MATH, LuaOptions::default())?;
+    let call = RefCell::new(call);
+
+    lua.scope(|scope| {
+        let globals = lua.globals();
+        let keys = lua.create_sequence_from(keys.iter().map(|key| lua.create_string(key)).collect::<mlua::Result<Vec<_>>>()?)?;
+        let argv = lua.create_sequence_from(argv.iter().map(|arg| lua.create_string(arg)).collect::<mlua::Result<Vec<_>>>()?)?;
+
+        let redis = lua.create_table()?;
+        if !matches!(mode, Mode::Load) {
+            redis.set("call", scope.create_function(|lua, args: Variadic<Value>| {
+                match dispatch(args, read_only, &call) {
+                    Ok(DataType::Error(err)) | Err(err) => Err(reply_error(err)),
+                    Ok(reply) => to_lua(lua, reply),
+                }
+            })?)?;
+            redis.set("pcall", scope.create_function(|lua, args: Variadic<Value>| {
+                match dispatch(args, read_only, &call) {
+                    Ok(reply) => to_lua(lua, reply),
+                    Err(err) => to_lua(lua, DataType::Error(err)),
+                }
+            })?)?;
+        }
+        if !matches!(mode, Mode::Script) {
+            lua.set_named_registry_value(FUNCTIONS_KEY, lua.create_table()?)?;
+            redis.set("register_function", scope.create_function(|lua, (first, callback): (Value, Option<Function>)| {
+                register_function(lua, registered, first, callback)
+            })?)?;
+        }
+        redis.set("error_reply", lua.create_function(|lua, msg: mlua::String| {
+            let reply = lua.create_table()?;
+            reply.set("err", msg)?;
+            Ok(reply)
+        })?)?;
+        redis.set("status_reply", lua.create_function(|lua, msg: mlua::String| {
+            let reply = lua.create_table()?;
+            reply.set("ok", msg)?;
+            Ok(reply)
+        })?)?;
+        redis.set("sha1hex", lua.create_function(|_, val: mlua::String| Ok(sha1_hex(val.as_bytes())))?)?;
+        redis.set("log", lua.create_function(|_, (_level, msg): (i64, mlua::String)| {
+            println!("script log: {}", msg.to_string_lossy());
+            Ok(())
+        })?)?;
+        for (i, level) in ["LOG_DEBUG", "LOG_VERBOSE", "LOG_NOTICE", "LOG_WARNING"].iter().enumerate() {
+            redis.set(*level, i as i64)?;
+        }
+        globals.set("redis", redis)?;
+
+        match mode {
+            Mode::Script => {
+                globals.set("KEYS", keys)?;
+                globals.set("ARGV", argv)?;
+                let value: Value = lua.load(code).set_name("@user_script").call(())?;
+                Ok(from_lua(value))
+            },
+            Mode::Load => {
+                lua.load(code).set_name("@user_function").exec()?;
+                Ok(DataType::BulkString(None))
+            },
+            Mode::Call(name) => {
+                lua.load(code).set_name("@user_function").exec()?;
+                let functions: Table = lua.named_registry_value(FUNCTIONS_KEY)?;
+                let Some(function) = functions.raw_get::<_, Option<Function>>(name)? else {
+                    return Err(reply_error("ERR Function not found".to_owned()));
+                };
+                let value: Value = function.call((keys, argv))?;
+                Ok(from_lua(value))
+            },
+        }
+    })
+}
+
+/// `redis.register_function(name, callback)`, or the table form with `function_name`,
+/// `callback`, and optional `flags` and `description`.
+fn register_function<'lua>(
+    lua: &'lua Lua,
+    registered: &RefCell<Vec<FunctionInfo>>,
+    first: Value<'lua>,
+    callback: Option<Function<'lua>>,
+) -> mlua::Result<()> {
+    let (name, callback, flags, description) = match first {
+        Value::String(name) => (name.to_string_lossy().into_owned(), callback, Vec::new(), None),
+        Value::Table(args) if callback.is_none() => {
+            let name: Option<String> = args.get("function_name")?;
+            let callback: Option<Function> = args.get("callback")?;
+            let flags: Option<Vec<String>> = args.get("flags")?;
+            let description: Option<String> = args.get("description")?;
+            (name.unwrap_or_default(), callback, flags.unwrap_or_default(), description)
+        },
+        _ => return Err(reply_error("ERR wrong arguments given to redis.register_function".to_owned())),
+    };
+
+    if !valid_name(&name) {
+        return Err(reply_error("ERR Function names can only contain letters, numbers, or underscores(_) and must be at least one character long".to_owned()));
+    }
+    let Some(callback) = callback else {
+        return Err(reply_error("ERR callback is required and must be a function".to_owned()));
+    };
+    if let Some(flag) = flags.iter().find(|flag| !FUNCTION_FLAGS.contains(&flag.as_str())) {
+        return Err(reply_error(f!("ERR unknown flag given: {flag}")));
+    }
+
+    let mut registered = registered.borrow_mut();
+    if registered.iter().any(|function| function.name == name) {
+        return Err(reply_error("ERR Function already exists in the library".to_owned()));
+    }
+    let functions: Table = lua.named_registry_value(FUNCTIONS_KEY)?;
+    functions.raw_set(name.as_str(), callback)?;
+    registered.push(FunctionInfo { name, description, flags });
+    Ok(())
+}
+
+/// Split the `#!lua name=<library>` line off a library's code. The line is replaced by an
+/// empty one so Lua still reports the right line numbers.
+fn parse_metadata(code: &[u8]) -> std::result::Result<(String, Vec<u8>), String> {
+    let Some(header) = code.strip_prefix(b"#!") else {
+        return Err("ERR Missing library metadata".to_owned());
+    };
+    let end = header.iter().position(|byte| *byte == b'\n').unwrap_or(header.len());
+    let line = String::from_utf8_lossy(&header[..end]);
+
+    let mut parts = line.split_whitespace();
+    let engine = parts.next().unwrap_or_default();
+    if !engine.eq_ignore_ascii_case("lua") {
+        return Err(f!("ERR Engine '{engine}' not found"));
+    }
+    let mut name = None;
+    for part in parts {
+        match part.strip_prefix("name=") {
+            Some(val) => name = Some(val.to_owned()),
+            None => return Err(f!("ERR Invalid metadata value given: {part}")),
+        }
+    }
+    let Some(name) = name else {
+        return Err("ERR Library name was not given".to_owned());
+    };
+    if !valid_name(&name) {
+        return Err("ERR Library names can only contain letters, numbers, or underscores(_) and must be at least one character long".to_owned());
+    }
+
+    Ok((name, header[end..].to_vec()))
+}
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(|byte| byte.is_ascii_alphanumeric() || byte == b'_')
+}
+
+/// Validate and run one `redis.call`, returning the parsed reply or a Redis error message.
+fn dispatch<F>(args: Variadic<Value>, read_only: bool, call: &RefCell<F>) -> std::result::Result<DataType, String>
+where
+    F: FnMut(Vec<DataType>) -> Vec<u8>,
+{
+    if args.is_empty() {
+        return Err("ERR Please specify at least one argument for this redis lib call".to_owned());
+    }
+
+    let mut d_command = Vec::new();
+    for arg in args.iter() {
+        let bytes = match arg {
+            Value::String(val) => val.as_bytes().to_vec(),
+            Value::Integer(val) => val.to_string().into_bytes(),
+            Value::Number(val) => f!("{val}").into_bytes(),
+            _ => return Err("ERR Lua redis lib command arguments must be strings or integers".to_owned()),
+        };
+        d_command.push(DataType::BulkString(Some(bytes)));
+    }
+
+    let name = match &d_command[0] {
+        DataType::BulkString(Some(val)) => String::from_utf8_lossy(val).to_lowercase(),
+        _ => String::new(),
+    };
+    let Some(spec) = command::lookup(&name) else {
+        return Err("ERR Unknown Redis command called from script".to_owned());
+    };
+    if spec.flags & command::NOSCRIPT != 0 {
+        return Err("ERR This Redis command is not allowed from script".to_owned());
+    }
+    if !spec.arity_ok(d_command.len()) {
+        return Err("ERR Wrong number of args calling Redis command from script".to_owned());
+    }
+    if read_only && spec.flags & command::WRITE != 0 {
+        return Err("ERR Write commands are not allowed from read-only scripts.".to_owned());
+    }
+    d_command[0] = DataType::BulkString(Some(name.into_bytes()));
+
+    let reply = (call.borrow_mut())(d_command);
+    deserialize(&reply).map_err(|e| f!("ERR unreadable reply from command: {e}"))
+}
+
+/// Redis reply to Lua: integers become numbers, nil becomes false, and status or error replies
+/// become tables with a single `ok` or `err` field.
+fn to_lua(lua: &Lua, reply: DataType) -> mlua::Result<Value<'_>> {
+    Ok(match reply {
+        DataType::Integer(val) => Value::Integer(val as mlua::Integer),
+        DataType::BulkString(Some(val)) => Value::String(lua.create_string(&val)?),
+        DataType::BulkString(None) | DataType::Array(None) => Value::Boolean(false),
+        DataType::SimpleString(val) => Value::Table(single_field(lua, "ok", &val)?),
+        DataType::Error(val) => Value::Table(single_field(lua, "err", &val)?),
+        DataType::Array(Some(items)) => {
+            let table = lua.create_table()?;
+            for (i, item) in items.into_iter().enumerate() {
+                table.raw_set(i + 1, to_lua(lua, item)?)?;
+            }
+            Value::Table(table)
+        },
+    })
+}
+
+fn single_field<'lua>(lua: &'lua Lua, field: &str, val: &str) -> mlua::Result<Table<'lua>> {
+    let table = lua.create_table()?;
+    table.set(field, val)?;
+    Ok(table)
+}
+
+/// Lua value to Redis reply: numbers are truncated to integers, tables with `err` or `ok` become
+/// error or status replies, other tables become arrays up to the first nil, and false is nil.
+fn from_lua(value: Value) -> DataType {
+    match value {
+        Value::Boolean(true) => DataType::Integer(1),
+        Value::Integer(val) => DataType::Integer(val),
+        Value::Number(val) => DataType::Integer(val as i64),
+        Value::String(val) => DataType::BulkString(Some(val.as_bytes().to_vec())),
+        Value::Table(table) => {
+            if let Ok(Value::String(err)) = table.raw_get::<_, Value>("err") {
+                return DataType::Error(err.to_string_lossy().into_owned());
+            }
+            if let Ok(Value::String(ok)) = table.raw_get::<_, Value>("ok") {
+                return DataType::SimpleString(ok.to_string_lossy().into_owned());
+            }
+
+            let mut items = Vec::new();
+            for i in 1.. {
+                match table.raw_get::<_, Value>(i) {
+                    Ok(Value::Nil) | Err(_) => break,
+                    Ok(item) => items.push(from_lua(item)),
+                }
+            }
+            DataType::Array(Some(items))
+        },
+        _ => DataType::BulkString(None),
+    }
+}
+
+fn reply_error(err: String) -> mlua::Error {
+    mlua::Error::external(CallError(err))
+}
+
+/// The error reply raised with `reply_error`, if that is what stopped the script.
+fn reply_message(e: &mlua::Error) -> Option<String> {
+    match e {
+        mlua::Error::CallbackError { cause, .. } => reply_message(cause),
+        other => other.downcast_ref::<CallError>().map(|err| err.0.clone()),
+    }
+}
+
+fn script_error(e: &mlua::Error, origin: &str) -> DataType {
+    match reply_message(e) {
+        Some(err) => DataType::Error(err),
+        None => DataType::Error(f!("ERR {} script: {origin}", error_message(e))),
+    }
+}
+
+fn error_message(e: &mlua::Error) -> String {
+    match e {
+        // Drop the traceback, error replies must be a single line
+        mlua::Error::RuntimeError(msg) => msg.lines().next().unwrap_or_default().to_owned(),
+        mlua::Error::SyntaxError { message, .. } => f!("Error compiling script: {message}"),
+        mlua::Error::CallbackError { cause, .. } => error_message(cause),
+        other => other.to_string(),
+    }
+}
+
+
+// region: --- tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_simple(body: &str, keys: &[&str], argv: &[&str]) -> DataType {
+        let keys: Vec<Vec<u8>> = keys.iter().map(|key| key.as_bytes().to_vec()).collect();
+        let argv: Vec<Vec<u8>> = argv.iter().map(|arg| arg.as_bytes().to_vec()).collect();
+        run(body.as_bytes(), "sha", &keys, &argv, false, |_| b"+OK\r\n".to_vec())
+    }
+
+    #[test]
+    fn converts_lua_values_to_replies() {
+        assert_eq!(run_simple("return 3.99", &[], &[]), DataType::Integer(3));
+        assert_eq!(run_simple("return true", &[], &[]), DataType::Integer(1));
+        assert_eq!(run_simple("return false", &[], &[]), DataType::BulkString(None));
+        assert_eq!(run_simple("return {1, 'two', nil, 4}", &[], &[]), DataType::Array(Some(vec![
+            DataType::Integer(1),
+            DataType::BulkString(Some(b"two".to_vec())),
+        ])));
+        assert_eq!(run_simple("return redis.status_reply('FINE')", &[], &[]), DataType::SimpleString("FINE".to_owned()));
+        assert_eq!(run_simple("return redis.error_reply('MY err')", &[], &[]), DataType::Error("MY err".to_owned()));
+        assert_eq!(run_simple("return {KEYS[1], ARGV[2]}", &["k"], &["a", "b"]), DataType::Array(Some(vec![
+            DataType::BulkString(Some(b"k".to_vec())),
+            DataType::BulkString(Some(b"b".to_vec())),
+        ])));
+    }
+
+    #[test]
+    fn converts_replies_to_lua_values() {
+        assert_eq!(run_simple("return redis.call('set', 'a', 1)['ok']", &[], &[]), DataType::BulkString(Some(b"OK".to_vec())));
+        let reply = run_simple("return redis.call('nosuchcommand')", &[], &[]);
+        assert_eq!(reply, DataType::Error("ERR Unknown Redis command called from script".to_owned()));
+        let reply = run_simple("return redis.pcall('multi')['err']", &[], &[]);
+        assert_eq!(reply, DataType::BulkString(Some(b"ERR This Redis command is not allowed from script".to_vec())));
+    }
+
+    #[test]
+    fn read_only_scripts_reject_writes() {
+        let reply = run(b"return redis.call('set', 'a', 1)", "sha", &[], &[], true, |_| b"+OK\r\n".to_vec());
+        assert_eq!(reply, DataType::Error("ERR Write commands are not allowed from read-only scripts.".to_owned()));
+    }
+
+    #[test]
+    fn sandbox_has_no_os_library() {
+        let reply = run_simple("return os.time()", &[], &[]);
+        assert!(matches!(reply, DataType::Error(_)));
+    }
+}
+// endregion: --- tests
@@ -0,0 +1,600 @@
+//! The keyspace split in shards, each a dictionary behind a lock of its own, so commands on
+//! keys of different shards run at the same time. A key belongs to the shard of its hash slot,
+//! which keeps keys sharing a `{hashtag}` together.
+//!
+//! Every map of the keyspace is kept in one part per shard, and each shard's dictionary holds
+//! its own parts. A command whose keys span several shards locks them all, lowest first, and
+//! the others lend their parts to the lowest one for the duration, so it runs on a single
+//! dictionary that sees every key it names, atomically, and the parts go back when the lock is
+//! released. Commands on the whole keyspace, and transactions holding one, lock every shard.
+//!
+//! What is not keyed, the replication stream, the AOF, subscriptions and cluster state, is
+//! shared by all shards behind locks held only briefly, taken after any shard lock.
+//!
+//! The locks are plain `std` mutexes although clients are tokio tasks: a command runs
+//! synchronously from parsing to reply, so no lock is ever held across an `.await` and each is
+//! held for one command only, which is the case tokio recommends blocking mutexes for. Work
+//! that could hold a lock for long, the network round trips of MIGRATE, runs with none held,
+//! on a blocking thread so the tokio workers keep serving the other connections.
+
+use std::borrow::Borrow;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::ops::{Deref, DerefMut, Index};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use utils::DataType;
+
+use crate::aof::Aof;
+use crate::cluster::{self, Cluster};
+use crate::command;
+use crate::config::Config;
+use crate::dictionary::dictionary::Dictionary;
+use crate::persistence::SaveStatus;
+use crate::pubsub::PubSub;
+use crate::replication::Replication;
+
+/// Shards the keyspace is split in.
+pub const SHARDS: usize = 16;
+
+/// The shard `key` belongs to.
+pub fn shard_of(key: &[u8]) -> usize {
+    cluster::key_slot(key) as usize % SHARDS
+}
+
+/// Lock `mutex`, even when a panic left it poisoned: the panicking command is lost, the
+/// server goes on.
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// The shards the command `arr` runs on: every one for a `GLOBAL` command, otherwise those of
+/// its keys, or `fallback` when it names none.
+pub fn route(arr: &[DataType], fallback: usize) -> Vec<usize> {
+    let Some(DataType::BulkString(Some(name))) = arr.first() else { return vec![fallback] };
+    let Some(command) = command::lookup(&String::from_utf8_lossy(name).to_ascii_lowercase()) else { return vec![fallback] };
+    if command.flags & command::GLOBAL != 0 {
+        return (0..SHARDS).collect();
+    }
+    let keys = match command.arity_ok(arr.len()) {
+        true => command.key_args(arr),
+        false => Vec::new(),
+    };
+    match keys.is_empty() {
+        true => vec![fallback],
+        false => keys.into_iter().map(shard_of).collect(),
+    }
+}
+
+pub struct Shards {
+    shards: Box<[Mutex<Dictionary>]>,
+    shared: Arc<Shared>,
+}
+
+impl Shards {
+    pub fn new(config: Config) -> Self {
+        let shared = Arc::new(Shared::new(&config));
+        let shards = (0..SHARDS)
+            .map(|shard| Mutex::new(Dictionary::new(shard, config.clone(), Arc::clone(&shared))))
+            .collect();
+        Self { shards, shared }
+    }
+
+    pub fn shared(&self) -> &Shared {
+        &self.shared
+    }
+
+    /// Lock `shards`, in ascending order so two commands never wait on each other.
+    pub fn lock(&self, mut shards: Vec<usize>) -> Locked<'_> {
+        shards.sort_unstable();
+        shards.dedup();
+        let mut guards: Vec<(usize, MutexGuard<'_, Dictionary>)> = shards.into_iter()
+            .map(|shard| (shard, lock(&self.shards[shard])))
+            .collect();
+        if let Some(((_, first), rest)) = guards.split_first_mut() {
+            for (shard, guard) in rest {
+                first.exchange(guard, *shard);
+            }
+        }
+        Locked { shards: self, guards }
+    }
+
+    pub fn lock_all(&self) -> Locked<'_> {
+        self.lock((0..SHARDS).collect())
+    }
+
+    /// Drop every expired key, one shard at a time.
+    pub fn expire_keys(&self) {
+        for shard in 0..SHARDS {
+            self.lock(vec![shard]).expire_keys();
+        }
+    }
+
+    /// Evict keys per `maxmemory-policy` until the dataset fits in `maxmemory` again, before
+    /// a client on database `db` runs a command with `flags`. Each key comes from the shard
+    /// holding the most memory that has one to evict, locked alone. Commands that may grow the
+    /// dataset are refused while it does not fit. A replica leaves eviction to its primary.
+    pub fn make_room(&self, flags: u8, db: usize) -> std::result::Result<(), Vec<u8>> {
+        let maxmemory = self.shared.maxmemory.load(Ordering::Relaxed);
+        if maxmemory == 0 || self.shared.used_memory() <= maxmemory || self.shared.replication().is_replica() {
+            return Ok(());
+        }
+        while self.shared.used_memory() > maxmemory {
+            let mut by_size: Vec<usize> = (0..SHARDS).collect();
+            by_size.sort_by_key(|shard| std::cmp::Reverse(self.shared.used[*shard].load(Ordering::Relaxed)));
+            let evicted = by_size.into_iter().any(|shard| {
+                let mut dict = self.lock(vec![shard]);
+                dict.select(db);
+                dict.evict_one()
+            });
+            if !evicted {
+                break;
+            }
+        }
+        if self.shared.used_memory() > maxmemory && flags & command::DENYOOM != 0 {
+            return Err(b"-OOM command not allowed when used memory > 'maxmemory'.\r\n".to_vec());
+        }
+        Ok(())
+    }
+
+    /// Start a background save when one is due, locking every shard for its snapshot only
+    /// then, and fsync the AOF as `appendfsync` asks, which needs no shard.
+    pub fn persistence_cron(&self) {
+        let (rules, appendfsync) = {
+            let config = self.shared.config();
+            (config.save.clone(), config.appendfsync)
+        };
+        if self.shared.save_status.due(&rules) {
+            self.lock_all().save_if_due();
+        }
+
+        let replication = self.shared.replication();
+        if let Some(aof) = self.shared.aof().as_mut() {
+            let offset = replication.offset();
+            let fsynced = aof.fsynced_offset(offset);
+            aof.tick(appendfsync);
+            if aof.fsynced_offset(offset) != fsynced {
+                replication.wake_waiters();
+            }
+        }
+    }
+}
+
+/// State every shard works with besides its part of the keyspace.
+pub struct Shared {
+    replication: Mutex<Replication>,
+    /// The append only file, once enabled or rewritten. Locked after `replication` when both
+    /// are needed.
+    aof: Mutex<Option<Aof>>,
+    pubsub: Mutex<PubSub>,
+    cluster: Mutex<Cluster>,
+    cluster_enabled: bool,
+    save_status: Arc<SaveStatus>,
+    /// Estimated bytes held by each shard, as of the last time it was unlocked.
+    used: Box<[AtomicUsize]>,
+    /// The most memory the datasets were seen holding together.
+    peak: AtomicUsize,
+    /// The configuration as of its last change, for work done with no shard locked, and the
+    /// `maxmemory` in it, read before every command.
+    config: Mutex<Config>,
+    maxmemory: AtomicUsize,
+    evicted_keys: AtomicU64,
+}
+
+impl Shared {
+    fn new(config: &Config) -> Self {
+        Self {
+            replication: Mutex::new(Replication::new()),
+            aof: Mutex::new(None),
+            pubsub: Mutex::new(PubSub::default()),
+            cluster: Mutex::new(Cluster::new()),
+            cluster_enabled: config.cluster_enabled,
+            save_status: Arc::new(SaveStatus::new()),
+            used: (0..SHARDS).map(|_| AtomicUsize::new(0)).collect(),
+            peak: AtomicUsize::new(0),
+            config: Mutex::new(config.clone()),
+            maxmemory: AtomicUsize::new(config.maxmemory),
+            evicted_keys: AtomicU64::new(0),
+        }
+    }
+
+    pub fn replication(&self) -> MutexGuard<'_, Replication> {
+        lock(&self.replication)
+    }
+
+    pub fn aof(&self) -> MutexGuard<'_, Option<Aof>> {
+        lock(&self.aof)
+    }
+
+    pub fn pubsub(&self) -> MutexGuard<'_, PubSub> {
+        lock(&self.pubsub)
+    }
+
+    pub fn cluster(&self) -> MutexGuard<'_, Cluster> {
+        lock(&self.cluster)
+    }
+
+    pub fn config(&self) -> MutexGuard<'_, Config> {
+        lock(&self.config)
+    }
+
+    /// Whether the server runs in cluster mode, without taking the cluster lock.
+    pub fn cluster_enabled(&self) -> bool {
+        self.cluster_enabled
+    }
+
+    pub fn save_status(&self) -> &Arc<SaveStatus> {
+        &self.save_status
+    }
+
+    /// The replication offset the AOF is fsynced up to, `None` when it is off.
+    pub fn aof_fsynced_offset(&self) -> Option<u64> {
+        let current = self.replication().offset();
+        self.aof().as_ref().filter(|aof| aof.is_enabled()).map(|aof| aof.fsynced_offset(current))
+    }
+
+    /// Estimated bytes held by all the shards.
+    pub fn used_memory(&self) -> usize {
+        self.used.iter().map(|used| used.load(Ordering::Relaxed)).sum()
+    }
+
+    /// Record that the shards hold `used` bytes together, returning the peak so far.
+    pub fn peak_memory(&self, used: usize) -> usize {
+        self.peak.fetch_max(used, Ordering::Relaxed).max(used)
+    }
+
+    pub fn evicted(&self) {
+        self.evicted_keys.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn evicted_keys(&self) -> u64 {
+        self.evicted_keys.load(Ordering::Relaxed)
+    }
+}
+
+/// Locked shards, running commands on the lowest one, which holds the parts of the others
+/// until the lock is released.
+pub struct Locked<'a> {
+    shards: &'a Shards,
+    /// By shard, ascending.
+    guards: Vec<(usize, MutexGuard<'a, Dictionary>)>,
+}
+
+impl Deref for Locked<'_> {
+    type Target = Dictionary;
+
+    fn deref(&self) -> &Dictionary {
+        &self.guards[0].1
+    }
+}
+
+impl DerefMut for Locked<'_> {
+    fn deref_mut(&mut self) -> &mut Dictionary {
+        &mut self.guards[0].1
+    }
+}
+
+impl Drop for Locked<'_> {
+    /// Give the lent parts back, even when unwinding from a panic, so every shard keeps its
+    /// keys, and pass on configuration changes.
+    fn drop(&mut self) {
+        let Some(((_, first), rest)) = self.guards.split_first_mut() else { return };
+        for (shard, guard) in rest.iter_mut() {
+            first.exchange(guard, *shard);
+        }
+        let shared = &self.shards.shared;
+        if let Some(config) = first.take_changed_config() {
+            for (_, guard) in rest.iter_mut() {
+                guard.set_config(config.clone());
+            }
+            shared.maxmemory.store(config.maxmemory, Ordering::Relaxed);
+            *shared.config() = config;
+        }
+
+        for (shard, guard) in &self.guards {
+            shared.used[*shard].store(guard.shard_memory(), Ordering::Relaxed);
+        }
+        if self.guards.len() == SHARDS {
+            shared.peak_memory(shared.used_memory());
+        }
+    }
+}
+
+/// A map split in one part per shard, by the shard of each key.
+pub struct Sharded<T> {
+    parts: Vec<T>,
+}
+
+impl<T: Default> Default for Sharded<T> {
+    fn default() -> Self {
+        Self { parts: (0..SHARDS).map(|_| T::default()).collect() }
+    }
+}
+
+impl<T> Sharded<T> {
+    pub fn part(&self, shard: usize) -> &T {
+        &self.parts[shard]
+    }
+
+    pub fn part_mut(&mut self, shard: usize) -> &mut T {
+        &mut self.parts[shard]
+    }
+
+    pub fn parts(&self) -> impl Iterator<Item = &T> {
+        self.parts.iter()
+    }
+
+    pub fn parts_mut(&mut self) -> impl Iterator<Item = &mut T> {
+        self.parts.iter_mut()
+    }
+
+    /// Trade part `shard` with `other`'s, to lend it or to give it back.
+    pub fn exchange(&mut self, other: &mut Self, shard: usize) {
+        std::mem::swap(&mut self.parts[shard], &mut other.parts[shard]);
+    }
+
+    fn part_of(&self, key: &[u8]) -> &T {
+        &self.parts[shard_of(key)]
+    }
+
+    fn part_of_mut(&mut self, key: &[u8]) -> &mut T {
+        &mut self.parts[shard_of(key)]
+    }
+}
+
+/// The subset of the `HashMap` interface the keyspace uses, each key going to its part.
+impl<V> Sharded<HashMap<Vec<u8>, V>> {
+    pub fn get<Q>(&self, key: &Q) -> Option<&V>
+    where
+        Vec<u8>: Borrow<Q>,
+        Q: Hash + Eq + AsRef<[u8]> + ?Sized,
+    {
+        self.part_of(key.as_ref()).get(key)
+    }
+
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        Vec<u8>: Borrow<Q>,
+        Q: Hash + Eq + AsRef<[u8]> + ?Sized,
+    {
+        self.part_of_mut(key.as_ref()).get_mut(key)
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        Vec<u8>: Borrow<Q>,
+        Q: Hash + Eq + AsRef<[u8]> + ?Sized,
+    {
+        self.part_of(key.as_ref()).contains_key(key)
+    }
+
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        Vec<u8>: Borrow<Q>,
+        Q: Hash + Eq + AsRef<[u8]> + ?Sized,
+    {
+        self.part_of_mut(key.as_ref()).remove(key)
+    }
+
+    pub fn insert(&mut self, key: Vec<u8>, value: V) -> Option<V> {
+        self.part_of_mut(&key).insert(key, value)
+    }
+
+    pub fn entry(&mut self, key: Vec<u8>) -> Entry<'_, Vec<u8>, V> {
+        self.part_of_mut(&key).entry(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.parts.iter().map(HashMap::len).sum()
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &Vec<u8>> {
+        self.parts.iter().flat_map(HashMap::keys)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.parts.iter().flat_map(HashMap::values)
+    }
+
+    pub fn values_mut(&mut self) -> impl Iterator<Item = &mut V> {
+        self.parts.iter_mut().flat_map(HashMap::values_mut)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Vec<u8>, &V)> {
+        self.parts.iter().flat_map(HashMap::iter)
+    }
+}
+
+impl<V, Q> Index<&Q> for Sharded<HashMap<Vec<u8>, V>>
+where
+    Vec<u8>: Borrow<Q>,
+    Q: Hash + Eq + AsRef<[u8]> + ?Sized,
+{
+    type Output = V;
+
+    fn index(&self, key: &Q) -> &V {
+        &self.part_of(key.as_ref())[key]
+    }
+}
+
+// region: --- tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::tests::{command, connect, server};
+    use std::panic::{self, AssertUnwindSafe};
+    use std::thread;
+    use utils::deserializer::deserialize;
+    use utils::prelude::*;
+
+    /// Run `args` with the shards of its keys locked, as a client would.
+    fn run(redis: &Shards, args: &[&str]) -> Vec<u8> {
+        let d_command = command(args);
+        let DataType::Array(Some(arr)) = &d_command else { unreachable!() };
+        let shards = route(arr, 0);
+        redis.lock(shards).handle_command(d_command)
+    }
+
+    /// Keys named `prefix` and a number, each in a shard of its own.
+    fn keys_in_distinct_shards(prefix: &str, count: usize) -> Vec<String> {
+        let mut seen = Vec::new();
+        (0..)
+            .map(|n| f!("{prefix}{n}"))
+            .filter(|key| {
+                let shard = shard_of(key.as_bytes());
+                let new = !seen.contains(&shard);
+                seen.push(shard);
+                new
+            })
+            .take(count)
+            .collect()
+    }
+
+    #[test]
+    fn routes_keys_and_global_commands() {
+        let DataType::Array(Some(arr)) = command(&["exists", "a", "{a}b", "c"]) else { unreachable!() };
+        assert_eq!(route(&arr, 3), vec![shard_of(b"a"), shard_of(b"a"), shard_of(b"c")]);
+        let DataType::Array(Some(arr)) = command(&["ping"]) else { unreachable!() };
+        assert_eq!(route(&arr, 3), vec![3]);
+        let DataType::Array(Some(arr)) = command(&["dbsize"]) else { unreachable!() };
+        assert_eq!(route(&arr, 3).len(), SHARDS);
+    }
+
+    #[test]
+    fn multi_key_commands_span_shards() {
+        let redis = Shards::new(Config::default());
+        let keys = keys_in_distinct_shards("key", 3);
+        let (a, b, c) = (keys[0].as_str(), keys[1].as_str(), keys[2].as_str());
+
+        run(&redis, &["set", a, "1"]);
+        run(&redis, &["set", b, "2"]);
+        assert_eq!(run(&redis, &["exists", a, b, c]), b":2\r\n");
+        assert_eq!(run(&redis, &["rename", a, c]), b"+OK\r\n");
+        assert_eq!(run(&redis, &["dbsize"]), b":2\r\n");
+        // the renamed key went back to the shard it belongs to
+        assert_eq!(redis.lock(vec![shard_of(c.as_bytes())]).handle_command(command(&["get", c])), b"$1\r\n1\r\n");
+        assert_eq!(redis.lock(vec![shard_of(a.as_bytes())]).handle_command(command(&["get", a])), b"$-1\r\n");
+        assert_eq!(run(&redis, &["del", b, c]), b":2\r\n");
+        assert_eq!(run(&redis, &["dbsize"]), b":0\r\n");
+    }
+
+    #[test]
+    fn parts_go_back_after_a_panic() {
+        let redis = Shards::new(Config::default());
+        let keys = keys_in_distinct_shards("key", 2);
+        let (a, b) = (keys[0].as_str(), keys[1].as_str());
+        run(&redis, &["set", b, "kept"]);
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let mut locked = redis.lock(vec![shard_of(a.as_bytes()), shard_of(b.as_bytes())]);
+            locked.handle_command(command(&["set", a, "1"]));
+            panic!("command failed");
+        }));
+        assert!(result.is_err());
+
+        // both shards are poisoned now, and still serve their keys
+        assert_eq!(redis.lock(vec![shard_of(b.as_bytes())]).handle_command(command(&["get", b])), b"$4\r\nkept\r\n");
+        assert_eq!(run(&redis, &["get", a]), b"$1\r\n1\r\n");
+    }
+
+    #[test]
+    fn concurrent_writes_are_not_lost() {
+        let redis = server();
+        let keys = keys_in_distinct_shards("counter", 4);
+
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let (redis, keys) = (Arc::clone(&redis), keys.clone());
+                thread::spawn(move || {
+                    for n in 0..500 {
+                        run(&redis, &["incr", &keys[n % keys.len()]]);
+                        run(&redis, &["incr", "shared"]);
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        for key in &keys {
+            assert_eq!(run(&redis, &["get", key]), b"$4\r\n1000\r\n");
+        }
+        assert_eq!(run(&redis, &["get", "shared"]), b"$4\r\n4000\r\n");
+    }
+
+    #[test]
+    fn transactions_across_shards_are_atomic() {
+        let redis = server();
+        let keys = keys_in_distinct_shards("key", 2);
+
+        let writer = {
+            let (redis, keys) = (Arc::clone(&redis), keys.clone());
+            thread::spawn(move || {
+                let mut client = connect();
+                for _ in 0..500 {
+                    client.handle(command(&["multi"]), &redis);
+                    client.handle(command(&["incr", &keys[0]]), &redis);
+                    client.handle(command(&["incr", &keys[1]]), &redis);
+                    client.handle(command(&["exec"]), &redis);
+                }
+            })
+        };
+        // a reader never sees one increment without the other
+        let mut client = connect();
+        while !writer.is_finished() {
+            client.handle(command(&["multi"]), &redis);
+            client.handle(command(&["get", &keys[0]]), &redis);
+            client.handle(command(&["get", &keys[1]]), &redis);
+            let DataType::Array(Some(values)) = deserialize(&client.handle(command(&["exec"]), &redis)).unwrap() else { panic!() };
+            assert_eq!(values[0], values[1]);
+        }
+        writer.join().unwrap();
+        assert_eq!(run(&redis, &["get", &keys[1]]), b"$3\r\n500\r\n");
+    }
+
+    #[test]
+    fn keys_need_not_be_utf8() {
+        let redis = Shards::new(Config::default());
+        let run_bytes = |args: &[&[u8]]| {
+            let arr: Vec<DataType> = args.iter().map(|arg| DataType::BulkString(Some(arg.to_vec()))).collect();
+            let shards = route(&arr, 0);
+            redis.lock(shards).handle_command(DataType::Array(Some(arr)))
+        };
+
+        // both would read as the same key once made UTF-8
+        assert_eq!(run_bytes(&[b"set", b"k\xff", b"1"]), b"+OK\r\n");
+        assert_eq!(run_bytes(&[b"set", b"k\xfe", b"2"]), b"+OK\r\n");
+        assert_eq!(run_bytes(&[b"get", b"k\xff"]), b"$1\r\n1\r\n");
+        assert_eq!(run_bytes(&[b"get", b"k\xfe"]), b"$1\r\n2\r\n");
+        assert_eq!(run(&redis, &["dbsize"]), b":2\r\n");
+        assert_eq!(run_bytes(&[b"keys", b"k\xff"]), b"*1\r\n$2\r\nk\xff\r\n");
+    }
+
+    #[test]
+    fn eviction_takes_keys_from_the_biggest_shards() {
+        let redis = Shards::new(Config::default());
+        let keys = keys_in_distinct_shards("key", 4);
+        for key in &keys {
+            run(&redis, &["set", key, "v"]);
+        }
+        let big = keys_in_distinct_shards("big", 8).into_iter().find(|key| !keys.iter().any(|other| shard_of(other.as_bytes()) == shard_of(key.as_bytes()))).unwrap();
+        run(&redis, &["set", &big, &"x".repeat(10_000)]);
+        run(&redis, &["config", "set", "maxmemory-policy", "allkeys-random"]);
+        let maxmemory = (redis.shared().used_memory() - 5_000).to_string();
+        run(&redis, &["config", "set", "maxmemory", &maxmemory]);
+
+        // the one big key frees enough on its own, without the other shards losing theirs
+        assert_eq!(redis.make_room(command::DENYOOM, 0), Ok(()));
+        assert_eq!(redis.shared().evicted_keys(), 1);
+        assert_eq!(run(&redis, &["exists", &big]), b":0\r\n");
+        for key in &keys {
+            assert_eq!(run(&redis, &["exists", key]), b":1\r\n");
+        }
+    }
+}
+
+// endregion: --- tests
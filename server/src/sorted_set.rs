@@ -0,0 +1,136 @@
+//! Sorted set value type: members ordered by score, ties broken by member bytes
+
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap};
+use std::ops::Bound;
+
+/// A score with a total order so it can key the ordered index.
+#[derive(Clone, Copy, Debug)]
+struct Score(f64);
+
+impl PartialEq for Score {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Score {}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Score {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct SortedSet {
+    scores: HashMap<Vec<u8>, f64>,
+    ordered: BTreeSet<(Score, Vec<u8>)>,
+}
+
+impl SortedSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    pub fn score(&self, member: &[u8]) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    /// Insert or update `member`, returning true when it was not present before.
+    pub fn insert(&mut self, member: &[u8], score: f64) -> bool {
+        match self.scores.insert(member.to_vec(), score) {
+            Some(old) => {
+                self.ordered.remove(&(Score(old), member.to_vec()));
+                self.ordered.insert((Score(score), member.to_vec()));
+                false
+            },
+            None => {
+                self.ordered.insert((Score(score), member.to_vec()));
+                true
+            },
+        }
+    }
+
+    pub fn remove(&mut self, member: &[u8]) -> bool {
+        match self.scores.remove(member) {
+            Some(old) => {
+                self.ordered.remove(&(Score(old), member.to_vec()));
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Members in score order.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (&[u8], f64)> {
+        self.ordered.iter().map(|(score, member)| (member.as_slice(), score.0))
+    }
+
+    /// Members with `min <= score < max`, in score order.
+    pub fn range_by_score(&self, min: f64, max: f64) -> impl Iterator<Item = (&[u8], f64)> {
+        let start = Bound::Included((Score(min), Vec::new()));
+        let end = Bound::Excluded((Score(max), Vec::new()));
+        self.ordered.range((start, end)).map(|(score, member)| (member.as_slice(), score.0))
+    }
+
+    /// Members by rank with Redis style negative indexes, both ends inclusive.
+    pub fn range(&self, start: i64, stop: i64) -> Vec<(&[u8], f64)> {
+        let len = self.len() as i64;
+        let start = if start < 0 { (len + start).max(0) } else { start };
+        let stop = if stop < 0 { len + stop } else { stop.min(len - 1) };
+        if start > stop || start >= len {
+            return Vec::new();
+        }
+
+        self.iter().skip(start as usize).take((stop - start + 1) as usize).collect()
+    }
+}
+
+
+// region: --- tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn orders_by_score_then_member() {
+        let mut zset = SortedSet::new();
+        assert!(zset.insert(b"b", 1.0));
+        assert!(zset.insert(b"a", 1.0));
+        assert!(zset.insert(b"c", 0.5));
+        assert!(!zset.insert(b"c", 2.0));
+
+        let members: Vec<&[u8]> = zset.iter().map(|(member, _)| member).collect();
+        assert_eq!(members, vec![b"a".as_slice(), b"b", b"c"]);
+        assert_eq!(zset.range(-2, -1).len(), 2);
+        assert_eq!(zset.range(5, 10).len(), 0);
+    }
+
+    #[test]
+    fn range_by_score_excludes_max() {
+        let mut zset = SortedSet::new();
+        for (i, member) in [b"a", b"b", b"c", b"d"].iter().enumerate() {
+            zset.insert(*member, i as f64);
+        }
+        let found: Vec<f64> = zset.range_by_score(1.0, 3.0).map(|(_, score)| score).collect();
+        assert_eq!(found, vec![1.0, 2.0]);
+        assert!(zset.remove(b"b"));
+        assert_eq!(zset.range_by_score(1.0, 3.0).count(), 1);
+    }
+}
+// endregion: --- tests
@@ -2,47 +2,143 @@
 use crate::prelude::*;
 use crate::DataType;
 
+/// Longest bulk string accepted, as Redis' default `proto-max-bulk-len`.
+pub const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+/// Most elements accepted in one array.
+pub const MAX_ARRAY_LEN: usize = 1024 * 1024;
 
-pub fn deserialize(input: &str) -> Result<DataType> {
-    match deserialize_helper(input) {
+
+pub fn deserialize<T: AsRef<[u8]> + ?Sized>(input: &T) -> Result<DataType> {
+    match deserialize_helper(input.as_ref(), &mut 0) {
         Ok(result) => Ok(result.0),
         Err(e) => Err(e),
     }
 }
 
-fn deserialize_helper(input: &str) -> Result<(DataType, usize)> {
-    match input.chars().nth(0) {
+/// Deserialize the first value in `input`, also returning how many bytes it used.
+/// Returns `Error::Incomplete` when `input` ends before the value does.
+pub fn deserialize_partial(input: &[u8]) -> Result<(DataType, usize)> {
+    deserialize_helper(input, &mut 0)
+}
+
+/// Values read off a stream as it arrives. What was read is kept along with the elements of
+/// a top level array read so far, and the length it must reach before another try, so a
+/// large value costs one pass instead of one per read.
+#[derive(Default)]
+pub struct Decoder {
+    pending: Vec<u8>,
+    /// Where the unread part of `pending` starts.
+    pos: usize,
+    /// `pending` holds too little for the next value until it is this long.
+    needed: usize,
+    /// The top level array being read: its length and the elements read so far.
+    array: Option<(usize, Vec<DataType>)>,
+}
+
+impl Decoder {
+    pub fn extend(&mut self, bytes: &[u8]) {
+        if self.pos > 0 {
+            self.pending.drain(..self.pos);
+            self.needed = self.needed.saturating_sub(self.pos);
+            self.pos = 0;
+        }
+        self.pending.extend_from_slice(bytes);
+    }
+
+    /// The next complete value, `None` until more input arrives.
+    pub fn decode(&mut self) -> Result<Option<DataType>> {
+        loop {
+            if self.pending.len() < self.needed {
+                return Ok(None);
+            }
+            let input = &self.pending[self.pos..];
+            let mut needed = 0;
+
+            if let Some((len, items)) = &mut self.array {
+                if items.len() == *len {
+                    let items = std::mem::take(items);
+                    self.array = None;
+                    return Ok(Some(DataType::Array(Some(items))));
+                }
+                match deserialize_helper(input, &mut needed) {
+                    Ok((item, used)) => {
+                        items.push(item);
+                        self.pos += used;
+                    },
+                    Err(Error::Incomplete) | Err(Error::EmptyInput) => {
+                        self.needed = self.pos + needed.max(1);
+                        return Ok(None);
+                    },
+                    Err(e) => return Err(e),
+                }
+                continue;
+            }
+
+            // the header of a non empty array starts one kept across reads
+            if input.first() == Some(&b'*') {
+                if let Some(crlf_loc) = find_crlf(input) {
+                    let len = array_len(&input[1..crlf_loc])?;
+                    if len > 0 {
+                        self.array = Some((len as usize, Vec::new()));
+                        self.pos += crlf_loc + 2;
+                        continue;
+                    }
+                }
+            }
+            match deserialize_helper(input, &mut needed) {
+                Ok((value, used)) => {
+                    self.pos += used;
+                    return Ok(Some(value));
+                },
+                Err(Error::Incomplete) | Err(Error::EmptyInput) => {
+                    self.needed = self.pos + needed.max(1);
+                    return Ok(None);
+                },
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Deserialize the first value in `input`. When it ends too soon, `needed` is set to how
+/// long `input` has to be for another try to get further.
+fn deserialize_helper(input: &[u8], needed: &mut usize) -> Result<(DataType, usize)> {
+    *needed = input.len() + 1;
+    match input.first() {
         Some(resp_type) => match resp_type {
-            '+' => {
+            b'+' => {
                 match parse_crlf( &input[1..]) {
-                    Ok(val) => Ok((DataType::SimpleString(val.0), val.1+1)),
+                    Ok(val) => Ok((DataType::SimpleString(to_string(val.0)?), val.1+1)),
                     Err(e) => Err(e),
                 }
             },
-            '-' => {
+            b'-' => {
                 match parse_crlf( &input[1..]) {
-                    Ok(val) => Ok((DataType::Error(val.0), val.1+1)),
+                    Ok(val) => Ok((DataType::Error(to_string(val.0)?), val.1+1)),
                     Err(e) => Err(e),
                 }
             },
-            ':' => {
-                match input.find("\r\n") {
+            b':' => {
+                match find_crlf(input) {
                     Some(crlf_loc) => {
                         let num_part = &input[1..crlf_loc];
-                        match num_part.parse::<i64>() {
+                        match to_string(num_part.to_vec())?.parse::<i64>() {
                             Ok(val) => Ok((DataType::Integer(val), crlf_loc+2)),
-                            Err(_) => Err(Error::ParseError(f!("integer parse"))),
+                            Err(_) => Err(Error::ParseError("integer parse".to_owned())),
                         }
                     },
-                    _ => Err(Error::ParseError(f!("integer parse crlf error"))),
+                    _ => Err(Error::Incomplete),
                 }
             },
-            '$' => {
-                match input.find("\r\n") {
+            b'$' => {
+                match find_crlf(input) {
                     Some(crlf_loc) => {
-                        let len: i64 = input[1..crlf_loc]
+                        let len: i64 = to_string(input[1..crlf_loc].to_vec())?
                             .parse()
-                            .map_err(|_| Error::ParseError(f!("bulk string length parse")))?;
+                            .map_err(|_| Error::ParseError("bulk string length parse".to_owned()))?;
+                        if len > MAX_BULK_LEN as i64 {
+                            return Err(Error::ParseError("invalid bulk length".to_owned()));
+                        }
 
 
                         if len == -1 {
@@ -50,7 +146,7 @@ fn deserialize_helper(input: &str) -> Result<(DataType, usize)> {
                         }
 
                         if len < 0 {
-                            return Err(Error::ParseError(f!("bulk string parse")));
+                            return Err(Error::ParseError("bulk string parse".to_owned()));
                         }
 
                         let ulen: usize = usize::try_from(len).unwrap();
@@ -58,34 +154,30 @@ fn deserialize_helper(input: &str) -> Result<(DataType, usize)> {
                         let end_pos = start_pos + ulen + 2;
 
                         if end_pos > input.len() {
-                            return Err(Error::ParseError(f!("out of bounds bulk parse")));
+                            *needed = end_pos;
+                            return Err(Error::Incomplete);
                         }
 
-                        match parse_crlf( &input[start_pos..end_pos]) {
-                            Ok(val) => Ok((DataType::BulkString(Some(val.0)), end_pos)),
-                            Err(e) => Err(e),
+                        if &input[end_pos-2..end_pos] != b"\r\n" {
+                            return Err(Error::ParseError("bulk string missing crlf".to_owned()));
                         }
+
+                        Ok((DataType::BulkString(Some(input[start_pos..end_pos-2].to_vec())), end_pos))
                     },
-                    _ => Err(Error::ParseError(f!("bulk string parse")))
+                    _ => Err(Error::Incomplete)
                 }
             },
-            '*' => {
-                match input.find("\r\n") {
+            b'*' => {
+                match find_crlf(input) {
                     Some(crlf_loc) => {
-                        let len: i64 = input[1..crlf_loc]
-                            .parse()
-                            .map_err(|_| Error::ParseError(f!("array parse invalid length")))?;
+                        let len = array_len(&input[1..crlf_loc])?;
 
                         if len == -1 {
                             return Ok((DataType::Array(None), crlf_loc+2));
                         }
 
-                        if len < 0 {
-                            return Err(Error::ParseError(f!("array parse invalid length")));
-                        }
-
                         if len == 0 {
-                            return Ok((DataType::Array(Some(Vec::new())), crlf_loc+1));
+                            return Ok((DataType::Array(Some(Vec::new())), crlf_loc+2));
                         }
 
                         let ulen: usize = usize::try_from(len).unwrap();
@@ -94,11 +186,15 @@ fn deserialize_helper(input: &str) -> Result<(DataType, usize)> {
                         let mut result_array: Vec<DataType> = Vec::new();
 
                         while cur_len < ulen {
-                            match deserialize_helper(&input[input_index..]) {
+                            match deserialize_helper(&input[input_index..], needed) {
                                 Ok(result) => {
                                     result_array.push(result.0);
                                     input_index += result.1;
                                 },
+                                Err(Error::EmptyInput) | Err(Error::Incomplete) => {
+                                    *needed += input_index;
+                                    return Err(Error::Incomplete);
+                                },
                                 Err(e) => return Err(e),
                             }
 
@@ -107,7 +203,7 @@ fn deserialize_helper(input: &str) -> Result<(DataType, usize)> {
 
                         Ok((DataType::Array(Some(result_array)), input_index))
                     },
-                    _ => Err(Error::ParseError(f!("array parse crlf not found")))
+                    _ => Err(Error::Incomplete)
                 }
             },
             _ => Err(Error::IdentifierInvalid),
@@ -116,14 +212,33 @@ fn deserialize_helper(input: &str) -> Result<(DataType, usize)> {
     }
 }
 
-fn parse_crlf(input: &str) -> Result<(String, usize)> {
+/// The length in an array header: -1 for a null array, otherwise up to `MAX_ARRAY_LEN`.
+fn array_len(digits: &[u8]) -> Result<i64> {
+    let len: i64 = to_string(digits.to_vec())?
+        .parse()
+        .map_err(|_| Error::ParseError("array parse invalid length".to_owned()))?;
+    if len < -1 || len > MAX_ARRAY_LEN as i64 {
+        return Err(Error::ParseError("invalid multibulk length".to_owned()));
+    }
+    Ok(len)
+}
+
+fn find_crlf(input: &[u8]) -> Option<usize> {
+    input.windows(2).position(|window| window == b"\r\n")
+}
+
+fn to_string(input: Vec<u8>) -> Result<String> {
+    String::from_utf8(input).map_err(|_| Error::ParseError("invalid utf-8".to_owned()))
+}
+
+fn parse_crlf(input: &[u8]) -> Result<(Vec<u8>, usize)> {
     if input.len() < 2 {
-        return Err(Error::ParseError(f!("length error Basic parse")));
+        return Err(Error::Incomplete);
     }
 
-    match input.find("\r\n") {
-        Some(crlf_loc) => Ok((input[..crlf_loc].to_string(), crlf_loc+2)),
-            _ => Err(Error::ParseError(f!("mssing crlf Basic parse"))),
+    match find_crlf(input) {
+        Some(crlf_loc) => Ok((input[..crlf_loc].to_vec(), crlf_loc+2)),
+            _ => Err(Error::Incomplete),
     }
 }
 
@@ -170,7 +285,7 @@ mod tests {
     #[test]
     fn bulk_string_happy() {
         let tests = ["$5\r\nhello\r\n", "$0\r\n\r\n", "$-1\r\n"];
-        let expected = [Some("hello".to_owned()), Some("".to_owned()), None];
+        let expected = [Some(b"hello".to_vec()), Some(b"".to_vec()), None];
 
         for (test, expect)  in zip(tests, expected) {
             let result = deserialize(test).unwrap();
@@ -190,11 +305,11 @@ mod tests {
             "*0\r\n",
         ];
         let expected = [
-            Some(vec![DataType::BulkString(Some("hello".to_owned())), DataType::BulkString(Some("world".to_owned()))]),
-            Some(vec![DataType::Integer(1), DataType::Integer(2), DataType::Integer(3), DataType::BulkString(Some("hello".to_owned()))]),
-            Some(vec![DataType::BulkString(Some("ping".to_owned()))]),
-            Some(vec![DataType::BulkString(Some("echo".to_owned())), DataType::BulkString(Some("hello world".to_owned()))]),
-            Some(vec![DataType::BulkString(Some("get".to_owned())), DataType::BulkString(Some("key".to_owned()))]),
+            Some(vec![DataType::BulkString(Some(b"hello".to_vec())), DataType::BulkString(Some(b"world".to_vec()))]),
+            Some(vec![DataType::Integer(1), DataType::Integer(2), DataType::Integer(3), DataType::BulkString(Some(b"hello".to_vec()))]),
+            Some(vec![DataType::BulkString(Some(b"ping".to_vec()))]),
+            Some(vec![DataType::BulkString(Some(b"echo".to_vec())), DataType::BulkString(Some(b"hello world".to_vec()))]),
+            Some(vec![DataType::BulkString(Some(b"get".to_vec())), DataType::BulkString(Some(b"key".to_vec()))]),
             None,
             Some(vec![]),
         ];
@@ -220,12 +335,12 @@ mod tests {
         let expected: [Vec<DataType>; 1] = [
             vec![
                 DataType::Array(Some(vec![
-                    DataType::BulkString(Some("hello".to_owned())),
-                    DataType::BulkString(Some("world".to_owned()))]
+                    DataType::BulkString(Some(b"hello".to_vec())),
+                    DataType::BulkString(Some(b"world".to_vec()))]
                 )),
                 DataType::Array(Some(vec![
                     DataType::Integer(1), DataType::Integer(2),
-                    DataType::Integer(3), DataType::BulkString(Some("hello".to_owned()))]
+                    DataType::Integer(3), DataType::BulkString(Some(b"hello".to_vec()))]
                 ))
             ]
         ];
@@ -265,5 +380,42 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn decoder_reads_values_split_anywhere() {
+        let input = b"*2\r\n$3\r\nget\r\n$1\r\na\r\n+OK\r\n*0\r\n";
+        let mut decoder = Decoder::default();
+        let mut values = Vec::new();
+        for byte in input {
+            decoder.extend(&[*byte]);
+            while let Some(value) = decoder.decode().unwrap() {
+                values.push(value);
+            }
+        }
+        assert_eq!(values, [
+            DataType::Array(Some(vec![DataType::BulkString(Some(b"get".to_vec())), DataType::BulkString(Some(b"a".to_vec()))])),
+            DataType::SimpleString("OK".to_owned()),
+            DataType::Array(Some(Vec::new())),
+        ]);
+    }
+
+    #[test]
+    fn decoder_waits_for_the_declared_length() {
+        let mut decoder = Decoder::default();
+        decoder.extend(b"*1\r\n$10\r\nab");
+        assert_eq!(decoder.decode(), Ok(None));
+        assert_eq!(decoder.needed, 21);
+        decoder.extend(b"cdefghij\r\n");
+        assert_eq!(decoder.decode(), Ok(Some(DataType::Array(Some(vec![DataType::BulkString(Some(b"abcdefghij".to_vec()))])))));
+    }
+
+    #[test]
+    fn lengths_over_the_limits_are_refused() {
+        assert!(deserialize(&f!("${}\r\n", MAX_BULK_LEN + 1)).is_err());
+        assert!(deserialize(&f!("*{}\r\n", MAX_ARRAY_LEN + 1)).is_err());
+        let mut decoder = Decoder::default();
+        decoder.extend(f!("*{}\r\n", MAX_ARRAY_LEN + 1).as_bytes());
+        assert!(decoder.decode().is_err());
+    }
 }
 // endregion: --- tests
@@ -9,6 +9,8 @@ pub enum Error {
     IdentifierInvalid,
     #[error("Input for deserialization empty")]
     EmptyInput,
+    #[error("Input ended before the value was complete")]
+    Incomplete,
     #[error("Parse error: {0}")]
     ParseError(String),
     #[error("SerializeError")]
@@ -6,12 +6,12 @@ pub mod serializer;
 pub mod prelude;
 pub mod error;
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum DataType {
     SimpleString(String),
     Error(String),
     Integer(i64),
-    BulkString(Option<String>),
+    BulkString(Option<Vec<u8>>),
     Array(Option<Vec<DataType>>),
 }
 
@@ -20,8 +20,8 @@ impl PartialEq<&str> for DataType {
         match self {
             DataType::SimpleString(s) => s == *other,
             DataType::Error(e) => e == *other,
-            DataType::BulkString(Some(s)) => s == *other,
-            DataType::BulkString(None) => *other == "",
+            DataType::BulkString(Some(s)) => s == other.as_bytes(),
+            DataType::BulkString(None) => other.is_empty(),
             _ => false, // For Integer or Array, return false
         }
     }
@@ -33,7 +33,17 @@ impl fmt::Display for DataType {
             DataType::SimpleString(val) => write!(f, "\"{}\"", val),
             DataType::Error(err) => write!(f, "{}", err),
             DataType::Integer(num) => write!(f, "(integer) {}", num),
-            DataType::BulkString(Some(val)) => write!(f, "\"{}\"", val),
+            DataType::BulkString(Some(val)) => {
+                write!(f, "\"")?;
+                for byte in val {
+                    match byte {
+                        b'"' | b'\\' => write!(f, "\\{}", *byte as char)?,
+                        0x20..=0x7e => write!(f, "{}", *byte as char)?,
+                        _ => write!(f, "\\x{:02x}", byte)?,
+                    }
+                }
+                write!(f, "\"")
+            },
             DataType::BulkString(None) => write!(f, "(nil)"),
             DataType::Array(Some(arr)) => {
                 write!(f, "Array: [")?;
@@ -3,24 +3,27 @@ use crate::prelude::*;
 use crate::DataType;
 
 
-pub fn serialize(input: &DataType) -> Result<String> {
+pub fn serialize(input: &DataType) -> Result<Vec<u8>> {
     match input {
-        DataType::SimpleString(val) => Ok(f!("+{val}\r\n")),
-        DataType::Error(val) => Ok(f!("-{val}\r\n")),
-        DataType::Integer(val) => Ok(f!(":{val}\r\n")),
+        DataType::SimpleString(val) => Ok(f!("+{val}\r\n").into_bytes()),
+        DataType::Error(val) => Ok(f!("-{val}\r\n").into_bytes()),
+        DataType::Integer(val) => Ok(f!(":{val}\r\n").into_bytes()),
         DataType::BulkString(o_val) => {
             if let Some(val) = o_val {
-                return Ok(f!("${}\r\n{}\r\n", val.len(), val));
+                let mut serialized = f!("${}\r\n", val.len()).into_bytes();
+                serialized.extend_from_slice(val);
+                serialized.extend_from_slice(b"\r\n");
+                return Ok(serialized);
             }
 
-            return Ok("$-1\r\n".to_string());
+            Ok(b"$-1\r\n".to_vec())
         },
         DataType::Array(o_arr) => {
             if let Some(arr) = o_arr {
-                let mut serialized = f!("*{}\r\n", arr.len());
+                let mut serialized = f!("*{}\r\n", arr.len()).into_bytes();
                 for data in arr {
                     match serialize(data) {
-                        Ok(val) => serialized.push_str(&val),
+                        Ok(val) => serialized.extend_from_slice(&val),
                         Err(e) => return Err(e),
                     }
                 }
@@ -28,7 +31,7 @@ pub fn serialize(input: &DataType) -> Result<String> {
                 return Ok(serialized);
             }
 
-            return Ok("*-1\r\n".to_string());
+            Ok(b"*-1\r\n".to_vec())
         }
     }
 }
@@ -42,23 +45,23 @@ mod tests {
 
     #[test]
     fn simple_string_happy() {
-        let tests = [DataType::SimpleString(f!("")), DataType::SimpleString(f!("test"))];
+        let tests = [DataType::SimpleString(String::new()), DataType::SimpleString("test".to_owned())];
         let expected = ["+\r\n", "+test\r\n"];
 
         for (test, expect)  in zip(tests, expected) {
             let result = serialize(&test).unwrap();
-            assert_eq!(&result, expect);
+            assert_eq!(result, expect.as_bytes());
         }
     }
 
     #[test]
     fn errors_happy() {
-        let tests = [DataType::Error(f!("")), DataType::Error(f!("ERROR error"))];
+        let tests = [DataType::Error(String::new()), DataType::Error("ERROR error".to_owned())];
         let expected = ["-\r\n", "-ERROR error\r\n"];
 
         for (test, expect)  in zip(tests, expected) {
             let result = serialize(&test).unwrap();
-            assert_eq!(&result, expect);
+            assert_eq!(result, expect.as_bytes());
         }
     }
 
@@ -69,29 +72,29 @@ mod tests {
 
         for (test, expect)  in zip(tests, expected) {
             let result = serialize(&test).unwrap();
-            assert_eq!(&result, expect);
+            assert_eq!(result, expect.as_bytes());
         }
     }
 
     #[test]
     fn bulk_string_happy() {
-        let tests = [DataType::BulkString(Some(f!(""))), DataType::BulkString(Some(f!("test"))), DataType::BulkString(None)];
+        let tests = [DataType::BulkString(Some(b"".to_vec())), DataType::BulkString(Some(b"test".to_vec())), DataType::BulkString(None)];
         let expected = ["$0\r\n\r\n", "$4\r\ntest\r\n", "$-1\r\n"];
 
         for (test, expect)  in zip(tests, expected) {
             let result = serialize(&test).unwrap();
-            assert_eq!(&result, expect);
+            assert_eq!(result, expect.as_bytes());
         }
     }
 
     #[test]
     fn arrays_happy() {
         let tests = [
-            DataType::Array(Some(vec![DataType::BulkString(Some("hello".to_owned())), DataType::BulkString(Some("world".to_owned()))])),
-            DataType::Array(Some(vec![DataType::Integer(1), DataType::Integer(2), DataType::Integer(3), DataType::BulkString(Some("hello".to_owned()))])),
-            DataType::Array(Some(vec![DataType::BulkString(Some("ping".to_owned()))])),
-            DataType::Array(Some(vec![DataType::BulkString(Some("echo".to_owned())), DataType::BulkString(Some("hello world".to_owned()))])),
-            DataType::Array(Some(vec![DataType::BulkString(Some("get".to_owned())), DataType::BulkString(Some("key".to_owned()))])),
+            DataType::Array(Some(vec![DataType::BulkString(Some(b"hello".to_vec())), DataType::BulkString(Some(b"world".to_vec()))])),
+            DataType::Array(Some(vec![DataType::Integer(1), DataType::Integer(2), DataType::Integer(3), DataType::BulkString(Some(b"hello".to_vec()))])),
+            DataType::Array(Some(vec![DataType::BulkString(Some(b"ping".to_vec()))])),
+            DataType::Array(Some(vec![DataType::BulkString(Some(b"echo".to_vec())), DataType::BulkString(Some(b"hello world".to_vec()))])),
+            DataType::Array(Some(vec![DataType::BulkString(Some(b"get".to_vec())), DataType::BulkString(Some(b"key".to_vec()))])),
             DataType::Array(None),
             DataType::Array(Some(vec![])),
         ];
@@ -107,7 +110,7 @@ mod tests {
 
         for (test, expect)  in zip(tests, expected) {
             let result = serialize(&test).unwrap();
-            assert_eq!(&result, expect);
+            assert_eq!(result, expect.as_bytes());
         }
     }
 }